@@ -0,0 +1,326 @@
+// Opens a growing number of chatting connections against a running server and
+// reports how long messages take to arrive, eg
+//
+//     cargo run --release --example loadtest -- --clients 100 --ramp 100 --max-clients 1000
+//
+// Each room has an observer connection that never talks, which times every
+// message from when it was sent. Senders are spread evenly across the rooms.
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::command::Command;
+use rand::Rng;
+use tokio::time::{self, Instant};
+
+// How long to wait for messages still on their way once sending stops
+const DRAIN: Duration = Duration::from_secs(2);
+
+struct Options {
+    addr: SocketAddr,
+    clients: usize,
+    rooms: usize,
+    // Messages per second from each client
+    rate: f64,
+    stage: Duration,
+    // Clients added each stage, until there are `max_clients`
+    ramp: usize,
+    max_clients: usize,
+}
+
+#[derive(Default)]
+struct Stage {
+    clients: usize,
+    sent: u64,
+    received: u64,
+    errors: u64,
+    latencies: Vec<Duration>,
+}
+
+// Shared by every connection, messages are counted against the stage they
+// were sent in
+struct Run {
+    start: Instant,
+    stage: AtomicUsize,
+    stages: Mutex<Vec<Stage>>,
+    stopping: AtomicBool,
+}
+
+impl Run {
+    fn record(&self, stage: usize, f: impl FnOnce(&mut Stage)) {
+        let mut stages = self.stages.lock().unwrap();
+        if let Some(stage) = stages.get_mut(stage) {
+            f(stage);
+        }
+    }
+
+    fn error(&self) {
+        self.record(self.stage.load(Ordering::Relaxed), |s| s.errors += 1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "Usage: loadtest [--addr 127.0.0.1:8000] [--clients 100] [--rooms 10] [--rate 1] \
+                 [--stage-secs 10] [--ramp 0] [--max-clients n]"
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let rooms: Vec<String> = (0..options.rooms).map(|i| format!("load-{}", i)).collect();
+    if let Err(e) = create_rooms(options.addr, &rooms).await {
+        eprintln!("Couldn't create rooms: {}", e);
+        std::process::exit(1);
+    }
+
+    let run = Arc::new(Run {
+        start: Instant::now(),
+        stage: AtomicUsize::new(0),
+        stages: Mutex::new(Vec::new()),
+        stopping: AtomicBool::new(false),
+    });
+
+    for (i, room) in rooms.iter().enumerate() {
+        let mut observer = match join(options.addr, &format!("observer-{}", i), room).await {
+            Ok(observer) => observer,
+            Err(e) => {
+                eprintln!("Observer for {} couldn't join: {}", room, e);
+                std::process::exit(1);
+            }
+        };
+        let run = Arc::clone(&run);
+        tokio::spawn(async move { observe(&mut observer, &run).await });
+    }
+
+    let mut clients = 0;
+    let mut target = options.clients;
+    loop {
+        let stage = run.stage.load(Ordering::Relaxed);
+        run.stages.lock().unwrap().push(Stage {
+            clients: target,
+            ..Stage::default()
+        });
+        eprintln!("Stage {}: {} clients", stage + 1, target);
+
+        while clients < target {
+            let room = rooms[clients % rooms.len()].clone();
+            let username = format!("load-{}", clients);
+            let run = Arc::clone(&run);
+            let (addr, rate) = (options.addr, options.rate);
+            tokio::spawn(async move { chat(addr, &username, &room, rate, &run).await });
+            clients += 1;
+        }
+
+        time::sleep(options.stage).await;
+
+        if options.ramp == 0 || target >= options.max_clients {
+            break;
+        }
+        target = (target + options.ramp).min(options.max_clients);
+        run.stage.fetch_add(1, Ordering::Relaxed);
+    }
+
+    run.stopping.store(true, Ordering::Relaxed);
+    time::sleep(DRAIN).await;
+
+    print_summary(&mut run.stages.lock().unwrap());
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        addr: SocketAddr::from(([127, 0, 0, 1], 8000)),
+        clients: 100,
+        rooms: 10,
+        rate: 1.0,
+        stage: Duration::from_secs(10),
+        ramp: 0,
+        max_clients: 0,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Error: {} requires a value", flag))?;
+
+        match flag.as_str() {
+            "--addr" => options.addr = parse(&flag, &value)?,
+            "--clients" => options.clients = parse(&flag, &value)?,
+            "--rooms" => options.rooms = parse(&flag, &value)?,
+            "--rate" => options.rate = parse(&flag, &value)?,
+            "--stage-secs" => options.stage = Duration::from_secs(parse(&flag, &value)?),
+            "--ramp" => options.ramp = parse(&flag, &value)?,
+            "--max-clients" => options.max_clients = parse(&flag, &value)?,
+            _ => return Err(format!("Error: Unknown flag '{}'", flag)),
+        }
+    }
+
+    if options.clients == 0 || options.rooms == 0 {
+        return Err("Error: --clients and --rooms must be positive".to_owned());
+    }
+    if options.rate <= 0.0 || !options.rate.is_finite() {
+        return Err("Error: --rate must be positive".to_owned());
+    }
+    options.max_clients = options.max_clients.max(options.clients);
+
+    Ok(options)
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Error: Invalid value '{}' for {}", value, flag))
+}
+
+// Rooms left over from an earlier run are reused
+async fn create_rooms(
+    addr: SocketAddr,
+    rooms: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = Client::connect(addr).await?;
+    client.set_username("loadtest").await?;
+    for room in rooms {
+        client.create_room(room).await?;
+    }
+
+    // Once they're all listed they've all been created
+    client.command(Command::List(Default::default())).await?;
+    let mut listed = 0;
+    while listed < rooms.len() {
+        if let ServerEvent::Info(line) = client.next_event().await? {
+            if rooms.contains(&line) {
+                listed += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn join(
+    addr: SocketAddr,
+    username: &str,
+    room: &str,
+) -> Result<Client, Box<dyn std::error::Error>> {
+    let mut client = Client::connect(addr).await?;
+    client.set_username(username).await?;
+    client.join(room).await?;
+
+    // Commands are handled in order, so once the room's listed it's joined
+    client.command(Command::List(Default::default())).await?;
+    loop {
+        match client.next_event().await? {
+            ServerEvent::Info(line) if line == room => return Ok(client),
+            ServerEvent::Error { text, .. } => return Err(text.into()),
+            _ => {}
+        }
+    }
+}
+
+async fn observe(observer: &mut Client, run: &Run) {
+    while let Ok(Some(event)) = observer.recv().await {
+        let ServerEvent::Chat { text, .. } = event else {
+            continue;
+        };
+        let Some((stage, sent)) = parse_message(&text) else {
+            continue;
+        };
+
+        let latency = run.start.elapsed().saturating_sub(sent);
+        run.record(stage, |s| {
+            s.received += 1;
+            s.latencies.push(latency);
+        });
+    }
+}
+
+// Sends at `rate` until the run stops, reading whatever arrives in between so
+// the server never has to wait on it
+async fn chat(addr: SocketAddr, username: &str, room: &str, rate: f64, run: &Run) {
+    let mut client = match join(addr, username, room).await {
+        Ok(client) => client,
+        Err(_) => return run.error(),
+    };
+
+    let interval = Duration::from_secs_f64(1.0 / rate);
+    // Spread out so everyone doesn't send at once
+    let offset = interval.mul_f64(rand::rng().random());
+    let mut next = Instant::now() + offset;
+
+    while !run.stopping.load(Ordering::Relaxed) {
+        loop {
+            match time::timeout_at(next, client.recv()).await {
+                Ok(Ok(Some(ServerEvent::Error { .. }))) => run.error(),
+                Ok(Ok(Some(_))) => {}
+                Ok(Ok(None)) | Ok(Err(_)) => return run.error(),
+                Err(_) => break,
+            }
+        }
+
+        let stage = run.stage.load(Ordering::Relaxed);
+        let sent = run.start.elapsed();
+        match client
+            .send(&format!("lt {} {}", stage, sent.as_micros()))
+            .await
+        {
+            Ok(()) => run.record(stage, |s| s.sent += 1),
+            Err(_) => return run.error(),
+        }
+
+        next += interval;
+    }
+}
+
+// `lt <stage> <micros since the start>`
+fn parse_message(text: &str) -> Option<(usize, Duration)> {
+    let mut parts = text.strip_prefix("lt ")?.split(' ');
+    let stage = parts.next()?.parse().ok()?;
+    let sent = parts.next()?.parse().ok()?;
+
+    Some((stage, Duration::from_micros(sent)))
+}
+
+fn print_summary(stages: &mut [Stage]) {
+    println!(
+        "{:>7} {:>9} {:>9} {:>7} {:>7} {:>9} {:>9} {:>9}",
+        "clients", "sent", "received", "drops", "errors", "p50", "p95", "p99"
+    );
+
+    for stage in stages {
+        stage.latencies.sort();
+        // Observers see each message once, so anything unseen was dropped
+        let drops = stage.sent.saturating_sub(stage.received);
+
+        println!(
+            "{:>7} {:>9} {:>9} {:>7} {:>7} {:>9} {:>9} {:>9}",
+            stage.clients,
+            stage.sent,
+            stage.received,
+            drops,
+            stage.errors,
+            percentile(&stage.latencies, 0.50),
+            percentile(&stage.latencies, 0.95),
+            percentile(&stage.latencies, 0.99),
+        );
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> String {
+    if sorted.is_empty() {
+        return "-".to_owned();
+    }
+
+    let i = ((sorted.len() - 1) as f64 * p).round() as usize;
+
+    format!("{:.1}ms", sorted[i].as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,111 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use tokio::sync::RwLock;
+
+use crate::ban::IpNet;
+use crate::config::AccessConfig;
+use crate::store::{RoomStore, StoreError};
+
+// Added to `access.allow` and `access.deny` from the config file, so they
+// can be changed without a reload, eg with `redis-cli SADD`
+pub const ALLOW_KEY: &str = "server:ipallow";
+pub const DENY_KEY: &str = "server:ipdeny";
+
+// How long the accept loops trust their copy of the sets
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+// The line sent to connections that aren't let in, before they're closed
+pub const DENIED: &str = "Access denied";
+
+// Shared by the accept loops, like `BanList`
+#[derive(Default)]
+pub struct AccessList {
+    cache: RwLock<Option<(Instant, Nets)>>,
+}
+
+#[derive(Clone, Default)]
+struct Nets {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+/// Whether `addr` may connect at all. A deny entry covering it wins over any
+/// allow entry, and with no allow entries everyone not denied is let in.
+/// Otherwise an allow entry has to cover it. IPv4 clients of a dual stack
+/// listener are matched as IPv4.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::access::permits;
+/// use chatsapp::ban::IpNet;
+///
+/// let nets = |nets: &[&str]| nets.iter().map(|net| IpNet::parse(net).unwrap()).collect::<Vec<_>>();
+/// let addr = |addr: &str| addr.parse().unwrap();
+///
+/// // Nothing set lets everyone in
+/// assert!(permits(&[], &[], addr("203.0.113.7")));
+///
+/// let allow = nets(&["10.0.0.0/8", "2001:db8::/32"]);
+/// assert!(permits(&allow, &[], addr("10.1.2.3")));
+/// assert!(permits(&allow, &[], addr("::ffff:10.1.2.3")));
+/// assert!(permits(&allow, &[], addr("2001:db8:1::1")));
+/// assert!(!permits(&allow, &[], addr("203.0.113.7")));
+/// assert!(!permits(&allow, &[], addr("2001:db9::1")));
+///
+/// // Denying wins, however narrow
+/// let deny = nets(&["10.0.0.5", "2001:db8:bad::/48"]);
+/// assert!(!permits(&allow, &deny, addr("10.0.0.5")));
+/// assert!(permits(&allow, &deny, addr("10.0.0.6")));
+/// assert!(!permits(&allow, &deny, addr("2001:db8:bad::1")));
+/// assert!(!permits(&[], &deny, addr("10.0.0.5")));
+/// assert!(permits(&[], &deny, addr("203.0.113.7")));
+/// ```
+pub fn permits(allow: &[IpNet], deny: &[IpNet], addr: IpAddr) -> bool {
+    if deny.iter().any(|net| net.contains(addr)) {
+        return false;
+    }
+
+    allow.is_empty() || allow.iter().any(|net| net.contains(addr))
+}
+
+impl AccessList {
+    // `permits` with the config's lists and the sets, from a copy of the sets
+    // at most `CACHE_TTL` old
+    pub async fn permits(
+        &self,
+        store: &dyn RoomStore,
+        config: &AccessConfig,
+        addr: IpAddr,
+    ) -> Result<bool, StoreError> {
+        let nets = self.nets(store).await?;
+        let allow: Vec<IpNet> = config.allow.iter().chain(&nets.allow).copied().collect();
+        let deny: Vec<IpNet> = config.deny.iter().chain(&nets.deny).copied().collect();
+
+        Ok(permits(&allow, &deny, addr))
+    }
+
+    async fn nets(&self, store: &dyn RoomStore) -> Result<Nets, StoreError> {
+        if let Some((fetched, nets)) = &*self.cache.read().await {
+            if fetched.elapsed() < CACHE_TTL {
+                return Ok(nets.clone());
+            }
+        }
+
+        // Anything that isn't an address or range is left out
+        let parse = |members: Vec<String>| -> Vec<IpNet> {
+            members
+                .iter()
+                .filter_map(|member| IpNet::parse(member).ok())
+                .collect()
+        };
+        let nets = Nets {
+            allow: parse(store.set_members(ALLOW_KEY).await?),
+            deny: parse(store.set_members(DENY_KEY).await?),
+        };
+        *self.cache.write().await = Some((Instant::now(), nets.clone()));
+
+        Ok(nets)
+    }
+}
@@ -0,0 +1,191 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::hash;
+use crate::store::{RoomStore, StoreError};
+use crate::username;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+// Given to every connection until it sets a name, and to anyone who doesn't
+// log in as the registered name they've set, so they can't be set or
+// registered themselves
+pub const GUEST_PREFIX: &str = "guest-";
+
+/// What happens when a registered name logs in while it's already connected,
+/// set with `>set multi-login`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MultiLogin {
+    Allow,
+    // The older connections are closed
+    #[default]
+    KickOld,
+    Deny,
+}
+
+impl MultiLogin {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "allow" => Some(MultiLogin::Allow),
+            "kick-old" => Some(MultiLogin::KickOld),
+            "deny" => Some(MultiLogin::Deny),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for MultiLogin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultiLogin::Allow => write!(f, "allow"),
+            MultiLogin::KickOld => write!(f, "kick-old"),
+            MultiLogin::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// Registers `username` with a password, returning false if it already is.
+/// Setting a registered name then needs `>login <password>` within
+/// `limits.login_grace_secs`, or the connection is renamed to a guest, and it
+/// can't join rooms until then.
+pub async fn register(
+    store: &dyn RoomStore,
+    username: &str,
+    password: &str,
+) -> Result<bool, StoreError> {
+    if is_registered(store, username).await? {
+        return Ok(false);
+    }
+
+    set_password(store, username, password).await?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    store
+        .hash_set(&key(username), "registered", &now.to_string())
+        .await?;
+
+    Ok(true)
+}
+
+/// Every connection starts out as a guest, so it can join rooms before picking
+/// a name.
+pub fn is_guest(username: &str) -> bool {
+    username::fold(username).starts_with(GUEST_PREFIX)
+}
+
+pub async fn is_registered(store: &dyn RoomStore, username: &str) -> Result<bool, StoreError> {
+    Ok(store.hash_get(&key(username), "password").await?.is_some())
+}
+
+// False if the name isn't registered. Hashes from before Argon2 are replaced
+// once they've been verified.
+pub async fn verify(
+    store: &dyn RoomStore,
+    username: &str,
+    password: &str,
+) -> Result<bool, StoreError> {
+    let Some(stored) = store.hash_get(&key(username), "password").await? else {
+        return Ok(false);
+    };
+    let legacy = hash::is_legacy(&stored);
+
+    let owned = password.to_owned();
+    let verified = tokio::task::spawn_blocking(move || hash::verify_password(&owned, &stored))
+        .await
+        .unwrap_or(false);
+
+    if verified && legacy {
+        set_password(store, username, password).await?;
+    }
+
+    Ok(verified)
+}
+
+pub async fn set_password(
+    store: &dyn RoomStore,
+    username: &str,
+    password: &str,
+) -> Result<(), StoreError> {
+    // Hashing is slow on purpose, so is kept off the async workers
+    let password = password.to_owned();
+    let stored = tokio::task::spawn_blocking(move || hash::hash_password(&password))
+        .await
+        .expect("hashing doesn't panic");
+
+    store.hash_set(&key(username), "password", &stored).await
+}
+
+pub async fn multi_login(store: &dyn RoomStore, username: &str) -> Result<MultiLogin, StoreError> {
+    let policy = store.hash_get(&key(username), "multilogin").await?;
+
+    Ok(policy
+        .as_deref()
+        .and_then(MultiLogin::parse)
+        .unwrap_or_default())
+}
+
+pub async fn set_multi_login(
+    store: &dyn RoomStore,
+    username: &str,
+    policy: MultiLogin,
+) -> Result<(), StoreError> {
+    store
+        .hash_set(&key(username), "multilogin", &policy.to_string())
+        .await
+}
+
+/// Whether `>dnd` is on for a registered name, None if it's never been set.
+/// Direct messages to them are refused, telling the sender, and mentions
+/// aren't kept for them. Sending a direct message turns it off.
+pub async fn dnd(store: &dyn RoomStore, username: &str) -> Result<Option<bool>, StoreError> {
+    let dnd = store.hash_get(&key(username), "dnd").await?;
+
+    Ok(dnd.map(|dnd| dnd == "on"))
+}
+
+pub async fn set_dnd(store: &dyn RoomStore, username: &str, on: bool) -> Result<(), StoreError> {
+    let dnd = if on { "on" } else { "off" };
+
+    store.hash_set(&key(username), "dnd", dnd).await
+}
+
+/// Whether a registered name sees joins and leaves, None if they never said.
+/// With `>set-presence-msgs off` they're left out live, and the history shown
+/// on joining has a line saying how many were, while everyone else in the
+/// room still sees them.
+pub async fn presence_msgs(
+    store: &dyn RoomStore,
+    username: &str,
+) -> Result<Option<bool>, StoreError> {
+    let shown = store.hash_get(&key(username), "presence-msgs").await?;
+
+    Ok(shown.map(|shown| shown == "on"))
+}
+
+pub async fn set_presence_msgs(
+    store: &dyn RoomStore,
+    username: &str,
+    on: bool,
+) -> Result<(), StoreError> {
+    let shown = if on { "on" } else { "off" };
+
+    store.hash_set(&key(username), "presence-msgs", shown).await
+}
+
+/// The `>set-theme` a registered name picked, None if they never have.
+pub async fn theme(store: &dyn RoomStore, username: &str) -> Result<Option<String>, StoreError> {
+    store.hash_get(&key(username), "theme").await
+}
+
+pub async fn set_theme(
+    store: &dyn RoomStore,
+    username: &str,
+    theme: &str,
+) -> Result<(), StoreError> {
+    store.hash_set(&key(username), "theme", theme).await
+}
+
+// Folded so `Bob` can't register alongside `bob`
+fn key(username: &str) -> String {
+    format!("account:{}", username::fold(username))
+}
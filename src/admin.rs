@@ -0,0 +1,397 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+use crate::audit::{self, AuditAction, AuditEntry};
+use crate::broker;
+use crate::config::{self, RuntimeConfig};
+use crate::registry::{ConnId, Control};
+use crate::room;
+use crate::server::ServerContext;
+use crate::shutdown::{Shutdown, ShutdownRequest};
+
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    Help,
+    Connections,
+    Rooms,
+    Kick(ConnId),
+    ForceLeave(String),
+    DeleteRoom(String),
+    SetMotd(Option<String>),
+    ReloadConfig,
+    Audit(usize),
+    Notice(String),
+    Shutdown(ShutdownRequest),
+    MissingArgument {
+        command: &'static str,
+        usage: &'static str,
+    },
+    Invalid,
+    Exit,
+}
+
+const HELP: &str = ">help";
+const EXIT: &str = ">exit";
+const CONNECTIONS: &str = ">connections";
+const ROOMS: &str = ">rooms";
+const KICK: &str = ">kick";
+const FORCE_LEAVE: &str = ">force-leave";
+const DELETE_ROOM: &str = ">delete-room";
+const SET_MOTD: &str = ">set-motd";
+const RELOAD_CONFIG: &str = ">reload-config";
+const AUDIT: &str = ">audit";
+const NOTICE: &str = ">notice";
+const SHUTDOWN: &str = ">shutdown";
+
+// Each command with its usage and what it does, for `>help`
+const COMMANDS: [(&str, &str, &str); 12] = [
+    (HELP, HELP, "Display commands"),
+    (EXIT, EXIT, "Close connection"),
+    (CONNECTIONS, CONNECTIONS, "List connections"),
+    (ROOMS, ROOMS, "List rooms and how backed up they are"),
+    (KICK, ">kick id", "Disconnect a connection"),
+    (
+        FORCE_LEAVE,
+        ">force-leave name",
+        "Remove a user from their room",
+    ),
+    (
+        DELETE_ROOM,
+        ">delete-room room",
+        "Delete a room and its history",
+    ),
+    (SET_MOTD, ">set-motd [text]", "Set or clear the MOTD"),
+    (RELOAD_CONFIG, RELOAD_CONFIG, "Re-read the config file"),
+    (
+        AUDIT,
+        ">audit [n]",
+        "Show the last n moderation actions, 20 by default",
+    ),
+    (
+        NOTICE,
+        ">notice text",
+        "Send every connection a server notice",
+    ),
+    (
+        SHUTDOWN,
+        ">shutdown [seconds] [reason]",
+        "Warn everyone, then gracefully stop the server",
+    ),
+];
+
+// Shared by every admin connection
+pub struct AdminContext {
+    pub server: Arc<ServerContext>,
+    // When set, the first line of each connection must be `AUTH <token>`
+    pub token: Option<String>,
+    pub config_path: Option<PathBuf>,
+}
+
+impl AdminCommand {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::admin::AdminCommand;
+    ///
+    /// assert_eq!(AdminCommand::parse(">kick 3".into()), AdminCommand::Kick(3));
+    /// assert_eq!(AdminCommand::parse(">kick bob".into()), AdminCommand::Invalid);
+    /// assert_eq!(
+    ///     AdminCommand::parse(">kick".into()),
+    ///     AdminCommand::MissingArgument { command: ">kick", usage: ">kick id" }
+    /// );
+    /// assert_eq!(AdminCommand::parse(">set-motd".into()), AdminCommand::SetMotd(None));
+    /// assert_eq!(
+    ///     AdminCommand::parse(">delete-room rust".into()),
+    ///     AdminCommand::DeleteRoom("rust".to_owned())
+    /// );
+    ///
+    /// let AdminCommand::Shutdown(request) = AdminCommand::parse(">shutdown 60 upgrading".into()) else {
+    ///     panic!("expected a shutdown");
+    /// };
+    /// assert_eq!(request.countdown_secs, Some(60));
+    /// assert_eq!(request.reason.as_deref(), Some("upgrading"));
+    /// ```
+    pub fn parse(s: String) -> Self {
+        // These commands don't require extra args
+        match s.as_str() {
+            HELP => return AdminCommand::Help,
+            EXIT => return AdminCommand::Exit,
+            CONNECTIONS => return AdminCommand::Connections,
+            ROOMS => return AdminCommand::Rooms,
+            SET_MOTD => return AdminCommand::SetMotd(None),
+            RELOAD_CONFIG => return AdminCommand::ReloadConfig,
+            AUDIT => return AdminCommand::Audit(audit::DEFAULT_COUNT),
+            SHUTDOWN => return AdminCommand::Shutdown(ShutdownRequest::default()),
+            _ => {}
+        };
+
+        let (command, rest) = match s.split_once(" ") {
+            Some(s) => s,
+            None => (s.as_str(), ""),
+        };
+
+        let Some(&(command, usage, _)) = COMMANDS.iter().find(|(name, ..)| *name == command) else {
+            return AdminCommand::Invalid;
+        };
+
+        match command {
+            KICK | FORCE_LEAVE | DELETE_ROOM | NOTICE if rest.trim().is_empty() => {
+                AdminCommand::MissingArgument { command, usage }
+            }
+            KICK => match rest.parse() {
+                Ok(id) => AdminCommand::Kick(id),
+                Err(_) => AdminCommand::Invalid,
+            },
+            FORCE_LEAVE => AdminCommand::ForceLeave(rest.into()),
+            DELETE_ROOM => AdminCommand::DeleteRoom(rest.into()),
+            SET_MOTD => AdminCommand::SetMotd(Some(rest.into())),
+            NOTICE => AdminCommand::Notice(rest.trim().into()),
+            SHUTDOWN => AdminCommand::Shutdown(ShutdownRequest::parse(rest)),
+            AUDIT => match rest.parse() {
+                Ok(count) => AdminCommand::Audit(count),
+                Err(_) => AdminCommand::Invalid,
+            },
+            _ => AdminCommand::Invalid,
+        }
+    }
+}
+
+pub async fn listen(
+    listener: TcpListener,
+    ctx: Arc<AdminContext>,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = shutdown.recv() => return Ok(()),
+        };
+
+        let ctx = Arc::clone(&ctx);
+
+        tokio::spawn(async move {
+            info!("Admin connection from {}", addr);
+
+            if let Err(e) = handle(stream, &format!("admin@{}", addr), &ctx).await {
+                error!("{}", e)
+            };
+        });
+    }
+}
+
+// `actor` names the connection in the audit log
+async fn handle(stream: TcpStream, actor: &str, ctx: &AdminContext) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    if let Some(token) = &ctx.token {
+        let line = lines.next_line().await?.unwrap_or_default();
+        let given = line.strip_prefix("AUTH ").unwrap_or_default();
+
+        if !constant_time_eq(given.as_bytes(), token.as_bytes()) {
+            warn!("Rejected admin connection with a bad token");
+            writer.write_all(b"Unauthorized\n").await?;
+
+            return Ok(());
+        }
+    }
+
+    writer.write_all(b"ChatsApp admin\n").await?;
+
+    while let Some(line) = lines.next_line().await? {
+        let command = AdminCommand::parse(line);
+        if command == AdminCommand::Exit {
+            break;
+        }
+
+        let res = execute(command, actor, ctx).await;
+        writer.write_all(res.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn execute(command: AdminCommand, actor: &str, ctx: &AdminContext) -> String {
+    let server = &ctx.server;
+    let store = &*server.store;
+
+    match command {
+        AdminCommand::Help => COMMANDS.iter().fold(
+            "Commands:\n".to_owned(),
+            |mut help, (_, usage, description)| {
+                help.push_str(&format!("{:<18} - {}\n", usage, description));
+                help
+            },
+        ),
+        AdminCommand::Connections => {
+            let mut res = String::new();
+
+            for conn in server.registry.snapshot() {
+                let connected = SystemTime::now()
+                    .duration_since(conn.connected_at)
+                    .unwrap_or_default();
+
+                res.push_str(&format!(
+                    "{} {} via {} user={} room={} connected={}s\n",
+                    conn.id,
+                    conn.addr,
+                    conn.listener,
+                    conn.username.as_deref().unwrap_or("-"),
+                    conn.room.as_deref().unwrap_or("-"),
+                    connected.as_secs(),
+                ));
+            }
+
+            res
+        }
+        AdminCommand::Rooms => {
+            let rooms = server.rooms.read().await;
+            let mut names: Vec<&String> = rooms.keys().collect();
+            names.sort();
+
+            let mut res = String::new();
+            for name in names {
+                let room = &rooms[name];
+                res.push_str(&format!(
+                    "{} members={} queue={:.0}%\n",
+                    name,
+                    room.members(),
+                    room.queue_pressure() * 100.0,
+                ));
+            }
+
+            res
+        }
+        AdminCommand::Kick(id) => {
+            if server.registry.send_control(id, Control::Disconnect) {
+                info!("Admin disconnected connection {}", id);
+                let entry = AuditEntry {
+                    target: Some(id.to_string()),
+                    ..AuditEntry::new(actor, AuditAction::Kick)
+                };
+                audit::record(store, entry).await;
+                format!("Disconnected {}\n", id)
+            } else {
+                format!("No connection with id {}\n", id)
+            }
+        }
+        AdminCommand::ForceLeave(username) => {
+            let mut removed = 0;
+
+            for conn in server.registry.snapshot() {
+                if conn.username.as_deref() == Some(username.as_str())
+                    && conn.room.is_some()
+                    && server.registry.send_control(conn.id, Control::LeaveRoom)
+                {
+                    removed += 1;
+                }
+            }
+
+            info!("Admin removed {} from their room", username);
+            let entry = AuditEntry {
+                target: Some(username),
+                ..AuditEntry::new(actor, AuditAction::ForceLeave)
+            };
+            audit::record(store, entry).await;
+            format!("Removed {} connection(s)\n", removed)
+        }
+        AdminCommand::DeleteRoom(name) => {
+            // Stop new joins first, then turn out whoever is inside
+            if !broker::remove_broker(&name, &server.rooms).await {
+                return "Room not found\n".to_owned();
+            }
+
+            for conn in server.registry.snapshot() {
+                if conn.room.as_deref() == Some(name.as_str()) {
+                    server.registry.send_control(conn.id, Control::RoomDeleted);
+                }
+            }
+
+            match room::delete(store, &name).await {
+                Ok(_) => {
+                    info!("Admin deleted room {}", name);
+                    let entry = AuditEntry {
+                        room: Some(name.clone()),
+                        ..AuditEntry::new(actor, AuditAction::DeleteRoom)
+                    };
+                    audit::record(store, entry).await;
+
+                    format!("Deleted {}\n", name)
+                }
+                Err(e) => e.to_string(),
+            }
+        }
+        AdminCommand::SetMotd(motd) => {
+            server.config.rcu(|config| RuntimeConfig {
+                motd: motd.clone(),
+                ..RuntimeConfig::clone(config)
+            });
+
+            "MOTD updated\n".to_owned()
+        }
+        AdminCommand::ReloadConfig => {
+            let Some(path) = &ctx.config_path else {
+                return "Not started with --config\n".to_owned();
+            };
+
+            match config::reload(path, &server.config) {
+                Ok(()) => {
+                    info!("Reloaded config from {}", path.display());
+
+                    match server.reload_filter().await {
+                        Ok(_) => "Config reloaded\n".to_owned(),
+                        Err(e) => format!("Config reloaded, keeping the old word list\n{}", e),
+                    }
+                }
+                Err(e) => format!("{}\n", e),
+            }
+        }
+        AdminCommand::Audit(count) => audit::render(store, count).await,
+        AdminCommand::Notice(text) => {
+            let notice = format!("[SERVER] {}", text);
+            server.registry.send_control_all(Control::Notice(notice));
+
+            let entry = AuditEntry {
+                reason: Some(text),
+                ..AuditEntry::new(actor, AuditAction::Notice)
+            };
+            audit::record(store, entry).await;
+            "Notice sent\n".to_owned()
+        }
+        AdminCommand::Shutdown(request) => {
+            info!("Admin triggered shutdown");
+            let entry = AuditEntry {
+                reason: request.reason.clone(),
+                ..AuditEntry::new(actor, AuditAction::Shutdown)
+            };
+            audit::record(store, entry).await;
+            server.shutdown.trigger_with(request);
+
+            "Shutting down\n".to_owned()
+        }
+        AdminCommand::MissingArgument { command, usage } => {
+            format!("'{}' is missing an argument. Usage: {}\n", command, usage)
+        }
+        AdminCommand::Invalid => "Invalid command.
+Enter \">help\" for a list of commands and their usage.\n"
+            .to_owned(),
+        AdminCommand::Exit => String::new(),
+    }
+}
+
+// Compares without bailing on the first mismatch so the token can't be
+// guessed a byte at a time
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
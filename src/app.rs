@@ -1,163 +1,3338 @@
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use redis::Client as RedisClient;
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::Sender;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::SendError;
 use tokio::sync::Mutex;
+use tokio::time;
+use tracing::{debug_span, error, field, info, info_span, warn, Instrument};
+
+use crate::account::{self, MultiLogin};
+use crate::audit::{self, AuditAction, AuditEntry};
+use crate::ban::{IpBan, IpNet};
+use crate::broker::{self, BrokerEvent, RoomHandle, RoomMap, SharedStream};
+use crate::caps::{Cap, Caps};
+use crate::chatlog;
+use crate::client::ServerEvent;
+use crate::command::{Command, ListOptions, ParseError, DEFAULT_PREFIX};
+use crate::compact;
+use crate::config::FilterMode;
+use crate::dm;
+use crate::errors::{Code, UserError};
+use crate::forward::Target;
+use crate::keepalive::{self, Due, Keepalive};
+use crate::mention;
+use crate::metrics::{metrics, WINDOW_MINUTES};
+use crate::page;
+use crate::quote::Delivered;
+use crate::reactions;
+use crate::registry::{glob_match, ConnId, Control, Registration};
+use crate::render::{format_duration, format_uptime, RoomView, ServerMessage, TextRenderer};
+use crate::report;
+use crate::roles;
+use crate::room::{self, CreateRoomOpts, Role, RoomError, RoomEvent, SortBy};
+use crate::server::ServerContext;
+use crate::session;
+use crate::spam::{self, Repeat, Repeats};
+use crate::store::StoreError;
+use crate::telemetry::{Stage, Timings};
+use crate::telnet::{LossyLines, TelnetReader};
+use crate::themes;
+use crate::throttle::Throttle;
+use crate::username::{self, UsernameError};
+use crate::webhook;
+
+type BoxedReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+
+// Longer topics are cut short in `>find` results
+const TOPIC_SNIPPET_LEN: usize = 40;
+
+// `>users` only lists this many, then how many more there are
+const MAX_USERS_LISTED: usize = 100;
+
+// Wrong passwords before a claim to a registered name is given up on
+const MAX_LOGIN_FAILURES: u32 = 3;
+
+// Ends the history replayed on joining, what follows is live
+const LIVE: &str = "--- you are now live ---";
+
+// Sent before closing a connection that's hit `limits.max_invalid_commands`
+const TOO_MANY_INVALID: &str = "Too many invalid commands, disconnecting";
+
+// Sent instead when the first line is an HTTP request
+const NOT_HTTP: &str = "This is a chat server, not a web server";
+
+// Request methods that give away an HTTP client
+const HTTP_METHODS: [&str; 8] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT",
+];
+
+// `>users` only shows someone's idle time past this, or once they're away
+const IDLE_SHOWN_AFTER: Duration = Duration::from_secs(60);
+
+/// Who's on the connection. The last room they were in, however they came
+/// to leave it, is kept for `>rejoin`.
+pub struct User {
+    addr: String,
+    username: Option<String>,
+    // Logged in as the username, or registered it, rather than just set it.
+    // Admin and room roles are only honoured once it is.
+    authenticated: bool,
+    // What they've opted in to, with `>hello` or eg `>set-typing on`
+    caps: Caps,
+    // A registered name that's been set but not logged in as yet
+    claim: Option<Claim>,
+    // When they last sent a line, they're away once it's long enough ago
+    last_active: Instant,
+    away: bool,
+    // For `>rejoin`
+    last_room: Option<String>,
+}
+
+struct Claim {
+    username: String,
+    // When it's given up on and the connection renamed to a guest
+    deadline: Instant,
+    failures: u32,
+}
+
+enum State {
+    Inside {
+        room: String,
+        tx: RoomHandle,
+        // Boxed, being most of the size of `State`
+        settings: Box<RoomSettings>,
+        // What was said here lately, to catch the same message over and over
+        repeats: Repeats,
+        // Their latest chat here, for `>edit`. Its id is when it was sent, in ms
+        last_sent: Option<i64>,
+    },
+    Outside,
+}
+
+// Cached on join and re-read on `Control::SettingsChanged`, so sending a
+// message doesn't touch the store for them
+struct RoomSettings {
+    slow_mode: SlowMode,
+    // The room's override of `limits.max_message_len`
+    max_message_len: Option<usize>,
+    // How long messages are kept for, if they expire
+    ephemeral: Option<Duration>,
+}
+
+struct SlowMode {
+    interval: Duration,
+    // The owner's and moderators' messages are never held back
+    exempt: bool,
+    last_message: Option<Instant>,
+}
+
+/// One connection speaking the line protocol. Anything else that finds its
+/// way to the port, like a scanner or a browser, is closed once it's sent
+/// `limits.max_invalid_commands` invalid commands in a row. Chat from a guest
+/// outside any room counts as invalid too, and any other command starts the
+/// count again. A first line that's an HTTP request is closed straight away.
+/// Telnet's option negotiation is stripped, and anything that isn't UTF-8 is
+/// replaced with U+FFFD.
+pub struct App {
+    ctx: Arc<ServerContext>,
+    conn: Registration,
+    stream: SharedStream,
+    lines: LossyLines<TelnetReader<BoxedReader>>,
+    user: User,
+    state: State,
+    timings: Timings,
+    throttle: Throttle,
+    // The `>resume` token, if one's been issued
+    session: Option<String>,
+    // What the current room's sent them lately, for `>quote`
+    delivered: Arc<Delivered>,
+    // Shared with the room's writer, so changes apply to what's queued
+    view: Arc<RoomView>,
+    // Invalid commands in a row, see `limits.max_invalid_commands`
+    invalid: u32,
+    // None when a bridge keeps the connection alive its own way
+    keepalive: Option<Keepalive>,
+}
+
+impl App {
+    pub fn new<S>(stream: S, addr: SocketAddr, conn: Registration, ctx: Arc<ServerContext>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    {
+        let (reader, writer) = io::split(stream);
+        let reader: BoxedReader = Box::new(reader);
+        let lines = LossyLines::new(TelnetReader::new(reader));
+        let keepalive = Keepalive::new();
+        let stream: SharedStream = Arc::new(Mutex::new(Box::new(keepalive.writer(writer))));
+
+        Self {
+            ctx,
+            conn,
+            stream,
+            lines,
+            user: User {
+                addr: addr.to_string(),
+                username: None,
+                authenticated: false,
+                caps: Caps::default(),
+                claim: None,
+                last_active: Instant::now(),
+                away: false,
+                last_room: None,
+            },
+            state: State::Outside,
+            timings: Timings::default(),
+            throttle: Throttle::default(),
+            session: None,
+            delivered: Arc::default(),
+            view: Arc::default(),
+            invalid: 0,
+            keepalive: Some(keepalive),
+        }
+    }
+
+    // For WebSocket and IRC connections, whose bridges ping in their own
+    // protocol rather than sending `keepalive::PROBE`
+    pub fn without_keepalive(mut self) -> Self {
+        self.keepalive = None;
+        self
+    }
+
+    /// Serves the connection until it exits or goes away. One that stops
+    /// reading is dropped, leaving its room, once a write to it takes longer
+    /// than the broker's `write_timeout_secs`.
+    pub async fn run(self) -> io::Result<()> {
+        let span = info_span!("connection", addr = %self.user.addr);
+
+        self.serve().instrument(span).await
+    }
+
+    async fn serve(mut self) -> io::Result<()> {
+        let room_map = Arc::clone(&self.ctx.rooms);
+
+        // Everyone starts out as a guest, so rooms can be joined straight away
+        let guest = self.guest_name();
+        self.conn
+            .registry()
+            .set_username(self.conn.id(), Some(guest.clone()));
+        self.user.username = Some(guest);
+
+        self.write_greeting().await?;
+
+        // Closed without `>exit`, eg a phone losing signal
+        let mut dropped = false;
+        let mut first_line = true;
+
+        loop {
+            let deadline = self.user.claim.as_ref().map(|claim| claim.deadline);
+            let away_at = self.away_at();
+            let check = self
+                .keepalive
+                .as_ref()
+                .and_then(|keepalive| keepalive.next_check(&self.ctx.config.load()));
+
+            let message = tokio::select! {
+                line = self.lines.next_line() => match line {
+                    Ok(Some(message)) => message,
+                    Ok(None) | Err(_) => {
+                        dropped = true;
+                        break;
+                    }
+                },
+                _ = time::sleep_until(check.unwrap_or_else(Instant::now).into()),
+                    if check.is_some() =>
+                {
+                    match self.check_keepalive().await {
+                        Ok(true) => continue,
+                        Ok(false) => info!("Dropping connection: keepalive went unanswered"),
+                        Err(e) => warn!("Dropping connection: {}", e),
+                    }
+                    dropped = true;
+                    break;
+                }
+                // Not announced, it'd only be noise in the room
+                _ = time::sleep_until(away_at.unwrap_or_else(Instant::now).into()),
+                    if away_at.is_some() =>
+                {
+                    self.user.away = true;
+                    self.conn.registry().set_away(self.conn.id());
+                    continue;
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now).into()),
+                    if deadline.is_some() =>
+                {
+                    let claim = self.user.claim.take().unwrap();
+                    if let Err(e) = self.claim_expired(claim).await {
+                        warn!("Dropping connection: {}", e);
+                        dropped = true;
+                        break;
+                    }
+                    continue;
+                }
+                Some(control) = self.conn.recv_control() => {
+                    match self.handle_control(control).await {
+                        Ok(true) => break,
+                        Ok(false) => continue,
+                        Err(e) => {
+                            warn!("Dropping connection: {}", e);
+                            dropped = true;
+                            break;
+                        }
+                    }
+                }
+            };
+
+            // Blank lines only answer the keepalive, so aren't activity
+            if let Some(keepalive) = &mut self.keepalive {
+                keepalive.heard();
+            }
+            if message.is_empty() {
+                continue;
+            }
+
+            if std::mem::take(&mut first_line) && is_http_request(&message) {
+                info!("Closing an HTTP request");
+                dropped = self.write_info(NOT_HTTP).await.is_err();
+                break;
+            }
+
+            self.user.last_active = Instant::now();
+            self.user.away = false;
+            self.conn.registry().set_active(self.conn.id());
+
+            let command = self.ctx.commands.parse(message);
+            let name = command.name();
+            let invalid = self.is_invalid(&command);
+
+            metrics().commands.with_label_values(&[name]).inc();
+
+            let span = info_span!("command", command = name, elapsed_ms = field::Empty);
+            let start = Instant::now();
+            self.timings.reset();
+
+            // A write that failed or timed out means the connection's gone
+            let exit = match self
+                .dispatch(command, &room_map)
+                .instrument(span.clone())
+                .await
+            {
+                Ok(exit) => exit,
+                Err(e) => {
+                    warn!("Dropping connection: {}", e);
+                    dropped = true;
+                    break;
+                }
+            };
+
+            metrics().observe_command(name, start);
+            self.timings.finish(&span, name, start.elapsed());
+
+            if exit {
+                break;
+            }
+
+            self.invalid = if invalid { self.invalid + 1 } else { 0 };
+            let max = self.ctx.config.load().limits.max_invalid_commands;
+            if max > 0 && self.invalid >= max {
+                info!("Closing after {} invalid commands", self.invalid);
+                dropped = self.write_info(TOO_MANY_INVALID).await.is_err();
+                break;
+            }
+        }
+
+        // Connection closed, make sure the room doesn't keep a dead member.
+        // With a session it's given a while to come back first.
+        match (&self.state, &self.session) {
+            (State::Inside { room, tx, .. }, Some(token)) if dropped => {
+                self.defer_leave(token, room, tx).await;
+            }
+            (State::Inside { room, tx, .. }, _) => {
+                self.leave_room(tx, room).await?;
+            }
+            _ => {}
+        }
+        if let (Some(token), false) = (&self.session, dropped) {
+            if let Err(e) = session::revoke(&*self.ctx.store, token).await {
+                error!("{}", e.to_string().trim_end());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Towards `limits.max_invalid_commands`. Chat from a guest outside any
+    // room is as likely to be a scanner as a person.
+    fn is_invalid(&self, command: &Command) -> bool {
+        match command {
+            Command::Invalid(_) => true,
+            Command::Message(_) => {
+                matches!(self.state, State::Outside)
+                    && self.user.username.as_deref().is_some_and(account::is_guest)
+            }
+            _ => false,
+        }
+    }
+
+    // Only in a room, and not once they're already away
+    fn away_at(&self) -> Option<Instant> {
+        let after = self.ctx.config.load().away_after_secs;
+        if after == 0 || self.user.away || matches!(self.state, State::Outside) {
+            return None;
+        }
+
+        Some(self.user.last_active + Duration::from_secs(after))
+    }
+
+    // Probes a connection that's been quiet for long enough. Returns false if
+    // the last probe went unanswered.
+    async fn check_keepalive(&mut self) -> io::Result<bool> {
+        let Some(keepalive) = &mut self.keepalive else {
+            return Ok(true);
+        };
+
+        match keepalive.due(&self.ctx.config.load()) {
+            Some(Due::Probe) => {
+                keepalive.probed();
+                let probe = format!("{}\n", keepalive::PROBE);
+                self.write_all(probe.as_bytes()).await?;
+            }
+            Some(Due::Dead) => return Ok(false),
+            None => {}
+        }
+
+        Ok(true)
+    }
+
+    async fn claim_expired(&mut self, claim: Claim) -> io::Result<()> {
+        let guest = self.rename_to_guest().await?;
+        let msg = format!(
+            "You didn't log in as {} in time, you're now {}",
+            claim.username, guest
+        );
+
+        self.write_info(msg).await
+    }
+
+    // Returns true when the connection should be closed
+    async fn handle_control(&mut self, control: Control) -> io::Result<bool> {
+        match control {
+            Control::Disconnect => {
+                self.write_info("You have been disconnected by an admin")
+                    .await?;
+
+                return Ok(true);
+            }
+            Control::LeaveRoom => {
+                if let State::Inside { room, tx, .. } = &self.state {
+                    self.leave_room(tx, room).await?;
+                    self.set_state(State::Outside);
+                    self.save_session().await;
+
+                    self.write_info("You have been removed from the room by an admin")
+                        .await?;
+                }
+            }
+            // Notices are sent as whole lines
+            Control::Notice(notice) => {
+                self.write_message(ServerMessage::Lines {
+                    lines: vec![notice],
+                })
+                .await?;
+            }
+            Control::SettingsChanged { room } => {
+                if let State::Inside { room: current, .. } = &self.state {
+                    if *current == room {
+                        let (fresh, _) = self.room_settings(&room).await;
+                        if let State::Inside { settings, .. } = &mut self.state {
+                            // Changing the interval doesn't forget the last message
+                            let last_message = settings.slow_mode.last_message;
+                            **settings = fresh;
+                            settings.slow_mode.last_message = last_message;
+                        }
+                    }
+                }
+            }
+            Control::Replaced => {
+                self.write_info("Logged in from another location").await?;
+
+                return Ok(true);
+            }
+            Control::Goodbye => {
+                self.write_info("[SERVER] Goodbye").await?;
+
+                return Ok(true);
+            }
+            Control::WriteTimedOut => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Room couldn't write to the connection in time",
+                ));
+            }
+            Control::RoomDeleted => {
+                if let State::Inside { .. } = self.state {
+                    self.set_state(State::Outside);
+                    self.save_session().await;
+
+                    self.write_info("The room has been deleted by an admin")
+                        .await?;
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    // Returns true when the connection should be closed
+    async fn dispatch(&mut self, command: Command, room_map: &RoomMap) -> io::Result<bool> {
+        let stream = self.stream.clone();
+
+        match command {
+            Command::Help => {
+                self.write_help().await?;
+            }
+            Command::HelpErrors => {
+                self.write_help_errors().await?;
+            }
+            Command::List(opts) => {
+                self.write_rooms(opts, room_map).await?;
+            }
+            Command::Me => {
+                self.write_user_info().await?;
+            }
+            Command::Stats => {
+                self.write_stats(room_map).await?;
+            }
+            Command::Uptime => {
+                self.write_uptime().await?;
+            }
+            Command::Dm { to, text } => {
+                self.handle_dm(&to, &text).await?;
+            }
+            Command::DmHistory { with, count } => {
+                self.write_dm_history(&with, count).await?;
+            }
+            Command::Users(filter) => {
+                let show_addrs = self.check_admin().await;
+                self.write_users(filter.as_deref(), show_addrs).await?;
+            }
+            Command::SetUsername(username) => {
+                self.handle_set_username(username).await?;
+            }
+            Command::Register(password) => {
+                self.handle_register(&password).await?;
+            }
+            Command::Login(password) => {
+                self.handle_login(&password).await?;
+            }
+            Command::Passwd { old, new } => {
+                self.handle_passwd(&old, &new).await?;
+            }
+            Command::Session => {
+                self.handle_session().await?;
+            }
+            Command::Resume(token) => {
+                self.handle_resume(&token, Arc::clone(&stream), room_map)
+                    .await?;
+            }
+            Command::Mentions => {
+                self.write_mentions().await?;
+            }
+            Command::Report { user, reason } => {
+                self.handle_report(&user, &reason).await?;
+            }
+            Command::CreateRoom { name: room, opts } => {
+                if !self.check_running().await? {
+                    return Ok(false);
+                }
+
+                // Checked up front so a room isn't left behind when the join fails
+                if opts.join && !self.check_can_join().await? {
+                    return Ok(false);
+                }
+
+                if !self.check_room_limits(&room).await? {
+                    return Ok(false);
+                }
+
+                // Only for new rooms, a taken name would replace its broker.
+                // Guest names are given out again, so guests don't own rooms.
+                let owner = self
+                    .user
+                    .username
+                    .as_deref()
+                    .filter(|name| !account::is_guest(name));
+                if let Err(e) = room::create(&*self.ctx.store, &room, owner, &opts).await {
+                    self.write_error(e).await?;
+                    return Ok(false);
+                }
+
+                let config = self.ctx.config.load().broker;
+                broker::spawn_broker(room.clone(), room_map, &self.ctx.store, &config).await;
+
+                if opts.join {
+                    let msg = format!("Room '{}' created", room);
+                    self.write_info(msg).await?;
+                    self.handle_join(Arc::clone(&stream), room, room_map, None)
+                        .await?;
+                } else {
+                    let hint = self.ctx.commands.format(&Command::JoinRoom(room.clone()));
+                    let msg = format!("Room '{}' created, join it with {}", room, hint);
+                    self.write_info(msg).await?;
+                }
+            }
+            Command::JoinRoom(room) => {
+                if !self.check_can_join().await? {
+                    return Ok(false);
+                }
+
+                self.handle_join(Arc::clone(&stream), room.clone(), room_map, None)
+                    .await?;
+            }
+            Command::RandomRoom => {
+                if !self.check_can_join().await? {
+                    return Ok(false);
+                }
+
+                self.handle_random_room(Arc::clone(&stream), room_map)
+                    .await?;
+            }
+            Command::Rejoin => {
+                let Some(room) = self.user.last_room.clone() else {
+                    self.write_info("You haven't been in a room yet").await?;
+                    return Ok(false);
+                };
+                if !self.check_can_join().await? {
+                    return Ok(false);
+                }
+
+                self.handle_join(Arc::clone(&stream), room, room_map, None)
+                    .await?;
+            }
+            Command::Message(msg) => {
+                self.handle_message(msg).await?;
+            }
+            Command::Quote { n, text } => {
+                self.handle_quote(n, &text).await?;
+            }
+            Command::React { n, reaction } => {
+                self.handle_react(n, reaction).await?;
+            }
+            Command::Reactions(n) => {
+                self.handle_reactions(n).await?;
+            }
+            Command::Edit(text) => {
+                self.handle_edit(text).await?;
+            }
+            Command::Undo => {
+                self.handle_undo().await?;
+            }
+            Command::Leave => {
+                self.handle_leave().await?;
+            }
+            Command::Typing => {
+                // Ephemeral, so there's nothing to do outside a room
+                if let State::Inside { tx, .. } = &self.state {
+                    let user = self.user.username.clone().unwrap();
+                    let event = BrokerEvent::Typing {
+                        conn: self.conn.id(),
+                        user,
+                    };
+                    if let Err(e) = self.broker_send(tx, event).await {
+                        self.write_error(e).await?;
+                    }
+                }
+            }
+            Command::SetMultiLogin(policy) => {
+                self.handle_set_multi_login(policy).await?;
+            }
+            Command::SetTyping(on) => {
+                let mut caps = self.user.caps;
+                caps.set(Cap::Typing, on);
+                self.set_caps(caps).await?;
+            }
+            Command::SetQuiet(on) => {
+                self.view.set_quiet(on);
+            }
+            Command::SetPresenceMsgs(on) => {
+                self.handle_set_presence_msgs(on).await?;
+            }
+            Command::SetAnsi(on) => {
+                let mut caps = self.user.caps;
+                caps.set(Cap::Ansi, on);
+                self.set_caps(caps).await?;
+            }
+            Command::Capabilities => {
+                self.write_info(Caps::advertise()).await?;
+            }
+            Command::Hello { client, caps } => {
+                if let Some(client) = client {
+                    info!("{} is using {}", self.user.addr, client);
+                }
+                let caps = Caps::trim(caps.iter().map(String::as_str));
+                self.set_caps(caps).await?;
+                self.write_info(caps.ack()).await?;
+            }
+            Command::SetDnd(on) => {
+                self.handle_set_dnd(on).await?;
+            }
+            Command::SetTheme(name) => {
+                self.handle_set_theme(&name).await?;
+            }
+            Command::Themes => {
+                self.write_themes().await?;
+            }
+            Command::History { count, ids } => {
+                self.write_history(count, ids).await?;
+            }
+            Command::SlowMode(secs) => {
+                if self.check_role(Role::Moderator).await? {
+                    self.handle_slow_mode(secs).await?;
+                }
+            }
+            Command::DeleteMessage(id) => {
+                if self.check_role(Role::Moderator).await? {
+                    self.handle_delete_message(id).await?;
+                }
+            }
+            Command::Tags => {
+                self.write_tags().await?;
+            }
+            Command::MyRooms => {
+                self.write_my_rooms().await?;
+            }
+            Command::Find { query, page } => {
+                self.write_find(&query, page).await?;
+            }
+            Command::SetTopic(topic) => {
+                if self.check_role(Role::Owner).await? {
+                    self.handle_set_topic(topic.as_deref()).await?;
+                }
+            }
+            Command::SetTags(tags) => {
+                if self.check_role(Role::Owner).await? {
+                    self.handle_set_tags(&tags).await?;
+                }
+            }
+            Command::SetMaxLength(len) => {
+                if self.check_role(Role::Owner).await? {
+                    self.handle_set_max_length(len).await?;
+                }
+            }
+            Command::SetEphemeral(ttl) => {
+                if self.check_role(Role::Owner).await? {
+                    self.handle_set_ephemeral(ttl).await?;
+                }
+            }
+            Command::RoomInfo => {
+                self.write_room_info().await?;
+            }
+            Command::AddMod(_) | Command::RemoveMod(_) | Command::Mods => {
+                if self.check_role(Role::Owner).await? {
+                    self.handle_moderators(command).await?;
+                }
+            }
+            Command::TransferOwnership { to, force: false } => {
+                if self.check_role(Role::Owner).await? {
+                    self.handle_transfer_ownership(&to).await?;
+                }
+            }
+            Command::TransferOwnership { to, force: true } => {
+                if !self.check_admin().await {
+                    self.write_failure(Code::Forbidden, "You need to be an admin to do that")
+                        .await?;
+                    return Ok(false);
+                }
+
+                self.handle_transfer_ownership(&to).await?;
+            }
+            Command::CreateWebhook(_)
+            | Command::Webhooks
+            | Command::RevokeWebhook(_)
+            | Command::AddOutgoingWebhook(_)
+            | Command::OutgoingWebhooks
+            | Command::RemoveOutgoingWebhook(_) => {
+                if self.check_role(Role::Owner).await? {
+                    self.handle_webhooks(command).await?;
+                }
+            }
+            Command::Op(_)
+            | Command::Deop(_)
+            | Command::Broadcast(_)
+            | Command::Notice(_)
+            | Command::Shutdown(_)
+            | Command::IpBan { .. }
+            | Command::IpUnban(_)
+            | Command::IpBans
+            | Command::PurgeUser { .. }
+            | Command::Compact(_)
+            | Command::Audit(_)
+            | Command::Reports(_)
+            | Command::ReportClose { .. } => {
+                if !self.check_admin().await {
+                    self.write_failure(Code::Forbidden, "You need to be an admin to do that")
+                        .await?;
+                    return Ok(false);
+                }
+
+                self.handle_admin(command).await?;
+            }
+            Command::Invalid(e) => {
+                self.write_invalid(e).await?;
+            }
+            Command::Exit => return Ok(true),
+        }
+
+        Ok(false)
+    }
+
+    // Writes why when they can't be in a room yet
+    async fn check_can_join(&self) -> io::Result<bool> {
+        if !self.check_running().await? {
+            return Ok(false);
+        }
+
+        // Not even under the old name, so the room can't be told otherwise
+        if let Some(claim) = &self.user.claim {
+            let msg = format!(
+                "Log in as {} with {}login password first",
+                claim.username,
+                self.prefix()
+            );
+            self.write_failure(Code::NeedsLogin, msg).await?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    // Rooms aren't joined or created once shutdown has started
+    async fn check_running(&self) -> io::Result<bool> {
+        if self.ctx.shutdown.is_triggered() {
+            self.write_failure(Code::ShuttingDown, "Server is shutting down")
+                .await?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    // Writes why when creating another room would go over a limit
+    async fn check_room_limits(&mut self, room: &str) -> io::Result<bool> {
+        let limits = self.ctx.config.load().limits.clone();
+
+        let reserved = limits
+            .reserved_rooms
+            .iter()
+            .any(|pattern| glob_match(pattern, room));
+        if reserved && !self.check_admin().await {
+            let msg = format!("'{}' is a reserved room name", room);
+            self.write_failure(Code::Reserved, msg).await?;
+            return Ok(false);
+        }
+
+        if let Some(max) = limits.max_rooms {
+            match self.ctx.store.list().await {
+                Ok(rooms) if rooms.len() >= max => {
+                    let msg = format!("Server room limit reached ({})", max);
+                    self.write_failure(Code::LimitReached, msg).await?;
+                    return Ok(false);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.write_error(e).await?;
+                    return Ok(false);
+                }
+            }
+        }
+
+        let Some(username) = self.user.username.clone() else {
+            return Ok(true);
+        };
+        if limits.rooms_per_user == 0 || self.check_admin().await {
+            return Ok(true);
+        }
+
+        match room::owned_by(&*self.ctx.store, &username).await {
+            Ok(owned) if owned >= limits.rooms_per_user => {
+                let msg = format!(
+                    "You have reached your room limit ({})",
+                    limits.rooms_per_user
+                );
+                self.write_failure(Code::LimitReached, msg).await?;
+                Ok(false)
+            }
+            Ok(_) => Ok(true),
+            Err(e) => {
+                self.write_error(e).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    // Looks the username up every time, so revoking takes effect straight
+    // away. Anyone can set an unregistered name, so it has to be logged in.
+    async fn check_admin(&self) -> bool {
+        let Some(username) = &self.user.username else {
+            return false;
+        };
+        if !self.user.authenticated {
+            return false;
+        }
+
+        roles::is_admin(&*self.ctx.store, username)
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn handle_admin(&self, command: Command) -> io::Result<()> {
+        let store = &*self.ctx.store;
+
+        let message = match command {
+            // They'd never be able to use it, and anyone could take it
+            Command::Op(name) => match account::is_registered(store, &name).await {
+                Ok(true) => match roles::grant(store, &name).await {
+                    Ok(true) => {
+                        self.audit(AuditAction::Op, Some(&name), None, None).await;
+                        ServerMessage::info(format!("{} is now an admin", name))
+                    }
+                    Ok(false) => ServerMessage::info(format!("{} is already an admin", name)),
+                    Err(e) => ServerMessage::error(&e),
+                },
+                Ok(false) => {
+                    let msg = format!(
+                        "{} isn't registered, only registered names can be admins",
+                        name
+                    );
+                    return self.write_failure(Code::NotRegistered, msg).await;
+                }
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::Deop(name) => match roles::revoke(store, &name).await {
+                Ok(true) => {
+                    self.audit(AuditAction::Deop, Some(&name), None, None).await;
+                    ServerMessage::info(format!("{} is no longer an admin", name))
+                }
+                Ok(false) => ServerMessage::info(format!("{} isn't an admin", name)),
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::Broadcast(text) => {
+                let notice = format!("[broadcast] {}\n", text);
+                let registry = self.conn.registry();
+
+                for conn in registry.snapshot() {
+                    registry.send_control(conn.id, Control::Notice(notice.clone()));
+                }
+                self.audit(AuditAction::Broadcast, None, None, Some(&text))
+                    .await;
+
+                return Ok(());
+            }
+            Command::Notice(text) => {
+                let notice = format!("[SERVER] {}", text);
+                self.ctx.registry.send_control_all(Control::Notice(notice));
+                self.audit(AuditAction::Notice, None, None, Some(&text))
+                    .await;
+
+                return Ok(());
+            }
+            Command::Shutdown(request) => {
+                info!("Shutdown triggered by {:?}", self.user.username);
+                let reason = request.reason.as_deref();
+                self.audit(AuditAction::Shutdown, None, None, reason).await;
+                self.ctx.shutdown.trigger_with(request);
+
+                ServerMessage::info("Shutting down")
+            }
+            Command::IpBan { target, reason } => match IpNet::parse(&target) {
+                Ok(net) => self.ip_ban(IpBan { net, reason }).await,
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::IpUnban(target) => match IpNet::parse(&target) {
+                Ok(net) => match self.ctx.bans.remove(store, &net).await {
+                    Ok(true) => {
+                        let target = net.to_string();
+                        self.audit(AuditAction::IpUnban, Some(&target), None, None)
+                            .await;
+                        ServerMessage::info(format!("{} is no longer banned", net))
+                    }
+                    Ok(false) => ServerMessage::info(format!("{} isn't banned", net)),
+                    Err(e) => ServerMessage::error(&e),
+                },
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::IpBans => match self.ctx.bans.list(store).await {
+                Ok(bans) if bans.is_empty() => ServerMessage::info("No addresses are banned"),
+                Ok(bans) => {
+                    let bans: Vec<String> = bans
+                        .iter()
+                        .map(|ban| match &ban.reason {
+                            Some(reason) => format!("{} - {}", ban.net, reason),
+                            None => ban.net.to_string(),
+                        })
+                        .collect();
+                    ServerMessage::info(bans.join("\n"))
+                }
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::PurgeUser { user, room } => self.purge_user(user, room).await,
+            Command::Compact(room) => self.compact(room).await,
+            // Shared with the admin console, so it's already text
+            Command::Audit(count) => ServerMessage::Lines {
+                lines: vec![audit::render(store, count).await],
+            },
+            Command::Reports(count) => {
+                self.audit(AuditAction::Reports, None, None, None).await;
+                match report::open(store, count).await {
+                    Ok(reports) if reports.is_empty() => ServerMessage::info("No open reports"),
+                    Ok(reports) => ServerMessage::Lines {
+                        lines: reports
+                            .iter()
+                            .map(|report| format!("{}\n", report))
+                            .collect(),
+                    },
+                    Err(e) => ServerMessage::error(&e),
+                }
+            }
+            Command::ReportClose { id, note } => match report::close(store, id).await {
+                Ok(Some(report)) => {
+                    let reason = match note {
+                        Some(note) => format!("#{} {}", id, note),
+                        None => format!("#{}", id),
+                    };
+                    let (target, room) = (Some(report.target.as_str()), report.room.as_deref());
+                    self.audit(AuditAction::ReportClose, target, room, Some(&reason))
+                        .await;
+                    ServerMessage::info(format!("Closed report #{} about {}", id, report.target))
+                }
+                Ok(None) => ServerMessage::info(format!("There's no open report #{}", id)),
+                Err(e) => ServerMessage::error(&e),
+            },
+            _ => return Ok(()),
+        };
+
+        self.write_message(message).await
+    }
+
+    // Registered names wait for `>login`, anything else is set straight away
+    async fn handle_set_username(&mut self, username: String) -> io::Result<()> {
+        // Quoting gets blanks past the parser
+        let username = match username::normalize(&username) {
+            Ok(username) => username,
+            Err(UsernameError::Blank) => {
+                let msg = format!(
+                    "Usernames can't be blank. Usage: {}set-username name",
+                    self.prefix()
+                );
+                return self.write_failure(Code::InvalidUsername, msg).await;
+            }
+            Err(e) => return self.write_error(e).await,
+        };
+
+        // Differently cased, it's still their name
+        let same = self
+            .user
+            .username
+            .as_deref()
+            .is_some_and(|old| username::fold(old) == username::fold(&username));
+
+        if !same && account::is_guest(&username) {
+            let msg = format!(
+                "Names starting with '{}' are for guests, pick another",
+                account::GUEST_PREFIX
+            );
+            return self.write_failure(Code::Reserved, msg).await;
+        }
+
+        if !same {
+            match account::is_registered(&*self.ctx.store, &username).await {
+                Ok(true) => {
+                    let grace = self.ctx.config.load().limits.login_grace_secs;
+                    let msg = format!(
+                        "{} is registered, use {}login password within {}s or you'll be renamed",
+                        username,
+                        self.prefix(),
+                        grace
+                    );
+                    self.user.claim = Some(Claim {
+                        username,
+                        deadline: Instant::now() + Duration::from_secs(grace),
+                        failures: 0,
+                    });
+
+                    return self.write_info(msg).await;
+                }
+                // Anyone can have it, but only one connection at a time
+                Ok(false) => {
+                    if !self
+                        .conn
+                        .registry()
+                        .claim_username(self.conn.id(), &username)
+                    {
+                        let msg = format!("{} is already in use, pick another", username);
+                        return self.write_failure(Code::NameTaken, msg).await;
+                    }
+                }
+                Err(e) => return self.write_error(e).await,
+            }
+        }
+
+        self.user.claim = None;
+
+        // Going from a guest name is setting one, not changing it
+        let msg = match &self.user.username {
+            Some(old) if *old != username && !account::is_guest(old) => {
+                format!("Username changed from '{}' to '{}'", old, username)
+            }
+            _ => format!("Username set to '{}'", username),
+        };
+        self.write_info(msg).await?;
+
+        self.set_username(username).await
+    }
+
+    async fn set_username(&mut self, username: String) -> io::Result<()> {
+        self.conn
+            .registry()
+            .set_username(self.conn.id(), Some(username.clone()));
+        // Differently cased it's still what they logged in as
+        let same = self
+            .user
+            .username
+            .as_deref()
+            .is_some_and(|old| username::fold(old) == username::fold(&username));
+        if !same {
+            self.user.authenticated = false;
+        }
+        // Otherwise it carries over from the name they had
+        if let Ok(Some(dnd)) = account::dnd(&*self.ctx.store, &username).await {
+            self.conn.registry().set_dnd(&username, dnd);
+        }
+        if let Ok(Some(name)) = account::theme(&*self.ctx.store, &username).await {
+            if let Some(theme) = themes::find(&name) {
+                self.view.set_theme(theme);
+            }
+        }
+        if let Ok(Some(on)) = account::presence_msgs(&*self.ctx.store, &username).await {
+            self.view.set_quiet(!on);
+        }
+
+        match mention::unread(&*self.ctx.store, &username).await {
+            Ok(0) => {}
+            Ok(unread) => {
+                let notice = format!(
+                    "You have {} unread mention{} — use {}mentions to view",
+                    unread,
+                    if unread == 1 { "" } else { "s" },
+                    self.prefix()
+                );
+                self.write_info(notice).await?;
+            }
+            Err(e) => self.write_error(e).await?,
+        }
+        self.write_offline_dms(&username).await?;
+
+        if let (State::Inside { room, tx, .. }, Some(old)) = (&self.state, &self.user.username) {
+            if *old != username {
+                self.announce_rename(tx, room, old, &username).await?;
+            }
+        }
+
+        self.user.username = Some(username);
+        self.sync_mentions();
+
+        Ok(())
+    }
+
+    // The room goes on to know them by the new name
+    async fn announce_rename(
+        &self,
+        tx: &RoomHandle,
+        room: &str,
+        old: &str,
+        new: &str,
+    ) -> io::Result<()> {
+        let notice = format!("{} is now known as {}", old, new);
+        let msg = self.room_event(RoomEvent::Notice(notice), room).await;
+
+        let rename = BrokerEvent::Rename {
+            conn: self.conn.id(),
+            user: new.to_owned(),
+            msg,
+        };
+        if let Err(e) = self.broker_send(tx, rename).await {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    // Returns the name picked
+    async fn rename_to_guest(&mut self) -> io::Result<String> {
+        let guest = self.guest_name();
+        self.set_username(guest.clone()).await?;
+
+        Ok(guest)
+    }
+
+    // One no other connection's using. Nobody else can set or register a
+    // guest name, so there's nothing else to check.
+    fn guest_name(&self) -> String {
+        let registry = self.conn.registry();
+
+        loop {
+            let guest = format!("{}{:04x}", account::GUEST_PREFIX, rand::random::<u16>());
+            if registry.find_by_username(&guest).is_none() {
+                return guest;
+            }
+        }
+    }
+
+    async fn handle_register(&mut self, password: &str) -> io::Result<()> {
+        let Some(username) = self.user.username.clone() else {
+            return self
+                .write_failure(Code::NeedsUsername, "You need to pick a username first")
+                .await;
+        };
+
+        if account::is_guest(&username) {
+            return self
+                .write_failure(Code::Reserved, "Guest names can't be registered")
+                .await;
+        }
+        if password.chars().count() < account::MIN_PASSWORD_LEN {
+            return self.write_password_too_short().await;
+        }
+
+        match account::register(&*self.ctx.store, &username, password).await {
+            Ok(true) => {
+                self.user.authenticated = true;
+                self.write_info(format!("{} is now registered", username))
+                    .await
+            }
+            Ok(false) => {
+                let msg = format!("{} is already registered", username);
+                self.write_failure(Code::NameTaken, msg).await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_login(&mut self, password: &str) -> io::Result<()> {
+        let Some(claim) = &mut self.user.claim else {
+            let msg = format!(
+                "Nothing to log in to, {}set-username a registered name first",
+                self.prefix()
+            );
+            return self.write_failure(Code::NotRegistered, msg).await;
+        };
+        let username = claim.username.clone();
+
+        match account::verify(&*self.ctx.store, &username, password).await {
+            Ok(true) => {
+                let policy = match account::multi_login(&*self.ctx.store, &username).await {
+                    Ok(policy) => policy,
+                    Err(e) => return self.write_error(e).await,
+                };
+                // Refused logins keep their claim, to try again in time
+                if !self
+                    .conn
+                    .registry()
+                    .log_in(self.conn.id(), &username, policy)
+                {
+                    let msg = format!("{} is already logged in elsewhere", username);
+                    return self.write_failure(Code::AlreadyLoggedIn, msg).await;
+                }
+
+                self.user.claim = None;
+                self.set_username(username.clone()).await?;
+                self.user.authenticated = true;
+
+                let msg = format!("Logged in as {}", username);
+                self.write_info(msg).await?;
+
+                self.handle_session().await
+            }
+            Ok(false) => {
+                info!(username, "wrong password");
+                claim.failures += 1;
+                if claim.failures < MAX_LOGIN_FAILURES {
+                    return self
+                        .write_failure(Code::WrongPassword, "Wrong password")
+                        .await;
+                }
+
+                self.user.claim = None;
+                let guest = self.rename_to_guest().await?;
+                let msg = format!("Too many wrong passwords, you're now {}", guest);
+                self.write_failure(Code::WrongPassword, msg).await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_set_multi_login(&self, policy: MultiLogin) -> io::Result<()> {
+        let Some(username) = &self.user.username else {
+            return self
+                .write_failure(Code::NeedsUsername, "You need to pick a username first")
+                .await;
+        };
+
+        let store = &*self.ctx.store;
+        match account::is_registered(store, username).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let msg = format!(
+                    "{} isn't registered, use {}register password",
+                    username,
+                    self.prefix()
+                );
+                return self.write_failure(Code::NotRegistered, msg).await;
+            }
+            Err(e) => return self.write_error(e).await,
+        }
+
+        match account::set_multi_login(store, username, policy).await {
+            Ok(()) => {
+                let msg = format!("Multi-login set to {}", policy);
+                self.write_info(msg).await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_set_dnd(&self, on: bool) -> io::Result<()> {
+        let Some(username) = &self.user.username else {
+            return self
+                .write_failure(Code::NeedsUsername, "You need to pick a username first")
+                .await;
+        };
+
+        // Only kept for registered names, anyone could take the others
+        let store = &*self.ctx.store;
+        match account::is_registered(store, username).await {
+            Ok(true) => {
+                if let Err(e) = account::set_dnd(store, username, on).await {
+                    return self.write_error(e).await;
+                }
+            }
+            Ok(false) => {}
+            Err(e) => return self.write_error(e).await,
+        }
+        self.conn.registry().set_dnd(username, on);
+
+        if on {
+            self.write_info("Do not disturb is on, direct messages to you will be refused")
+                .await
+        } else {
+            self.write_info("Do not disturb is off").await
+        }
+    }
+
+    async fn handle_set_presence_msgs(&self, on: bool) -> io::Result<()> {
+        // Kept for registered names, like `>dnd`
+        if let Some(username) = &self.user.username {
+            let store = &*self.ctx.store;
+            match account::is_registered(store, username).await {
+                Ok(true) => {
+                    if let Err(e) = account::set_presence_msgs(store, username, on).await {
+                        return self.write_error(e).await;
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => return self.write_error(e).await,
+            }
+        }
+        self.view.set_quiet(!on);
+
+        match on {
+            true => self.write_info("Joins and leaves are shown").await,
+            false => self.write_info("Joins and leaves are hidden").await,
+        }
+    }
+
+    // What changes with each capability, a room they're in included
+    async fn set_caps(&mut self, caps: Caps) -> io::Result<()> {
+        let typing = caps.contains(Cap::Typing);
+        let was_typing = std::mem::replace(&mut self.user.caps, caps).contains(Cap::Typing);
+        self.view.set_ansi(caps.contains(Cap::Ansi));
+        self.sync_mentions();
+
+        if let (State::Inside { tx, .. }, true) = (&self.state, typing != was_typing) {
+            let user = self.user.username.clone().unwrap();
+            let event = BrokerEvent::SetTyping {
+                conn: self.conn.id(),
+                user,
+                enabled: typing,
+            };
+            if let Err(e) = self.broker_send(tx, event).await {
+                self.write_error(e).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Chat mentioning their current name is picked out, with `Cap::Mentions`
+    fn sync_mentions(&self) {
+        let name = match self.user.caps.contains(Cap::Mentions) {
+            true => self.user.username.clone(),
+            false => None,
+        };
+        self.view.set_mention(name);
+    }
+
+    async fn handle_set_theme(&self, name: &str) -> io::Result<()> {
+        // The parser only lets known names through
+        let Some(theme) = themes::find(name) else {
+            return Ok(());
+        };
+
+        // Kept for registered names, like `>dnd`
+        if let Some(username) = &self.user.username {
+            let store = &*self.ctx.store;
+            match account::is_registered(store, username).await {
+                Ok(true) => {
+                    if let Err(e) = account::set_theme(store, username, theme.name).await {
+                        return self.write_error(e).await;
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => return self.write_error(e).await,
+            }
+        }
+        self.view.set_theme(theme);
+
+        self.write_info(format!("Theme set to {}", theme.name))
+            .await
+    }
+
+    // Each with how a message would look in it
+    async fn write_themes(&self) -> io::Result<()> {
+        let sample = ServerMessage::Chat {
+            user: "bob".to_owned(),
+            text: "hi there".to_owned(),
+            ts: None,
+        };
+        let at = room::get_time_in_ms();
+
+        let lines = themes::THEMES
+            .iter()
+            .map(|theme| {
+                let shown = theme.render(sample.clone(), at).concat();
+                format!("{:<14}{}", theme.name, shown)
+            })
+            .collect();
+
+        self.write_message(ServerMessage::Lines { lines }).await
+    }
+
+    // Whether they're refusing direct messages, and mentions are kept from them
+    async fn is_dnd(&self, username: &str) -> Result<bool, StoreError> {
+        match self.conn.registry().find_by_username(username) {
+            Some(conn) => Ok(conn.dnd),
+            None => Ok(account::dnd(&*self.ctx.store, username).await? == Some(true)),
+        }
+    }
+
+    async fn handle_passwd(&mut self, old: &str, new: &str) -> io::Result<()> {
+        let Some(username) = self.user.username.clone() else {
+            return self
+                .write_failure(Code::NeedsUsername, "You need to pick a username first")
+                .await;
+        };
+
+        let store = &*self.ctx.store;
+        match account::is_registered(store, &username).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let msg = format!(
+                    "{} isn't registered, use {}register password",
+                    username,
+                    self.prefix()
+                );
+                return self.write_failure(Code::NotRegistered, msg).await;
+            }
+            Err(e) => return self.write_error(e).await,
+        }
+
+        match account::verify(store, &username, old).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return self
+                    .write_failure(Code::WrongPassword, "Wrong password")
+                    .await
+            }
+            Err(e) => return self.write_error(e).await,
+        }
+
+        if new.chars().count() < account::MIN_PASSWORD_LEN {
+            return self.write_password_too_short().await;
+        }
+
+        match account::set_password(store, &username, new).await {
+            Ok(()) => self.write_info("Password changed").await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    // Issues a new `>resume` token, revoking any previous one
+    async fn handle_session(&mut self) -> io::Result<()> {
+        let Some(username) = self.user.username.clone() else {
+            return self
+                .write_failure(Code::NeedsUsername, "You need to pick a username first")
+                .await;
+        };
+
+        let store = &*self.ctx.store;
+        if let Some(old) = self.session.take() {
+            if let Err(e) = session::revoke(store, &old).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        let ttl = Duration::from_secs(self.ctx.config.load().session_ttl_secs);
+        let room = match &self.state {
+            State::Inside { room, .. } => Some(room.as_str()),
+            State::Outside => None,
+        };
+        let authenticated = self.user.authenticated;
+        let token =
+            match session::create(store, self.conn.id(), &username, authenticated, room, ttl).await
+            {
+                Ok(token) => token,
+                Err(e) => return self.write_error(e).await,
+            };
+
+        let msg = format!(
+            "Session token {}, send {}resume {} first thing after reconnecting",
+            token,
+            self.prefix(),
+            token
+        );
+        self.session = Some(token);
+
+        self.write_info(msg).await
+    }
+
+    async fn handle_resume(
+        &mut self,
+        token: &str,
+        stream: SharedStream,
+        room_map: &RoomMap,
+    ) -> io::Result<()> {
+        // Still the guest they connected as, and nowhere yet
+        let fresh = self.user.username.as_deref().is_some_and(account::is_guest)
+            && matches!(self.state, State::Outside);
+        if !fresh || self.user.claim.is_some() || self.session.is_some() {
+            let msg = format!("{}resume only works as the first command", self.prefix());
+            return self.write_failure(Code::ResumeNotFirst, msg).await;
+        }
+
+        let store = Arc::clone(&self.ctx.store);
+        let resumed = match session::take(&*store, token).await {
+            Ok(Some(resumed)) => resumed,
+            Ok(None) => {
+                return self
+                    .write_failure(
+                        Code::SessionExpired,
+                        "Session expired, set your username again",
+                    )
+                    .await
+            }
+            Err(e) => return self.write_error(e).await,
+        };
+
+        // Taken by someone who hadn't logged in as it, it's since been registered
+        // or someone else has it now
+        if !resumed.authenticated {
+            match account::is_registered(&*store, &resumed.username).await {
+                Ok(false) => {
+                    let registry = self.conn.registry();
+                    if !registry.claim_username(self.conn.id(), &resumed.username) {
+                        let msg = format!(
+                            "{} is already in use, set your username again",
+                            resumed.username
+                        );
+                        return self.write_failure(Code::NameTaken, msg).await;
+                    }
+                }
+                Ok(true) => {
+                    return self
+                        .write_failure(
+                            Code::SessionExpired,
+                            "Session expired, set your username again",
+                        )
+                        .await
+                }
+                Err(e) => return self.write_error(e).await,
+            }
+        }
+
+        self.set_username(resumed.username.clone()).await?;
+        self.user.authenticated = resumed.authenticated;
+
+        let ttl = Duration::from_secs(self.ctx.config.load().session_ttl_secs);
+        let room = resumed.room.as_deref();
+        let conn = self.conn.id();
+        let authenticated = resumed.authenticated;
+        let username = &resumed.username;
+        let token = match session::create(&*store, conn, username, authenticated, room, ttl).await {
+            Ok(token) => token,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let msg = match room {
+            Some(room) => format!(
+                "Resumed as {} in {}, your new session token is {}",
+                resumed.username, room, token
+            ),
+            None => format!(
+                "Resumed as {}, your new session token is {}",
+                resumed.username, token
+            ),
+        };
+        self.session = Some(token);
+        self.write_info(msg).await?;
+
+        if let Some(room) = resumed.room.clone() {
+            // Takes over from the old connection if the room still has it
+            let old = resumed.conn.filter(|_| resumed.still_in_room());
+            self.handle_join(stream, room, room_map, old).await?;
+        }
+
+        Ok(())
+    }
+
+    // Keeps the session's room up to date, refreshing its TTL
+    async fn save_session(&self) {
+        let Some(token) = &self.session else {
+            return;
+        };
+        let room = match &self.state {
+            State::Inside { room, .. } => Some(room.as_str()),
+            State::Outside => None,
+        };
+        let ttl = Duration::from_secs(self.ctx.config.load().session_ttl_secs);
+
+        if let Err(e) = session::set_room(&*self.ctx.store, token, room, ttl).await {
+            error!("{}", e.to_string().trim_end());
+        }
+    }
+
+    // Leaves the room once the grace period's up, unless the session's been
+    // resumed by then
+    async fn defer_leave(&self, token: &str, room: &str, tx: &RoomHandle) {
+        let store = Arc::clone(&self.ctx.store);
+        match session::set_away(&*store, token).await {
+            Ok(true) => {}
+            // Already resumed elsewhere, which took over in the room
+            Ok(false) => return,
+            Err(e) => error!("{}", e.to_string().trim_end()),
+        }
+
+        let token = token.to_owned();
+        let room = room.to_owned();
+        let tx = tx.clone();
+        let conn = self.conn.id();
+        let user = self.user.username.clone().unwrap_or_default();
+        let ephemeral = self.ephemeral(&room);
+        let ctx = Arc::clone(&self.ctx);
+
+        tokio::spawn(async move {
+            time::sleep(session::RESUME_GRACE).await;
+            if !matches!(session::find(&*store, &token).await, Ok(Some(_))) {
+                return;
+            }
+
+            let msg = room::event(&ctx, RoomEvent::Leave, &room, &user, ephemeral).await;
+            let _ = tx.send(BrokerEvent::LeaveRoom { conn, user, msg }).await;
+        });
+    }
+
+    async fn write_password_too_short(&self) -> io::Result<()> {
+        let msg = format!(
+            "Passwords must be at least {} characters",
+            account::MIN_PASSWORD_LEN
+        );
+
+        self.write_failure(Code::PasswordTooShort, msg).await
+    }
+
+    async fn audit(
+        &self,
+        action: AuditAction,
+        target: Option<&str>,
+        room: Option<&str>,
+        reason: Option<&str>,
+    ) {
+        let actor = self.user.username.as_deref().unwrap_or_default();
+        let entry = AuditEntry {
+            target: target.map(str::to_owned),
+            room: room.map(str::to_owned),
+            reason: reason.map(str::to_owned),
+            ..AuditEntry::new(actor, action)
+        };
+
+        audit::record(&*self.ctx.store, entry).await;
+    }
+
+    // Until when the user is muted for repeating themselves, if they are
+    fn muted(&self) -> Option<Instant> {
+        let username = self.user.username.as_deref()?;
+        let until = *self.ctx.mutes.get(username)?;
+        if until > Instant::now() {
+            return Some(until);
+        }
+
+        self.ctx
+            .mutes
+            .remove_if(username, |_, until| *until <= Instant::now());
+        None
+    }
+
+    // Purges in the background, telling the admin as each room's done
+    // The room if it exists, or every room without one
+    async fn rooms_or_all(&self, room: &Option<String>) -> Result<Vec<String>, ServerMessage> {
+        let store = &*self.ctx.store;
+
+        match room {
+            Some(room) => match store.meta(room).await {
+                Ok(Some(_)) => Ok(vec![room.clone()]),
+                Ok(None) => Err(ServerMessage::Error {
+                    code: Code::RoomNotFound,
+                    text: "Room not found".into(),
+                }),
+                Err(e) => Err(ServerMessage::error(&e)),
+            },
+            None => store.list().await.map_err(|e| ServerMessage::error(&e)),
+        }
+    }
+
+    async fn purge_user(&self, user: String, room: Option<String>) -> ServerMessage {
+        let rooms = match self.rooms_or_all(&room).await {
+            Ok(rooms) => rooms,
+            Err(message) => return message,
+        };
+        let reply = format!("Purging {}'s messages from {} room(s)", user, rooms.len());
+
+        let ctx = Arc::clone(&self.ctx);
+        let control = self.conn.control_sender();
+        let by = self.user.username.clone().unwrap_or_default();
+        tokio::spawn(async move {
+            let store = &*ctx.store;
+            let mut total = 0;
+            for room in &rooms {
+                let progress = match room::purge_user(store, room, &user, &by).await {
+                    Ok(0) => continue,
+                    Ok(n) => {
+                        total += n;
+                        format!("Purged {} message(s) from {} in {}", n, user, room)
+                    }
+                    Err(e) => {
+                        let e = e.to_string();
+                        format!("Couldn't purge {} in {}: {}", user, room, e.trim_end())
+                    }
+                };
+                // They may have gone, the purge carries on regardless
+                let _ = control.send(Control::Notice(progress)).await;
+            }
+
+            let entry = AuditEntry {
+                target: Some(user.clone()),
+                room,
+                reason: Some(format!("{} message(s)", total)),
+                ..AuditEntry::new(&by, AuditAction::PurgeUser)
+            };
+            audit::record(store, entry).await;
+
+            let done = format!(
+                "Purge of {} finished, {} message(s) in {} room(s)",
+                user,
+                total,
+                rooms.len()
+            );
+            let _ = control.send(Control::Notice(done)).await;
+        });
+
+        ServerMessage::info(reply)
+    }
+
+    // Runs in the background like the periodic compaction, reporting each room
+    async fn compact(&self, room: Option<String>) -> ServerMessage {
+        let rooms = match self.rooms_or_all(&room).await {
+            Ok(rooms) => rooms,
+            Err(message) => return message,
+        };
+        let reply = format!("Compacting {} room(s)", rooms.len());
+
+        let ctx = Arc::clone(&self.ctx);
+        let control = self.conn.control_sender();
+        tokio::spawn(async move {
+            let removed = compact::run(&ctx, &rooms, Some(&control)).await;
+            let done = format!(
+                "Compaction finished, {} message(s) removed from {} room(s)",
+                removed,
+                rooms.len()
+            );
+            let _ = control.send(Control::Notice(done)).await;
+        });
+
+        ServerMessage::info(reply)
+    }
+
+    // Stores the ban, then closes every connection it covers
+    async fn ip_ban(&self, ban: IpBan) -> ServerMessage {
+        if let Err(e) = self.ctx.bans.add(&*self.ctx.store, &ban).await {
+            return ServerMessage::error(&e);
+        }
+        info!("{} banned by {:?}", ban.net, self.user.username);
+        let target = ban.net.to_string();
+        self.audit(
+            AuditAction::IpBan,
+            Some(&target),
+            None,
+            ban.reason.as_deref(),
+        )
+        .await;
+
+        let registry = self.conn.registry();
+        let mut disconnected = 0;
+
+        for conn in registry.snapshot() {
+            let Ok(addr) = conn.addr.parse::<SocketAddr>() else {
+                continue;
+            };
+
+            if ban.net.contains(addr.ip()) {
+                registry.send_control(conn.id, Control::Notice(ban.notice()));
+                registry.send_control(conn.id, Control::Disconnect);
+                disconnected += 1;
+            }
+        }
+
+        ServerMessage::info(format!(
+            "{} is now banned, {} connection(s) closed",
+            ban.net, disconnected
+        ))
+    }
+
+    async fn write_user_info(&self) -> io::Result<()> {
+        let mut info = format!(
+            "Username: {}, IP: {}",
+            self.user.username.as_deref().unwrap_or_default(),
+            self.user.addr
+        );
+        if let Some(conn) = self.conn.registry().get(self.conn.id()) {
+            let connected = conn.connected_at.elapsed().unwrap_or_default();
+            info.push_str(&format!(", Connected: {} ago", format_duration(connected)));
+            if let (Some(room), Some(joined_at)) = (conn.room, conn.joined_at) {
+                let joined = format_duration(joined_at.elapsed());
+                info.push_str(&format!(", Joined {}: {} ago", room, joined));
+            }
+        }
+        if let Some(room) = &self.user.last_room {
+            info.push_str(&format!(", Last room: {}", room));
+        }
+        if self
+            .conn
+            .registry()
+            .get(self.conn.id())
+            .is_some_and(|conn| conn.dnd)
+        {
+            info.push_str(", Do not disturb: on");
+        }
+        let theme = self.view.theme();
+        if !std::ptr::eq(theme, &themes::THEMES[0]) {
+            info.push_str(&format!(", Theme: {}", theme.name));
+        }
+
+        self.write_info(info).await?;
+
+        Ok(())
+    }
+
+    async fn write_stats(&self, room_map: &RoomMap) -> io::Result<()> {
+        let registry = self.conn.registry();
+        let rooms = room_map.read().await.len();
+
+        let mut stats = vec![format!("Connections: {}", registry.len())];
+        for (listener, count) in registry.per_listener() {
+            stats.push(format!("  {}: {}", listener, count));
+        }
+        stats.push(format!("Rooms: {}", rooms));
+
+        // Who's been waited on, see `chatsapp_queue_full_total`
+        let rooms = metrics().full_rooms(5);
+        if !rooms.is_empty() {
+            stats.push("Full queues:".to_owned());
+            for (room, full) in rooms {
+                stats.push(format!("  room {}: {}", room, full));
+            }
+            for (user, full) in metrics().full_users(5) {
+                stats.push(format!("  user {}: {}", user, full));
+            }
+        }
+
+        // Whether it's Redis that's slow, see `TimedStore`
+        let ops = metrics().redis_summary();
+        if !ops.is_empty() {
+            stats.push(format!("Redis, last {} minutes:", WINDOW_MINUTES));
+            for op in ops {
+                stats.push(format!(
+                    "  {}: {} ops, p50 {:.1}ms, p99 {:.1}ms",
+                    op.op,
+                    op.count,
+                    op.p50.as_secs_f64() * 1000.0,
+                    op.p99.as_secs_f64() * 1000.0
+                ));
+            }
+        }
+
+        self.write_info(stats.join("\n")).await?;
+
+        Ok(())
+    }
+
+    async fn write_uptime(&self) -> io::Result<()> {
+        let server = format_uptime(self.ctx.started.elapsed());
+        let since = self
+            .ctx
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // `2024-05-01T09:00:00Z` as `2024-05-01 09:00`
+        let since = chatlog::timestamp(since.as_millis() as i64);
+        let since = format!("{} {}", &since[..10], &since[11..16]);
+
+        let session = self
+            .conn
+            .registry()
+            .get(self.conn.id())
+            .and_then(|conn| conn.connected_at.elapsed().ok())
+            .unwrap_or_default();
+
+        let uptime = format!(
+            "Server up {} (since {} UTC); your session: {}",
+            server,
+            since,
+            format_uptime(session)
+        );
+
+        self.write_info(uptime).await
+    }
+
+    // What they said lately in the reporter's room goes with it
+    async fn handle_report(&self, target: &str, reason: &str) -> io::Result<()> {
+        let Some(reporter) = &self.user.username else {
+            return self
+                .write_failure(Code::NeedsUsername, "You need to pick a username first")
+                .await;
+        };
+        if username::fold(reporter) == username::fold(target) {
+            return self
+                .write_failure(Code::InvalidArgument, "You can't report yourself")
+                .await;
+        }
+
+        let room = match &self.state {
+            State::Inside { room, .. } => Some(room.as_str()),
+            State::Outside => None,
+        };
+        match report::file(&*self.ctx.store, reporter, target, room, reason).await {
+            Ok(id) => {
+                info!(id, target, "report filed");
+                let msg = format!(
+                    "Thanks, your report about {} has been passed on to the admins",
+                    target
+                );
+                self.write_info(msg).await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_dm(&self, to: &str, text: &str) -> io::Result<()> {
+        let Some(from) = &self.user.username else {
+            return self
+                .write_failure(
+                    Code::NeedsUsername,
+                    "You need to pick a username before sending direct messages",
+                )
+                .await;
+        };
+        if from == to {
+            return self
+                .write_failure(Code::InvalidArgument, "You can't message yourself")
+                .await;
+        }
+        // Refused up front, so the sender knows it wasn't seen
+        match self.is_dnd(to).await {
+            Ok(true) => {
+                let msg = format!("{} is in do-not-disturb mode", to);
+                return self.write_failure(Code::DoNotDisturb, msg).await;
+            }
+            Ok(false) => {}
+            Err(e) => return self.write_error(e).await,
+        }
+
+        let retention = self.ctx.config.load().retention;
+        let msg = match dm::send(&*self.ctx.store, from, to, text, retention).await {
+            Ok(msg) => msg,
+            Err(e) => return self.write_error(e).await,
+        };
+        self.ctx.chat_log.dm(&dm::key(from, to), &msg);
+
+        // Every connection they have open gets it. They're only away if
+        // every one of them is.
+        let registry = self.conn.registry();
+        let mut delivered = false;
+        let mut idle = Vec::new();
+        for conn in registry.snapshot() {
+            if conn.username.as_deref() == Some(to) {
+                let notice = Control::Notice(format!("[dm] {}", msg));
+                delivered |= registry.send_control(conn.id, notice);
+                idle.push(conn.idle());
+            }
+        }
+
+        // Guest names go to someone else next time
+        let ttl = self.ctx.config.load().offline_dm_ttl_secs;
+        let queued = !delivered && ttl > 0 && !account::is_guest(to);
+        if queued {
+            let at = room::get_time_in_ms();
+            if let Err(e) = dm::queue(&*self.ctx.store, to, &msg, at).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        let reply = if delivered {
+            let mut reply = format!("[dm to {}] {}", to, text);
+            if let Some(idle) = idle.into_iter().min().flatten() {
+                let idle = format_duration(idle);
+                reply.push_str(&format!("\n{} is away, idle for {}", to, idle));
+            }
+            reply
+        } else if queued {
+            format!(
+                "{} is offline; message will be delivered when they return",
+                to
+            )
+        } else {
+            format!(
+                "{} isn't online, they can read it with {}dm-history {}",
+                to,
+                self.prefix(),
+                from
+            )
+        };
+        self.write_info(reply).await?;
+
+        // Messaging someone means they're around to hear back
+        let dnd = self
+            .conn
+            .registry()
+            .get(self.conn.id())
+            .is_some_and(|conn| conn.dnd);
+        if dnd {
+            self.handle_set_dnd(false).await?;
+        }
+
+        Ok(())
+    }
+
+    // Whispers sent while they were offline, with when each was sent
+    async fn write_offline_dms(&self, username: &str) -> io::Result<()> {
+        let ttl = Duration::from_secs(self.ctx.config.load().offline_dm_ttl_secs);
+        let queued = match dm::take_offline(&*self.ctx.store, username, ttl).await {
+            Ok(queued) if queued.is_empty() => return Ok(()),
+            Ok(queued) => queued,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let mut lines = vec![format!(
+            "{} direct message{} while you were offline:",
+            queued.len(),
+            if queued.len() == 1 { "" } else { "s" }
+        )];
+        for (at, msg) in queued {
+            lines.push(format!("[dm {}] {}", audit::format_time(at / 1000), msg));
+        }
+
+        self.write_message(ServerMessage::Lines { lines }).await
+    }
+
+    async fn write_mentions(&self) -> io::Result<()> {
+        let Some(username) = &self.user.username else {
+            return self
+                .write_failure(
+                    Code::NeedsUsername,
+                    "You need to pick a username to be mentioned",
+                )
+                .await;
+        };
+
+        match mention::take(&*self.ctx.store, username).await {
+            Ok(mentions) if mentions.is_empty() => {
+                self.write_info("No unread mentions").await?;
+            }
+            Ok(mentions) => {
+                self.write_message(ServerMessage::Lines { lines: mentions })
+                    .await?
+            }
+            Err(e) => self.write_error(e).await?,
+        }
+
+        Ok(())
+    }
+
+    // Only ever looked up with our own name, so other people's messages stay
+    // private
+    async fn write_dm_history(&self, with: &str, count: Option<usize>) -> io::Result<()> {
+        let Some(username) = &self.user.username else {
+            return self
+                .write_failure(
+                    Code::NeedsUsername,
+                    "You need to pick a username before sending direct messages",
+                )
+                .await;
+        };
+
+        let count = count.unwrap_or(self.ctx.config.load().history);
+        match dm::history(&*self.ctx.store, username, with, count).await {
+            Ok(msgs) if msgs.is_empty() => {
+                let reply = format!("No messages with {}", with);
+                self.write_info(reply).await?;
+            }
+            Ok(msgs) => {
+                self.write_message(ServerMessage::Lines { lines: msgs })
+                    .await?
+            }
+            Err(e) => self.write_error(e).await?,
+        }
+
+        Ok(())
+    }
+
+    // Named users sorted by name, with everyone yet to pick a name on one line
+    async fn write_users(&self, filter: Option<&str>, show_addrs: bool) -> io::Result<()> {
+        let mut named = Vec::new();
+        let mut anonymous = 0;
+
+        for conn in self.conn.registry().snapshot() {
+            match conn.username.clone() {
+                Some(username) => {
+                    if filter.is_none_or(|filter| glob_match(filter, &username)) {
+                        named.push((username, conn));
+                    }
+                }
+                None => anonymous += 1,
+            }
+        }
+        // Stable, so someone's connections stay in the order they were made
+        named.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut users = Vec::new();
+        for (username, conn) in named.iter().take(MAX_USERS_LISTED) {
+            let room = conn.room.as_deref().unwrap_or("lobby");
+            let mut user = format!("{} - {}", username, room);
+
+            let mut times = Vec::new();
+            if let Some(joined_at) = conn.joined_at {
+                let joined = format_duration(joined_at.elapsed());
+                times.push(format!("joined {} ago", joined));
+            }
+            let idle = conn.last_active.elapsed();
+            if conn.away || idle >= IDLE_SHOWN_AFTER {
+                times.push(format!("idle {}", format_duration(idle)));
+            }
+            if !times.is_empty() {
+                user.push_str(&format!(" ({})", times.join(", ")));
+            }
+
+            if show_addrs {
+                user.push_str(&format!(" ({})", conn.addr));
+            }
+            users.push(user);
+        }
+
+        // Nameless users can't match a filter
+        if filter.is_none() && anonymous > 0 {
+            users.push(format!("anonymous ({})", anonymous));
+        }
+        if named.len() > MAX_USERS_LISTED {
+            users.push(format!("and {} more…", named.len() - MAX_USERS_LISTED));
+        }
+        if users.is_empty() {
+            users.push("No users found".to_owned());
+        }
+
+        self.write_info(users.join("\n")).await?;
+
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, msg: String) -> io::Result<()> {
+        // Checked first, so an overlong message costs nothing
+        if !self.check_length(&msg).await? {
+            return Ok(());
+        }
+
+        if !self.paced().await? {
+            return Ok(());
+        }
+
+        if let State::Inside { room, repeats, .. } = &mut self.state {
+            let limits = &self.ctx.config.load().limits;
+            let window = Duration::from_secs(limits.duplicate_window_secs);
+
+            match repeats.check(&msg, limits.duplicate_limit, window, Instant::now()) {
+                Repeat::Fresh => {}
+                Repeat::Suppressed => {
+                    return self
+                        .write_failure(Code::Duplicate, "Duplicate message suppressed")
+                        .await;
+                }
+                Repeat::Abuse => {
+                    let room = room.clone();
+                    let username = self.user.username.clone().unwrap_or_default();
+                    self.ctx
+                        .mutes
+                        .insert(username.clone(), Instant::now() + spam::MUTE);
+
+                    let entry = AuditEntry {
+                        target: Some(username),
+                        room: Some(room),
+                        reason: Some("repeated messages".to_owned()),
+                        ..AuditEntry::new("server", AuditAction::Mute)
+                    };
+                    audit::record(&*self.ctx.store, entry).await;
+
+                    let msg = format!(
+                        "Duplicate message suppressed, you're muted for {}s",
+                        spam::MUTE.as_secs()
+                    );
+                    return self.write_failure(Code::Duplicate, msg).await;
+                }
+            }
+        }
+
+        let Some(msg) = self.filter(msg).await? else {
+            return Ok(());
+        };
+
+        let State::Inside { room, tx, .. } = &self.state else {
+            return self.write_not_in_room().await;
+        };
+        let id = self.send_message(tx, room, msg).await?;
+        if let State::Inside { last_sent, .. } = &mut self.state {
+            *last_sent = Some(id);
+        }
+
+        Ok(())
+    }
+
+    // Whether the rate limit, slow mode and any mute let them say something
+    // in the room now, telling them if not
+    async fn paced(&mut self) -> io::Result<bool> {
+        if let State::Inside { .. } = self.state {
+            let limits = &self.ctx.config.load().limits;
+            let window = Duration::from_secs(limits.window_secs);
+
+            if let Err(wait) = self.throttle.check(limits.messages_per_window, window) {
+                let msg = format!(
+                    "You're sending messages too quickly, try again in {}s",
+                    wait.as_secs() + 1
+                );
+                self.write_failure(Code::RateLimited, msg).await?;
+
+                return Ok(false);
+            }
+        }
+
+        if let State::Inside { settings, .. } = &mut self.state {
+            if let Err(wait) = settings.slow_mode.check() {
+                let msg = format!("Slow mode: wait {}s", wait.as_secs() + 1);
+                self.write_failure(Code::RateLimited, msg).await?;
+
+                return Ok(false);
+            }
+        }
+
+        if let Some(until) = self.muted() {
+            let wait = until.saturating_duration_since(Instant::now());
+            let msg = format!(
+                "You're muted for repeating messages, try again in {}s",
+                wait.as_secs() + 1
+            );
+            self.write_failure(Code::RateLimited, msg).await?;
+
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    // Their latest chat in the room, rewritten for everyone
+    async fn handle_edit(&mut self, text: String) -> io::Result<()> {
+        if let State::Outside = self.state {
+            return self.write_not_in_room().await;
+        }
+        let Some(id) = self.changeable("Editing", "edit", "edited").await? else {
+            return Ok(());
+        };
+
+        if !self.check_length(&text).await? {
+            return Ok(());
+        }
+        // Each edit is sent to the room like a message is
+        if !self.paced().await? {
+            return Ok(());
+        }
+        let Some(text) = self.filter(text).await? else {
+            return Ok(());
+        };
+
+        let State::Inside { room, tx, .. } = &self.state else {
+            return Ok(());
+        };
+        let user = self.user.username.as_deref().unwrap_or_default();
+
+        let msg = match room::edit_message(&*self.ctx.store, room, id, user, &text).await {
+            Ok(msg) => msg,
+            Err(e) => return self.write_error(e).await,
+        };
+        self.ctx.chat_log.room(room, &msg, room::get_time_in_ms());
+
+        let event = BrokerEvent::Message {
+            conn: self.conn.id(),
+            user: user.to_owned(),
+            msg,
+            id: Some(id),
+        };
+        if let Err(e) = self.broker_send(tx, event).await {
+            return self.write_error(e).await;
+        }
+
+        self.write_info("Message edited").await
+    }
+
+    // Their latest chat in the room, swapped for a note that they removed it
+    async fn handle_undo(&self) -> io::Result<()> {
+        let State::Inside { room, tx, .. } = &self.state else {
+            return self.write_not_in_room().await;
+        };
+        let Some(id) = self.changeable("Removing", "undo", "removed").await? else {
+            return Ok(());
+        };
+        let user = self.user.username.as_deref().unwrap_or_default();
+
+        let note = match room::retract_message(&*self.ctx.store, room, id, user).await {
+            Ok(note) => note,
+            Err(e) => return self.write_error(e).await,
+        };
+        self.ctx.chat_log.room(room, &note, room::get_time_in_ms());
+
+        let event = BrokerEvent::Message {
+            conn: self.conn.id(),
+            user: user.to_owned(),
+            msg: note,
+            id: None,
+        };
+        if let Err(e) = self.broker_send(tx, event).await {
+            return self.write_error(e).await;
+        }
+
+        self.write_info("Message removed").await
+    }
+
+    // The id of their latest chat in the room if it can still be edited or
+    // removed, telling them why not otherwise
+    async fn changeable(&self, doing: &str, verb: &str, done: &str) -> io::Result<Option<i64>> {
+        let State::Inside { last_sent, .. } = &self.state else {
+            return Ok(None);
+        };
+
+        let window = Duration::from_secs(self.ctx.config.load().edit_window_secs);
+        if window.is_zero() {
+            let msg = format!("{} messages is turned off", doing);
+            self.write_failure(Code::Forbidden, msg).await?;
+            return Ok(None);
+        }
+        let Some(id) = *last_sent else {
+            let msg = format!("You haven't sent a message in this room to {}", verb);
+            self.write_failure(Code::MessageNotFound, msg).await?;
+            return Ok(None);
+        };
+        let elapsed = room::get_time_in_ms().saturating_sub(id).max(0) as u128;
+        if elapsed > window.as_millis() {
+            let msg = format!(
+                "Messages can only be {} for {} after they're sent",
+                done,
+                format_duration(window)
+            );
+            self.write_failure(Code::EditExpired, msg).await?;
+            return Ok(None);
+        }
+
+        Ok(Some(id))
+    }
+
+    // Whether it's within the room's length limit, telling them if not
+    async fn check_length(&self, msg: &str) -> io::Result<bool> {
+        let State::Inside { settings, .. } = &self.state else {
+            return Ok(true);
+        };
+        let max = settings
+            .max_message_len
+            .unwrap_or(self.ctx.config.load().limits.max_message_len);
+        let len = msg.chars().count();
+
+        if max > 0 && len > max {
+            let msg = format!("Message too long ({}/{} characters)", len, max);
+            self.write_failure(Code::TooLong, msg).await?;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    // The message as the word filter leaves it, None if it's blocked
+    async fn filter(&self, msg: String) -> io::Result<Option<String>> {
+        let msg = match self.ctx.config.load().filter.mode {
+            FilterMode::Off => msg,
+            FilterMode::Mask => self.ctx.filter.load().mask(&msg).unwrap_or(msg),
+            FilterMode::Block => {
+                if self.ctx.filter.load().mask(&msg).is_some() {
+                    self.write_failure(
+                        Code::Blocked,
+                        "Your message was blocked by the word filter",
+                    )
+                    .await?;
+
+                    return Ok(None);
+                }
+
+                msg
+            }
+        };
+
+        Ok(Some(msg))
+    }
+
+    // Sent like any other message, so it's checked and stored the same way
+    async fn handle_quote(&mut self, n: usize, reply: &str) -> io::Result<()> {
+        if let State::Outside = self.state {
+            return self.write_not_in_room().await;
+        }
+
+        match self.delivered.quote(n, reply) {
+            Ok(msg) => self.handle_message(msg).await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    // Everyone in the room is told, but it isn't kept in history
+    async fn handle_react(&mut self, n: usize, reaction: String) -> io::Result<()> {
+        if let State::Outside = self.state {
+            return self.write_not_in_room().await;
+        }
+        let (author, id) = match reactions::target(&self.delivered, n) {
+            Ok(target) => target,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        // The room's told, so it's held to what a message would be
+        if !self.paced().await? {
+            return Ok(());
+        }
+        let Some(reaction) = self.filter(reaction).await? else {
+            return Ok(());
+        };
+
+        let State::Inside {
+            room, tx, settings, ..
+        } = &self.state
+        else {
+            return Ok(());
+        };
+        let user = self.user.username.as_ref().unwrap();
+        let store = &*self.ctx.store;
+        let ttl = settings.ephemeral;
+        let reacted = reactions::react(store, room, id, &author, user, &reaction, ttl).await;
+        if let Err(e) = reacted {
+            return self.write_error(e).await;
+        }
+
+        let notice = format!("{} reacted to {}'s message with {}", user, author, reaction);
+        let event = BrokerEvent::Message {
+            conn: self.conn.id(),
+            user: user.to_owned(),
+            msg: TextRenderer::text(ServerMessage::info(&notice)),
+            id: None,
+        };
+        if let Err(e) = self.broker_send(tx, event).await {
+            return self.write_error(e).await;
+        }
+
+        self.write_info(notice).await
+    }
+
+    async fn handle_reactions(&self, n: usize) -> io::Result<()> {
+        let State::Inside { room, .. } = &self.state else {
+            return self.write_not_in_room().await;
+        };
+
+        let (author, id) = match reactions::target(&self.delivered, n) {
+            Ok(target) => target,
+            Err(e) => return self.write_error(e).await,
+        };
+        let listed = match reactions::list(&*self.ctx.store, room, id).await {
+            Ok(listed) => listed,
+            Err(e) => return self.write_error(e).await,
+        };
+        if listed.is_empty() {
+            let msg = format!("No reactions to {}'s message", author);
+            return self.write_info(msg).await;
+        }
+
+        let mut lines = vec![format!("Reactions to {}'s message:", author)];
+        for (reaction, users) in listed {
+            lines.push(format!("{} {}", reaction, users.join(", ")));
+        }
+
+        self.write_message(ServerMessage::Lines { lines }).await
+    }
+
+    async fn handle_join(
+        &mut self,
+        stream: SharedStream,
+        new_room: String,
+        room_map: &RoomMap,
+        resumed: Option<ConnId>,
+    ) -> io::Result<()> {
+        if let State::Inside { room, .. } = &self.state {
+            if *room == new_room {
+                let msg = format!("You're already in '{}'", room);
+                return self.write_info(msg).await;
+            }
+        }
+
+        // The old room's only left once the new one's joined, so a failed
+        // join leaves them where they were
+        let (settings, topic) = self.room_settings(&new_room).await;
+        let joined = self.join_room(stream, room_map, &new_room, &settings, topic, resumed);
+        let Some((tx, delivered)) = joined.await? else {
+            return Ok(());
+        };
+        if let State::Inside { room, tx, .. } = &self.state {
+            self.leave_room(tx, room).await?;
+        }
+
+        // Update state
+        self.set_state(State::Inside {
+            room: new_room.clone(),
+            tx,
+            settings: Box::new(settings),
+            repeats: Repeats::default(),
+            last_sent: None,
+        });
+        self.delivered = delivered;
+
+        self.save_session().await;
+
+        Ok(())
+    }
+
+    // Any room but the current one, picking again if it's been deleted by the
+    // time it's joined
+    async fn handle_random_room(
+        &mut self,
+        stream: SharedStream,
+        room_map: &RoomMap,
+    ) -> io::Result<()> {
+        let rooms = match self.ctx.store.list().await {
+            Ok(rooms) => rooms,
+            Err(e) => return self.write_error(e).await,
+        };
+        let current = match &self.state {
+            State::Inside { room, .. } => Some(room.clone()),
+            State::Outside => None,
+        };
+
+        let conns = self.conn.registry().snapshot();
+        let mut candidates: Vec<(String, usize)> = rooms
+            .into_iter()
+            .filter(|room| Some(room) != current.as_ref())
+            .map(|room| {
+                let members = conns
+                    .iter()
+                    .filter(|conn| conn.room.as_deref() == Some(room.as_str()))
+                    .count();
+                (room, members)
+            })
+            .collect();
+
+        loop {
+            let Some(room) = room::pick_random(&candidates).map(str::to_owned) else {
+                let hint = self.ctx.commands.format(&Command::CreateRoom {
+                    name: "name".to_owned(),
+                    opts: CreateRoomOpts {
+                        join: true,
+                        ..Default::default()
+                    },
+                });
+                let msg = format!("No rooms to join, why not create one with {}", hint);
+                return self.write_info(msg).await;
+            };
+
+            if room_map.read().await.contains_key(&room) {
+                let msg = format!("Picked '{}' at random", room);
+                self.write_info(msg).await?;
+
+                return self.handle_join(stream, room, room_map, None).await;
+            }
+            candidates.retain(|(name, _)| *name != room);
+        }
+    }
+
+    // Falls back to no slow mode and the server's limit if the room's info
+    // can't be read. The topic's only needed on joining, so isn't kept.
+    async fn room_settings(&self, room: &str) -> (RoomSettings, Option<String>) {
+        let store = &*self.ctx.store;
+        let user = self.user.username.as_ref().unwrap();
+        let info = room::info(store, room).await.unwrap_or_default();
+        // Anyone can set an unregistered name, so it has to be logged in
+        let role = match self.user.authenticated {
+            true => room::permission(store, room, user)
+                .await
+                .unwrap_or(Role::Member),
+            false => Role::Member,
+        };
+
+        let slow_mode = SlowMode {
+            interval: info.slow_mode,
+            exempt: role >= Role::Moderator,
+            last_message: None,
+        };
+
+        let settings = RoomSettings {
+            slow_mode,
+            max_message_len: info.max_message_len,
+            ephemeral: info.ephemeral,
+        };
+
+        (settings, info.topic)
+    }
+
+    // Everyone in the room re-reads its settings, us included
+    fn settings_changed(&self, room: &str) {
+        let registry = self.conn.registry();
+        for conn in registry.snapshot() {
+            if conn.room.as_deref() == Some(room) {
+                let control = Control::SettingsChanged {
+                    room: room.to_owned(),
+                };
+                registry.send_control(conn.id, control);
+            }
+        }
+    }
+
+    // Whether our role in the current room is at least `needed`, telling the
+    // user why not otherwise
+    async fn check_role(&self, needed: Role) -> io::Result<bool> {
+        let State::Inside { room, .. } = &self.state else {
+            self.write_not_in_room().await?;
+            return Ok(false);
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        // Anyone can set an unregistered name, so it has to be logged in
+        let denied = match room::permission(&*self.ctx.store, room, user).await {
+            Ok(role) if role >= needed && self.user.authenticated => return Ok(true),
+            Ok(role) if role >= needed => format!(
+                "You need to be logged in to use your role in {}, {}register or {}login first",
+                room,
+                self.prefix(),
+                self.prefix()
+            ),
+            Ok(_) if needed == Role::Owner => "Only the room owner can do that".to_owned(),
+            Ok(_) => "You need to be a room moderator to do that".to_owned(),
+            Err(e) => {
+                self.write_error(e).await?;
+                return Ok(false);
+            }
+        };
+        self.write_failure(Code::Forbidden, denied).await?;
+
+        Ok(false)
+    }
+
+    async fn handle_transfer_ownership(&self, to: &str) -> io::Result<()> {
+        let State::Inside { room, tx, .. } = &self.state else {
+            return self.write_not_in_room().await;
+        };
+        let user = self.user.username.as_ref().unwrap();
+        let store = &*self.ctx.store;
+
+        // As they're known, so the owner check matches their name exactly
+        let online = self
+            .conn
+            .registry()
+            .find_by_username(to)
+            .and_then(|conn| conn.username);
+        let known = match online {
+            Some(name) => Some(name),
+            None => match account::is_registered(store, to).await {
+                Ok(true) => Some(to.to_owned()),
+                Ok(false) => None,
+                Err(e) => return self.write_error(e).await,
+            },
+        };
+        let Some(to) = known.filter(|known| !account::is_guest(known)) else {
+            let text = format!("There's nobody called {} online or registered", to);
+            return self.write_failure(Code::InvalidArgument, &text).await;
+        };
+
+        match room::permission(store, room, &to).await {
+            Ok(Role::Owner) => {
+                return self
+                    .write_info(format!("{} already owns {}", to, room))
+                    .await
+            }
+            Ok(_) => {}
+            Err(e) => return self.write_error(e).await,
+        }
+        let from = match room::transfer(store, room, &to).await {
+            Ok(from) => from,
+            Err(e) => return self.write_error(e).await,
+        };
+        let reason = format!("from {}", from.as_deref().unwrap_or("nobody"));
+        self.audit(
+            AuditAction::TransferOwnership,
+            Some(&to),
+            Some(room),
+            Some(&reason),
+        )
+        .await;
+
+        let notice = format!("{} handed ownership of {} to {}", user, room, to);
+        let msg = self.room_event(RoomEvent::Notice(notice), room).await;
+
+        // The owner isn't held back by slow mode
+        self.settings_changed(room);
+
+        self.write_message(ServerMessage::Lines {
+            lines: vec![msg.clone()],
+        })
+        .await?;
+        if let Err(e) = self
+            .broker_send(
+                tx,
+                BrokerEvent::Message {
+                    conn: self.conn.id(),
+                    user: user.to_owned(),
+                    msg,
+                    id: None,
+                },
+            )
+            .await
+        {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_moderators(&self, command: Command) -> io::Result<()> {
+        let State::Inside { room, .. } = &self.state else {
+            return Ok(());
+        };
+        let store = &*self.ctx.store;
+
+        let message = match command {
+            Command::AddMod(name) => match room::add_moderator(store, room, &name).await {
+                Ok(true) => {
+                    self.audit(AuditAction::AddMod, Some(&name), Some(room), None)
+                        .await;
+                    // Moderators aren't held back by slow mode
+                    self.settings_changed(room);
+                    ServerMessage::info(format!("{} is now a moderator of {}", name, room))
+                }
+                Ok(false) => {
+                    ServerMessage::info(format!("{} is already a moderator of {}", name, room))
+                }
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::RemoveMod(name) => match room::remove_moderator(store, room, &name).await {
+                Ok(true) => {
+                    self.audit(AuditAction::RemoveMod, Some(&name), Some(room), None)
+                        .await;
+                    self.settings_changed(room);
+                    ServerMessage::info(format!("{} is no longer a moderator of {}", name, room))
+                }
+                Ok(false) => ServerMessage::info(format!("{} isn't a moderator of {}", name, room)),
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::Mods => match room::moderators(store, room).await {
+                Ok(mods) if mods.is_empty() => ServerMessage::info("No moderators"),
+                Ok(mods) => ServerMessage::info(mods.join("\n")),
+                Err(e) => ServerMessage::error(&e),
+            },
+            _ => return Ok(()),
+        };
+
+        self.write_message(message).await
+    }
+
+    async fn handle_webhooks(&self, command: Command) -> io::Result<()> {
+        let State::Inside { room, .. } = &self.state else {
+            return Ok(());
+        };
+        let store = &*self.ctx.store;
+
+        let message = match command {
+            Command::CreateWebhook(name) => {
+                let name = name.as_deref().unwrap_or(webhook::DEFAULT_NAME);
+                if !webhook::valid_name(name) {
+                    let error = ServerMessage::Error {
+                        code: Code::InvalidArgument,
+                        text: format!(
+                            "Webhook names are up to {} characters",
+                            webhook::MAX_NAME_LEN
+                        ),
+                    };
+                    return self.write_message(error).await;
+                }
+
+                match webhook::create(store, room, name).await {
+                    Ok(token) => ServerMessage::info(format!(
+                        "Webhook {} created, POST to /hooks/{} on the HTTP listener",
+                        name, token
+                    )),
+                    Err(e) => ServerMessage::error(&e),
+                }
+            }
+            Command::Webhooks => match webhook::list(store, room).await {
+                Ok(hooks) if hooks.is_empty() => ServerMessage::info("No webhooks"),
+                Ok(hooks) => {
+                    let hooks: Vec<String> = hooks
+                        .iter()
+                        .map(|hook| format!("{} {}", hook.name, hook.token))
+                        .collect();
+                    ServerMessage::info(hooks.join("\n"))
+                }
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::RevokeWebhook(token) => match webhook::revoke(store, room, &token).await {
+                Ok(true) => ServerMessage::info("Webhook revoked"),
+                Ok(false) => ServerMessage::info(format!("{} has no webhook {}", room, token)),
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::AddOutgoingWebhook(url) => self.add_outgoing_webhook(room, &url).await,
+            Command::OutgoingWebhooks => match webhook::outgoing(store, room).await {
+                Ok(hooks) if hooks.is_empty() => ServerMessage::info("No outgoing webhooks"),
+                Ok(hooks) => {
+                    let urls: Vec<&str> = hooks.iter().map(|hook| hook.url.as_str()).collect();
+                    ServerMessage::info(urls.join("\n"))
+                }
+                Err(e) => ServerMessage::error(&e),
+            },
+            Command::RemoveOutgoingWebhook(url) => {
+                match webhook::remove_outgoing(store, room, &url).await {
+                    Ok(true) => {
+                        ServerMessage::info(format!("No longer sending messages to {}", url))
+                    }
+                    Ok(false) => ServerMessage::info(format!(
+                        "{} isn't an outgoing webhook of {}",
+                        url, room
+                    )),
+                    Err(e) => ServerMessage::error(&e),
+                }
+            }
+            _ => return Ok(()),
+        };
+
+        self.write_message(message).await
+    }
+
+    // The reply, with the secret payloads will be signed with
+    async fn add_outgoing_webhook(&self, room: &str, url: &str) -> ServerMessage {
+        let store = &*self.ctx.store;
+
+        let Some(target) = Target::parse(url) else {
+            return ServerMessage::Error {
+                code: Code::InvalidArgument,
+                text: "Only http:// URLs are supported".to_owned(),
+            };
+        };
+        // Checked again on every delivery, in case it resolves differently
+        let allow_private = self.ctx.config.load().broker.allow_private_webhooks;
+        if let Err(e) = target.resolve(allow_private).await {
+            return ServerMessage::Error {
+                code: Code::InvalidArgument,
+                text: format!("Can't send messages to {}: {}", url, e),
+            };
+        }
+        match webhook::outgoing(store, room).await {
+            Ok(hooks) if hooks.len() >= webhook::MAX_OUTGOING => {
+                return ServerMessage::Error {
+                    code: Code::LimitReached,
+                    text: format!(
+                        "{} already has {} outgoing webhooks",
+                        room,
+                        webhook::MAX_OUTGOING
+                    ),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => return ServerMessage::error(&e),
+        }
+
+        match webhook::add_outgoing(store, room, url).await {
+            Ok(Some(secret)) => ServerMessage::info(format!(
+                "Sending messages to {}, signed with secret {}",
+                url, secret
+            )),
+            Ok(None) => ServerMessage::info(format!(
+                "{} is already an outgoing webhook of {}",
+                url, room
+            )),
+            Err(e) => ServerMessage::error(&e),
+        }
+    }
+
+    async fn handle_set_tags(&self, tags: &[String]) -> io::Result<()> {
+        let State::Inside { room, .. } = &self.state else {
+            return Ok(());
+        };
+
+        let message = match room::set_tags(&*self.ctx.store, room, tags).await {
+            Ok(tags) if tags.is_empty() => {
+                ServerMessage::info(format!("Tags for {} cleared", room))
+            }
+            Ok(tags) => ServerMessage::info(format!("Tags for {} set to {}", room, tags.join(","))),
+            Err(e) => ServerMessage::error(&e),
+        };
+
+        self.write_message(message).await
+    }
+
+    async fn write_my_rooms(&self) -> io::Result<()> {
+        let Some(username) = &self.user.username else {
+            return self
+                .write_failure(Code::NeedsUsername, "You need to pick a username first")
+                .await;
+        };
+        let (owned, joined) = match room::my_rooms(&*self.ctx.store, username).await {
+            Ok(rooms) => rooms,
+            Err(e) => return self.write_error(e).await,
+        };
+        if owned.is_empty() && joined.is_empty() {
+            return self
+                .write_info("You haven't created or joined any rooms")
+                .await;
+        }
+
+        let conns = self.conn.registry().snapshot();
+        let now = room::get_time_in_ms();
+        let mut lines = vec![];
+        for (title, rooms) in [("Rooms you own:", owned), ("Rooms you've joined:", joined)] {
+            if rooms.is_empty() {
+                continue;
+            }
+            lines.push(title.to_owned());
 
-use crate::broker::{self, BrokerEvent, RoomMap, SharedStream};
-use crate::command::Command;
-use crate::room::{self, RoomEvent};
+            for my_room in rooms.iter().take(room::MAX_MY_ROOMS) {
+                let Some(last_activity) = my_room.last_activity else {
+                    lines.push(format!("  (deleted) {}", my_room.name));
+                    continue;
+                };
+                let here = conns
+                    .iter()
+                    .filter(|conn| conn.room.as_deref() == Some(my_room.name.as_str()))
+                    .count();
+                let idle = Duration::from_millis(now.saturating_sub(last_activity).max(0) as u64);
+                lines.push(format!(
+                    "  {} - {} here, active {} ago",
+                    my_room.name,
+                    here,
+                    format_duration(idle)
+                ));
+            }
+            if rooms.len() > room::MAX_MY_ROOMS {
+                lines.push(format!(
+                    "  ...and {} more",
+                    rooms.len() - room::MAX_MY_ROOMS
+                ));
+            }
+        }
 
-pub struct User {
-    addr: String,
-    username: Option<String>,
-}
+        self.write_message(ServerMessage::Lines { lines }).await
+    }
 
-enum State {
-    Inside {
-        room: String,
-        tx: Sender<BrokerEvent>,
-    },
-    Outside,
-}
+    // Sorted the same way each time, so pages keep to the same order
+    async fn write_rooms(&self, opts: ListOptions, room_map: &RoomMap) -> io::Result<()> {
+        let store = &*self.ctx.store;
+        let rooms = match &opts.tag {
+            Some(tag) => room::tagged(store, tag).await,
+            None => store.list().await,
+        };
+        // Only running rooms have anyone in them
+        let members: HashMap<String, usize> = match opts.sort {
+            SortBy::Members => room_map
+                .read()
+                .await
+                .iter()
+                .map(|(room, handle)| (room.clone(), handle.members()))
+                .collect(),
+            _ => HashMap::new(),
+        };
+        let rooms = match rooms {
+            Ok(rooms) => room::sort(store, rooms, opts.sort, &members).await,
+            Err(e) => Err(e),
+        };
 
-pub struct App {
-    redis: Arc<RedisClient>,
-    stream: SharedStream,
-    lines: Lines<BufReader<OwnedReadHalf>>,
-    user: User,
-    state: State,
-}
+        match rooms {
+            Ok(rooms) if rooms.is_empty() && opts.tag.is_some() => {
+                let reply = format!("No rooms tagged {}", opts.tag.unwrap_or_default());
+                self.write_info(reply).await
+            }
+            Ok(rooms) => {
+                self.write_page(rooms, opts.page, |page| {
+                    Command::List(ListOptions { page, ..opts })
+                })
+                .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
 
-impl App {
-    pub fn new(stream: TcpStream, addr: SocketAddr, redis: Arc<RedisClient>) -> Self {
-        let (reader, writer) = stream.into_split();
-        let lines = BufReader::new(reader).lines();
-        let stream = Arc::new(Mutex::new(writer));
+    // One page of a listing, then how to ask for the next if there's more
+    async fn write_page(
+        &self,
+        lines: Vec<String>,
+        page: usize,
+        next: impl FnOnce(usize) -> Command,
+    ) -> io::Result<()> {
+        let size = self.ctx.config.load().page_size;
+        let page = match page::paginate(lines, page, size) {
+            Ok(page) => page,
+            Err(e) => return self.write_error(e).await,
+        };
+        let footer = page.footer(|n| self.ctx.commands.format(&next(n)));
 
-        Self {
-            redis,
-            stream,
-            lines,
-            user: User {
-                addr: addr.to_string(),
-                username: None,
-            },
-            state: State::Outside,
+        self.write_message(ServerMessage::RoomList { rooms: page.items })
+            .await?;
+        match footer {
+            Some(footer) => self.write_info(footer).await,
+            None => Ok(()),
         }
     }
 
-    pub async fn run(mut self, room_map: RoomMap) -> io::Result<()> {
-        self.write_greeting().await?;
+    async fn handle_set_max_length(&self, len: Option<usize>) -> io::Result<()> {
+        let State::Inside { room, .. } = &self.state else {
+            return Ok(());
+        };
 
-        while let Some(message) = self.lines.next_line().await? {
-            let command = Command::parse(message);
-            let stream = self.stream.clone();
+        if let Err(e) = room::set_max_message_len(&*self.ctx.store, room, len).await {
+            return self.write_error(e).await;
+        }
 
-            match command {
-                Command::Help => {
-                    self.write_help().await?;
-                }
-                Command::List => {
-                    match room::list(&self.redis).await {
-                        Ok(list) => self.write_list(list, true).await?,
-                        Err(e) => self.write_error(e).await?,
-                    };
-                }
-                Command::Me => {
-                    self.write_user_info().await?;
-                }
-                Command::SetUsername(username) => {
-                    self.user.username = Some(username);
-                }
-                Command::CreateRoom(room) => {
-                    if let Err(e) = room::new(&self.redis, &room).await {
-                        self.write_error(e).await?
-                    };
+        self.settings_changed(room);
+
+        let message = match len {
+            Some(0) => ServerMessage::info(format!("Max message length for {} removed", room)),
+            Some(len) => ServerMessage::info(format!(
+                "Max message length for {} set to {} characters",
+                room, len
+            )),
+            None => ServerMessage::info(format!(
+                "Max message length for {} reset to the server's",
+                room
+            )),
+        };
+        self.write_message(message).await
+    }
 
-                    broker::spawn_broker(room, &room_map).await;
-                }
-                Command::JoinRoom(room) => {
-                    if self.user.username.is_none() {
-                        self.write_set_username().await?;
-                        continue;
-                    }
+    async fn handle_set_ephemeral(&self, ttl: Option<Duration>) -> io::Result<()> {
+        let State::Inside { room, .. } = &self.state else {
+            return Ok(());
+        };
 
-                    self.handle_join(Arc::clone(&stream), room.clone(), &room_map)
-                        .await?;
-                }
-                Command::Message(msg) => {
-                    self.handle_message(msg).await?;
-                }
-                Command::Leave => {
-                    self.handle_leave().await?;
-                }
-                Command::Invalid => {
-                    self.write_invalid().await?;
-                }
-                Command::Exit => break,
-            }
+        if let Err(e) = room::set_ephemeral(&*self.ctx.store, room, ttl).await {
+            return self.write_error(e).await;
         }
 
-        Ok(())
+        // Picked up by the next message anyone sends
+        self.settings_changed(room);
+
+        let message = match ttl {
+            Some(ttl) => ServerMessage::info(format!(
+                "Messages in {} now expire after {}",
+                room,
+                room::format_ttl(ttl)
+            )),
+            None => ServerMessage::info(format!("Messages in {} no longer expire", room)),
+        };
+        self.write_message(message).await
     }
 
-    async fn write_user_info(&self) -> io::Result<()> {
-        let info = format!(
-            "Username: {:?}, IP: {}\n",
-            self.user.username, self.user.addr
-        );
+    // One line for each setting that's been changed from the default
+    async fn write_room_info(&self) -> io::Result<()> {
+        let State::Inside { room, .. } = &self.state else {
+            return self.write_not_in_room().await;
+        };
+        let info = match room::info(&*self.ctx.store, room).await {
+            Ok(info) => info,
+            Err(e) => return self.write_error(e).await,
+        };
 
-        self.write_all(info.as_bytes()).await?;
+        let mut res = vec![format!("Room: {}", room)];
+        if let Some(owner) = &info.owner {
+            res.push(format!("Owner: {}", owner));
+        }
+        if let Some(topic) = &info.topic {
+            res.push(format!("Topic: {}", topic));
+        }
+        if !info.tags.is_empty() {
+            res.push(format!("Tags: {}", info.tags.join(",")));
+        }
+        if !info.slow_mode.is_zero() {
+            res.push(format!("Slow mode: {}s", info.slow_mode.as_secs()));
+        }
+        if let Some(len) = info.max_message_len {
+            res.push(format!("Max message length: {}", len));
+        }
+        if let Some(ttl) = info.ephemeral {
+            res.push(format!("Messages expire after {}", room::format_ttl(ttl)));
+        }
 
-        Ok(())
+        self.write_info(res.join("\n")).await
     }
 
-    async fn handle_message(&mut self, msg: String) -> io::Result<()> {
-        match &self.state {
-            State::Inside { room, tx } => self.send_message(tx, room, msg).await?,
-            State::Outside => self.write_not_in_room().await?,
-        }
-        Ok(())
+    async fn handle_set_topic(&self, topic: Option<&str>) -> io::Result<()> {
+        let State::Inside { room, .. } = &self.state else {
+            return Ok(());
+        };
+
+        let message = match room::set_topic(&*self.ctx.store, room, topic).await {
+            Ok(()) if topic.is_none() => ServerMessage::info(format!("Topic for {} cleared", room)),
+            Ok(()) => ServerMessage::info(format!("Topic for {} set", room)),
+            Err(e) => ServerMessage::error(&e),
+        };
+
+        self.write_message(message).await
     }
 
-    async fn handle_join(
-        &mut self,
-        stream: SharedStream,
-        new_room: String,
-        room_map: &RoomMap,
-    ) -> io::Result<()> {
-        match &self.state {
-            State::Inside { room, tx } => {
-                self.leave_room(tx, room).await?;
+    // Each room with how many are in it, and the start of its topic
+    async fn write_find(&self, query: &str, page: usize) -> io::Result<()> {
+        let found = match room::find(&*self.ctx.store, query).await {
+            Ok(found) => found,
+            Err(e) => return self.write_error(e).await,
+        };
+        if found.is_empty() {
+            let reply = format!(
+                "No rooms match '{}', try {}list to see them all",
+                query,
+                self.prefix()
+            );
+            return self.write_info(reply).await;
+        }
 
-                if let Some(tx) = self.join_room(stream, room_map, &new_room).await? {
-                    // Update state
-                    self.state = State::Inside { room: new_room, tx }
+        let conns = self.conn.registry().snapshot();
+        let mut res = Vec::new();
+        for (name, topic) in found {
+            let members = conns
+                .iter()
+                .filter(|conn| conn.room.as_deref() == Some(name.as_str()))
+                .count();
+            let mut line = format!("{} ({} online)", name, members);
+
+            if let Some(topic) = topic {
+                let snippet: String = topic.chars().take(TOPIC_SNIPPET_LEN).collect();
+                let more = if snippet.len() < topic.len() {
+                    "…"
+                } else {
+                    ""
                 };
+                line.push_str(&format!(" - {}{}", snippet, more));
             }
-            State::Outside => {
-                if let Some(tx) = self.join_room(stream, &room_map, &new_room).await? {
-                    // Update state
-                    self.state = State::Inside { room: new_room, tx }
-                }
+            res.push(line);
+        }
+
+        let query = query.to_owned();
+        self.write_page(res, page, |page| Command::Find { query, page })
+            .await
+    }
+
+    async fn write_tags(&self) -> io::Result<()> {
+        let message = match room::tag_counts(&*self.ctx.store).await {
+            Ok(counts) if counts.is_empty() => ServerMessage::info("No tags"),
+            Ok(counts) => {
+                let counts: Vec<String> = counts
+                    .iter()
+                    .map(|(tag, count)| format!("{} ({})", tag, count))
+                    .collect();
+                ServerMessage::info(counts.join("\n"))
+            }
+            Err(e) => ServerMessage::error(&e),
+        };
+
+        self.write_message(message).await
+    }
+
+    async fn handle_slow_mode(&self, secs: u64) -> io::Result<()> {
+        let State::Inside { room, tx, .. } = &self.state else {
+            return Ok(());
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = room::set_slow_mode(&*self.ctx.store, room, secs).await {
+            return self.write_error(e).await;
+        }
+        let target = secs.to_string();
+        self.audit(AuditAction::SlowMode, Some(&target), Some(room), None)
+            .await;
+
+        let notice = match secs {
+            0 => format!("Slow mode disabled by {}", user),
+            secs => format!("Slow mode set to {}s by {}", secs, user),
+        };
+        let msg = self.room_event(RoomEvent::Notice(notice), room).await;
+
+        self.settings_changed(room);
+
+        self.write_message(ServerMessage::Lines {
+            lines: vec![msg.clone()],
+        })
+        .await?;
+        if let Err(e) = self
+            .broker_send(
+                tx,
+                BrokerEvent::Message {
+                    conn: self.conn.id(),
+                    user: user.to_owned(),
+                    msg,
+                    id: None,
+                },
+            )
+            .await
+        {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    // Shown as on joining, except for anything `>set-quiet` hides
+    async fn write_history(&self, count: Option<usize>, ids: bool) -> io::Result<()> {
+        let State::Inside { room, settings, .. } = &self.state else {
+            return self.write_not_in_room().await;
+        };
+
+        let count = count.unwrap_or(self.ctx.config.load().history);
+        let store = &*self.ctx.store;
+        let msgs = match room::recent_before(store, room, settings.ephemeral, None, count).await {
+            Ok(msgs) => msgs,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let mut lines = vec![];
+        let mut hidden = 0;
+        for (line, id) in msgs {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            let Some(shown) = self.view.render_at(ServerEvent::parse(line).into(), id) else {
+                hidden += 1;
+                continue;
+            };
+            match ids {
+                true => lines.push(format!("[{}] {}", id, shown)),
+                false => lines.push(shown),
             }
         }
+        if hidden > 0 {
+            lines.extend(self.view.render(presence_hidden(hidden)));
+        }
+        if lines.is_empty() {
+            return self.write_info("No messages to show").await;
+        }
+
+        self.write_message(ServerMessage::Lines { lines }).await
+    }
+
+    async fn handle_delete_message(&self, id: i64) -> io::Result<()> {
+        let State::Inside { room, tx, .. } = &self.state else {
+            return Ok(());
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        let (removed, note) = match room::remove_message(&*self.ctx.store, room, id, user).await {
+            Ok(removed) => removed,
+            Err(e) => return self.write_error(e).await,
+        };
+        let target = id.to_string();
+        self.audit(
+            AuditAction::DeleteMessage,
+            Some(&target),
+            Some(room),
+            Some(removed.trim_end()),
+        )
+        .await;
+
+        self.write_info(format!("Removed message {}", id)).await?;
+        // It's gone from history either way, this only tells whoever's here
+        if let Err(e) = self
+            .broker_send(
+                tx,
+                BrokerEvent::Message {
+                    conn: self.conn.id(),
+                    user: user.to_owned(),
+                    msg: note,
+                    id: None,
+                },
+            )
+            .await
+        {
+            self.write_error(e).await?;
+        }
 
         Ok(())
     }
 
+    // Keeps the registry in sync with which room we're in, and remembers the
+    // room being left
+    fn set_state(&mut self, state: State) {
+        let room = match &state {
+            State::Inside { room, .. } => Some(room.clone()),
+            State::Outside => None,
+        };
+        if let State::Inside { room: left, .. } = &self.state {
+            if room.as_ref() != Some(left) {
+                self.user.last_room = Some(left.clone());
+            }
+        }
+
+        self.conn.registry().set_room(self.conn.id(), room);
+        self.state = state;
+    }
+
     async fn handle_leave(&mut self) -> io::Result<()> {
         match &self.state {
-            State::Inside { room, tx } => {
-                self.leave_room(tx, room).await?;
+            State::Inside { room, tx, .. } => {
+                let room = room.clone();
+                let left = self.leave_room(tx, &room).await?;
 
                 // Update state
-                self.state = State::Outside
+                self.set_state(State::Outside);
+                self.save_session().await;
+
+                if left {
+                    let msg = format!("You left '{}'", room);
+                    self.write_info(msg).await?;
+                }
             }
             State::Outside => self.write_not_in_room().await?,
         }
@@ -165,55 +3340,84 @@ impl App {
         Ok(())
     }
 
-    async fn send_message(
-        &self,
-        tx: &Sender<BrokerEvent>,
-        room: &String,
-        msg: String,
-    ) -> io::Result<()> {
+    // Returns the message's id
+    async fn send_message(&self, tx: &RoomHandle, room: &str, msg: String) -> io::Result<i64> {
         let user = self.user.username.as_ref().unwrap();
+        let mentioned: Vec<String> = mention::parse(&msg)
+            .into_iter()
+            .filter(|name| name != user)
+            .map(str::to_owned)
+            .collect();
 
-        let msg = match room::event(
-            &self.redis,
-            RoomEvent::Chat(msg),
-            room,
-            self.user.username.as_ref().unwrap(),
-        )
-        .await
-        {
-            Ok(msg) => msg,
-            Err(e) => {
-                self.write_error(e).await?;
-                return Ok(());
-            }
-        };
+        let (msg, id) = self.record_event(RoomEvent::Chat(msg), room).await;
+
+        if !mentioned.is_empty() {
+            self.deliver_mentions(&mentioned, room, &msg).await;
+        }
 
         // Send broker event
-        if let Err(e) = tx
-            .send(BrokerEvent::Message {
-                user: user.to_owned(),
-                msg,
-            })
+        if let Err(e) = self
+            .broker_send(
+                tx,
+                BrokerEvent::Message {
+                    conn: self.conn.id(),
+                    user: user.to_owned(),
+                    msg,
+                    id: Some(id),
+                },
+            )
             .await
         {
             self.write_error(e).await?;
         }
 
-        Ok(())
+        Ok(id)
+    }
+
+    // Only those who aren't in the room to see it get it in their inbox
+    async fn deliver_mentions(&self, mentioned: &[String], room: &str, msg: &str) {
+        let present: Vec<String> = self
+            .conn
+            .registry()
+            .snapshot()
+            .into_iter()
+            .filter(|conn| conn.room.as_deref() == Some(room))
+            .filter_map(|conn| conn.username)
+            .collect();
+
+        let line = format!("[{}] {}", room, msg);
+        for name in mentioned.iter().filter(|name| !present.contains(name)) {
+            match self.is_dnd(name).await {
+                Ok(false) => {}
+                Ok(true) => continue,
+                Err(e) => {
+                    error!("Failed to deliver a mention to {}: {}", name, e);
+                    continue;
+                }
+            }
+            if let Err(e) = mention::deliver(&*self.ctx.store, name, &line).await {
+                error!("Failed to deliver a mention to {}: {}", name, e);
+            }
+        }
     }
 
     async fn join_room(
         &self,
         stream: SharedStream,
         room_map: &RoomMap,
-        room: &String,
-    ) -> io::Result<Option<Sender<BrokerEvent>>> {
-        let room_map = room_map.read().await;
+        room: &str,
+        settings: &RoomSettings,
+        topic: Option<String>,
+        resumed: Option<ConnId>,
+    ) -> io::Result<Option<(RoomHandle, Arc<Delivered>)>> {
         let user = self.user.username.as_ref().unwrap();
+        let ephemeral = settings.ephemeral;
 
-        // Get new rooms tx
-        let tx = match room_map.get(room) {
-            Some(tx) => tx.clone(),
+        // Get new rooms tx. The guard's dropped straight away, so a slow
+        // store doesn't hold up rooms being created.
+        let tx = room_map.read().await.get(room).cloned();
+        let tx = match tx {
+            Some(tx) => tx,
             None => {
                 self.write_room_not_found().await?;
 
@@ -221,16 +3425,44 @@ impl App {
             }
         };
 
-        // Join message
-        let join_msg = match room::event(
-            &self.redis,
-            RoomEvent::Join,
-            &room,
-            self.user.username.as_ref().unwrap(),
-        )
-        .await
-        {
-            Ok(msg) => msg,
+        // The join message and history in one go. The room never heard
+        // they'd left if resuming, so there's no join message then.
+        let history = self.ctx.config.load().history;
+        let retention = self.ctx.config.load().retention;
+        let span = info_span!("join", room, elapsed_ms = field::Empty);
+        let joined = match resumed.is_some() {
+            true => {
+                let recent = room::recent(&*self.ctx.store, room, ephemeral, history);
+                self.timings
+                    .time(Stage::Redis, span, recent)
+                    .await
+                    .map(|recent| Some((String::new(), recent)))
+                    .map_err(RoomError::from)
+            }
+            false => {
+                let store = &*self.ctx.store;
+                let join = room::join(store, room, user, retention, ephemeral, history);
+                let joined = self.timings.time(Stage::Redis, span, join).await;
+                if let Ok(Some((msg, _))) = &joined {
+                    self.ctx.chat_log.room(room, msg, room::get_time_in_ms());
+                    // Guest names don't outlive the connection
+                    if !account::is_guest(user) {
+                        if let Err(e) = room::add_member(store, room, user).await {
+                            warn!("Couldn't record {} joining {}: {}", user, room, e);
+                        }
+                    }
+                }
+
+                joined
+            }
+        };
+        let (join_msg, recent_msgs) = match joined {
+            Ok(Some(joined)) => joined,
+            Ok(None) => {
+                self.write_room_not_found().await?;
+
+                return Ok(None);
+            }
             Err(e) => {
                 self.write_error(e).await?;
 
@@ -238,13 +3470,49 @@ impl App {
             }
         };
 
+        // History's shown the way live messages are, and counts as
+        // delivered ahead of anything the room sends
+        let delivered = Arc::new(Delivered::default());
+        let mut history = vec![];
+        let mut hidden = 0;
+        for (line, at) in &recent_msgs {
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            let mut message = ServerMessage::from(ServerEvent::parse(line));
+            // Live chat comes with its id too, for `>react`
+            if let ServerMessage::Chat { ts, .. } = &mut message {
+                *ts = Some(*at);
+            }
+            match self.view.render_at(message.clone(), *at) {
+                Some(shown) => {
+                    history.push(shown);
+                    delivered.push(message);
+                }
+                None => hidden += 1,
+            }
+        }
+        if hidden > 0 {
+            let collapsed = presence_hidden(hidden);
+            history.extend(self.view.render(collapsed.clone()));
+            delivered.push(collapsed);
+        }
+
         // Send broker event
-        if let Err(e) = tx
-            .send(BrokerEvent::JoinRoom {
-                user: user.to_owned(),
-                stream: Arc::clone(&stream),
-                msg: join_msg,
-            })
+        if let Err(e) = self
+            .broker_send(
+                &tx,
+                BrokerEvent::JoinRoom {
+                    conn: self.conn.id(),
+                    user: user.to_owned(),
+                    stream: Arc::clone(&stream),
+                    msg: join_msg,
+                    typing: self.user.caps.contains(Cap::Typing),
+                    resumed,
+                    control: self.conn.control_sender(),
+                    write_timeout: self.write_timeout(),
+                    delivered: Arc::clone(&delivered),
+                    view: Arc::clone(&self.view),
+                },
+            )
             .await
         {
             self.write_error(e).await?;
@@ -252,132 +3520,305 @@ impl App {
             return Ok(None);
         };
 
-        // Write recent messages
-        let recent_msgs = match room::recent_msgs(&self.redis, &room).await {
-            Ok(m) => m,
-            Err(e) => {
-                self.write_error(e).await?;
-
-                // Connected by this point so return tx
-                return Ok(Some(tx));
-            }
+        // Users rather than connections, someone on two devices counts once.
+        // This one isn't in the room as far as the registry knows just yet.
+        let mut members: HashSet<String> = self
+            .conn
+            .registry()
+            .snapshot()
+            .into_iter()
+            .filter(|conn| conn.room.as_deref() == Some(room))
+            .filter_map(|conn| conn.username)
+            .collect();
+        members.insert(user.to_owned());
+        let mut header = match members.len() {
+            1 => format!("Joined '{}' — 1 member", room),
+            n => format!("Joined '{}' — {} members", room, n),
         };
-        self.write_list(recent_msgs, false).await?;
+        if let Some(topic) = topic {
+            header.push_str(&format!(", topic: {}", topic));
+        }
 
-        Ok(Some(tx))
+        // Written in one go, so nothing live lands in the middle of it
+        let mut lines = vec![header];
+        lines.extend(history);
+        lines.push(LIVE.to_owned());
+        self.write_message(ServerMessage::Lines { lines }).await?;
+
+        Ok(Some((tx, delivered)))
     }
 
-    async fn leave_room(&self, tx: &Sender<BrokerEvent>, room: &String) -> io::Result<()> {
+    // Returns false if the room couldn't be told, the error's been written
+    async fn leave_room(&self, tx: &RoomHandle, room: &str) -> io::Result<bool> {
         let user = self.user.username.as_ref().unwrap();
 
         // Leave msg
-        let msg = match room::event(
-            &self.redis,
-            RoomEvent::Leave,
-            room,
-            self.user.username.as_ref().unwrap(),
-        )
-        .await
-        {
-            Ok(msg) => msg,
-            Err(e) => {
-                self.write_error(e).await?;
-                return Ok(());
-            }
-        };
+        let msg = self.room_event(RoomEvent::Leave, room).await;
 
         // Send broker event
-        if let Err(e) = tx
-            .send(BrokerEvent::LeaveRoom {
-                user: user.to_owned(),
-                msg,
-            })
+        if let Err(e) = self
+            .broker_send(
+                tx,
+                BrokerEvent::LeaveRoom {
+                    conn: self.conn.id(),
+                    user: user.to_owned(),
+                    msg,
+                },
+            )
             .await
         {
             self.write_error(e).await?;
+
+            return Ok(false);
         };
 
-        Ok(())
+        Ok(true)
     }
 
-    async fn write_greeting(&self) -> io::Result<()> {
-        let greeting = b"Welcome to ChatsApp!
-Enter \">help\" for a list of commands and their usage.\n\n\n";
+    async fn room_event(&self, event: RoomEvent, room: &str) -> String {
+        self.record_event(event, room).await.0
+    }
 
-        self.write_all(greeting).await?;
+    // With the message's id, see `room::record`
+    async fn record_event(&self, event: RoomEvent, room: &str) -> (String, i64) {
+        let user = self.user.username.as_ref().unwrap();
+        let ephemeral = self.ephemeral(room);
+        let span = info_span!("room_event", room, elapsed_ms = field::Empty);
+
+        self.timings
+            .time(
+                Stage::Redis,
+                span,
+                room::record(&self.ctx, event, room, user, ephemeral),
+            )
+            .await
+    }
 
-        Ok(())
+    // Only known for the room we're in, anywhere else's expired messages are
+    // left for its own members' next write
+    fn ephemeral(&self, room: &str) -> Option<Duration> {
+        match &self.state {
+            State::Inside {
+                room: current,
+                settings,
+                ..
+            } if current == room => settings.ephemeral,
+            _ => None,
+        }
     }
 
-    async fn write_invalid(&self) -> io::Result<()> {
-        let invalid = b"Invalid command.
-Enter \">help\" for a list of commands and their usage.\n";
+    async fn broker_send(
+        &self,
+        tx: &RoomHandle,
+        event: BrokerEvent,
+    ) -> Result<(), SendError<BrokerEvent>> {
+        let span = info_span!("broker_send", elapsed_ms = field::Empty);
+
+        self.timings.time(Stage::Broker, span, tx.send(event)).await
+    }
 
-        self.write_all(invalid).await?;
+    async fn write_greeting(&self) -> io::Result<()> {
+        let greeting = ServerMessage::Greeting {
+            prefix: self.prefix(),
+            guest: self.user.username.clone(),
+            motd: self.ctx.config.load().motd.clone(),
+        };
 
-        Ok(())
+        self.write_message(greeting).await
+    }
+
+    async fn write_invalid(&self, error: ParseError) -> io::Result<()> {
+        let invalid = ServerMessage::Invalid {
+            error,
+            prefix: self.prefix(),
+        };
+
+        self.write_message(invalid).await
     }
 
     async fn write_help(&self) -> io::Result<()> {
-        let help = b"\
+        // `>` only appears at the start of commands
+        let help = "\
 Commands:
 >help              - Display commands
+>help errors       - List error codes and what they mean
 >exit              - Close connection
->list              - List rooms
+>list [tag:name] [sort:order] [page n] - List rooms, or only those with a tag, a page at a time
+>tags              - List tags and how many rooms have each
+>find query [page n] - Search room names and topics
 >me                - Your user info
+>stats             - Server statistics
+>uptime            - How long the server and your connection have been up
+>users [filter]    - List who's online, filtered with eg bo*
+>my-rooms          - List the rooms you own and the others you've joined
 >set-username name - Set username
->create-room room  - Create room
->join-room room    - Join room\n";
-
-        self.write_all(help).await?;
+>register password - Register your username, so setting it needs a password
+>login password    - Log in as the registered username you've set
+>passwd old new    - Change your password
+>session           - Get a token to pick up where you left off after reconnecting
+>resume token      - Restore a session, as the first command
+>create-room room [--join] [--ephemeral 1h] [--topic text] [--max-length n] - Create room with any of the settings below, and join it with --join
+>join-room room    - Join room
+>random-room       - Join a room picked at random, likely one with people in it
+>rejoin            - Join the last room you were in again
+>typing            - Let the room know you're typing
+>set-typing on|off - Show when others are typing
+>set-quiet on|off  - Hide joins and leaves in rooms
+>set-presence-msgs on|off - Show joins and leaves in rooms, kept with a registered name
+>set-ansi on|off   - Dim joins, leaves and room notices with ANSI styling
+>capabilities      - List the capabilities clients can ask for
+>hello [client=name/version] [caps=a,b] - Ask for capabilities, replacing any you had
+>dnd on|off        - Refuse direct messages, and save no mentions for you
+>set-theme name    - Change how rooms look to you: plain, compact, verbose or high-contrast
+>themes            - List the themes, with how a message looks in each
+>set multi-login allow|kick-old|deny - Logging in elsewhere keeps both, closes the old connection (default) or is refused
+>dm name text      - Send someone a direct message
+>dm-history name [n] - Show your last n direct messages with someone
+>mentions          - Show and clear mentions you missed
+>report name reason - Flag someone to the admins, with what they said lately in your room
+>quote n text      - Reply to the nth latest message in the room, 1 being the latest
+>react n reaction  - React to the nth latest message with an emoji or a word, again to change it
+>reactions n       - Show the reactions to the nth latest message
+>history [n] [--ids] - Show the room's last n messages again, with their ids for >delete-msg
+>edit text         - Fix your latest message in the room, within 2 minutes of sending it
+>undo              - Remove your latest message in the room, within 2 minutes of sending it
+>slowmode seconds  - Seconds between each member's messages, 0 disables (moderators)
+>delete-msg id     - Remove a message, leaving a note of who removed it (moderators)
+>mod add|remove name - Add or remove a moderator of the room (owner only)
+>mods              - List the room's moderators (owner only)
+>transfer-ownership name [--force] - Hand the room to someone registered or online (owner only, or admins with --force)
+>room-set tags a,b - Tag the room, or clear its tags (owner only)
+>room-set topic [text] - Set or clear the room's topic (owner only)
+>room-set max-length [n] - Limit messages to n characters, or use the server's (owner only)
+>room-set ephemeral [time] - Expire messages after eg 30m or 2h, or keep them (owner only)
+>room-info         - Show the room's settings
+>webhook create [name] - Create a webhook posting to the room as name (owner only)
+>webhook list      - List the room's webhooks (owner only)
+>webhook revoke token - Revoke a webhook (owner only)
+>webhook-out add|remove url - Send the room's messages to a URL, or stop (owner only)
+>webhook-out list  - List where the room's messages are sent (owner only)
+Admins:
+>op name           - Make a user an admin
+>deop name         - Revoke a user's admin
+>broadcast text    - Message every connection
+>notice text       - Send every connection a server notice
+>shutdown [seconds] [reason] - Warn everyone, then gracefully stop the server
+>ipban address     - Ban an address or range, with an optional reason
+>ipunban address   - Lift a ban
+>ipbans            - List banned addresses
+>audit [n]         - Show the last n moderation actions, 20 by default
+>reports [n]       - Show the last n open reports, 20 by default
+>report-close id [note] - Close a report, noting what was done in the audit log
+>purge-user name [room] - Replace everything someone said in a room, or every room, with a removal note
+>compact [room]    - Apply retention to a room's history, or every room's, now rather than on the next run"
+            .replace(DEFAULT_PREFIX, &self.prefix().to_string());
+
+        self.write_info(help).await?;
 
         Ok(())
     }
 
-    async fn write_list(&self, list: Vec<String>, new_line: bool) -> io::Result<()> {
-        let mut res = String::new();
-
-        for item in list {
-            res.push_str(&item);
-            if new_line {
-                res.push_str("\n");
-            }
-        }
+    async fn write_help_errors(&self) -> io::Result<()> {
+        let mut help = vec!["Errors are sent as [code] text:".to_owned()];
+        help.extend(
+            Code::ALL
+                .iter()
+                .map(|code| format!("{:<20} - {}", code, code.description())),
+        );
 
-        self.write_all(res.as_bytes()).await?;
+        self.write_info(help.join("\n")).await
+    }
 
-        Ok(())
+    fn prefix(&self) -> char {
+        self.ctx.commands.prefix()
     }
 
-    async fn write_error(&self, error: impl std::error::Error) -> io::Result<()> {
-        self.write_all(error.to_string().as_bytes()).await?;
+    async fn write_error(&self, error: impl UserError) -> io::Result<()> {
+        self.write_message(ServerMessage::error(&error)).await
+    }
 
-        Ok(())
+    // For refusals that aren't an error type of their own
+    async fn write_failure(&self, code: Code, text: impl Into<String>) -> io::Result<()> {
+        let text = text.into();
+        self.write_message(ServerMessage::Error { code, text })
+            .await
     }
 
     async fn write_not_in_room(&self) -> io::Result<()> {
-        self.write_all(b"You're not currently in a room.\n").await?;
-
-        Ok(())
+        self.write_failure(Code::NotInRoom, "You're not currently in a room.")
+            .await
     }
 
     async fn write_room_not_found(&self) -> io::Result<()> {
-        self.write_all(b"Room not found\n").await?;
+        self.write_failure(Code::RoomNotFound, "Room not found")
+            .await
+    }
 
-        Ok(())
+    async fn write_info(&self, text: impl Into<String>) -> io::Result<()> {
+        self.write_message(ServerMessage::info(text)).await
     }
 
-    async fn write_set_username(&self) -> io::Result<()> {
-        self.write_all(b"You need to pick a username before joining a room\n")
-            .await?;
+    // Connections all speak the line protocol, others are translated from it
+    async fn write_message(&self, message: ServerMessage) -> io::Result<()> {
+        self.write_all(TextRenderer::text(message).as_bytes()).await
+    }
+
+    // Timing out counts as an error, so a connection that stops reading is
+    // dropped rather than left holding the stream
+    async fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        let span = debug_span!("socket_write", elapsed_ms = field::Empty);
+        let write = async {
+            let mut stream = self.stream.lock().await;
+            stream.write_all(bytes).await
+        };
+        let write = async {
+            time::timeout(self.write_timeout(), write)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Write timed out"))?
+        };
+
+        self.timings.time(Stage::Write, span, write).await?;
 
         Ok(())
     }
 
-    async fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
-        let mut stream = self.stream.lock().await;
-        stream.write_all(bytes).await?;
+    fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.ctx.config.load().broker.write_timeout_secs)
+    }
+}
+
+impl SlowMode {
+    // Records the message unless it's too soon, in which case it's how much
+    // longer to wait
+    fn check(&mut self) -> Result<(), Duration> {
+        if self.exempt || self.interval.is_zero() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        if let Some(last) = self.last_message {
+            let elapsed = now.duration_since(last);
+            if elapsed < self.interval {
+                return Err(self.interval - elapsed);
+            }
+        }
+        self.last_message = Some(now);
 
         Ok(())
     }
 }
+
+// eg `GET / HTTP/1.1`
+fn is_http_request(line: &str) -> bool {
+    let Some((method, rest)) = line.split_once(' ') else {
+        return false;
+    };
+
+    HTTP_METHODS.contains(&method) && rest.rsplit(' ').next().unwrap().starts_with("HTTP/")
+}
+
+// In place of the joins and leaves replayed history leaves out, so it's clear
+// there were some
+fn presence_hidden(hidden: usize) -> ServerMessage {
+    ServerMessage::info(format!("joins/leaves hidden ({})", hidden))
+}
@@ -0,0 +1,231 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::error;
+
+use crate::store::{RoomStore, StoreError};
+
+pub const AUDIT_LOG_KEY: &str = "server:auditlog";
+
+// Older entries are dropped as new ones arrive
+const MAX_ENTRIES: usize = 10_000;
+
+// Shown by `>audit` without a count
+pub const DEFAULT_COUNT: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditAction {
+    Kick,
+    ForceLeave,
+    DeleteRoom,
+    IpBan,
+    IpUnban,
+    Op,
+    Deop,
+    Broadcast,
+    Notice,
+    Shutdown,
+    SlowMode,
+    AddMod,
+    RemoveMod,
+    Mute,
+    DeleteMessage,
+    PurgeUser,
+    Reports,
+    ReportClose,
+    TransferOwnership,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    // A username, or `admin@<addr>` for the admin listener
+    pub actor: String,
+    pub action: AuditAction,
+    pub target: Option<String>,
+    pub room: Option<String>,
+    // Unix time in seconds
+    pub timestamp: i64,
+    pub reason: Option<String>,
+}
+
+const ACTIONS: [(AuditAction, &str); 19] = [
+    (AuditAction::Kick, "kick"),
+    (AuditAction::ForceLeave, "force-leave"),
+    (AuditAction::DeleteRoom, "delete-room"),
+    (AuditAction::IpBan, "ipban"),
+    (AuditAction::IpUnban, "ipunban"),
+    (AuditAction::Op, "op"),
+    (AuditAction::Deop, "deop"),
+    (AuditAction::Broadcast, "broadcast"),
+    (AuditAction::Notice, "notice"),
+    (AuditAction::Shutdown, "shutdown"),
+    (AuditAction::SlowMode, "slowmode"),
+    (AuditAction::AddMod, "mod-add"),
+    (AuditAction::RemoveMod, "mod-remove"),
+    (AuditAction::Mute, "mute"),
+    (AuditAction::DeleteMessage, "delete-msg"),
+    (AuditAction::PurgeUser, "purge-user"),
+    (AuditAction::Reports, "reports"),
+    (AuditAction::ReportClose, "report-close"),
+    (AuditAction::TransferOwnership, "transfer-ownership"),
+];
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        ACTIONS
+            .iter()
+            .find(|(action, _)| action == self)
+            .map(|(_, name)| *name)
+            .unwrap_or_default()
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        ACTIONS
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(action, _)| *action)
+    }
+}
+
+impl AuditEntry {
+    // Timestamped now, fill in the rest with struct update syntax
+    pub fn new(actor: &str, action: AuditAction) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs() as i64)
+            .unwrap_or_default();
+
+        Self {
+            actor: actor.to_owned(),
+            action,
+            target: None,
+            room: None,
+            timestamp,
+            reason: None,
+        }
+    }
+
+    // Tab separated, with empty fields for None
+    fn to_record(&self) -> String {
+        let field = |value: &Option<String>| clean(value.as_deref().unwrap_or_default());
+
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.timestamp,
+            clean(&self.actor),
+            self.action.as_str(),
+            field(&self.target),
+            field(&self.room),
+            field(&self.reason),
+        )
+    }
+
+    fn from_record(record: &str) -> Option<Self> {
+        let mut fields = record.split('\t');
+        let mut next = || fields.next();
+        let optional = |field: &str| (!field.is_empty()).then(|| field.to_owned());
+
+        Some(Self {
+            timestamp: next()?.parse().ok()?,
+            actor: next()?.to_owned(),
+            action: AuditAction::parse(next()?)?,
+            target: optional(next()?),
+            room: optional(next()?),
+            reason: optional(next()?),
+        })
+    }
+}
+
+impl std::fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            format_time(self.timestamp),
+            self.actor,
+            self.action.as_str()
+        )?;
+
+        if let Some(target) = &self.target {
+            write!(f, " {}", target)?;
+        }
+        if let Some(room) = &self.room {
+            write!(f, " in {}", room)?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Failing to record shouldn't stop the action itself, so errors are only
+// logged
+pub async fn record(store: &dyn RoomStore, entry: AuditEntry) {
+    if let Err(e) = store
+        .list_push(AUDIT_LOG_KEY, &entry.to_record(), MAX_ENTRIES)
+        .await
+    {
+        error!(
+            "Failed to record {:?} in the audit log: {}",
+            entry,
+            e.to_string().trim_end()
+        );
+    }
+}
+
+/// The last `count` entries, oldest first.
+pub async fn recent(store: &dyn RoomStore, count: usize) -> Result<Vec<AuditEntry>, StoreError> {
+    let records = store.list_recent(AUDIT_LOG_KEY, count).await?;
+
+    Ok(records
+        .iter()
+        .filter_map(|record| AuditEntry::from_record(record))
+        .collect())
+}
+
+// Renders the last `count` entries, one per line
+pub async fn render(store: &dyn RoomStore, count: usize) -> String {
+    match recent(store, count).await {
+        Ok(entries) if entries.is_empty() => "The audit log is empty\n".to_owned(),
+        Ok(entries) => entries.iter().map(|entry| format!("{}\n", entry)).collect(),
+        Err(e) => e.to_string(),
+    }
+}
+
+// Fields can't contain the separator, or span lines
+pub fn clean(field: &str) -> String {
+    field.replace(['\t', '\r', '\n'], " ")
+}
+
+// `2024-01-31 13:45:00 UTC`
+pub fn format_time(timestamp: i64) -> String {
+    let (year, month, day) = civil_date(timestamp.div_euclid(86_400));
+    let secs = timestamp.rem_euclid(86_400);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// <Year, Month, Day> from days since the epoch, as in
+// http://howardhinnant.github.io/date_algorithms.html
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
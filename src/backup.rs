@@ -0,0 +1,485 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+use crate::room;
+use crate::store::{RoomStore, StoreError};
+
+// Messages read from, or written to, storage at a time
+const BATCH: usize = 500;
+
+// `chatsapp backup` and `chatsapp restore`, see `Task::from_args`
+#[derive(Debug, PartialEq)]
+pub enum Task {
+    Backup { out: PathBuf },
+    Restore { input: PathBuf, mode: RestoreMode },
+}
+
+// What to do with a room in the dump that's already in storage
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RestoreMode {
+    // Leave it as it is, skipping it
+    #[default]
+    Skip,
+    // Add the messages and settings it doesn't have
+    Merge,
+    // Delete it first
+    Replace,
+}
+
+// One line of a dump, a room's messages following it oldest first
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Record {
+    Room {
+        name: String,
+        // <Field, Value> from `roominfo:{name}`
+        settings: Vec<(String, String)>,
+        mods: Vec<String>,
+    },
+    Message {
+        score: i64,
+        msg: String,
+    },
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub rooms: usize,
+    pub messages: usize,
+    pub skipped_rooms: usize,
+    pub skipped_messages: usize,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    Store(StoreError),
+    Io(io::Error),
+    // The line number, from 1
+    Parse(usize, String),
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} room(s), {} message(s)", self.rooms, self.messages)?;
+        if self.skipped_rooms > 0 || self.skipped_messages > 0 {
+            write!(
+                f,
+                ", skipped {} room(s) and {} message(s)",
+                self.skipped_rooms, self.skipped_messages
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupError::Store(e) => write!(f, "{}", e),
+            BackupError::Io(e) => writeln!(f, "Error: {}", e),
+            BackupError::Parse(line, reason) => writeln!(f, "Error: Line {}: {}", line, reason),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+impl From<StoreError> for BackupError {
+    fn from(e: StoreError) -> Self {
+        BackupError::Store(e)
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(e: io::Error) -> Self {
+        BackupError::Io(e)
+    }
+}
+
+impl Task {
+    /// The subcommand, when the first arg is one, with its own flags taken
+    /// out. The rest are left for `AppConfig::from_args`, to say where
+    /// storage is.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::backup::{RestoreMode, Task};
+    ///
+    /// let args = ["backup", "--out", "dump.jsonl", "--storage", "sqlite"].map(String::from);
+    /// let (task, rest) = Task::from_args(args.to_vec()).unwrap();
+    /// assert_eq!(task, Some(Task::Backup { out: "dump.jsonl".into() }));
+    /// assert_eq!(rest, ["--storage", "sqlite"]);
+    ///
+    /// let args = ["restore", "--replace", "--in", "dump.jsonl"].map(String::from);
+    /// let (task, rest) = Task::from_args(args.to_vec()).unwrap();
+    /// let mode = RestoreMode::Replace;
+    /// assert_eq!(task, Some(Task::Restore { input: "dump.jsonl".into(), mode }));
+    /// assert!(rest.is_empty());
+    ///
+    /// // Serving as usual without one
+    /// let args = ["--bind", "0.0.0.0:8000"].map(String::from);
+    /// assert_eq!(Task::from_args(args.to_vec()).unwrap(), (None, args.to_vec()));
+    ///
+    /// assert!(Task::from_args(vec!["backup".into()]).is_err());
+    /// assert!(Task::from_args(["restore", "--in", "dump.jsonl", "--merge", "--replace"].map(String::from).to_vec()).is_err());
+    /// ```
+    pub fn from_args(args: Vec<String>) -> Result<(Option<Task>, Vec<String>), ConfigError> {
+        let name = match args.first().map(String::as_str) {
+            Some(name @ ("backup" | "restore")) => name.to_owned(),
+            _ => return Ok((None, args)),
+        };
+
+        let mut path = None;
+        let mut mode = None;
+        let mut rest = Vec::new();
+        let mut args = args.into_iter().skip(1);
+
+        while let Some(flag) = args.next() {
+            match (name.as_str(), flag.as_str()) {
+                ("backup", "--out") | ("restore", "--in") => {
+                    let value = args.next().ok_or(ConfigError::MissingValue(flag))?;
+                    path = Some(PathBuf::from(value));
+                }
+                ("restore", "--merge") => mode = Some(pick(mode, RestoreMode::Merge)?),
+                ("restore", "--replace") => mode = Some(pick(mode, RestoreMode::Replace)?),
+                _ => rest.push(flag),
+            }
+        }
+
+        let task = match (name.as_str(), path) {
+            ("backup", Some(out)) => Task::Backup { out },
+            ("backup", None) => Err(ConfigError::MissingValue("--out".to_owned()))?,
+            (_, Some(input)) => Task::Restore {
+                input,
+                mode: mode.unwrap_or_default(),
+            },
+            (_, None) => Err(ConfigError::MissingValue("--in".to_owned()))?,
+        };
+
+        Ok((Some(task), rest))
+    }
+}
+
+// Runs the task against its file, returning what to tell whoever ran it
+pub async fn run(task: Task, store: &dyn RoomStore) -> Result<String, BackupError> {
+    match task {
+        Task::Backup { out } => {
+            let file = BufWriter::new(File::create(&out)?);
+            let summary = backup(store, file).await?;
+
+            Ok(format!("Backed up {} to {}", summary, out.display()))
+        }
+        Task::Restore { input, mode } => {
+            let file = BufReader::new(File::open(&input)?);
+            let summary = restore(store, file, mode).await?;
+
+            Ok(format!("Restored {} from {}", summary, input.display()))
+        }
+    }
+}
+
+/// Writes every room's settings, moderators and history to `out`, as a line
+/// of JSON each, through `RoomStore` alone so any backend can be backed up and
+/// restored to any other. Each room's history is read into memory before
+/// it's written, and the server should be stopped first so nothing's missed.
+/// Webhooks aren't kept, nor is anything outside rooms like accounts and bans.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::backup::{self, RestoreMode, Summary};
+/// use chatsapp::room::{self, CreateRoomOpts};
+/// use chatsapp::store::{MemoryStore, RoomStore};
+///
+/// let store = MemoryStore::default();
+/// let opts = CreateRoomOpts { topic: Some("lifetimes".into()), ..Default::default() };
+/// room::create(&store, "rust", Some("alice"), &opts).await.unwrap();
+/// room::set_tags(&store, "rust", &["lang".into()]).await.unwrap();
+/// room::add_moderator(&store, "rust", "carol").await.unwrap();
+/// // More than a page
+/// let msgs: Vec<(String, i64)> = (1..=1200).map(|n| (format!("bob: {}\n", n), 1000 + n)).collect();
+/// store.append_many("rust", &msgs, None).await.unwrap();
+/// room::create(&store, "go", None, &CreateRoomOpts::default()).await.unwrap();
+///
+/// let mut dump = vec![];
+/// let summary = backup::backup(&store, &mut dump).await.unwrap();
+/// assert_eq!((summary.rooms, summary.messages), (2, 1202));
+///
+/// // Into somewhere empty, everything comes back as it was
+/// let copy = MemoryStore::default();
+/// let summary = backup::restore(&copy, &dump[..], RestoreMode::Skip).await.unwrap();
+/// assert_eq!(summary, Summary { rooms: 2, messages: 1202, ..Default::default() });
+/// for name in ["rust", "go"] {
+///     let history = store.recent_before(name, None, 2000).await.unwrap();
+///     assert_eq!(copy.recent_before(name, None, 2000).await.unwrap(), history);
+///     assert_eq!(room::info(&copy, name).await.unwrap(), room::info(&store, name).await.unwrap());
+/// }
+/// assert_eq!(room::moderators(&copy, "rust").await.unwrap(), ["carol"]);
+/// assert_eq!(room::tagged(&copy, "lang").await.unwrap(), ["rust"]);
+/// assert_eq!(room::owned_by(&copy, "alice").await.unwrap(), 1);
+///
+/// // Rooms that are already there are skipped unless told otherwise
+/// copy.append("go", "bob: newer\n", 5000, None).await.unwrap();
+/// let summary = backup::restore(&copy, &dump[..], RestoreMode::Skip).await.unwrap();
+/// assert_eq!(summary.to_string(), "0 room(s), 0 message(s), skipped 2 room(s) and 1202 message(s)");
+///
+/// // Merging only adds what's missing
+/// copy.delete("rust").await.unwrap();
+/// let summary = backup::restore(&copy, &dump[..], RestoreMode::Merge).await.unwrap();
+/// assert_eq!(summary.to_string(), "2 room(s), 1201 message(s), skipped 0 room(s) and 1 message(s)");
+/// assert_eq!(copy.recent("go", 10).await.unwrap(), ["Start of chat\n", "bob: newer\n"]);
+/// assert_eq!(room::owned_by(&copy, "alice").await.unwrap(), 1);
+///
+/// // Replacing starts the room over
+/// let summary = backup::restore(&copy, &dump[..], RestoreMode::Replace).await.unwrap();
+/// assert_eq!(summary, Summary { rooms: 2, messages: 1202, ..Default::default() });
+/// assert_eq!(copy.recent("go", 10).await.unwrap(), ["Start of chat\n"]);
+/// assert_eq!(room::owned_by(&copy, "alice").await.unwrap(), 1);
+/// assert_eq!(room::tag_counts(&copy).await.unwrap(), [("lang".to_owned(), 1)]);
+///
+/// let bad = b"{\"kind\":\"message\",\"score\":1,\"msg\":\"bob: hi\\n\"}\n";
+/// let e = backup::restore(&copy, &bad[..], RestoreMode::Skip).await.unwrap_err();
+/// assert_eq!(e.to_string(), "Error: Line 1: a message before any room\n");
+/// # }
+/// ```
+pub async fn backup(store: &dyn RoomStore, mut out: impl Write) -> Result<Summary, BackupError> {
+    let mut rooms = store.list().await?;
+    rooms.sort();
+
+    let mut summary = Summary::default();
+    for name in rooms {
+        let history = history(store, &name).await?;
+        let mut mods = room::moderators(store, &name).await?;
+        mods.sort();
+        let settings = room::settings(store, &name).await?;
+
+        write_record(
+            &mut out,
+            &Record::Room {
+                name,
+                settings,
+                mods,
+            },
+        )?;
+        summary.rooms += 1;
+        summary.messages += history.len();
+        for (msg, score) in history {
+            write_record(&mut out, &Record::Message { score, msg })?;
+        }
+    }
+    out.flush()?;
+
+    Ok(summary)
+}
+
+/// Reads back what `backup` wrote, each message with the score it had so
+/// history keeps its order, deciding what happens to rooms already in storage
+/// by `mode`. See `backup` for an example.
+pub async fn restore(
+    store: &dyn RoomStore,
+    input: impl BufRead,
+    mode: RestoreMode,
+) -> Result<Summary, BackupError> {
+    let mut summary = Summary::default();
+    let mut current: Option<Restoring> = None;
+
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record =
+            serde_json::from_str(&line).map_err(|e| BackupError::Parse(n + 1, e.to_string()))?;
+        match record {
+            Record::Room {
+                name,
+                settings,
+                mods,
+            } => {
+                if let Some(room) = current.take() {
+                    room.finish(store, &mut summary).await?;
+                }
+                let room = Restoring::start(store, name, mode, &mut summary).await?;
+                if !room.skip {
+                    room::restore_settings(store, &room.name, &settings, &mods).await?;
+                }
+                current = Some(room);
+            }
+            Record::Message { score, msg } => {
+                let Some(room) = &mut current else {
+                    let reason = "a message before any room".to_owned();
+                    return Err(BackupError::Parse(n + 1, reason));
+                };
+                room.push(store, msg, score, &mut summary).await?;
+            }
+        }
+    }
+
+    if let Some(room) = current {
+        room.finish(store, &mut summary).await?;
+    }
+
+    Ok(summary)
+}
+
+// A room being restored, its messages written a batch at a time
+struct Restoring {
+    name: String,
+    // Already there, and left as it is
+    skip: bool,
+    // <Message, Score> it already had, when merging
+    existing: HashSet<(String, i64)>,
+    batch: Vec<(String, i64)>,
+    written: usize,
+}
+
+impl Restoring {
+    async fn start(
+        store: &dyn RoomStore,
+        name: String,
+        mode: RestoreMode,
+        summary: &mut Summary,
+    ) -> Result<Self, StoreError> {
+        let mut room = Restoring {
+            name,
+            skip: false,
+            existing: HashSet::new(),
+            batch: Vec::new(),
+            written: 0,
+        };
+
+        if store.meta(&room.name).await?.is_some() {
+            match mode {
+                RestoreMode::Skip => {
+                    room.skip = true;
+                    summary.skipped_rooms += 1;
+                }
+                RestoreMode::Merge => {
+                    room.existing = history(store, &room.name).await?.into_iter().collect();
+                }
+                RestoreMode::Replace => {
+                    room::delete(store, &room.name).await?;
+                }
+            }
+        }
+
+        Ok(room)
+    }
+
+    async fn push(
+        &mut self,
+        store: &dyn RoomStore,
+        msg: String,
+        score: i64,
+        summary: &mut Summary,
+    ) -> Result<(), StoreError> {
+        let msg = (msg, score);
+        if self.skip || self.existing.contains(&msg) {
+            summary.skipped_messages += 1;
+            return Ok(());
+        }
+
+        self.batch.push(msg);
+        if self.batch.len() >= BATCH {
+            self.flush(store, summary).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn flush(
+        &mut self,
+        store: &dyn RoomStore,
+        summary: &mut Summary,
+    ) -> Result<(), StoreError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        store.append_many(&self.name, &self.batch, None).await?;
+        summary.messages += self.batch.len();
+        self.written += self.batch.len();
+        self.batch.clear();
+
+        Ok(())
+    }
+
+    async fn finish(
+        mut self,
+        store: &dyn RoomStore,
+        summary: &mut Summary,
+    ) -> Result<(), StoreError> {
+        if self.skip {
+            return Ok(());
+        }
+
+        self.flush(store, summary).await?;
+        // A room with no history at all still has to exist
+        if self.written == 0 && store.meta(&self.name).await?.is_none() {
+            store.create(&self.name).await?;
+        }
+        summary.rooms += 1;
+
+        Ok(())
+    }
+}
+
+// The room's whole history, oldest first. Messages can share a ms, so a page
+// ending partway through one is read again from that ms rather than risk
+// skipping any.
+async fn history(store: &dyn RoomStore, room: &str) -> Result<Vec<(String, i64)>, StoreError> {
+    let mut pages = Vec::new();
+    let mut before = None;
+    let mut count = BATCH;
+
+    loop {
+        let mut page = store.recent_before(room, before, count).await?;
+        if page.len() < count {
+            pages.push(page);
+            break;
+        }
+
+        let oldest = page[0].1;
+        let ties = page
+            .iter()
+            .take_while(|(_, score)| *score == oldest)
+            .count();
+        // A whole page in one ms, so more are read at once until it ends
+        if ties == page.len() {
+            count *= 2;
+            continue;
+        }
+
+        page.drain(..ties);
+        pages.push(page);
+        before = Some(oldest + 1);
+        count = BATCH;
+    }
+
+    Ok(pages.into_iter().rev().flatten().collect())
+}
+
+// Either flag may be given, but not both
+fn pick(mode: Option<RestoreMode>, flag: RestoreMode) -> Result<RestoreMode, ConfigError> {
+    match mode {
+        Some(mode) if mode != flag => Err(ConfigError::Invalid(
+            "--merge and --replace can't be used together",
+        )),
+        _ => Ok(flag),
+    }
+}
+
+fn write_record(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    writeln!(out)
+}
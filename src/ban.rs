@@ -0,0 +1,234 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::de::{self, Deserialize, Deserializer};
+use tokio::sync::RwLock;
+
+use crate::store::{RoomStore, StoreError};
+
+pub const IP_BANS_KEY: &str = "server:ipbans";
+
+// How long the accept loops trust their copy of the ban list
+const CACHE_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpBan {
+    pub net: IpNet,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct InvalidNet(pub String);
+
+impl std::fmt::Display for InvalidNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Error: Invalid address or range '{}'", self.0)
+    }
+}
+
+impl std::error::Error for InvalidNet {}
+
+// Shared by the accept loops so they don't hit the store on every accept
+#[derive(Default)]
+pub struct BanList {
+    cache: RwLock<Option<(Instant, Vec<IpBan>)>>,
+}
+
+impl IpNet {
+    /// A single address, or a range in CIDR notation.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::ban::IpNet;
+    ///
+    /// let net = IpNet::parse("203.0.113.0/24").unwrap();
+    /// assert!(net.contains("203.0.113.7".parse().unwrap()));
+    /// assert!(!net.contains("203.0.114.7".parse().unwrap()));
+    ///
+    /// // IPv4 clients of a dual stack listener show up as mapped addresses
+    /// assert!(net.contains("::ffff:203.0.113.7".parse().unwrap()));
+    ///
+    /// let net = IpNet::parse("2001:db8::/32").unwrap();
+    /// assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+    /// assert!(!net.contains("2001:db9::1".parse().unwrap()));
+    /// assert!(!net.contains("203.0.113.7".parse().unwrap()));
+    ///
+    /// let host = IpNet::parse("2001:db8::1").unwrap();
+    /// assert_eq!(host.to_string(), "2001:db8::1/128");
+    /// let net = IpNet::parse("203.0.113.7/24").unwrap();
+    /// assert_eq!(net.to_string(), "203.0.113.0/24");
+    ///
+    /// assert!(IpNet::parse("203.0.113.0/33").is_err());
+    /// assert!(IpNet::parse("2001:db8::/129").is_err());
+    /// assert!(IpNet::parse("example.com").is_err());
+    /// ```
+    pub fn parse(s: &str) -> Result<Self, InvalidNet> {
+        let invalid = || InvalidNet(s.to_owned());
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max = max_prefix(addr);
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(invalid());
+        }
+
+        // Drop the host bits so the same range always compares equal
+        let addr = match addr {
+            IpAddr::V4(addr) => IpAddr::V4((u32::from(addr) & v4_mask(prefix)).into()),
+            IpAddr::V6(addr) => IpAddr::V6((u128::from(addr) & v6_mask(prefix)).into()),
+        };
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                u32::from(net) == u32::from(addr) & v4_mask(self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                u128::from(net) == u128::from(addr) & v6_mask(self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+// As `parse` takes them, eg in `[runtime.access]`
+impl<'de> Deserialize<'de> for IpNet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        IpNet::parse(&s).map_err(|_| de::Error::custom(format!("invalid address or range '{}'", s)))
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl IpBan {
+    // The line sent to a banned connection before it's closed
+    pub fn notice(&self) -> String {
+        match &self.reason {
+            Some(reason) => format!("You are banned from this server: {}\n", reason),
+            None => "You are banned from this server\n".to_owned(),
+        }
+    }
+
+    // Stored as set members: the range, then the reason if there is one
+    fn to_member(&self) -> String {
+        match &self.reason {
+            Some(reason) => format!("{} {}", self.net, reason),
+            None => self.net.to_string(),
+        }
+    }
+
+    fn from_member(member: &str) -> Option<Self> {
+        let (net, reason) = match member.split_once(' ') {
+            Some((net, reason)) => (net, Some(reason.to_owned())),
+            None => (member, None),
+        };
+
+        Some(Self {
+            net: IpNet::parse(net).ok()?,
+            reason,
+        })
+    }
+}
+
+impl BanList {
+    pub async fn add(&self, store: &dyn RoomStore, ban: &IpBan) -> Result<(), StoreError> {
+        // Replaces any earlier ban on the same range
+        self.remove(store, &ban.net).await?;
+        store.set_add(IP_BANS_KEY, &ban.to_member()).await?;
+        self.invalidate().await;
+
+        Ok(())
+    }
+
+    // Returns false if the range wasn't banned
+    pub async fn remove(&self, store: &dyn RoomStore, net: &IpNet) -> Result<bool, StoreError> {
+        let mut removed = false;
+
+        for member in store.set_members(IP_BANS_KEY).await? {
+            if IpBan::from_member(&member).is_some_and(|ban| ban.net == *net) {
+                removed |= store.set_remove(IP_BANS_KEY, &member).await?;
+            }
+        }
+        self.invalidate().await;
+
+        Ok(removed)
+    }
+
+    pub async fn list(&self, store: &dyn RoomStore) -> Result<Vec<IpBan>, StoreError> {
+        let members = store.set_members(IP_BANS_KEY).await?;
+
+        Ok(members
+            .iter()
+            .filter_map(|member| IpBan::from_member(member))
+            .collect())
+    }
+
+    // The ban covering `addr`, if any, from a copy at most `CACHE_TTL` old
+    pub async fn find(
+        &self,
+        store: &dyn RoomStore,
+        addr: IpAddr,
+    ) -> Result<Option<IpBan>, StoreError> {
+        let find = |bans: &[IpBan]| bans.iter().find(|ban| ban.net.contains(addr)).cloned();
+
+        if let Some((fetched, bans)) = &*self.cache.read().await {
+            if fetched.elapsed() < CACHE_TTL {
+                return Ok(find(bans));
+            }
+        }
+
+        let bans = self.list(store).await?;
+        let ban = find(&bans);
+        *self.cache.write().await = Some((Instant::now(), bans));
+
+        Ok(ban)
+    }
+
+    async fn invalidate(&self) {
+        *self.cache.write().await = None;
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
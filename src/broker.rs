@@ -1,131 +1,735 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use redis::Client as RedisClient;
 use tokio::{
-    io::{self, AsyncWriteExt},
-    net::tcp::OwnedWriteHalf,
+    io::{self, AsyncWrite, AsyncWriteExt},
     sync::{
-        mpsc::{self, Receiver, Sender},
+        mpsc::{
+            self,
+            error::{SendError, TrySendError},
+            Receiver, Sender,
+        },
         Mutex, RwLock,
     },
+    time,
 };
+use tracing::{error, warn};
+
+use crate::client::ServerEvent;
+use crate::config::BrokerConfig;
+use crate::forward::{Forwarder, Payload};
+use crate::metrics::metrics;
+use crate::quote::Delivered;
+use crate::registry::{ConnId, Control};
+use crate::render::{RoomView, ServerMessage};
+use crate::room;
+use crate::store::{RoomStore, StoreError};
+
+pub type SharedStream = Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
 
-use crate::room::{self, RoomError};
+// Typing lines from the same user closer together than this are dropped
+const TYPING_INTERVAL: Duration = Duration::from_secs(5);
 
-pub type SharedStream = Arc<Mutex<OwnedWriteHalf>>;
+// A queue that keeps filling up is warned about at most this often
+const SATURATION_WARNING_INTERVAL: Duration = Duration::from_secs(10);
 
-#[derive(Debug)]
+/// Everything a room's broker handles. Typing lines only go to members who
+/// asked for them, and aren't stored.
 pub enum BrokerEvent {
     JoinRoom {
+        conn: ConnId,
         user: String,
         stream: SharedStream,
         msg: String,
+        // Whether they want to see who's typing
+        typing: bool,
+        // The dropped connection this one's taking over from, so the room
+        // isn't told and the new stream replaces the old one
+        resumed: Option<ConnId>,
+        // Told when a write to `stream` takes longer than `write_timeout`
+        control: Sender<Control>,
+        write_timeout: Duration,
+        // Where what's written to `stream` is kept, for `>quote`
+        delivered: Arc<Delivered>,
+        // How it's written, with `>set-quiet` and `>set-ansi`
+        view: Arc<RoomView>,
     },
     LeaveRoom {
+        conn: ConnId,
         user: String,
         msg: String,
     },
     Message {
+        conn: ConnId,
+        user: String,
+        msg: String,
+        // Where chat is kept in the room's history, for `>react`
+        id: Option<i64>,
+    },
+    // `user` is the new name
+    Rename {
+        conn: ConnId,
         user: String,
         msg: String,
     },
+    // Relayed to members who opted in, never stored
+    Typing {
+        conn: ConnId,
+        user: String,
+    },
+    SetTyping {
+        conn: ConnId,
+        user: String,
+        enabled: bool,
+    },
+    // A read-only viewer, eg an HTTP event stream. It's sent what members
+    // are, but never announced, and dropped rather than waited on if it
+    // falls behind. `id` is its own, not a connection's.
+    Watch {
+        id: u64,
+        tx: Sender<ServerMessage>,
+    },
+    Unwatch {
+        id: u64,
+    },
+}
+
+// Stands in for a user in metrics for events from viewers
+const VIEWER: &str = "(viewer)";
+
+// A connection in the room, as the broker sees it
+struct Member {
+    user: String,
+    tx: Sender<ServerMessage>,
+    typing: bool,
+    last_typing: Option<Instant>,
+    saturation: Saturation,
+}
+
+// Times a queue was found full since it was last warned about
+#[derive(Default)]
+struct Saturation {
+    full: u64,
+    last_warning: Option<Instant>,
+}
+
+impl Saturation {
+    // Returns how many times it's been full when it's time to warn again
+    fn full(&mut self) -> Option<u64> {
+        self.full += 1;
+        if self
+            .last_warning
+            .is_some_and(|at| at.elapsed() < SATURATION_WARNING_INTERVAL)
+        {
+            return None;
+        }
+
+        self.last_warning = Some(Instant::now());
+        Some(std::mem::take(&mut self.full))
+    }
+}
+
+// Shared by a room's handles and its broker
+#[derive(Default)]
+pub struct RoomStats {
+    members: AtomicUsize,
+    viewers: AtomicUsize,
+    // How full the fullest member queue is, in thousandths
+    member_fill: AtomicU32,
+    saturation: std::sync::Mutex<Saturation>,
+}
+
+// A running room, cloned by everyone who joins it
+#[derive(Clone)]
+pub struct RoomHandle {
+    room: String,
+    tx: Sender<BrokerEvent>,
+    stats: Arc<RoomStats>,
+}
+
+// Handles to the same broker are equal
+impl PartialEq for RoomHandle {
+    fn eq(&self, other: &Self) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+}
+
+impl RoomHandle {
+    // For a broker run some other way, eg by tests
+    pub fn new(room: String, tx: Sender<BrokerEvent>) -> Self {
+        Self {
+            room,
+            tx,
+            stats: Arc::default(),
+        }
+    }
+
+    // Whether its broker has stopped
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    // Waits for room in the queue, but counts it if there wasn't any
+    pub async fn send(&self, event: BrokerEvent) -> Result<(), SendError<BrokerEvent>> {
+        let event = match self.tx.try_send(event) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(event)) => return Err(SendError(event)),
+            Err(TrySendError::Full(event)) => event,
+        };
+
+        metrics().record_queue_full("room", &self.room, event.user());
+        let warning = self
+            .stats
+            .saturation
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .full();
+        if let Some(full) = warning {
+            warn!(
+                "Queue for {} was full {} time(s), its broker isn't keeping up",
+                self.room, full
+            );
+        }
+
+        self.tx.send(event).await
+    }
+
+    pub fn members(&self) -> usize {
+        self.stats.members.load(Ordering::Relaxed)
+    }
+
+    // Viewers watching without being members, see `BrokerEvent::Watch`
+    pub fn viewers(&self) -> usize {
+        self.stats.viewers.load(Ordering::Relaxed)
+    }
+
+    /// Roughly how backed up the room is, from 0 to 1: how full its own queue
+    /// or its fullest member's is, whichever is more.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    ///
+    /// use chatsapp::broker::{self, BrokerEvent, RoomMap};
+    /// use chatsapp::config::BrokerConfig;
+    /// use chatsapp::metrics::metrics;
+    /// use chatsapp::store::{MemoryStore, RoomStore};
+    /// use tokio::io::AsyncReadExt;
+    /// use tokio::sync::{mpsc, Mutex};
+    ///
+    /// let store: Arc<dyn RoomStore> = Arc::new(MemoryStore::default());
+    /// let rooms = RoomMap::default();
+    /// let config = BrokerConfig {
+    ///     room_queue: 1,
+    ///     member_queue: 1,
+    ///     ..Default::default()
+    /// };
+    /// broker::spawn_broker("rust".into(), &rooms, &store, &config).await;
+    /// let room = rooms.read().await["rust"].clone();
+    /// assert_eq!(room.queue_pressure(), 0.0);
+    ///
+    /// // bob's connection takes a byte at a time, and isn't being read
+    /// let (stream, mut bob) = tokio::io::duplex(1);
+    /// let stream = Arc::new(Mutex::new(Box::new(stream) as Box<_>));
+    /// let (control, _) = mpsc::channel(1);
+    /// let join = BrokerEvent::JoinRoom {
+    ///     conn: 1,
+    ///     user: "bob".into(),
+    ///     stream,
+    ///     msg: String::new(),
+    ///     typing: false,
+    ///     resumed: None,
+    ///     control,
+    ///     write_timeout: Duration::from_secs(10),
+    ///     delivered: Default::default(),
+    ///     view: Default::default(),
+    /// };
+    /// room.send(join).await.unwrap();
+    ///
+    /// // The first is being written to bob and the next waits in his queue,
+    /// // so the broker waits on him and the rest back up behind it
+    /// let sending = tokio::spawn({
+    ///     let room = room.clone();
+    ///     async move {
+    ///         for i in 0..5 {
+    ///             let msg = format!("alice: {}\n", i);
+    ///             let event = BrokerEvent::Message { conn: 2, user: "alice".into(), msg, id: None };
+    ///             room.send(event).await.unwrap();
+    ///         }
+    ///     }
+    /// });
+    /// tokio::time::sleep(Duration::from_millis(100)).await;
+    /// assert_eq!(room.queue_pressure(), 1.0);
+    ///
+    /// // Nothing's dropped once bob catches up
+    /// let mut read = String::new();
+    /// while !read.ends_with("alice: 4\n") {
+    ///     let mut buf = [0; 64];
+    ///     let n = bob.read(&mut buf).await.unwrap();
+    ///     read.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+    /// }
+    /// assert_eq!(read.lines().count(), 5);
+    /// sending.await.unwrap();
+    ///
+    /// assert_eq!(metrics().full_rooms(5)[0].0, "rust");
+    /// let users = metrics().full_users(5);
+    /// assert!(users.iter().any(|(user, _)| user == "bob"));
+    /// assert!(users.iter().any(|(user, _)| user == "alice"));
+    /// let scrape = metrics().render();
+    /// assert!(scrape.contains(r#"chatsapp_queue_full_total{queue="member"}"#));
+    /// assert!(scrape.contains(r#"chatsapp_queue_full_total{queue="room"}"#));
+    /// # }
+    /// ```
+    pub fn queue_pressure(&self) -> f64 {
+        let used = self.tx.max_capacity() - self.tx.capacity();
+        let room = used as f64 / self.tx.max_capacity() as f64;
+        let member = self.stats.member_fill.load(Ordering::Relaxed) as f64 / 1000.0;
+
+        room.max(member)
+    }
+}
+
+impl BrokerEvent {
+    fn user(&self) -> &str {
+        match self {
+            BrokerEvent::JoinRoom { user, .. }
+            | BrokerEvent::LeaveRoom { user, .. }
+            | BrokerEvent::Message { user, .. }
+            | BrokerEvent::Rename { user, .. }
+            | BrokerEvent::Typing { user, .. }
+            | BrokerEvent::SetTyping { user, .. } => user,
+            BrokerEvent::Watch { .. } | BrokerEvent::Unwatch { .. } => VIEWER,
+        }
+    }
+}
+
+impl std::fmt::Debug for BrokerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrokerEvent::JoinRoom {
+                conn,
+                user,
+                msg,
+                typing,
+                resumed,
+                write_timeout,
+                ..
+            } => f
+                .debug_struct("JoinRoom")
+                .field("conn", conn)
+                .field("user", user)
+                .field("msg", msg)
+                .field("typing", typing)
+                .field("resumed", resumed)
+                .field("write_timeout", write_timeout)
+                .finish_non_exhaustive(),
+            BrokerEvent::LeaveRoom { conn, user, msg } => f
+                .debug_struct("LeaveRoom")
+                .field("conn", conn)
+                .field("user", user)
+                .field("msg", msg)
+                .finish(),
+            BrokerEvent::Message {
+                conn,
+                user,
+                msg,
+                id,
+            } => f
+                .debug_struct("Message")
+                .field("conn", conn)
+                .field("user", user)
+                .field("msg", msg)
+                .field("id", id)
+                .finish(),
+            BrokerEvent::Rename { conn, user, msg } => f
+                .debug_struct("Rename")
+                .field("conn", conn)
+                .field("user", user)
+                .field("msg", msg)
+                .finish(),
+            BrokerEvent::Typing { conn, user } => f
+                .debug_struct("Typing")
+                .field("conn", conn)
+                .field("user", user)
+                .finish(),
+            BrokerEvent::SetTyping {
+                conn,
+                user,
+                enabled,
+            } => f
+                .debug_struct("SetTyping")
+                .field("conn", conn)
+                .field("user", user)
+                .field("enabled", enabled)
+                .finish(),
+            BrokerEvent::Watch { id, .. } => f.debug_struct("Watch").field("id", id).finish(),
+            BrokerEvent::Unwatch { id } => f.debug_struct("Unwatch").field("id", id).finish(),
+        }
+    }
 }
 
-pub type RoomMap = Arc<RwLock<HashMap<String, Sender<BrokerEvent>>>>;
+pub type RoomMap = Arc<RwLock<HashMap<String, RoomHandle>>>;
 
-// Since rooms are persisted in redis, this function fetches and
+// Since rooms are persisted in the store, this function fetches and
 // stores each room into map, spawning new brokers for each.
-pub async fn bootstrap_rooms(redis: &RedisClient) -> Result<RoomMap, RoomError> {
+pub async fn bootstrap_rooms(
+    store: &Arc<dyn RoomStore>,
+    config: &BrokerConfig,
+) -> Result<RoomMap, StoreError> {
     let room_map = Arc::new(RwLock::new(HashMap::new()));
 
     // Get rooms:
-    let mut rooms = room::list(redis).await?;
+    let mut rooms = store.list().await?;
 
     // Spawn broker for each room
-    while let Some(mut room) = rooms.pop() {
-        // Remove `room:`
-        room = room.split_off(5);
-
-        spawn_broker(room, &room_map).await;
+    while let Some(room) = rooms.pop() {
+        spawn_broker(room, &room_map, store, config).await;
     }
 
     Ok(room_map)
 }
 
-pub async fn spawn_broker(room: String, rooms_map: &RoomMap) {
-    let (room_tx, room_rx) = mpsc::channel(100);
+/// Starts the room's broker and adds it to the map, unless it's already
+/// running. Nothing holds the map's lock while waiting on the store, so a slow
+/// store never holds this up.
+pub async fn spawn_broker(
+    room: String,
+    rooms_map: &RoomMap,
+    store: &Arc<dyn RoomStore>,
+    config: &BrokerConfig,
+) -> RoomHandle {
+    let mut rooms_map = rooms_map.write().await;
 
-    tokio::spawn(broker(room_rx));
+    // Starting another for a running room would strand everyone in it
+    let handle = match rooms_map.entry(room) {
+        Entry::Occupied(entry) if !entry.get().is_closed() => return entry.get().clone(),
+        Entry::Occupied(mut entry) => {
+            warn!("Broker for {} had stopped, starting another", entry.key());
+            let handle = start_broker(entry.key().clone(), store, config);
+            entry.insert(handle.clone());
+            handle
+        }
+        Entry::Vacant(entry) => {
+            let handle = start_broker(entry.key().clone(), store, config);
+            entry.insert(handle).clone()
+        }
+    };
+    metrics().rooms.set(rooms_map.len() as i64);
 
-    rooms_map.write().await.insert(room, room_tx);
+    handle
 }
 
-pub async fn broker(mut events: Receiver<BrokerEvent>) -> io::Result<()> {
-    // <User, Sender for the User>
-    let mut users: HashMap<String, Sender<String>> = HashMap::new();
+fn start_broker(room: String, store: &Arc<dyn RoomStore>, config: &BrokerConfig) -> RoomHandle {
+    let (room_tx, room_rx) = mpsc::channel(config.room_queue);
+    let stats = Arc::new(RoomStats::default());
+
+    let forwarder = Forwarder::spawn(
+        room.clone(),
+        Arc::clone(store),
+        config.allow_private_webhooks,
+    );
+    tokio::spawn(broker(
+        room.clone(),
+        room_rx,
+        forwarder,
+        config.member_queue,
+        Arc::clone(&stats),
+    ));
+
+    RoomHandle {
+        room,
+        tx: room_tx,
+        stats,
+    }
+}
+
+// The broker stops once every member has let go of its sender too
+pub async fn remove_broker(room: &str, rooms_map: &RoomMap) -> bool {
+    let mut rooms_map = rooms_map.write().await;
+    let removed = rooms_map.remove(room).is_some();
+    metrics().rooms.set(rooms_map.len() as i64);
+
+    removed
+}
+
+/// Relays a room's events to everyone in it. Members are keyed by
+/// connection rather than name, so the same name can be in a room more than
+/// once, eg from a laptop and a phone.
+pub async fn broker(
+    room: String,
+    mut events: Receiver<BrokerEvent>,
+    forwarder: Forwarder,
+    member_queue: usize,
+    stats: Arc<RoomStats>,
+) -> io::Result<()> {
+    let mut users: HashMap<ConnId, Member> = HashMap::new();
+    let mut viewers: HashMap<u64, Sender<ServerMessage>> = HashMap::new();
 
     while let Some(event) = events.recv().await {
         match event {
-            BrokerEvent::JoinRoom { user, stream, msg } => {
-                // Add user to peers:
-                match users.entry(user.clone()) {
-                    Entry::Occupied(..) => (),
-                    Entry::Vacant(entry) => {
-                        // Each user will have a tx associated with their name and
-                        // an rx associated with their tcp connection
-                        let (message_tx, message_rx) = mpsc::channel(100);
-                        entry.insert(message_tx);
-
-                        // This task is responsible for writing messages to the connected user.
-                        tokio::spawn(receive_messages(message_rx, stream));
-
-                        // Send join msg:
-                        send_messages(msg, user, &users).await;
+            BrokerEvent::JoinRoom {
+                conn,
+                user,
+                stream,
+                msg,
+                typing,
+                resumed,
+                control,
+                write_timeout,
+                delivered,
+                view,
+            } => {
+                // Taking over the dropped connection's place, dropping its
+                // sender ends the old stream's writer
+                if let Some(mut member) = resumed.and_then(|old| users.remove(&old)) {
+                    let (message_tx, message_rx) = mpsc::channel(member_queue);
+                    member.user = user;
+                    member.tx = message_tx;
+                    member.typing = typing;
+                    users.insert(conn, member);
+                    tokio::spawn(receive_messages(
+                        message_rx,
+                        stream,
+                        control,
+                        write_timeout,
+                        delivered,
+                        view,
+                    ));
+                } else if let Entry::Vacant(entry) = users.entry(conn) {
+                    // Each connection will have a tx associated with its id and
+                    // an rx associated with its stream
+                    let (message_tx, message_rx) = mpsc::channel(member_queue);
+                    entry.insert(Member {
+                        user,
+                        tx: message_tx,
+                        typing,
+                        last_typing: None,
+                        saturation: Saturation::default(),
+                    });
+
+                    // This task is responsible for writing messages to the connected user.
+                    tokio::spawn(receive_messages(
+                        message_rx,
+                        stream,
+                        control,
+                        write_timeout,
+                        delivered,
+                        view,
+                    ));
+
+                    // Send join msg:
+                    if resumed.is_none() {
+                        metrics().joins.inc();
+                        send_messages(msg, None, conn, &room, &mut users, &mut viewers, &stats)
+                            .await;
                     }
-                };
+                }
+
+                metrics().set_room_members(&room, users.len());
             }
-            BrokerEvent::LeaveRoom { user, msg } => {
+            BrokerEvent::LeaveRoom { conn, msg, .. } => {
                 // Remove user from peers:
-                users.remove(&user);
+                users.remove(&conn);
+                metrics().set_room_members(&room, users.len());
 
                 // Send leave msg
-                send_messages(msg, user, &users).await;
+                send_messages(msg, None, conn, &room, &mut users, &mut viewers, &stats).await;
+            }
+            BrokerEvent::Message {
+                conn,
+                user,
+                msg,
+                id,
+            } => {
+                // Only chat goes to outgoing webhooks, not notices
+                let text = msg
+                    .strip_prefix(user.as_str())
+                    .and_then(|rest| rest.strip_prefix(": "));
+                if let Some(text) = text {
+                    forwarder.forward(Payload {
+                        room: room.clone(),
+                        user: user.clone(),
+                        text: text.trim_end().to_owned(),
+                        ts: room::get_time_in_ms(),
+                    });
+                }
+
+                send_messages(msg, id, conn, &room, &mut users, &mut viewers, &stats).await;
+            }
+            BrokerEvent::Rename { conn, user, msg } => {
+                if let Some(member) = users.get_mut(&conn) {
+                    member.user = user;
+                }
+
+                send_messages(msg, None, conn, &room, &mut users, &mut viewers, &stats).await;
+            }
+            BrokerEvent::Typing { conn, .. } => {
+                let Some(member) = users.get_mut(&conn) else {
+                    continue;
+                };
+
+                if member
+                    .last_typing
+                    .is_some_and(|last| last.elapsed() < TYPING_INTERVAL)
+                {
+                    continue;
+                }
+                member.last_typing = Some(Instant::now());
+
+                // Not worth waiting on anyone who's behind
+                let typing = ServerMessage::Typing {
+                    user: member.user.clone(),
+                };
+                for (id, member) in &users {
+                    if *id != conn
+                        && member.typing
+                        && matches!(
+                            member.tx.try_send(typing.clone()),
+                            Err(TrySendError::Full(_))
+                        )
+                    {
+                        metrics().record_queue_full("member", &room, &member.user);
+                    }
+                }
             }
-            BrokerEvent::Message { user, msg } => {
-                send_messages(msg, user, &users).await;
+            BrokerEvent::SetTyping { conn, enabled, .. } => {
+                if let Some(member) = users.get_mut(&conn) {
+                    member.typing = enabled;
+                }
+            }
+            BrokerEvent::Watch { id, tx } => {
+                viewers.insert(id, tx);
+            }
+            BrokerEvent::Unwatch { id } => {
+                viewers.remove(&id);
             }
         }
+
+        stats.members.store(users.len(), Ordering::Relaxed);
+        stats.viewers.store(viewers.len(), Ordering::Relaxed);
+        stats
+            .member_fill
+            .store(member_fill(&users), Ordering::Relaxed);
     }
 
     Ok(())
 }
 
-async fn send_messages(msg: String, sender: String, users: &HashMap<String, Sender<String>>) {
-    // Loop over each user in the room
-    for (user, tx) in users {
-        // If they're the sender of the message, skip since they'll see
-        // their message twice
-        if user == &sender {
+// How full the fullest member queue is, in thousandths
+fn member_fill(users: &HashMap<ConnId, Member>) -> u32 {
+    users
+        .values()
+        .map(|Member { tx, .. }| {
+            let used = tx.max_capacity() - tx.capacity();
+            (used * 1000 / tx.max_capacity()) as u32
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+async fn send_messages(
+    msg: String,
+    id: Option<i64>,
+    sender: ConnId,
+    room: &str,
+    users: &mut HashMap<ConnId, Member>,
+    viewers: &mut HashMap<u64, Sender<ServerMessage>>,
+    stats: &RoomStats,
+) {
+    // Parsed back out of the line protocol, as that's what's stored
+    let mut message = ServerMessage::from(ServerEvent::parse(msg.trim_end_matches('\n')));
+    if let ServerMessage::Chat { ts, .. } = &mut message {
+        *ts = id;
+    }
+
+    // Loop over each connection in the room
+    for (conn, member) in users {
+        // If it sent the message, skip since they'll see their message
+        // twice. Their other connections still get it.
+        if *conn == sender {
             continue;
         }
 
-        // Send to each user
-        if let Err(e) = tx.send(msg.clone()).await {
-            eprintln!("{}", e);
+        // Send to each user, waiting on anyone who's behind
+        let sent = match member.tx.try_send(message.clone()) {
+            Err(TrySendError::Full(msg)) => {
+                metrics().record_queue_full("member", room, &member.user);
+                stats.member_fill.store(1000, Ordering::Relaxed);
+                if let Some(full) = member.saturation.full() {
+                    warn!(
+                        "Queue for {} in {} was full {} time(s), they aren't keeping up",
+                        member.user, room, full
+                    );
+                }
+
+                member.tx.send(msg).await
+            }
+            Err(TrySendError::Closed(msg)) => Err(SendError(msg)),
+            Ok(()) => Ok(()),
+        };
+        match sent {
+            Ok(()) => metrics().messages_relayed.inc(),
+            Err(e) => error!("{}", e),
         };
     }
+
+    // Dropping a viewer's sender ends its stream, so one that's gone or
+    // behind is let go of
+    viewers.retain(|id, tx| match tx.try_send(message.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!("Dropping viewer {} of {}, it isn't keeping up", id, room);
+            false
+        }
+        Err(TrySendError::Closed(_)) => false,
+    });
 }
 
-async fn receive_messages(mut messages: Receiver<String>, stream: SharedStream) {
+async fn receive_messages(
+    mut messages: Receiver<ServerMessage>,
+    stream: SharedStream,
+    control: Sender<Control>,
+    write_timeout: Duration,
+    delivered: Arc<Delivered>,
+    view: Arc<RoomView>,
+) {
     // Dropping the Sender should kill this task
-    while let Some(msg) = messages.recv().await {
-        let mut stream = stream.lock().await;
+    while let Some(message) = messages.recv().await {
+        let Some(msg) = view.render(message.clone()) else {
+            continue;
+        };
+        // Typing lines come and go, they'd only throw the numbering off
+        if !matches!(message, ServerMessage::Typing { .. }) {
+            delivered.push(message);
+        }
 
-        if let Err(e) = stream.write_all(msg.as_bytes()).await {
-            eprintln!("{}", e);
+        let write = async {
+            let mut stream = stream.lock().await;
+            stream.write_all(msg.as_bytes()).await
         };
+
+        match time::timeout(write_timeout, write).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("{}", e),
+            // Stopping drops the receiver, so the room stops waiting on them
+            Err(_) => {
+                warn!(
+                    "Dropping a connection that hasn't taken a write in {:?}",
+                    write_timeout
+                );
+                let _ = control.send(Control::WriteTimedOut).await;
+                return;
+            }
+        }
     }
 }
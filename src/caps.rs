@@ -0,0 +1,119 @@
+// What the capability set looks like, bumped whenever `NAMES` changes
+pub const VERSION: u32 = 1;
+
+// Something a connection can opt in to beyond the plain line protocol. Without
+// any, it gets exactly what clients that predate them do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cap {
+    // Seeing who's typing, as `>set-typing on`
+    Typing,
+    // Chat that mentions them in bold
+    Mentions,
+    // Dimmed system messages, as `>set-ansi on`
+    Ansi,
+}
+
+// Each capability's name on the wire, in the order they're listed. Adding one
+// is a line here, bumping `VERSION`, and whatever it changes for connections
+// that have it.
+const NAMES: [(Cap, &str); 3] = [
+    (Cap::Typing, "typing"),
+    (Cap::Mentions, "mentions"),
+    (Cap::Ansi, "ansi"),
+];
+
+impl Cap {
+    pub fn name(self) -> &'static str {
+        NAMES
+            .iter()
+            .find(|(cap, _)| *cap == self)
+            .map_or("", |(_, name)| name)
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        NAMES
+            .iter()
+            .find(|(_, known)| *known == name)
+            .map(|(cap, _)| *cap)
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// The capabilities a connection has, none to start with. `>capabilities`
+/// lists what the server supports, `>hello` asks for some of them and is
+/// told which it got. Anything the server doesn't know is left out rather
+/// than refused, so clients can ask for what newer servers support.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::caps::{Cap, Caps};
+///
+/// let caps = Caps::trim(["ansi", "json", "typing", "typing"]);
+/// assert!(caps.contains(Cap::Typing) && caps.contains(Cap::Ansi));
+/// assert!(!caps.contains(Cap::Mentions));
+/// assert_eq!(caps.to_string(), "typing ansi");
+/// assert_eq!(Caps::default().to_string(), "");
+/// assert_eq!(Cap::parse("mentions"), Some(Cap::Mentions));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Caps(u32);
+
+impl Caps {
+    pub fn all() -> Self {
+        Self::trim(NAMES.iter().map(|(_, name)| *name))
+    }
+
+    // Those of `names` the server supports
+    pub fn trim<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut caps = Self::default();
+        for cap in names.into_iter().filter_map(Cap::parse) {
+            caps.set(cap, true);
+        }
+
+        caps
+    }
+
+    pub fn contains(self, cap: Cap) -> bool {
+        self.0 & cap.bit() != 0
+    }
+
+    pub fn set(&mut self, cap: Cap, on: bool) {
+        match on {
+            true => self.0 |= cap.bit(),
+            false => self.0 &= !cap.bit(),
+        }
+    }
+
+    // `CAP v=1 typing mentions ansi`, what `>capabilities` answers
+    pub fn advertise() -> String {
+        line("CAP", Self::all())
+    }
+
+    // `CAP-ACK v=1 typing`, what `>hello` answers with those it got
+    pub fn ack(self) -> String {
+        line("CAP-ACK", self)
+    }
+}
+
+fn line(kind: &str, caps: Caps) -> String {
+    match caps.0 {
+        0 => format!("{} v={}", kind, VERSION),
+        _ => format!("{} v={} {}", kind, VERSION, caps),
+    }
+}
+
+impl std::fmt::Display for Caps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = NAMES
+            .iter()
+            .filter(|(cap, _)| self.contains(*cap))
+            .map(|(_, name)| *name)
+            .collect();
+
+        write!(f, "{}", names.join(" "))
+    }
+}
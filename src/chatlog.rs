@@ -0,0 +1,187 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tracing::{error, warn};
+
+use crate::audit;
+use crate::metrics::metrics;
+use crate::room;
+
+// Lines waiting to be written, past this they're dropped rather than hold up
+// chat
+const QUEUE: usize = 10_000;
+
+// Lines written before each fsync, when that many are waiting
+const BATCH: usize = 256;
+
+/// A plaintext copy of everything said in rooms, kept whatever Redis keeps.
+/// Lines are appended to a file per day by a thread of its own, so chat never
+/// waits on the disk. Direct messages are only logged when `dms` is set.
+#[derive(Default)]
+pub struct ChatLog {
+    // None when disabled
+    tx: Option<Sender<Entry>>,
+    dms: bool,
+}
+
+pub struct Entry {
+    // In ms
+    pub ts: i64,
+    // The room, or `dm:<a>:<b>` for direct messages
+    pub room: String,
+    pub msg: String,
+}
+
+impl ChatLog {
+    // Starts the writer, creating `dir` if need be
+    pub fn start(dir: PathBuf, dms: bool) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let (tx, rx) = mpsc::channel(QUEUE);
+        thread::Builder::new()
+            .name("chat-log".to_owned())
+            .spawn(move || run(LogWriter::new(dir), rx))?;
+
+        Ok(Self { tx: Some(tx), dms })
+    }
+
+    pub fn room(&self, room: &str, msg: &str, ts: i64) {
+        self.send(Entry {
+            ts,
+            room: room.to_owned(),
+            msg: msg.to_owned(),
+        });
+    }
+
+    // `key` names the conversation, eg `dm:alice:bob`
+    pub fn dm(&self, key: &str, msg: &str) {
+        if self.dms {
+            self.room(key, msg, room::get_time_in_ms());
+        }
+    }
+
+    fn send(&self, entry: Entry) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+
+        match tx.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(entry)) => {
+                metrics().chat_log_dropped.inc();
+                warn!(room = entry.room, "The chat log is behind, dropped a line");
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Appends entries to `chat-<date>.log` in its directory, starting a new file
+/// when the day (in UTC) changes. Each batch is synced to disk once.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::chatlog::{Entry, LogWriter};
+///
+/// let dir = std::env::temp_dir().join(format!("chatsapp-rotate-{}", std::process::id()));
+/// std::fs::create_dir_all(&dir).unwrap();
+///
+/// // 2024-05-01T23:59:59Z, then a second later
+/// let entry = |ts: i64, msg: &str| Entry { ts, room: "rust".into(), msg: msg.into() };
+/// let mut writer = LogWriter::new(dir.clone());
+/// writer.write(&[entry(1_714_607_999_000, "bob: hi\n")]).unwrap();
+/// writer.write(&[entry(1_714_608_000_000, "bob: still\nhere\n")]).unwrap();
+///
+/// let first = std::fs::read_to_string(dir.join("chat-2024-05-01.log")).unwrap();
+/// assert_eq!(first, "2024-05-01T23:59:59Z rust bob: hi\n");
+/// let second = std::fs::read_to_string(dir.join("chat-2024-05-02.log")).unwrap();
+/// assert_eq!(second, "2024-05-02T00:00:00Z rust bob: still here\n");
+///
+/// // Appended to, even by a new writer
+/// LogWriter::new(dir.clone()).write(&[entry(1_714_608_001_000, "bob: bye\n")]).unwrap();
+/// let second = std::fs::read_to_string(dir.join("chat-2024-05-02.log")).unwrap();
+/// assert_eq!(second.lines().count(), 2);
+/// std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct LogWriter {
+    dir: PathBuf,
+    // <File name, File> for the day being written
+    file: Option<(String, File)>,
+}
+
+impl LogWriter {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, file: None }
+    }
+
+    pub fn write(&mut self, entries: &[Entry]) -> io::Result<()> {
+        for entry in entries {
+            let name = file_name(entry.ts);
+            if self.file.as_ref().is_none_or(|(open, _)| *open != name) {
+                // Synced before moving on, the batch's sync is only for the last
+                if let Some((_, file)) = self.file.take() {
+                    file.sync_data()?;
+                }
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(&name))?;
+                self.file = Some((name, file));
+            }
+
+            let (_, file) = self.file.as_mut().unwrap();
+            let msg = entry.msg.trim_end().replace('\n', " ");
+            writeln!(file, "{} {} {}", timestamp(entry.ts), entry.room, msg)?;
+        }
+
+        match &self.file {
+            Some((_, file)) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+// `chat-2024-05-01.log`, for the UTC day `ts` (in ms) falls on
+pub fn file_name(ts: i64) -> String {
+    format!("chat-{}.log", &timestamp(ts)[..10])
+}
+
+// `2024-05-01T12:00:00Z`, from ms
+pub fn timestamp(ts: i64) -> String {
+    let secs = ts.div_euclid(1000);
+    let (year, month, day) = audit::civil_date(secs.div_euclid(86_400));
+    let secs = secs.rem_euclid(86_400);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Until every sender's gone, writing whatever's waiting in one go
+fn run(mut writer: LogWriter, mut entries: Receiver<Entry>) {
+    while let Some(entry) = entries.blocking_recv() {
+        let mut batch = vec![entry];
+        while batch.len() < BATCH {
+            match entries.try_recv() {
+                Ok(entry) => batch.push(entry),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = writer.write(&batch) {
+            error!("Failed to write the chat log: {}", e);
+            // Reopened for the next batch, in case it was moved or deleted
+            writer.file = None;
+        }
+    }
+}
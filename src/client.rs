@@ -0,0 +1,382 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::warn;
+
+use crate::command::{Command, CommandParser};
+use crate::errors::Code;
+use crate::keepalive;
+use crate::render::{BOLD, DIM, RESET};
+use crate::room::CreateRoomOpts;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+// A line of server output. The text protocol doesn't mark what each line is,
+// so anything that isn't recognisable as an event is `Info`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerEvent {
+    Chat { user: String, text: String },
+    Joined(String),
+    Left(String),
+    // Where a message was, with who removed it
+    Removed(String),
+    // Where a message was, removed by whoever sent it
+    Retracted(String),
+    Error { code: Code, text: String },
+    Info(String),
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    TimedOut,
+    Closed,
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "Error: {}", e),
+            ClientError::TimedOut => write!(f, "Error: Timed out waiting for the server"),
+            ClientError::Closed => write!(f, "Error: Connection closed by the server"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+/// A connection to a server, for bots and tests. Every call gives up after
+/// `timeout` (5s by default).
+pub struct Client {
+    addr: SocketAddr,
+    writer: OwnedWriteHalf,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    timeout: Duration,
+    // Formats commands for servers started with another prefix
+    commands: CommandParser,
+    // Replayed by `reconnect`
+    username: Option<String>,
+    room: Option<String>,
+}
+
+impl ServerEvent {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::client::ServerEvent;
+    /// use chatsapp::errors::Code;
+    ///
+    /// assert_eq!(
+    ///     ServerEvent::parse("bob: hi: there"),
+    ///     ServerEvent::Chat { user: "bob".into(), text: "hi: there".into() }
+    /// );
+    /// assert_eq!(ServerEvent::parse("bob has left the room"), ServerEvent::Left("bob".into()));
+    /// assert_eq!(
+    ///     ServerEvent::parse("[E_NAME_TAKEN] Room name taken"),
+    ///     ServerEvent::Error { code: Code::NameTaken, text: "Room name taken".into() }
+    /// );
+    /// // Codes this client doesn't know aren't errors it can handle
+    /// let unknown = "[E_FROM_THE_FUTURE] Something new";
+    /// assert_eq!(ServerEvent::parse(unknown), ServerEvent::Info(unknown.into()));
+    /// assert_eq!(ServerEvent::parse("Room list:"), ServerEvent::Info("Room list:".into()));
+    /// // System messages as rooms show them
+    /// assert_eq!(ServerEvent::parse("-- Topic: async --"), ServerEvent::Info("Topic: async".into()));
+    /// let removed = ServerEvent::Removed("carol".into());
+    /// assert_eq!(ServerEvent::parse("-- message removed by carol --"), removed);
+    /// let retracted = ServerEvent::Retracted("bob".into());
+    /// assert_eq!(ServerEvent::parse("-- bob removed their message --"), retracted);
+    /// // Chat that mentions them, with the `mentions` capability
+    /// let mention = ServerEvent::Chat { user: "bob".into(), text: "hi @carol".into() };
+    /// assert_eq!(ServerEvent::parse("\x1b[1mbob: hi @carol\x1b[0m"), mention);
+    ///
+    /// // Displaying gives back the line
+    /// for line in ["bob: hi: there", "[E_ROOM_NOT_FOUND] Room not found"] {
+    ///     assert_eq!(ServerEvent::parse(line).to_string(), line);
+    /// }
+    /// ```
+    pub fn parse(line: &str) -> Self {
+        // `[E_CODE] text`
+        if let Some((code, text)) = line.strip_prefix('[').and_then(|e| e.split_once("] ")) {
+            if let Some(code) = Code::parse(code) {
+                return ServerEvent::Error {
+                    code,
+                    text: text.to_owned(),
+                };
+            }
+        }
+
+        // Chat picked out for mentioning them
+        if let Some(chat) = line
+            .strip_prefix(BOLD)
+            .and_then(|line| line.strip_suffix(RESET))
+        {
+            return ServerEvent::parse(chat);
+        }
+
+        // `-- text --`, maybe dimmed, is from the room but never chat
+        let unstyled = line
+            .strip_prefix(DIM)
+            .and_then(|line| line.strip_suffix(RESET))
+            .unwrap_or(line);
+        if let Some(text) = unstyled
+            .strip_prefix("-- ")
+            .and_then(|text| text.strip_suffix(" --"))
+        {
+            return match ServerEvent::parse(text) {
+                event @ (ServerEvent::Joined(_)
+                | ServerEvent::Left(_)
+                | ServerEvent::Removed(_)
+                | ServerEvent::Retracted(_)) => event,
+                _ => ServerEvent::Info(text.to_owned()),
+            };
+        }
+
+        if let Some(user) = line.strip_suffix(" has joined the room") {
+            return ServerEvent::Joined(user.to_owned());
+        }
+
+        if let Some(user) = line.strip_suffix(" has left the room") {
+            return ServerEvent::Left(user.to_owned());
+        }
+
+        if let Some(by) = line.strip_prefix("message removed by ") {
+            if !by.is_empty() && !by.contains(char::is_whitespace) {
+                return ServerEvent::Removed(by.to_owned());
+            }
+        }
+
+        if let Some(user) = line.strip_suffix(" removed their message") {
+            if !user.is_empty() && !user.contains(char::is_whitespace) {
+                return ServerEvent::Retracted(user.to_owned());
+            }
+        }
+
+        // Usernames are a single word
+        match line.split_once(": ") {
+            Some((user, text)) if !user.is_empty() && !user.contains(char::is_whitespace) => {
+                ServerEvent::Chat {
+                    user: user.to_owned(),
+                    text: text.to_owned(),
+                }
+            }
+            _ => ServerEvent::Info(line.to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for ServerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerEvent::Chat { user, text } => write!(f, "{}: {}", user, text),
+            ServerEvent::Joined(user) => write!(f, "{} has joined the room", user),
+            ServerEvent::Left(user) => write!(f, "{} has left the room", user),
+            ServerEvent::Removed(by) => write!(f, "message removed by {}", by),
+            ServerEvent::Retracted(user) => write!(f, "{} removed their message", user),
+            ServerEvent::Error { code, text } => write!(f, "[{}] {}", code, text),
+            ServerEvent::Info(line) => write!(f, "{}", line),
+        }
+    }
+}
+
+impl Client {
+    pub async fn connect(addr: SocketAddr) -> Result<Self, ClientError> {
+        Self::connect_with_timeout(addr, DEFAULT_TIMEOUT).await
+    }
+
+    pub async fn connect_with_timeout(
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Self, ClientError> {
+        let stream = with_timeout(timeout, TcpStream::connect(addr)).await??;
+        let (reader, writer) = stream.into_split();
+
+        let mut client = Self {
+            addr,
+            writer,
+            lines: BufReader::new(reader).lines(),
+            timeout,
+            commands: CommandParser::default(),
+            username: None,
+            room: None,
+        };
+        client.skip_greeting().await?;
+
+        Ok(client)
+    }
+
+    // Keeps trying with exponential backoff until `max_wait` has passed
+    pub async fn connect_with_backoff(
+        addr: SocketAddr,
+        max_wait: Duration,
+    ) -> Result<Self, ClientError> {
+        let deadline = time::Instant::now() + max_wait;
+        let mut backoff = Duration::from_millis(100);
+
+        loop {
+            match Self::connect(addr).await {
+                Ok(client) => return Ok(client),
+                Err(e) if time::Instant::now() + backoff > deadline => return Err(e),
+                Err(e) => warn!(
+                    "Failed to connect to {}, retrying in {:?}: {}",
+                    addr, backoff, e
+                ),
+            }
+
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    // Opens a new connection, then sets the username and rejoins the room
+    // from before
+    pub async fn reconnect(&mut self, max_wait: Duration) -> Result<(), ClientError> {
+        let mut client = Self::connect_with_backoff(self.addr, max_wait).await?;
+        client.timeout = self.timeout;
+        client.commands = self.commands;
+
+        if let Some(username) = &self.username {
+            client.set_username(username).await?;
+        }
+        if let Some(room) = &self.room {
+            client.join(room).await?;
+        }
+
+        *self = client;
+
+        Ok(())
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // For servers started with `--command-prefix`
+    pub fn set_command_prefix(&mut self, prefix: char) {
+        self.commands = CommandParser::new(prefix);
+    }
+
+    /// Sets the username, which the server acknowledges unless it's
+    /// registered, when it asks for the password instead.
+    pub async fn set_username(&mut self, username: &str) -> Result<(), ClientError> {
+        self.command(Command::SetUsername(username.to_owned()))
+            .await?;
+        self.username = Some(username.to_owned());
+
+        Ok(())
+    }
+
+    pub async fn create_room(&mut self, room: &str) -> Result<(), ClientError> {
+        self.command(Command::CreateRoom {
+            name: room.to_owned(),
+            opts: CreateRoomOpts::default(),
+        })
+        .await
+    }
+
+    /// Joins `room`, whose history follows as events. The current room is
+    /// only left once that works.
+    pub async fn join(&mut self, room: &str) -> Result<(), ClientError> {
+        self.command(Command::JoinRoom(room.to_owned())).await?;
+        self.room = Some(room.to_owned());
+
+        Ok(())
+    }
+
+    pub async fn leave(&mut self) -> Result<(), ClientError> {
+        self.command(Command::Leave).await?;
+        self.room = None;
+
+        Ok(())
+    }
+
+    pub async fn send(&mut self, text: &str) -> Result<(), ClientError> {
+        self.command(Command::Message(text.to_owned())).await
+    }
+
+    pub async fn command(&mut self, command: Command) -> Result<(), ClientError> {
+        let line = format!("{}\n", self.commands.format(&command));
+        with_timeout(self.timeout, self.writer.write_all(line.as_bytes())).await??;
+
+        Ok(())
+    }
+
+    pub async fn next_event(&mut self) -> Result<ServerEvent, ClientError> {
+        let line = self.next_line().await?;
+
+        Ok(ServerEvent::parse(&line))
+    }
+
+    // Unlike `next_event`, waits for as long as it takes. Returns None once
+    // the server closes the connection.
+    pub async fn recv(&mut self) -> Result<Option<ServerEvent>, ClientError> {
+        loop {
+            let line = self.lines.next_line().await?;
+            if line.as_deref() != Some(keepalive::PROBE) {
+                return Ok(line.map(|line| ServerEvent::parse(&line)));
+            }
+            self.answer_keepalive().await?;
+        }
+    }
+
+    pub fn into_stream(self) -> impl futures_util::Stream<Item = Result<ServerEvent, ClientError>> {
+        futures_util::stream::try_unfold(self, |mut client| async move {
+            Ok(client.recv().await?.map(|event| (event, client)))
+        })
+    }
+
+    async fn next_line(&mut self) -> Result<String, ClientError> {
+        loop {
+            let line = with_timeout(self.timeout, self.lines.next_line())
+                .await??
+                .ok_or(ClientError::Closed)?;
+            if line != keepalive::PROBE {
+                return Ok(line);
+            }
+            self.answer_keepalive().await?;
+        }
+    }
+
+    // Any line will do, and a blank one isn't taken as anything else
+    async fn answer_keepalive(&mut self) -> Result<(), ClientError> {
+        with_timeout(self.timeout, self.writer.write_all(b"\n")).await??;
+
+        Ok(())
+    }
+
+    // The greeting ends with two blank lines
+    async fn skip_greeting(&mut self) -> Result<(), ClientError> {
+        let mut blank = 0;
+
+        while blank < 2 {
+            if self.next_line().await?.is_empty() {
+                blank += 1;
+            } else {
+                blank = 0;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+async fn with_timeout<F: std::future::Future>(
+    timeout: Duration,
+    future: F,
+) -> Result<F::Output, ClientError> {
+    time::timeout(timeout, future)
+        .await
+        .map_err(|_| ClientError::TimedOut)
+}
@@ -1,68 +1,2131 @@
-#[derive(Debug, PartialEq)]
+use std::time::Duration;
+
+use crate::account::MultiLogin;
+use crate::audit;
+use crate::reactions;
+use crate::report;
+use crate::room::{self, CreateRoomOpts, SortBy};
+use crate::shutdown::ShutdownRequest;
+use crate::themes;
+
+/// Commands print in their wire form, so `parser.parse(c.to_string()) == c` with
+/// the default prefix.
+/// Arguments containing whitespace, or starting with a quote, are wrapped in
+/// double quotes with `"` and `\` escaped, as are room names starting with
+/// `--` so they aren't read as options.
+///
+/// This holds for everything but `Invalid`, and for non-empty arguments and
+/// messages that don't contain newlines (the protocol is line based) or, for
+/// messages, start with `>`. Broadcast text is taken as is, so can't start or
+/// end with whitespace, and neither can edits, notices, ban and shutdown reasons,
+/// direct messages, topics or searches. Shutdown reasons can't start with a
+/// number either, it'd be read as the countdown. Names
+/// in `>dm`, `>dm-history` and `>webhook`, URLs, and passwords in `>passwd`,
+/// are never quoted, so can't contain whitespace.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use chatsapp::account::MultiLogin;
+/// use chatsapp::command::{Command, CommandParser, ListOptions};
+/// use chatsapp::room::{CreateRoomOpts, SortBy};
+/// use chatsapp::shutdown::ShutdownRequest;
+/// use chatsapp::themes;
+/// use proptest::prelude::*;
+///
+/// let join = Command::JoinRoom("rust lang".into());
+/// assert_eq!(join.to_string(), r#">join-room "rust lang""#);
+/// assert_eq!(">join-room rust".parse::<Command>().unwrap(), Command::JoinRoom("rust".into()));
+///
+/// fn ttl() -> impl Strategy<Value = Duration> {
+///     (1..u32::MAX as u64).prop_map(Duration::from_millis)
+/// }
+///
+/// fn create_room_opts() -> impl Strategy<Value = CreateRoomOpts> {
+///     (
+///         any::<bool>(),
+///         proptest::option::of(ttl()),
+///         proptest::option::of("\\S([^\r\n]*\\S)?"),
+///         proptest::option::of(any::<usize>()),
+///     )
+///         .prop_map(|(join, ephemeral, topic, max_message_len)| CreateRoomOpts {
+///             join,
+///             ephemeral,
+///             topic,
+///             max_message_len,
+///         })
+/// }
+///
+/// fn list_options() -> impl Strategy<Value = ListOptions> {
+///     (
+///         proptest::option::of("[a-z0-9_-]+"),
+///         proptest::sample::select(SortBy::ALL.to_vec()),
+///         1..1000usize,
+///     )
+///         .prop_map(|(tag, sort, page)| ListOptions { tag, sort, page })
+/// }
+///
+/// fn command() -> impl Strategy<Value = Command> {
+///     let arg = "[^\r\n]+";
+///
+///     prop_oneof![
+///         Just(Command::Help),
+///         Just(Command::HelpErrors),
+///         list_options().prop_map(Command::List),
+///         Just(Command::Me),
+///         Just(Command::Stats),
+///         Just(Command::Uptime),
+///         Just(Command::Leave),
+///         Just(Command::Exit),
+///         Just(Command::IpBans),
+///         Just(Command::Mods),
+///         Just(Command::Typing),
+///         Just(Command::Mentions),
+///         Just(Command::Tags),
+///         Just(Command::RandomRoom),
+///         Just(Command::Rejoin),
+///         Just(Command::Themes),
+///         Just(Command::MyRooms),
+///         ("\\S([^\r\n]*\\S)?", 1..1000usize).prop_map(|(query, page)| Command::Find { query, page }),
+///         proptest::option::of("\\S([^\r\n]*\\S)?").prop_map(Command::SetTopic),
+///         proptest::collection::vec("[a-z0-9_-]+", 0..4).prop_map(Command::SetTags),
+///         proptest::option::of(any::<usize>()).prop_map(Command::SetMaxLength),
+///         proptest::option::of(ttl()).prop_map(Command::SetEphemeral),
+///         Just(Command::RoomInfo),
+///         proptest::option::of("\\S+").prop_map(Command::CreateWebhook),
+///         Just(Command::Webhooks),
+///         "[0-9a-f]+".prop_map(Command::RevokeWebhook),
+///         "\\S+".prop_map(Command::AddOutgoingWebhook),
+///         Just(Command::OutgoingWebhooks),
+///         "\\S+".prop_map(Command::RemoveOutgoingWebhook),
+///         proptest::option::of(arg).prop_map(Command::Users),
+///         any::<bool>().prop_map(Command::SetTyping),
+///         any::<bool>().prop_map(Command::SetQuiet),
+///         any::<bool>().prop_map(Command::SetPresenceMsgs),
+///         any::<bool>().prop_map(Command::SetAnsi),
+///         Just(Command::Capabilities),
+///         "\\S([^\r\n]*\\S)?".prop_map(Command::Edit),
+///         Just(Command::Undo),
+///         (proptest::option::of("[^\\s=]+"), proptest::collection::vec("[a-z0-9_-]+", 0..4))
+///             .prop_map(|(client, caps)| Command::Hello { client, caps }),
+///         any::<bool>().prop_map(Command::SetDnd),
+///         proptest::sample::select(themes::THEMES.iter().map(|theme| theme.name).collect::<Vec<_>>())
+///             .prop_map(|name| Command::SetTheme(name.to_owned())),
+///         prop_oneof![Just(MultiLogin::Allow), Just(MultiLogin::KickOld), Just(MultiLogin::Deny)]
+///             .prop_map(Command::SetMultiLogin),
+///         any::<usize>().prop_map(Command::Audit),
+///         any::<usize>().prop_map(Command::Reports),
+///         (any::<u64>(), proptest::option::of("\\S([^\r\n]*\\S)?"))
+///             .prop_map(|(id, note)| Command::ReportClose { id, note }),
+///         arg.prop_map(Command::SetUsername),
+///         arg.prop_map(Command::Register),
+///         arg.prop_map(Command::Login),
+///         Just(Command::Session),
+///         arg.prop_map(Command::Resume),
+///         ("\\S+", "\\S+").prop_map(|(old, new)| Command::Passwd { old, new }),
+///         (arg, create_room_opts()).prop_map(|(name, opts)| Command::CreateRoom { name, opts }),
+///         arg.prop_map(Command::JoinRoom),
+///         arg.prop_map(Command::Op),
+///         arg.prop_map(Command::Deop),
+///         "\\S([^\r\n]*\\S)?".prop_map(Command::Broadcast),
+///         "\\S([^\r\n]*\\S)?".prop_map(Command::Notice),
+///         (proptest::option::of(any::<u64>()), proptest::option::of("[^0-9+\\s]([^\r\n]*\\S)?"))
+///             .prop_map(|(countdown_secs, reason)| {
+///                 Command::Shutdown(ShutdownRequest { countdown_secs, reason })
+///             }),
+///         ("[0-9a-f.:/]+", proptest::option::of("\\S([^\r\n]*\\S)?"))
+///             .prop_map(|(target, reason)| Command::IpBan { target, reason }),
+///         arg.prop_map(Command::IpUnban),
+///         (arg, proptest::option::of(arg)).prop_map(|(user, room)| Command::PurgeUser { user, room }),
+///         (arg, any::<bool>()).prop_map(|(to, force)| Command::TransferOwnership { to, force }),
+///         proptest::option::of(arg).prop_map(Command::Compact),
+///         any::<u64>().prop_map(Command::SlowMode),
+///         any::<i64>().prop_map(Command::DeleteMessage),
+///         (proptest::option::of(any::<usize>()), any::<bool>())
+///             .prop_map(|(count, ids)| Command::History { count, ids }),
+///         arg.prop_map(Command::AddMod),
+///         arg.prop_map(Command::RemoveMod),
+///         ("\\S+", "\\S([^\r\n]*\\S)?")
+///             .prop_map(|(to, text)| Command::Dm { to, text }),
+///         ("\\S+", "\\S([^\r\n]*\\S)?")
+///             .prop_map(|(user, reason)| Command::Report { user, reason }),
+///         (any::<usize>(), "\\S([^\r\n]*\\S)?")
+///             .prop_map(|(n, text)| Command::Quote { n, text }),
+///         (any::<usize>(), "[^\\s\"']{1,32}")
+///             .prop_map(|(n, reaction)| Command::React { n, reaction }),
+///         any::<usize>().prop_map(Command::Reactions),
+///         ("\\S+", proptest::option::of(any::<usize>()))
+///             .prop_map(|(with, count)| Command::DmHistory { with, count }),
+///         "[^>\r\n][^\r\n]*".prop_map(Command::Message),
+///     ]
+/// }
+///
+/// proptest!(|(c in command())| {
+///     prop_assert_eq!(CommandParser::default().parse(c.to_string()), c);
+/// });
+/// ```
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     Help,
-    List,
+    // `>help errors`
+    HelpErrors,
+    List(ListOptions),
+    Tags,
+    // Rooms they own, then others they've joined
+    MyRooms,
+    // Matched against room names and topics
+    Find {
+        query: String,
+        page: usize,
+    },
     Me,
+    Stats,
+    // How long the server and this connection have been up
+    Uptime,
+    // Optionally filtered by a glob, eg `bo*`
+    Users(Option<String>),
     SetUsername(String),
-    CreateRoom(String),
+    // Passwords for the current username
+    Register(String),
+    Login(String),
+    Passwd {
+        old: String,
+        new: String,
+    },
+    // A token for `>resume`, which only works as a connection's first command
+    Session,
+    Resume(String),
+    CreateRoom {
+        name: String,
+        opts: CreateRoomOpts,
+    },
     JoinRoom(String),
+    // Somewhere picked at random, favouring rooms with people in them
+    RandomRoom,
+    // The room they were last in
+    Rejoin,
     Message(String),
+    // Rewrites their latest chat in the room, while it's recent enough
+    Edit(String),
+    // Removes their latest chat in the room, with the same limits as `Edit`
+    Undo,
     Leave,
-    Invalid,
+    Typing,
+    SetTyping(bool),
+    // Hides joins and leaves
+    SetQuiet(bool),
+    // The same the other way round, kept for registered names
+    SetPresenceMsgs(bool),
+    // Styles system messages with ANSI escapes
+    SetAnsi(bool),
+    // The capabilities the server supports, see `caps::Caps`
+    Capabilities,
+    // Asks for capabilities, `caps` as given, unknown ones included
+    Hello {
+        client: Option<String>,
+        caps: Vec<String>,
+    },
+    // Refuses direct messages to them and keeps mentions out of their inbox
+    SetDnd(bool),
+    // One of `themes::THEMES`, by name
+    SetTheme(String),
+    Themes,
+    // For the registered name they're logged in as
+    SetMultiLogin(MultiLogin),
+    Dm {
+        to: String,
+        text: String,
+    },
+    // The configured history length when no count is given
+    DmHistory {
+        with: String,
+        count: Option<usize>,
+    },
+    Mentions,
+    // Flags someone to the admins, with what they said lately in the room
+    Report {
+        user: String,
+        reason: String,
+    },
+    // Replies to the nth latest message delivered in the room, 1 being the
+    // latest
+    Quote {
+        n: usize,
+        text: String,
+    },
+    // Reacts to the nth latest message as `Quote` counts them, with an emoji
+    // or a word
+    React {
+        n: usize,
+        reaction: String,
+    },
+    Reactions(usize),
+    // The room's latest messages again, the configured history length when
+    // no count is given. `ids` shows what `>delete-msg` takes.
+    History {
+        count: Option<usize>,
+        ids: bool,
+    },
+    // Room owners and moderators only
+    SlowMode(u64),
+    DeleteMessage(i64),
+    // Room owners only
+    SetTags(Vec<String>),
+    SetTopic(Option<String>),
+    // None goes back to the server's limit
+    SetMaxLength(Option<usize>),
+    // None keeps messages for good
+    SetEphemeral(Option<Duration>),
+    // The current room's settings, for anyone in it
+    RoomInfo,
+    // The default display name when none is given
+    CreateWebhook(Option<String>),
+    Webhooks,
+    RevokeWebhook(String),
+    AddOutgoingWebhook(String),
+    OutgoingWebhooks,
+    RemoveOutgoingWebhook(String),
+    AddMod(String),
+    RemoveMod(String),
+    Mods,
+    // Admins only
+    Op(String),
+    Deop(String),
+    Broadcast(String),
+    // Sent to everyone as a server notice
+    Notice(String),
+    Shutdown(ShutdownRequest),
+    IpBan {
+        target: String,
+        reason: Option<String>,
+    },
+    IpUnban(String),
+    IpBans,
+    // Every room when none is given
+    PurgeUser {
+        user: String,
+        room: Option<String>,
+    },
+    // Of the room they're in, `force` for admins taking over abandoned rooms
+    TransferOwnership {
+        to: String,
+        force: bool,
+    },
+    // Every room when none is given
+    Compact(Option<String>),
+    Audit(usize),
+    // The latest open reports
+    Reports(usize),
+    ReportClose {
+        id: u64,
+        note: Option<String>,
+    },
+    Invalid(ParseError),
     Exit,
 }
 
+// `>list tag:name sort:order page n`, in any order
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListOptions {
+    pub tag: Option<String>,
+    pub sort: SortBy,
+    // Counts from 1
+    pub page: usize,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self {
+            tag: None,
+            sort: SortBy::default(),
+            page: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    UnknownCommand {
+        input: String,
+        suggestion: Option<&'static str>,
+        prefix: char,
+    },
+    MissingArgument {
+        command: &'static str,
+        usage: &'static str,
+        prefix: char,
+    },
+    TooManyArguments {
+        command: &'static str,
+        usage: &'static str,
+        prefix: char,
+    },
+    UnclosedQuote {
+        command: &'static str,
+        usage: &'static str,
+        prefix: char,
+    },
+    InvalidArgument {
+        command: &'static str,
+        usage: &'static str,
+        prefix: char,
+    },
+    // An `--option` the command doesn't have
+    UnknownOption {
+        command: &'static str,
+        option: String,
+        usage: &'static str,
+        prefix: char,
+    },
+    // A `key:value` whose key or value isn't one of `choices`, `what` being
+    // which, eg "sort"
+    InvalidChoice {
+        command: &'static str,
+        what: &'static str,
+        given: String,
+        choices: Vec<&'static str>,
+        prefix: char,
+    },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnknownCommand {
+                input,
+                suggestion: Some(suggestion),
+                prefix,
+            } => writeln!(
+                f,
+                "Unknown command '{}'. Did you mean '{}'?",
+                input,
+                with_prefix(suggestion, *prefix)
+            ),
+            ParseError::UnknownCommand {
+                input,
+                suggestion: None,
+                ..
+            } => writeln!(f, "Unknown command '{}'.", input),
+            ParseError::MissingArgument {
+                command,
+                usage,
+                prefix,
+            } => writeln!(
+                f,
+                "'{}' is missing an argument. Usage: {}",
+                with_prefix(command, *prefix),
+                with_prefix(usage, *prefix)
+            ),
+            ParseError::TooManyArguments {
+                command,
+                usage,
+                prefix,
+            } => writeln!(
+                f,
+                "Too many arguments for '{}'. Usage: {}",
+                with_prefix(command, *prefix),
+                with_prefix(usage, *prefix)
+            ),
+            ParseError::UnclosedQuote {
+                command,
+                usage,
+                prefix,
+            } => writeln!(
+                f,
+                "Unclosed quote in '{}'. Usage: {}",
+                with_prefix(command, *prefix),
+                with_prefix(usage, *prefix)
+            ),
+            ParseError::InvalidArgument {
+                command,
+                usage,
+                prefix,
+            } => writeln!(
+                f,
+                "Invalid argument for '{}'. Usage: {}",
+                with_prefix(command, *prefix),
+                with_prefix(usage, *prefix)
+            ),
+            ParseError::UnknownOption {
+                command,
+                option,
+                usage,
+                prefix,
+            } => writeln!(
+                f,
+                "Unknown option '{}' for '{}'. Usage: {}",
+                option,
+                with_prefix(command, *prefix),
+                with_prefix(usage, *prefix)
+            ),
+            ParseError::InvalidChoice {
+                command,
+                what,
+                given,
+                choices,
+                prefix,
+            } => {
+                let (last, rest) = choices.split_last().unwrap_or((&"", &[]));
+                let choices = match rest {
+                    [] => last.to_string(),
+                    rest => format!("{} or {}", rest.join(", "), last),
+                };
+                writeln!(
+                    f,
+                    "'{}' isn't a valid {} for '{}', pick one of {}",
+                    given,
+                    what,
+                    with_prefix(command, *prefix),
+                    choices
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// What commands start with unless configured otherwise, and in their wire
+// form. Every name below is written with it.
+pub const DEFAULT_PREFIX: char = '>';
+
 const HELP: &str = ">help";
 const EXIT: &str = ">exit";
 const LIST: &str = ">list";
+const TAGS: &str = ">tags";
+const MY_ROOMS: &str = ">my-rooms";
+const FIND: &str = ">find";
+const ROOM_SET: &str = ">room-set";
+const ROOM_INFO: &str = ">room-info";
+const WEBHOOK: &str = ">webhook";
+const WEBHOOK_OUT: &str = ">webhook-out";
 const ME: &str = ">me";
+const STATS: &str = ">stats";
+const UPTIME: &str = ">uptime";
+const USERS: &str = ">users";
 const LEAVE: &str = ">leave";
 const SET_USERNAME: &str = ">set-username";
+const REGISTER: &str = ">register";
+const LOGIN: &str = ">login";
+const PASSWD: &str = ">passwd";
+const SESSION: &str = ">session";
+const RESUME: &str = ">resume";
 const CREATE_ROOM: &str = ">create-room";
 const JOIN_ROOM: &str = ">join-room";
+const RANDOM_ROOM: &str = ">random-room";
+const REJOIN: &str = ">rejoin";
+const EDIT: &str = ">edit";
+const UNDO: &str = ">undo";
+const TYPING: &str = ">typing";
+const SET_TYPING: &str = ">set-typing";
+const SET_QUIET: &str = ">set-quiet";
+const SET_PRESENCE_MSGS: &str = ">set-presence-msgs";
+const SET_ANSI: &str = ">set-ansi";
+const CAPABILITIES: &str = ">capabilities";
+const HELLO: &str = ">hello";
+const DND: &str = ">dnd";
+const SET_THEME: &str = ">set-theme";
+const THEMES: &str = ">themes";
+const SET: &str = ">set";
+const DM: &str = ">dm";
+const DM_HISTORY: &str = ">dm-history";
+const MENTIONS: &str = ">mentions";
+const QUOTE: &str = ">quote";
+const REACT: &str = ">react";
+const REACTIONS: &str = ">reactions";
+const HISTORY: &str = ">history";
+const SLOW_MODE: &str = ">slowmode";
+const DELETE_MSG: &str = ">delete-msg";
+const MOD: &str = ">mod";
+const MODS: &str = ">mods";
+const OP: &str = ">op";
+const DEOP: &str = ">deop";
+const BROADCAST: &str = ">broadcast";
+const NOTICE: &str = ">notice";
+const SHUTDOWN: &str = ">shutdown";
+const IPBAN: &str = ">ipban";
+const IPUNBAN: &str = ">ipunban";
+const IPBANS: &str = ">ipbans";
+const AUDIT: &str = ">audit";
+const REPORT: &str = ">report";
+const REPORTS: &str = ">reports";
+const REPORT_CLOSE: &str = ">report-close";
+const PURGE_USER: &str = ">purge-user";
+const TRANSFER_OWNERSHIP: &str = ">transfer-ownership";
+const COMPACT: &str = ">compact";
+
+// <Command, Usage>, used for suggestions and error messages
+const COMMANDS: [(&str, &str); 64] = [
+    (HELP, ">help [errors]"),
+    (EXIT, EXIT),
+    (LIST, ">list [tag:name] [sort:order] [page n]"),
+    (TAGS, TAGS),
+    (MY_ROOMS, MY_ROOMS),
+    (FIND, ">find query [page n]"),
+    (ME, ME),
+    (STATS, STATS),
+    (UPTIME, UPTIME),
+    (USERS, ">users [filter]"),
+    (LEAVE, LEAVE),
+    (SET_USERNAME, ">set-username name"),
+    (REGISTER, ">register password"),
+    (LOGIN, ">login password"),
+    (PASSWD, ">passwd old new"),
+    (SESSION, SESSION),
+    (RESUME, ">resume token"),
+    (
+        CREATE_ROOM,
+        ">create-room room [--join] [--ephemeral time] [--topic text] [--max-length n]",
+    ),
+    (JOIN_ROOM, ">join-room room"),
+    (RANDOM_ROOM, RANDOM_ROOM),
+    (REJOIN, REJOIN),
+    (EDIT, ">edit text"),
+    (UNDO, UNDO),
+    (TYPING, TYPING),
+    (SET_TYPING, ">set-typing on|off"),
+    (SET_QUIET, ">set-quiet on|off"),
+    (SET_PRESENCE_MSGS, ">set-presence-msgs on|off"),
+    (SET_ANSI, ">set-ansi on|off"),
+    (CAPABILITIES, CAPABILITIES),
+    (HELLO, ">hello [client=name/version] [caps=a,b]"),
+    (DND, ">dnd on|off"),
+    (SET_THEME, ">set-theme plain|compact|verbose|high-contrast"),
+    (THEMES, THEMES),
+    (SET, ">set multi-login allow|kick-old|deny"),
+    (DM, ">dm name text"),
+    (DM_HISTORY, ">dm-history name [count]"),
+    (MENTIONS, MENTIONS),
+    (REPORT, ">report name reason"),
+    (QUOTE, ">quote n text"),
+    (REACT, ">react n reaction"),
+    (REACTIONS, ">reactions n"),
+    (HISTORY, ">history [count] [--ids]"),
+    (SLOW_MODE, ">slowmode seconds"),
+    (DELETE_MSG, ">delete-msg id"),
+    (
+        ROOM_SET,
+        ">room-set tags|topic|max-length|ephemeral [value]",
+    ),
+    (ROOM_INFO, ROOM_INFO),
+    (WEBHOOK, ">webhook create [name]|list|revoke token"),
+    (WEBHOOK_OUT, ">webhook-out add|remove url|list"),
+    (MOD, ">mod add|remove name"),
+    (MODS, MODS),
+    (TRANSFER_OWNERSHIP, ">transfer-ownership name [--force]"),
+    (OP, ">op name"),
+    (DEOP, ">deop name"),
+    (BROADCAST, ">broadcast text"),
+    (NOTICE, ">notice text"),
+    (SHUTDOWN, ">shutdown [seconds] [reason]"),
+    (IPBAN, ">ipban address [reason]"),
+    (IPUNBAN, ">ipunban address"),
+    (IPBANS, IPBANS),
+    (AUDIT, ">audit [count]"),
+    (REPORTS, ">reports [count]"),
+    (REPORT_CLOSE, ">report-close id [note]"),
+    (PURGE_USER, ">purge-user name [room]"),
+    (COMPACT, ">compact [room]"),
+];
+
+// Typos further than this from every command get no suggestion
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+// Options for `>create-room`
+const JOIN_FLAG: &str = "--join";
+const EPHEMERAL_FLAG: &str = "--ephemeral";
+const TOPIC_FLAG: &str = "--topic";
+const MAX_LENGTH_FLAG: &str = "--max-length";
+
+// Options for `>history`
+const IDS_FLAG: &str = "--ids";
+
+// Options for `>transfer-ownership`
+const FORCE_FLAG: &str = "--force";
+
+// <Alias, Command>, for users used to IRC or Discord style commands
+const ALIASES: [(&str, &str); 7] = [
+    (">join", JOIN_ROOM),
+    (">nick", SET_USERNAME),
+    (">part", LEAVE),
+    (">quit", EXIT),
+    (">msg", DM),
+    (">who", USERS),
+    (">delete-last", UNDO),
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct CommandParser {
+    prefix: char,
+}
+
+impl Default for CommandParser {
+    fn default() -> Self {
+        Self::new(DEFAULT_PREFIX)
+    }
+}
+
+impl CommandParser {
+    pub fn new(prefix: char) -> Self {
+        Self { prefix }
+    }
+
+    pub fn prefix(&self) -> char {
+        self.prefix
+    }
 
-impl Command {
     ///
     ///
     /// # Examples
     ///
     /// ```
-    /// use chatsapp::command::Command;
+    /// use std::time::Duration;
     ///
-    /// let c1 = Command::parse(">help".into());
-    /// let c2 = Command::parse(">set-username bob".into());
-    /// let c3 = Command::parse(">not a command".into());
+    /// use chatsapp::command::{Command, CommandParser, ListOptions, ParseError};
+    /// use chatsapp::room::{CreateRoomOpts, SortBy};
+    ///
+    /// let parser = CommandParser::default();
+    /// let c1 = parser.parse(">help".into());
+    /// let c2 = parser.parse(">set-username bob".into());
+    /// let c3 = parser.parse("hello everyone".into());
     ///
     /// assert_eq!(c1, Command::Help);
     /// assert_eq!(c2, Command::SetUsername("bob".to_owned()));
-    /// assert_eq!(c3, Command::Invalid);
+    /// assert_eq!(c3, Command::Message("hello everyone".to_owned()));
+    ///
+    /// let typo = parser.parse(">jion-room rust".into());
+    /// assert_eq!(
+    ///     typo,
+    ///     Command::Invalid(ParseError::UnknownCommand {
+    ///         input: ">jion-room".to_owned(),
+    ///         suggestion: Some(">join-room"),
+    ///         prefix: '>',
+    ///     })
+    /// );
+    /// let unknown = parser.parse(">not-a-command".into());
+    /// assert!(matches!(
+    ///     unknown,
+    ///     Command::Invalid(ParseError::UnknownCommand { suggestion: None, .. })
+    /// ));
+    ///
+    /// let missing = parser.parse(">join-room".into());
+    /// assert_eq!(
+    ///     missing,
+    ///     Command::Invalid(ParseError::MissingArgument {
+    ///         command: ">join-room",
+    ///         usage: ">join-room room",
+    ///         prefix: '>',
+    ///     })
+    /// );
+    ///
+    /// for (bare, usage) in [
+    ///     (">set-username", "Usage: >set-username name"),
+    ///     (
+    ///         ">create-room",
+    ///         "Usage: >create-room room [--join] [--ephemeral time] [--topic text] [--max-length n]",
+    ///     ),
+    /// ] {
+    ///     let Command::Invalid(e) = parser.parse(bare.into()) else {
+    ///         panic!("expected an error");
+    ///     };
+    ///     assert!(e.to_string().ends_with(&format!("{}\n", usage)));
+    /// }
+    ///
+    /// // Options go before or after the name, in any order
+    /// let create = |line: &str| parser.parse(line.into());
+    /// let opts = CreateRoomOpts {
+    ///     join: true,
+    ///     ephemeral: Some(Duration::from_secs(60 * 60)),
+    ///     topic: Some("Daily standup".into()),
+    ///     max_message_len: Some(200),
+    /// };
+    /// let standup = Command::CreateRoom { name: "standup".into(), opts: opts.clone() };
+    /// for line in [
+    ///     ">create-room standup --join --ephemeral 1h --topic \"Daily standup\" --max-length 200",
+    ///     ">create-room --max-length 200 --topic \"Daily standup\" --ephemeral 1h --join standup",
+    ///     ">create-room --ephemeral 1h standup --join --topic \"Daily standup\" --max-length 200",
+    /// ] {
+    ///     assert_eq!(create(line), standup);
+    /// }
+    /// let join = CreateRoomOpts { join: true, ..Default::default() };
+    /// let rust = Command::CreateRoom { name: "rust lang".into(), opts: join.clone() };
+    /// assert_eq!(create(">create-room \"rust lang\" --join"), rust);
+    /// assert_eq!(create(">create-room --join \"rust lang\""), rust);
+    ///
+    /// // Quoted, a name or topic can look like an option
+    /// let dashes = Command::CreateRoom { name: "--join".into(), opts: join };
+    /// assert_eq!(create(">create-room \"--join\" --join"), dashes);
+    /// let topic = CreateRoomOpts { topic: Some("--join".into()), ..Default::default() };
+    /// let quoted = Command::CreateRoom { name: "a".into(), opts: topic };
+    /// assert_eq!(create(">create-room a --topic \"--join\""), quoted);
+    ///
+    /// let Command::Invalid(e) = create(">create-room rust --jion") else {
+    ///     panic!("expected an error");
+    /// };
+    /// assert!(e.to_string().starts_with("Unknown option '--jion' for '>create-room'."));
+    /// for (line, expected) in [
+    ///     (">create-room --join", "is missing an argument"),
+    ///     (">create-room rust --topic", "is missing an argument"),
+    ///     (">create-room rust --ephemeral soon", "Invalid argument"),
+    ///     (">create-room rust --max-length -1", "Invalid argument"),
+    ///     (">create-room rust go", "Too many arguments"),
+    ///     (">create-room rust --topic \"unclosed", "Unclosed quote"),
+    /// ] {
+    ///     let Command::Invalid(e) = create(line) else {
+    ///         panic!("expected an error for {}", line);
+    ///     };
+    ///     assert!(e.to_string().contains(expected), "{}: {}", line, e);
+    /// }
+    ///
+    /// // `>list` takes its filters in any order too
+    /// let list = |line: &str| parser.parse(line.into());
+    /// let opts = ListOptions { tag: Some("gaming".into()), sort: SortBy::Members, page: 2 };
+    /// for line in [
+    ///     ">list tag:gaming sort:members page 2",
+    ///     ">list page 2 sort:members tag:gaming",
+    ///     ">list sort:members page 2 tag:gaming",
+    /// ] {
+    ///     assert_eq!(list(line), Command::List(opts.clone()));
+    /// }
+    /// assert_eq!(list(">list"), Command::List(ListOptions::default()));
+    /// assert_eq!(Command::List(opts).to_string(), ">list tag:gaming sort:members page 2");
+    ///
+    /// for (line, expected) in [
+    ///     (">list sort:size", "'size' isn't a valid sort for '>list', pick one of name, members, activity or created\n"),
+    ///     (">list colour:red", "'colour:red' isn't a valid argument for '>list', pick one of tag:name, sort:order or page n\n"),
+    ///     (">list sort:name sort:members", "Too many arguments for '>list'. Usage: >list [tag:name] [sort:order] [page n]\n"),
+    ///     (">list page 0", "Invalid argument for '>list'. Usage: >list [tag:name] [sort:order] [page n]\n"),
+    ///     (">list tag:", "'>list' is missing an argument. Usage: >list [tag:name] [sort:order] [page n]\n"),
+    /// ] {
+    ///     let Command::Invalid(e) = list(line) else {
+    ///         panic!("expected an error for {}", line);
+    ///     };
+    ///     assert_eq!(e.to_string(), expected);
+    /// }
+    ///
+    /// // Capabilities the server doesn't know are kept, it leaves them out when
+    /// // answering
+    /// let hello = Command::Hello {
+    ///     client: Some("tui/0.3".into()),
+    ///     caps: vec!["json".into(), "typing".into()],
+    /// };
+    /// assert_eq!(parser.parse(">hello client=tui/0.3 caps=json,typing".into()), hello);
+    /// assert_eq!(parser.parse(">hello caps=json,typing client=tui/0.3".into()), hello);
+    /// assert_eq!(parser.parse(">hello".into()), Command::Hello { client: None, caps: vec![] });
+    /// let Command::Invalid(e) = parser.parse(">hello typing".into()) else {
+    ///     panic!("expected an error");
+    /// };
+    /// assert_eq!(
+    ///     e.to_string(),
+    ///     "'typing' isn't a valid argument for '>hello', pick one of client=name/version or caps=a,b\n"
+    /// );
+    ///
+    /// let extra = parser.parse(">leave now".into());
+    /// assert!(matches!(
+    ///     extra,
+    ///     Command::Invalid(ParseError::TooManyArguments { command: ">leave", .. })
+    /// ));
+    ///
+    /// // Any other prefix, with errors to match
+    /// let slash = CommandParser::new('/');
+    /// assert_eq!(slash.parse("/join rust".into()), Command::JoinRoom("rust".into()));
+    /// assert_eq!(slash.parse(">help".into()), Command::Message(">help".into()));
+    /// let Command::Invalid(e) = slash.parse("/join-room".into()) else {
+    ///     panic!("expected an error");
+    /// };
+    /// assert_eq!(
+    ///     e.to_string(),
+    ///     "'/join-room' is missing an argument. Usage: /join-room room\n"
+    /// );
+    /// assert_eq!(slash.format(&Command::Leave), "/leave");
     /// ```
-    pub fn parse(s: String) -> Self {
-        if !s.starts_with(">") {
+    pub fn parse(&self, s: String) -> Command {
+        let prefix = self.prefix;
+        let Some(line) = s.strip_prefix(prefix) else {
             return Command::Message(s);
+        };
+
+        let (name, rest) = match line.split_once(" ") {
+            Some((name, rest)) => (name, rest.trim()),
+            None => (line, ""),
+        };
+
+        // Names are matched as if typed with the default prefix
+        let typed = format!("{}{}", DEFAULT_PREFIX, name);
+        let alias = ALIASES.iter().find(|(alias, _)| *alias == typed);
+        let typed = alias.map_or(typed.as_str(), |(_, command)| command);
+
+        let Some(&(command, usage)) = COMMANDS.iter().find(|(name, _)| *name == typed) else {
+            return Command::Invalid(ParseError::UnknownCommand {
+                input: format!("{}{}", prefix, name),
+                suggestion: suggest(typed),
+                prefix,
+            });
+        };
+
+        if command == HELP && rest == "errors" {
+            return Command::HelpErrors;
+        }
+
+        if command == LIST {
+            return match parse_list(rest) {
+                Ok(opts) => Command::List(opts),
+                Err(e) => Command::Invalid(e.at(command, usage, prefix)),
+            };
         }
 
-        // These commands don't require extra args
-        match s.as_str() {
-            HELP => return Command::Help,
-            EXIT => return Command::Exit,
-            LIST => return Command::List,
-            LEAVE => return Command::Leave,
-            ME => return Command::Me,
-            _ => {}
+        if command == HELLO {
+            return match parse_hello(rest) {
+                Ok((client, caps)) => Command::Hello { client, caps },
+                Err(e) => Command::Invalid(e.at(command, usage, prefix)),
+            };
+        }
+
+        // These commands don't take any args
+        let no_args = match command {
+            HELP => Some(Command::Help),
+            EXIT => Some(Command::Exit),
+            LEAVE => Some(Command::Leave),
+            ME => Some(Command::Me),
+            STATS => Some(Command::Stats),
+            UPTIME => Some(Command::Uptime),
+            IPBANS => Some(Command::IpBans),
+            MODS => Some(Command::Mods),
+            TYPING => Some(Command::Typing),
+            MENTIONS => Some(Command::Mentions),
+            TAGS => Some(Command::Tags),
+            MY_ROOMS => Some(Command::MyRooms),
+            SESSION => Some(Command::Session),
+            ROOM_INFO => Some(Command::RoomInfo),
+            RANDOM_ROOM => Some(Command::RandomRoom),
+            REJOIN => Some(Command::Rejoin),
+            THEMES => Some(Command::Themes),
+            CAPABILITIES => Some(Command::Capabilities),
+            UNDO => Some(Command::Undo),
+            _ => None,
         };
 
-        let (command, rest) = match s.split_once(" ") {
-            Some(s) => s,
-            None => return Command::Invalid,
+        if let Some(parsed) = no_args {
+            if !rest.is_empty() {
+                return Command::Invalid(ParseError::TooManyArguments {
+                    command,
+                    usage,
+                    prefix,
+                });
+            }
+
+            return parsed;
+        }
+
+        if command == SHUTDOWN {
+            return Command::Shutdown(ShutdownRequest::parse(rest));
+        }
+
+        if command == BROADCAST || command == NOTICE || command == EDIT || command == FIND {
+            if rest.is_empty() {
+                return Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                });
+            }
+
+            return match command {
+                BROADCAST => Command::Broadcast(rest.to_owned()),
+                NOTICE => Command::Notice(rest.to_owned()),
+                EDIT => Command::Edit(rest.to_owned()),
+                // `>find page 2` is a search for "page 2"
+                _ => match split_page(rest) {
+                    Some((query, 0)) if !query.is_empty() => {
+                        Command::Invalid(ParseError::InvalidArgument {
+                            command,
+                            usage,
+                            prefix,
+                        })
+                    }
+                    Some((query, page)) if !query.is_empty() => Command::Find {
+                        query: query.to_owned(),
+                        page,
+                    },
+                    _ => Command::Find {
+                        query: rest.to_owned(),
+                        page: 1,
+                    },
+                },
+            };
+        }
+
+        if command == USERS && rest.is_empty() {
+            return Command::Users(None);
+        }
+
+        if command == COMPACT && rest.is_empty() {
+            return Command::Compact(None);
+        }
+
+        // An optional number of entries
+        if command == AUDIT || command == REPORTS {
+            let count = match rest {
+                "" if command == AUDIT => audit::DEFAULT_COUNT,
+                "" => report::DEFAULT_COUNT,
+                count => match count.parse() {
+                    Ok(count) => count,
+                    Err(_) => {
+                        return Command::Invalid(ParseError::InvalidArgument {
+                            command,
+                            usage,
+                            prefix,
+                        })
+                    }
+                },
+            };
+
+            return match command {
+                AUDIT => Command::Audit(count),
+                _ => Command::Reports(count),
+            };
+        }
+
+        // Tags as a comma separated list, or the topic as is. Leaving the value
+        // out clears it.
+        if command == ROOM_SET {
+            return match rest.split_once(char::is_whitespace).unwrap_or((rest, "")) {
+                ("topic", "") => Command::SetTopic(None),
+                ("topic", topic) => Command::SetTopic(Some(topic.trim_start().to_owned())),
+                ("max-length", "") => Command::SetMaxLength(None),
+                ("max-length", len) => match len.trim_start().parse() {
+                    Ok(len) => Command::SetMaxLength(Some(len)),
+                    Err(_) => Command::Invalid(ParseError::InvalidArgument {
+                        command,
+                        usage,
+                        prefix,
+                    }),
+                },
+                ("ephemeral", "") => Command::SetEphemeral(None),
+                ("ephemeral", ttl) => match room::parse_ttl(ttl.trim_start()) {
+                    Some(ttl) => Command::SetEphemeral(Some(ttl)),
+                    None => Command::Invalid(ParseError::InvalidArgument {
+                        command,
+                        usage,
+                        prefix,
+                    }),
+                },
+                ("tags", tags) => Command::SetTags(
+                    tags.split(',')
+                        .map(str::trim)
+                        .filter(|tag| !tag.is_empty())
+                        .map(str::to_owned)
+                        .collect(),
+                ),
+                ("", _) => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // `create` with an optional display name, `list`, or `revoke` a token
+        if command == WEBHOOK {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+
+            return match args[..] {
+                ["create"] => Command::CreateWebhook(None),
+                ["create", name] => Command::CreateWebhook(Some(name.to_owned())),
+                ["list"] => Command::Webhooks,
+                ["revoke", token] => Command::RevokeWebhook(token.to_owned()),
+                [] | ["revoke"] => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                ["create" | "list" | "revoke", ..] => {
+                    Command::Invalid(ParseError::TooManyArguments {
+                        command,
+                        usage,
+                        prefix,
+                    })
+                }
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // A setting, then its value
+        if command == SET {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+
+            return match args[..] {
+                ["multi-login", policy] => match MultiLogin::parse(policy) {
+                    Some(policy) => Command::SetMultiLogin(policy),
+                    None => Command::Invalid(ParseError::InvalidArgument {
+                        command,
+                        usage,
+                        prefix,
+                    }),
+                },
+                [] | ["multi-login"] => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                ["multi-login", ..] => Command::Invalid(ParseError::TooManyArguments {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // `add` or `remove` a URL, or `list` them
+        if command == WEBHOOK_OUT {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+
+            return match args[..] {
+                ["add", url] => Command::AddOutgoingWebhook(url.to_owned()),
+                ["remove", url] => Command::RemoveOutgoingWebhook(url.to_owned()),
+                ["list"] => Command::OutgoingWebhooks,
+                [] | ["add" | "remove"] => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                ["add" | "remove" | "list", ..] => Command::Invalid(ParseError::TooManyArguments {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // A name, then the reason as is
+        if command == REPORT {
+            return match rest.split_once(char::is_whitespace) {
+                Some((user, reason)) => Command::Report {
+                    user: user.to_owned(),
+                    reason: reason.trim_start().to_owned(),
+                },
+                None => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // A name, then the message as is
+        if command == DM {
+            return match rest.split_once(char::is_whitespace) {
+                Some((to, text)) => Command::Dm {
+                    to: to.to_owned(),
+                    text: text.trim_start().to_owned(),
+                },
+                None => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // Which message, then the reply as is
+        if command == QUOTE {
+            return match rest.split_once(char::is_whitespace) {
+                Some((n, text)) => match n.parse() {
+                    Ok(n) => Command::Quote {
+                        n,
+                        text: text.trim_start().to_owned(),
+                    },
+                    Err(_) => Command::Invalid(ParseError::InvalidArgument {
+                        command,
+                        usage,
+                        prefix,
+                    }),
+                },
+                None => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // Which message, then one word or emoji
+        if command == REACT {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+
+            return match args[..] {
+                [n, reaction] => match n.parse() {
+                    Ok(n) if reaction.chars().count() <= reactions::MAX_LEN => Command::React {
+                        n,
+                        reaction: reaction.to_owned(),
+                    },
+                    _ => Command::Invalid(ParseError::InvalidArgument {
+                        command,
+                        usage,
+                        prefix,
+                    }),
+                },
+                [] | [_] => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                _ => Command::Invalid(ParseError::TooManyArguments {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // The current password, then the new one
+        if command == PASSWD {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+
+            return match args[..] {
+                [old, new] => Command::Passwd {
+                    old: old.to_owned(),
+                    new: new.to_owned(),
+                },
+                [] | [_] => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                _ => Command::Invalid(ParseError::TooManyArguments {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // A name, then an optional number of messages
+        if command == DM_HISTORY {
+            let mut args = rest.split_whitespace();
+            let Some(with) = args.next() else {
+                return Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                });
+            };
+
+            let count = match args.next().map(str::parse) {
+                Some(Ok(count)) => Some(count),
+                Some(Err(_)) => {
+                    return Command::Invalid(ParseError::InvalidArgument {
+                        command,
+                        usage,
+                        prefix,
+                    })
+                }
+                None => None,
+            };
+
+            if args.next().is_some() {
+                return Command::Invalid(ParseError::TooManyArguments {
+                    command,
+                    usage,
+                    prefix,
+                });
+            }
+
+            return Command::DmHistory {
+                with: with.to_owned(),
+                count,
+            };
+        }
+
+        // Which report, then an optional free form note
+        if command == REPORT_CLOSE {
+            if rest.is_empty() {
+                return Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                });
+            }
+
+            let (id, note) = match rest.split_once(char::is_whitespace) {
+                Some((id, note)) => (id, Some(note.trim_start().to_owned())),
+                None => (rest, None),
+            };
+
+            return match id.trim_start_matches('#').parse() {
+                Ok(id) => Command::ReportClose { id, note },
+                Err(_) => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // An address or range, then an optional free form reason
+        if command == IPBAN {
+            if rest.is_empty() {
+                return Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                });
+            }
+
+            let (target, reason) = match rest.split_once(char::is_whitespace) {
+                Some((target, reason)) => (target, Some(reason.trim_start().to_owned())),
+                None => (rest, None),
+            };
+
+            return Command::IpBan {
+                target: target.to_owned(),
+                reason,
+            };
+        }
+
+        // `add` or `remove`, then the name
+        let (rest, add) = match command {
+            MOD => match rest.split_once(char::is_whitespace).unwrap_or((rest, "")) {
+                ("add", name) => (name.trim_start(), true),
+                ("remove", name) => (name.trim_start(), false),
+                ("", _) => {
+                    return Command::Invalid(ParseError::MissingArgument {
+                        command,
+                        usage,
+                        prefix,
+                    })
+                }
+                _ => {
+                    return Command::Invalid(ParseError::InvalidArgument {
+                        command,
+                        usage,
+                        prefix,
+                    })
+                }
+            },
+            _ => (rest, false),
+        };
+
+        // An optional count, with `--ids` before or after it
+        if command == HISTORY {
+            return match parse_history(rest) {
+                Ok((count, ids)) => Command::History { count, ids },
+                Err(e) => Command::Invalid(e.at(command, usage, prefix)),
+            };
+        }
+
+        // The new owner, with `--force` before or after
+        if command == TRANSFER_OWNERSHIP {
+            return match parse_transfer(rest) {
+                Ok((to, force)) => Command::TransferOwnership { to, force },
+                Err(e) => Command::Invalid(e.at(command, usage, prefix)),
+            };
+        }
+
+        // A name, then an optional room
+        if command == PURGE_USER {
+            let args = match tokenize(rest) {
+                Ok(args) => args,
+                Err(e) => return Command::Invalid(e.at(command, usage, prefix)),
+            };
+
+            let mut args = args.into_iter().map(|arg| arg.text);
+            return match (args.next(), args.next(), args.next()) {
+                (Some(user), room, None) if !user.is_empty() => Command::PurgeUser { user, room },
+                (_, _, Some(_)) => Command::Invalid(ParseError::TooManyArguments {
+                    command,
+                    usage,
+                    prefix,
+                }),
+                _ => Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            };
+        }
+
+        // The name, with options before or after it
+        if command == CREATE_ROOM {
+            return match parse_create_room(rest) {
+                Ok((name, opts)) => Command::CreateRoom { name, opts },
+                Err(e) => Command::Invalid(e.at(command, usage, prefix)),
+            };
+        }
+
+        // The rest take exactly one
+        let arg = match parse_arg(rest) {
+            Ok(arg) if arg.is_empty() => {
+                return Command::Invalid(ParseError::MissingArgument {
+                    command,
+                    usage,
+                    prefix,
+                })
+            }
+            Ok(arg) => arg,
+            Err(e) => return Command::Invalid(e.at(command, usage, prefix)),
         };
 
         match command {
-            // TODO: make sure username is valid
-            SET_USERNAME => Command::SetUsername(rest.into()),
-            CREATE_ROOM => Command::CreateRoom(rest.into()),
-            JOIN_ROOM => Command::JoinRoom(rest.into()),
-            _ => Command::Invalid,
+            // Checked with `username::normalize` when it's set
+            SET_USERNAME => Command::SetUsername(arg),
+            REGISTER => Command::Register(arg),
+            LOGIN => Command::Login(arg),
+            RESUME => Command::Resume(arg),
+            JOIN_ROOM => Command::JoinRoom(arg),
+            USERS => Command::Users(Some(arg)),
+            SET_TYPING => match arg.as_str() {
+                "on" => Command::SetTyping(true),
+                "off" => Command::SetTyping(false),
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            SET_QUIET => match arg.as_str() {
+                "on" => Command::SetQuiet(true),
+                "off" => Command::SetQuiet(false),
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            SET_PRESENCE_MSGS => match arg.as_str() {
+                "on" => Command::SetPresenceMsgs(true),
+                "off" => Command::SetPresenceMsgs(false),
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            SET_ANSI => match arg.as_str() {
+                "on" => Command::SetAnsi(true),
+                "off" => Command::SetAnsi(false),
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            DND => match arg.as_str() {
+                "on" => Command::SetDnd(true),
+                "off" => Command::SetDnd(false),
+                _ => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            SET_THEME => match themes::find(&arg) {
+                Some(theme) => Command::SetTheme(theme.name.to_owned()),
+                None => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            SLOW_MODE => match arg.parse() {
+                Ok(secs) => Command::SlowMode(secs),
+                Err(_) => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            REACTIONS => match arg.parse() {
+                Ok(n) => Command::Reactions(n),
+                Err(_) => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            DELETE_MSG => match arg.parse() {
+                Ok(id) => Command::DeleteMessage(id),
+                Err(_) => Command::Invalid(ParseError::InvalidArgument {
+                    command,
+                    usage,
+                    prefix,
+                }),
+            },
+            OP => Command::Op(arg),
+            DEOP => Command::Deop(arg),
+            IPUNBAN => Command::IpUnban(arg),
+            COMPACT => Command::Compact(Some(arg)),
+            MOD if add => Command::AddMod(arg),
+            MOD => Command::RemoveMod(arg),
+            _ => unreachable!("every command in COMMANDS is handled"),
+        }
+    }
+
+    // The command as it should be sent with this prefix
+    pub fn format(&self, command: &Command) -> String {
+        let line = command.to_string();
+
+        match command {
+            Command::Message(_) => line,
+            _ => with_prefix(&line, self.prefix),
+        }
+    }
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Help | Command::HelpErrors => "help",
+            Command::List(_) => "list",
+            Command::Tags => "tags",
+            Command::MyRooms => "my-rooms",
+            Command::SetTags(_)
+            | Command::SetTopic(_)
+            | Command::SetMaxLength(_)
+            | Command::SetEphemeral(_) => "room-set",
+            Command::RoomInfo => "room-info",
+            Command::CreateWebhook(_) | Command::Webhooks | Command::RevokeWebhook(_) => "webhook",
+            Command::AddOutgoingWebhook(_)
+            | Command::OutgoingWebhooks
+            | Command::RemoveOutgoingWebhook(_) => "webhook-out",
+            Command::Find { .. } => "find",
+            Command::Me => "me",
+            Command::Stats => "stats",
+            Command::Uptime => "uptime",
+            Command::Users(_) => "users",
+            Command::SetUsername(_) => "set-username",
+            Command::Register(_) => "register",
+            Command::Login(_) => "login",
+            Command::Passwd { .. } => "passwd",
+            Command::Session => "session",
+            Command::Resume(_) => "resume",
+            Command::CreateRoom { .. } => "create-room",
+            Command::JoinRoom(_) => "join-room",
+            Command::RandomRoom => "random-room",
+            Command::Rejoin => "rejoin",
+            Command::Message(_) => "message",
+            Command::Edit(_) => "edit",
+            Command::Undo => "undo",
+            Command::Leave => "leave",
+            Command::Typing => "typing",
+            Command::SetTyping(_) => "set-typing",
+            Command::SetQuiet(_) => "set-quiet",
+            Command::SetPresenceMsgs(_) => "set-presence-msgs",
+            Command::SetAnsi(_) => "set-ansi",
+            Command::Capabilities => "capabilities",
+            Command::Hello { .. } => "hello",
+            Command::SetDnd(_) => "dnd",
+            Command::SetTheme(_) => "set-theme",
+            Command::Themes => "themes",
+            Command::SetMultiLogin(_) => "set",
+            Command::Dm { .. } => "dm",
+            Command::DmHistory { .. } => "dm-history",
+            Command::Mentions => "mentions",
+            Command::Quote { .. } => "quote",
+            Command::React { .. } => "react",
+            Command::Reactions(_) => "reactions",
+            Command::History { .. } => "history",
+            Command::SlowMode(_) => "slowmode",
+            Command::DeleteMessage(_) => "delete-msg",
+            Command::AddMod(_) | Command::RemoveMod(_) => "mod",
+            Command::Mods => "mods",
+            Command::Op(_) => "op",
+            Command::Deop(_) => "deop",
+            Command::Broadcast(_) => "broadcast",
+            Command::Notice(_) => "notice",
+            Command::Shutdown(_) => "shutdown",
+            Command::IpBan { .. } => "ipban",
+            Command::IpUnban(_) => "ipunban",
+            Command::IpBans => "ipbans",
+            Command::PurgeUser { .. } => "purge-user",
+            Command::TransferOwnership { .. } => "transfer-ownership",
+            Command::Compact(_) => "compact",
+            Command::Audit(_) => "audit",
+            Command::Report { .. } => "report",
+            Command::Reports(_) => "reports",
+            Command::ReportClose { .. } => "report-close",
+            Command::Invalid(_) => "invalid",
+            Command::Exit => "exit",
+        }
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Help => write!(f, "{}", HELP),
+            Command::HelpErrors => write!(f, "{} errors", HELP),
+            Command::List(opts) => {
+                write!(f, "{}", LIST)?;
+                if let Some(tag) = &opts.tag {
+                    write!(f, " tag:{}", tag)?;
+                }
+                if opts.sort != SortBy::default() {
+                    write!(f, " sort:{}", opts.sort.name())?;
+                }
+                match opts.page {
+                    1 => Ok(()),
+                    page => write!(f, " page {}", page),
+                }
+            }
+            Command::Tags => write!(f, "{}", TAGS),
+            Command::MyRooms => write!(f, "{}", MY_ROOMS),
+            // A query that looks like it ends in a page still needs one after it
+            Command::Find { query, page } => match *page != 1 || split_page(query).is_some() {
+                true => write!(f, "{} {} page {}", FIND, query, page),
+                false => write!(f, "{} {}", FIND, query),
+            },
+            Command::SetTopic(None) => write!(f, "{} topic", ROOM_SET),
+            Command::SetTopic(Some(topic)) => write!(f, "{} topic {}", ROOM_SET, topic),
+            Command::SetMaxLength(None) => write!(f, "{} max-length", ROOM_SET),
+            Command::SetMaxLength(Some(len)) => write!(f, "{} max-length {}", ROOM_SET, len),
+            Command::SetEphemeral(None) => write!(f, "{} ephemeral", ROOM_SET),
+            Command::SetEphemeral(Some(ttl)) => {
+                write!(f, "{} ephemeral {}", ROOM_SET, room::format_ttl(*ttl))
+            }
+            Command::RoomInfo => write!(f, "{}", ROOM_INFO),
+            Command::SetTags(tags) if tags.is_empty() => write!(f, "{} tags", ROOM_SET),
+            Command::SetTags(tags) => write!(f, "{} tags {}", ROOM_SET, tags.join(",")),
+            Command::CreateWebhook(None) => write!(f, "{} create", WEBHOOK),
+            Command::CreateWebhook(Some(name)) => write!(f, "{} create {}", WEBHOOK, name),
+            Command::Webhooks => write!(f, "{} list", WEBHOOK),
+            Command::RevokeWebhook(token) => write!(f, "{} revoke {}", WEBHOOK, token),
+            Command::AddOutgoingWebhook(url) => write!(f, "{} add {}", WEBHOOK_OUT, url),
+            Command::OutgoingWebhooks => write!(f, "{} list", WEBHOOK_OUT),
+            Command::RemoveOutgoingWebhook(url) => write!(f, "{} remove {}", WEBHOOK_OUT, url),
+            Command::Me => write!(f, "{}", ME),
+            Command::Stats => write!(f, "{}", STATS),
+            Command::Uptime => write!(f, "{}", UPTIME),
+            Command::Users(None) => write!(f, "{}", USERS),
+            Command::Users(Some(filter)) => write!(f, "{} {}", USERS, quote(filter)),
+            Command::SetUsername(name) => write!(f, "{} {}", SET_USERNAME, quote(name)),
+            Command::Register(password) => write!(f, "{} {}", REGISTER, quote(password)),
+            Command::Login(password) => write!(f, "{} {}", LOGIN, quote(password)),
+            Command::Passwd { old, new } => write!(f, "{} {} {}", PASSWD, old, new),
+            Command::Session => write!(f, "{}", SESSION),
+            Command::Resume(token) => write!(f, "{} {}", RESUME, quote(token)),
+            Command::CreateRoom { name, opts } => {
+                match name.starts_with("--") {
+                    true => write!(f, "{} {}", CREATE_ROOM, quote_always(name))?,
+                    false => write!(f, "{} {}", CREATE_ROOM, quote(name))?,
+                }
+                if opts.join {
+                    write!(f, " {}", JOIN_FLAG)?;
+                }
+                if let Some(ttl) = opts.ephemeral {
+                    write!(f, " {} {}", EPHEMERAL_FLAG, room::format_ttl(ttl))?;
+                }
+                if let Some(topic) = &opts.topic {
+                    write!(f, " {} {}", TOPIC_FLAG, quote(topic))?;
+                }
+                match opts.max_message_len {
+                    Some(len) => write!(f, " {} {}", MAX_LENGTH_FLAG, len),
+                    None => Ok(()),
+                }
+            }
+            Command::JoinRoom(room) => write!(f, "{} {}", JOIN_ROOM, quote(room)),
+            Command::RandomRoom => write!(f, "{}", RANDOM_ROOM),
+            Command::Rejoin => write!(f, "{}", REJOIN),
+            Command::Message(msg) => write!(f, "{}", msg),
+            Command::Edit(text) => write!(f, "{} {}", EDIT, text),
+            Command::Undo => write!(f, "{}", UNDO),
+            Command::Leave => write!(f, "{}", LEAVE),
+            Command::Typing => write!(f, "{}", TYPING),
+            Command::SetTyping(true) => write!(f, "{} on", SET_TYPING),
+            Command::SetTyping(false) => write!(f, "{} off", SET_TYPING),
+            Command::SetQuiet(true) => write!(f, "{} on", SET_QUIET),
+            Command::SetQuiet(false) => write!(f, "{} off", SET_QUIET),
+            Command::SetPresenceMsgs(true) => write!(f, "{} on", SET_PRESENCE_MSGS),
+            Command::SetPresenceMsgs(false) => write!(f, "{} off", SET_PRESENCE_MSGS),
+            Command::SetAnsi(true) => write!(f, "{} on", SET_ANSI),
+            Command::SetAnsi(false) => write!(f, "{} off", SET_ANSI),
+            Command::Capabilities => write!(f, "{}", CAPABILITIES),
+            Command::Hello { client, caps } => {
+                write!(f, "{}", HELLO)?;
+                if let Some(client) = client {
+                    write!(f, " client={}", client)?;
+                }
+                match caps.is_empty() {
+                    true => Ok(()),
+                    false => write!(f, " caps={}", caps.join(",")),
+                }
+            }
+            Command::SetDnd(true) => write!(f, "{} on", DND),
+            Command::SetDnd(false) => write!(f, "{} off", DND),
+            Command::SetTheme(name) => write!(f, "{} {}", SET_THEME, name),
+            Command::Themes => write!(f, "{}", THEMES),
+            Command::SetMultiLogin(policy) => write!(f, "{} multi-login {}", SET, policy),
+            Command::Dm { to, text } => write!(f, "{} {} {}", DM, to, text),
+            Command::DmHistory { with, count: None } => write!(f, "{} {}", DM_HISTORY, with),
+            Command::DmHistory {
+                with,
+                count: Some(count),
+            } => write!(f, "{} {} {}", DM_HISTORY, with, count),
+            Command::History { count, ids } => {
+                write!(f, "{}", HISTORY)?;
+                if let Some(count) = count {
+                    write!(f, " {}", count)?;
+                }
+                match ids {
+                    true => write!(f, " {}", IDS_FLAG),
+                    false => Ok(()),
+                }
+            }
+            Command::SlowMode(secs) => write!(f, "{} {}", SLOW_MODE, secs),
+            Command::DeleteMessage(id) => write!(f, "{} {}", DELETE_MSG, id),
+            Command::AddMod(name) => write!(f, "{} add {}", MOD, quote(name)),
+            Command::RemoveMod(name) => write!(f, "{} remove {}", MOD, quote(name)),
+            Command::Mods => write!(f, "{}", MODS),
+            Command::Mentions => write!(f, "{}", MENTIONS),
+            Command::Quote { n, text } => write!(f, "{} {} {}", QUOTE, n, text),
+            Command::React { n, reaction } => write!(f, "{} {} {}", REACT, n, reaction),
+            Command::Reactions(n) => write!(f, "{} {}", REACTIONS, n),
+            Command::Op(name) => write!(f, "{} {}", OP, quote(name)),
+            Command::Deop(name) => write!(f, "{} {}", DEOP, quote(name)),
+            Command::Broadcast(text) => write!(f, "{} {}", BROADCAST, text),
+            Command::Notice(text) => write!(f, "{} {}", NOTICE, text),
+            Command::Shutdown(request) if *request == ShutdownRequest::default() => {
+                write!(f, "{}", SHUTDOWN)
+            }
+            Command::Shutdown(request) => write!(f, "{} {}", SHUTDOWN, request),
+            Command::IpBan {
+                target,
+                reason: Some(reason),
+            } => write!(f, "{} {} {}", IPBAN, target, reason),
+            Command::IpBan {
+                target,
+                reason: None,
+            } => write!(f, "{} {}", IPBAN, target),
+            Command::IpUnban(target) => write!(f, "{} {}", IPUNBAN, quote(target)),
+            Command::IpBans => write!(f, "{}", IPBANS),
+            Command::TransferOwnership { to, force } => {
+                match to.starts_with("--") {
+                    true => write!(f, "{} {}", TRANSFER_OWNERSHIP, quote_always(to))?,
+                    false => write!(f, "{} {}", TRANSFER_OWNERSHIP, quote(to))?,
+                }
+                match force {
+                    true => write!(f, " {}", FORCE_FLAG),
+                    false => Ok(()),
+                }
+            }
+            Command::PurgeUser { user, room: None } => write!(f, "{} {}", PURGE_USER, quote(user)),
+            Command::PurgeUser {
+                user,
+                room: Some(room),
+            } => write!(f, "{} {} {}", PURGE_USER, quote(user), quote(room)),
+            Command::Compact(None) => write!(f, "{}", COMPACT),
+            Command::Compact(Some(room)) => write!(f, "{} {}", COMPACT, quote(room)),
+            Command::Audit(count) => write!(f, "{} {}", AUDIT, count),
+            Command::Report { user, reason } => write!(f, "{} {} {}", REPORT, user, reason),
+            Command::Reports(count) => write!(f, "{} {}", REPORTS, count),
+            Command::ReportClose { id, note: None } => write!(f, "{} {}", REPORT_CLOSE, id),
+            Command::ReportClose {
+                id,
+                note: Some(note),
+            } => write!(f, "{} {} {}", REPORT_CLOSE, id, note),
+            // There's no wire form, so the closest is whatever was typed
+            Command::Invalid(ParseError::UnknownCommand { input, .. }) => write!(f, "{}", input),
+            Command::Invalid(
+                ParseError::MissingArgument { command, .. }
+                | ParseError::TooManyArguments { command, .. }
+                | ParseError::UnclosedQuote { command, .. }
+                | ParseError::InvalidArgument { command, .. }
+                | ParseError::UnknownOption { command, .. }
+                | ParseError::InvalidChoice { command, .. },
+            ) => write!(f, "{}", command),
+            Command::Exit => write!(f, "{}", EXIT),
+        }
+    }
+}
+
+impl std::str::FromStr for Command {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match CommandParser::default().parse(s.to_owned()) {
+            Command::Invalid(e) => Err(e),
+            command => Ok(command),
+        }
+    }
+}
+
+enum ArgError {
+    Missing,
+    TooMany,
+    UnclosedQuote,
+    Invalid,
+    UnknownOption(String),
+    InvalidChoice {
+        what: &'static str,
+        given: String,
+        choices: Vec<&'static str>,
+    },
+}
+
+impl ArgError {
+    fn at(self, command: &'static str, usage: &'static str, prefix: char) -> ParseError {
+        match self {
+            ArgError::Missing => ParseError::MissingArgument {
+                command,
+                usage,
+                prefix,
+            },
+            ArgError::TooMany => ParseError::TooManyArguments {
+                command,
+                usage,
+                prefix,
+            },
+            ArgError::UnclosedQuote => ParseError::UnclosedQuote {
+                command,
+                usage,
+                prefix,
+            },
+            ArgError::Invalid => ParseError::InvalidArgument {
+                command,
+                usage,
+                prefix,
+            },
+            ArgError::UnknownOption(option) => ParseError::UnknownOption {
+                command,
+                option,
+                usage,
+                prefix,
+            },
+            ArgError::InvalidChoice {
+                what,
+                given,
+                choices,
+            } => ParseError::InvalidChoice {
+                command,
+                what,
+                given,
+                choices,
+                prefix,
+            },
         }
     }
 }
+
+struct Arg {
+    text: String,
+    // Quoted arguments are never options
+    quoted: bool,
+}
+
+// Bare words and double quoted strings, separated by whitespace
+fn tokenize(rest: &str) -> Result<Vec<Arg>, ArgError> {
+    let mut args = Vec::new();
+    let mut chars = rest.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(args);
+        };
+
+        if first != '"' {
+            let mut text = String::from(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                text.push(c);
+            }
+            args.push(Arg {
+                text,
+                quoted: false,
+            });
+            continue;
+        }
+
+        let mut text = String::new();
+        loop {
+            match chars.next() {
+                Some('\\') => match chars.next() {
+                    Some(c) => text.push(c),
+                    None => return Err(ArgError::UnclosedQuote),
+                },
+                Some('"') => break,
+                Some(c) => text.push(c),
+                None => return Err(ArgError::UnclosedQuote),
+            }
+        }
+        args.push(Arg { text, quoted: true });
+    }
+}
+
+// A single argument, either a bare word or a double quoted string
+fn parse_arg(rest: &str) -> Result<String, ArgError> {
+    let mut args = tokenize(rest)?;
+
+    match args.len() {
+        0 => Ok(String::new()),
+        1 => Ok(args.remove(0).text),
+        _ => Err(ArgError::TooMany),
+    }
+}
+
+// The room's name and the options around it. Bare words starting with `--`
+// are options, and the word after any that takes a value is its value.
+fn parse_create_room(rest: &str) -> Result<(String, CreateRoomOpts), ArgError> {
+    let mut name = None;
+    let mut opts = CreateRoomOpts::default();
+
+    let mut args = tokenize(rest)?.into_iter();
+    while let Some(arg) = args.next() {
+        if arg.quoted || !arg.text.starts_with("--") {
+            if name.replace(arg.text).is_some() {
+                return Err(ArgError::TooMany);
+            }
+            continue;
+        }
+
+        let option = arg.text.as_str();
+        if option == JOIN_FLAG {
+            opts.join = true;
+            continue;
+        }
+        if ![EPHEMERAL_FLAG, TOPIC_FLAG, MAX_LENGTH_FLAG].contains(&option) {
+            return Err(ArgError::UnknownOption(arg.text));
+        }
+
+        let value = args.next().ok_or(ArgError::Missing)?.text;
+        match option {
+            EPHEMERAL_FLAG => {
+                opts.ephemeral = Some(room::parse_ttl(&value).ok_or(ArgError::Invalid)?);
+            }
+            TOPIC_FLAG => opts.topic = Some(value).filter(|topic| !topic.is_empty()),
+            _ => opts.max_message_len = Some(value.parse().map_err(|_| ArgError::Invalid)?),
+        }
+    }
+
+    match name {
+        Some(name) if !name.is_empty() => Ok((name, opts)),
+        _ => Err(ArgError::Missing),
+    }
+}
+
+// What's before a trailing `page n`, and n, or None without one
+fn split_page(rest: &str) -> Option<(&str, usize)> {
+    let (before, page) = rest.rsplit_once(' ').unwrap_or(("", rest));
+    let before = match before.trim_end().strip_suffix("page") {
+        Some(before) if before.is_empty() || before.ends_with(char::is_whitespace) => before,
+        _ => return None,
+    };
+    if !page.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some((before.trim_end(), page.parse().ok()?))
+}
+
+// `tag:`, `sort:` and `page n`, each at most once and in any order
+fn parse_list(rest: &str) -> Result<ListOptions, ArgError> {
+    let mut opts = ListOptions::default();
+    let (mut tag, mut sort, mut page) = (false, false, false);
+    let mut args = tokenize(rest)?.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg.text == "page" {
+            if std::mem::replace(&mut page, true) {
+                return Err(ArgError::TooMany);
+            }
+            let n = args.next().ok_or(ArgError::Missing)?;
+            opts.page = match n.text.parse() {
+                Ok(0) | Err(_) => return Err(ArgError::Invalid),
+                Ok(n) => n,
+            };
+            continue;
+        }
+
+        match arg.text.split_once(':') {
+            Some(("tag", value)) => {
+                if std::mem::replace(&mut tag, true) {
+                    return Err(ArgError::TooMany);
+                }
+                if value.is_empty() {
+                    return Err(ArgError::Missing);
+                }
+                opts.tag = Some(value.to_owned());
+            }
+            Some(("sort", value)) => {
+                if std::mem::replace(&mut sort, true) {
+                    return Err(ArgError::TooMany);
+                }
+                opts.sort = SortBy::parse(value).ok_or_else(|| ArgError::InvalidChoice {
+                    what: "sort",
+                    given: value.to_owned(),
+                    choices: SortBy::ALL.iter().map(|by| by.name()).collect(),
+                })?;
+            }
+            Some(_) => {
+                return Err(ArgError::InvalidChoice {
+                    what: "argument",
+                    given: arg.text,
+                    choices: vec!["tag:name", "sort:order", "page n"],
+                })
+            }
+            None => return Err(ArgError::TooMany),
+        }
+    }
+
+    Ok(opts)
+}
+
+// `client=name/version` and `caps=a,b`, in either order and both optional
+fn parse_hello(rest: &str) -> Result<(Option<String>, Vec<String>), ArgError> {
+    let (mut client, mut caps) = (None, None);
+
+    for arg in rest.split_whitespace() {
+        match arg.split_once('=') {
+            Some(("client", "")) => return Err(ArgError::Missing),
+            Some(("client", name)) => {
+                if client.replace(name.to_owned()).is_some() {
+                    return Err(ArgError::TooMany);
+                }
+            }
+            Some(("caps", list)) => {
+                let list = list
+                    .split(',')
+                    .filter(|cap| !cap.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+                if caps.replace(list).is_some() {
+                    return Err(ArgError::TooMany);
+                }
+            }
+            _ => {
+                return Err(ArgError::InvalidChoice {
+                    what: "argument",
+                    given: arg.to_owned(),
+                    choices: vec!["client=name/version", "caps=a,b"],
+                })
+            }
+        }
+    }
+
+    Ok((client, caps.unwrap_or_default()))
+}
+
+// The new owner, and whether an admin's forcing it
+fn parse_transfer(rest: &str) -> Result<(String, bool), ArgError> {
+    let mut to = None;
+    let mut force = false;
+
+    for arg in tokenize(rest)? {
+        if !arg.quoted && arg.text == FORCE_FLAG {
+            force = true;
+            continue;
+        }
+        if !arg.quoted && arg.text.starts_with("--") {
+            return Err(ArgError::UnknownOption(arg.text));
+        }
+        if to.replace(arg.text).is_some() {
+            return Err(ArgError::TooMany);
+        }
+    }
+
+    match to {
+        Some(to) if !to.is_empty() => Ok((to, force)),
+        _ => Err(ArgError::Missing),
+    }
+}
+
+// How many messages, and whether to show their ids
+fn parse_history(rest: &str) -> Result<(Option<usize>, bool), ArgError> {
+    let mut count = None;
+    let mut ids = false;
+
+    for arg in tokenize(rest)? {
+        if !arg.quoted && arg.text == IDS_FLAG {
+            ids = true;
+            continue;
+        }
+        if !arg.quoted && arg.text.starts_with("--") {
+            return Err(ArgError::UnknownOption(arg.text));
+        }
+
+        let n = arg.text.parse().map_err(|_| ArgError::Invalid)?;
+        if count.replace(n).is_some() {
+            return Err(ArgError::TooMany);
+        }
+    }
+
+    Ok((count, ids))
+}
+
+fn quote(arg: &str) -> std::borrow::Cow<'_, str> {
+    if !arg.starts_with('"') && !arg.contains(char::is_whitespace) {
+        return arg.into();
+    }
+
+    quote_always(arg).into()
+}
+
+fn quote_always(arg: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in arg.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+// The closest known command, if it's close enough to be a typo
+// Swaps the default prefix at the start of `s` for `prefix`
+fn with_prefix(s: &str, prefix: char) -> String {
+    let rest = s.strip_prefix(DEFAULT_PREFIX).unwrap_or(s);
+
+    format!("{}{}", prefix, rest)
+}
+
+fn suggest(input: &str) -> Option<&'static str> {
+    COMMANDS
+        .iter()
+        .map(|(name, _)| (levenshtein(input, name), *name))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+
+    // Distances from the previous row, starting with the empty prefix of `a`
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            row[j + 1] = substitution.min(prev[j + 1] + 1).min(row[j] + 1);
+        }
+
+        prev = row;
+    }
+
+    prev[b.len()]
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::Sender;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::metrics::metrics;
+use crate::registry::Control;
+use crate::room;
+use crate::server::ServerContext;
+use crate::shutdown::Shutdown;
+
+// Between rooms, so a run doesn't crowd out chat's own storage calls
+const ROOM_PAUSE: Duration = Duration::from_millis(20);
+
+// How often to check whether a reload has turned compaction back on
+const DISABLED_POLL: Duration = Duration::from_secs(60);
+
+/// Compacts each room with `room::compact`, one at a time with a short pause
+/// between them, returning how many messages went in all. Quiet rooms only
+/// have their retention applied this way, since nobody's writing to them.
+/// With `progress` set each room that had anything removed, or couldn't be
+/// compacted, is reported to it as a notice.
+pub async fn run(
+    ctx: &ServerContext,
+    rooms: &[String],
+    progress: Option<&Sender<Control>>,
+) -> usize {
+    let retention = ctx.config.load().retention;
+    let mut total = 0;
+
+    for (n, room) in rooms.iter().enumerate() {
+        if n > 0 {
+            time::sleep(ROOM_PAUSE).await;
+        }
+
+        let report = match room::compact(&*ctx.store, room, retention).await {
+            Ok(0) => continue,
+            Ok(removed) => {
+                total += removed;
+                format!("Compacted {}, {} message(s) removed", room, removed)
+            }
+            Err(e) => {
+                let e = e.to_string();
+                warn!(room, "Failed to compact: {}", e.trim_end());
+                format!("Couldn't compact {}: {}", room, e.trim_end())
+            }
+        };
+        if let Some(progress) = progress {
+            // They may have gone, the run carries on regardless
+            let _ = progress.send(Control::Notice(report)).await;
+        }
+    }
+
+    metrics().compacted.inc_by(total as u64);
+    metrics().last_compacted.set(total as i64);
+
+    total
+}
+
+// Compacts every room each `compact_interval_secs` until shutdown
+pub async fn periodic(ctx: Arc<ServerContext>, mut shutdown: Shutdown) {
+    loop {
+        let wait = match ctx.config.load().compact_interval_secs {
+            0 => DISABLED_POLL,
+            secs => Duration::from_secs(secs),
+        };
+
+        tokio::select! {
+            _ = time::sleep(wait) => {}
+            _ = shutdown.recv() => return,
+        }
+
+        if ctx.config.load().compact_interval_secs == 0 {
+            continue;
+        }
+
+        let rooms = match ctx.store.list().await {
+            Ok(rooms) => rooms,
+            Err(e) => {
+                warn!("Skipping compaction: {}", e.to_string().trim_end());
+                continue;
+            }
+        };
+        let removed = run(&ctx, &rooms, None).await;
+        info!(
+            "Compacted {} room(s), {} message(s) removed",
+            rooms.len(),
+            removed
+        );
+    }
+}
@@ -0,0 +1,666 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use serde::Deserialize;
+
+use crate::ban::IpNet;
+use crate::command::DEFAULT_PREFIX;
+
+// Settings fixed for the lifetime of the process
+pub struct AppConfig {
+    pub binds: Vec<SocketAddr>,
+    pub ws_bind: Option<SocketAddr>,
+    pub irc_bind: Option<SocketAddr>,
+    pub http_bind: Option<SocketAddr>,
+    pub admin_bind: Option<SocketAddr>,
+    pub admin_token: Option<String>,
+    // Usernames added to the admin role at startup
+    pub admins: Vec<String>,
+    pub storage: StorageKind,
+    pub redis_url: String,
+    pub db_path: PathBuf,
+    // How long to keep retrying Redis at startup before giving up
+    pub redis_timeout: Duration,
+    pub proxy_protocol: bool,
+    // What commands start with, `>` unless set, eg `/` for `/join`
+    pub command_prefix: char,
+    pub config_path: Option<PathBuf>,
+    pub chat_log: ChatLogConfig,
+    pub metrics: MetricsConfig,
+    // Initial value, reloads replace it in the `SharedConfig`
+    pub runtime: RuntimeConfig,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Redis,
+    // Nothing is persisted, for tests and demos
+    Memory,
+    // A file at `db_path`, needs the `sqlite-backend` feature
+    Sqlite,
+}
+
+// Settings that can be changed while running, see `reload`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub motd: Option<String>,
+    // Messages replayed when joining a room
+    pub history: usize,
+    // Rooms in each page of `>list` and `>find`
+    pub page_size: usize,
+    // Messages kept per room, older ones are trimmed as new ones arrive
+    pub retention: Option<usize>,
+    // How often every room's history is compacted, see `compact::run`, 0
+    // disables
+    pub compact_interval_secs: u64,
+    // How long `>resume` tokens last since they were last used
+    pub session_ttl_secs: u64,
+    // How long whispers to someone offline are kept for them, 0 disables
+    pub offline_dm_ttl_secs: u64,
+    // How long after sending a message it can be changed with `>edit` or
+    // `>undo`, 0 disables
+    pub edit_window_secs: u64,
+    // How long everyone's warned before the server shuts down, 0 for no warning
+    pub shutdown_countdown_secs: u64,
+    // How long someone in a room can go without sending a line before
+    // they're away, 0 disables
+    pub away_after_secs: u64,
+    // How long a connection can go without being written to before it's
+    // checked on, see `keepalive::PROBE`, 0 disables
+    pub keepalive_secs: u64,
+    // How long it then has to send something back before it's dropped
+    pub keepalive_grace_secs: u64,
+    pub limits: LimitsConfig,
+    pub filter: FilterConfig,
+    pub broker: BrokerConfig,
+    pub access: AccessConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: Option<usize>,
+    // Chat messages allowed per connection within each window, 0 disables
+    pub messages_per_window: u32,
+    pub window_secs: u64,
+    // Rooms each username may own at once, 0 disables. Admins are exempt.
+    pub rooms_per_user: usize,
+    // Rooms on the whole server
+    pub max_rooms: Option<usize>,
+    // Room names only admins may create, as globs, eg `_*`
+    pub reserved_rooms: Vec<String>,
+    // Characters in a chat message, 0 disables. Rooms can override it.
+    pub max_message_len: usize,
+    // Times the same message may be sent to a room within the window before
+    // it's suppressed, 0 disables. Keeping at it gets the sender muted.
+    pub duplicate_limit: u32,
+    pub duplicate_window_secs: u64,
+    // How long someone setting a registered username has to log in as it
+    pub login_grace_secs: u64,
+    // Invalid commands in a row before the connection's closed, 0 disables.
+    // Chat from a guest outside any room counts too.
+    pub max_invalid_commands: u32,
+}
+
+// Queue sizes and the like, only rooms and members created after a reload
+// get new ones
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    // Events waiting for a room's broker
+    pub room_queue: usize,
+    // Lines waiting to be written to each member
+    pub member_queue: usize,
+    // How long a write to a connection can take before it's dropped as dead
+    pub write_timeout_secs: u64,
+    // Lets outgoing webhooks post to loopback and private addresses, see
+    // `forward::is_private`
+    pub allow_private_webhooks: bool,
+}
+
+// Who may connect at all, along with the `server:ipallow` and `server:ipdeny`
+// sets, see `access::permits`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    // Addresses or ranges, eg `10.0.0.0/8`. Empty lets everyone in.
+    pub allow: Vec<IpNet>,
+    // Refused even when allowed
+    pub deny: Vec<IpNet>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub mode: FilterMode,
+    // One word per line. Without it the words are read from the
+    // `server:filterwords` set.
+    pub words_file: Option<PathBuf>,
+}
+
+// Plaintext files of everything said, see `ChatLog`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatLogConfig {
+    pub enabled: bool,
+    pub dir: Option<PathBuf>,
+    // Direct messages are left out unless this is set
+    pub dms: bool,
+}
+
+// Where metrics go, only read at startup
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub exporters: Vec<Exporter>,
+    // Redis ops slower than this log a warning, 0 disables
+    pub slow_redis_ms: u64,
+    pub statsd: StatsdConfig,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Exporter {
+    // `GET /metrics` on the HTTP listener
+    Prometheus,
+    // Pushed over UDP, see `statsd::start`
+    Statsd,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsdConfig {
+    pub addr: Option<SocketAddr>,
+    pub flavor: StatsdFlavor,
+    // How often counters and gauges are sent, along with the timings since
+    pub flush_ms: u64,
+    // Datagrams sent each flush, lines that don't fit are dropped
+    pub max_packets: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFlavor {
+    // Labels as tags, eg `|#room:rust`
+    #[default]
+    Dogstatsd,
+    // Labels appended to the name, eg `.rust`
+    Plain,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterMode {
+    #[default]
+    Off,
+    // Matched words are replaced with asterisks
+    Mask,
+    // Messages with a matched word are rejected
+    Block,
+}
+
+pub type SharedConfig = Arc<ArcSwap<RuntimeConfig>>;
+
+// The config file: boot settings at the top level, the rest under `[runtime]`
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    binds: Vec<SocketAddr>,
+    ws_bind: Option<SocketAddr>,
+    irc_bind: Option<SocketAddr>,
+    http_bind: Option<SocketAddr>,
+    admin_bind: Option<SocketAddr>,
+    admin_token: Option<String>,
+    admins: Vec<String>,
+    storage: Option<StorageKind>,
+    redis_url: Option<String>,
+    db_path: Option<PathBuf>,
+    redis_timeout_secs: Option<u64>,
+    proxy_protocol: bool,
+    command_prefix: Option<char>,
+    chat_log: ChatLogConfig,
+    metrics: MetricsConfig,
+    runtime: RuntimeConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    MissingValue(String),
+    InvalidAddr(String),
+    InvalidNumber(String),
+    InvalidStorage(String),
+    UnknownFlag(String),
+    FailedToRead(String),
+    FailedToParse(String),
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::MissingValue(flag) => write!(f, "Error: {} requires a value", flag),
+            ConfigError::InvalidAddr(addr) => write!(f, "Error: Invalid address '{}'", addr),
+            ConfigError::InvalidNumber(n) => write!(f, "Error: Invalid number '{}'", n),
+            ConfigError::InvalidStorage(s) => {
+                write!(
+                    f,
+                    "Error: Unknown storage '{}', expected redis, memory or sqlite",
+                    s
+                )
+            }
+            ConfigError::UnknownFlag(flag) => write!(f, "Error: Unknown flag '{}'", flag),
+            ConfigError::FailedToRead(e) => write!(f, "Error: Failed to read config: {}", e),
+            ConfigError::FailedToParse(e) => write!(f, "Error: Failed to parse config: {}", e),
+            ConfigError::Invalid(reason) => write!(f, "Error: Invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            motd: None,
+            history: 10,
+            page_size: 25,
+            retention: None,
+            compact_interval_secs: 60 * 60,
+            session_ttl_secs: 24 * 60 * 60,
+            offline_dm_ttl_secs: 7 * 24 * 60 * 60,
+            edit_window_secs: 2 * 60,
+            shutdown_countdown_secs: 30,
+            away_after_secs: 15 * 60,
+            keepalive_secs: 5 * 60,
+            keepalive_grace_secs: 60,
+            limits: LimitsConfig::default(),
+            filter: FilterConfig::default(),
+            broker: BrokerConfig::default(),
+            access: AccessConfig::default(),
+        }
+    }
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            room_queue: 100,
+            member_queue: 100,
+            write_timeout_secs: 10,
+            allow_private_webhooks: false,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            exporters: vec![Exporter::Prometheus],
+            slow_redis_ms: 100,
+            statsd: StatsdConfig::default(),
+        }
+    }
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            addr: None,
+            flavor: StatsdFlavor::default(),
+            flush_ms: 1000,
+            max_packets: 100,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            messages_per_window: 0,
+            window_secs: 10,
+            rooms_per_user: 10,
+            max_rooms: None,
+            reserved_rooms: ["admin", "server", "lobby", "_*"]
+                .map(str::to_owned)
+                .to_vec(),
+            max_message_len: 2000,
+            duplicate_limit: 3,
+            duplicate_window_secs: 60,
+            login_grace_secs: 30,
+            max_invalid_commands: 10,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Command line flags take precedence over the config file.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::config::{AppConfig, Exporter, StorageKind};
+    ///
+    /// let args = ["--ws-bind", "127.0.0.1:8080"].map(String::from);
+    /// let config = AppConfig::from_args(args).unwrap();
+    ///
+    /// assert_eq!(config.binds.len(), 1);
+    /// assert_eq!(config.binds[0].port(), 8000);
+    /// assert_eq!(config.ws_bind.unwrap().port(), 8080);
+    ///
+    /// let args = ["--bind", "0.0.0.0:8000", "--bind", "[::]:8000"].map(String::from);
+    /// let config = AppConfig::from_args(args).unwrap();
+    ///
+    /// assert_eq!(config.binds.len(), 2);
+    /// assert!(config.binds[1].is_ipv6());
+    /// assert!(AppConfig::from_args(["--nope".to_owned()]).is_err());
+    ///
+    /// assert_eq!(config.command_prefix, '>');
+    /// let config = AppConfig::from_args(["--command-prefix", "/"].map(String::from)).unwrap();
+    /// assert_eq!(config.command_prefix, '/');
+    /// assert!(AppConfig::from_args(["--command-prefix", "a"].map(String::from)).is_err());
+    /// assert!(AppConfig::from_args(["--command-prefix", "//"].map(String::from)).is_err());
+    ///
+    /// // Prometheus unless told otherwise, `--statsd` adds StatsD
+    /// assert!(config.metrics.enabled(Exporter::Prometheus));
+    /// assert!(!config.metrics.enabled(Exporter::Statsd));
+    /// let config = AppConfig::from_args(["--statsd", "127.0.0.1:8125"].map(String::from)).unwrap();
+    /// assert!(config.metrics.enabled(Exporter::Prometheus));
+    /// assert!(config.metrics.enabled(Exporter::Statsd));
+    /// assert_eq!(config.metrics.statsd.addr.unwrap().port(), 8125);
+    ///
+    /// let args = ["--storage", "sqlite", "--db-path", "/var/lib/chatsapp/chats.db"].map(String::from);
+    /// let config = AppConfig::from_args(args).unwrap();
+    /// assert_eq!(config.storage, StorageKind::Sqlite);
+    /// assert!(config.db_path.ends_with("chats.db"));
+    /// ```
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut file = FileConfig::default();
+        let mut binds = Vec::new();
+        let mut ws_bind = None;
+        let mut irc_bind = None;
+        let mut http_bind = None;
+        let mut admin_bind = None;
+        let mut admin_token = None;
+        let mut storage = None;
+        let mut redis_url = None;
+        let mut db_path = None;
+        let mut redis_timeout_secs = None;
+        let mut proxy_protocol = false;
+        let mut command_prefix = None;
+        let mut config_path: Option<PathBuf> = None;
+        let mut statsd_addr = None;
+
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ConfigError::MissingValue(flag.clone()))
+            };
+
+            match flag.as_str() {
+                "--bind" => binds.push(parse_addr(value()?)?),
+                "--ws-bind" => ws_bind = Some(parse_addr(value()?)?),
+                "--irc-bind" => irc_bind = Some(parse_addr(value()?)?),
+                "--http-bind" => http_bind = Some(parse_addr(value()?)?),
+                "--admin" => admin_bind = admin_bind.or(Some(default_admin_bind())),
+                "--admin-bind" => admin_bind = Some(parse_addr(value()?)?),
+                "--admin-token" => admin_token = Some(value()?),
+                "--storage" => storage = Some(parse_storage(value()?)?),
+                "--redis-url" => redis_url = Some(value()?),
+                "--db-path" => db_path = Some(PathBuf::from(value()?)),
+                "--redis-timeout" => redis_timeout_secs = Some(parse_secs(value()?)?),
+                "--proxy-protocol" => proxy_protocol = true,
+                "--command-prefix" => command_prefix = Some(parse_prefix(value()?)?),
+                "--config" => config_path = Some(value()?.into()),
+                "--statsd" => statsd_addr = Some(parse_addr(value()?)?),
+                _ => return Err(ConfigError::UnknownFlag(flag)),
+            }
+        }
+
+        if let Some(path) = &config_path {
+            file = read_file(path)?;
+        }
+
+        if binds.is_empty() {
+            binds = file.binds;
+        }
+        if binds.is_empty() {
+            binds.push(SocketAddr::from(([0, 0, 0, 0], 8000)));
+        }
+
+        file.runtime.validate()?;
+        if file.chat_log.enabled && file.chat_log.dir.is_none() {
+            Err(ConfigError::Invalid("chat_log.dir is needed to enable it"))?;
+        }
+
+        let mut metrics = file.metrics;
+        if let Some(addr) = statsd_addr {
+            metrics.statsd.addr = Some(addr);
+            if !metrics.exporters.contains(&Exporter::Statsd) {
+                metrics.exporters.push(Exporter::Statsd);
+            }
+        }
+        metrics.validate()?;
+
+        let command_prefix = command_prefix
+            .or(file.command_prefix)
+            .unwrap_or(DEFAULT_PREFIX);
+        // It has to be told apart from the start of a chat message
+        if command_prefix.is_alphanumeric() || command_prefix.is_whitespace() {
+            Err(ConfigError::Invalid(
+                "command_prefix can't be a letter, digit or space",
+            ))?;
+        }
+
+        Ok(Self {
+            binds,
+            ws_bind: ws_bind.or(file.ws_bind),
+            irc_bind: irc_bind.or(file.irc_bind),
+            http_bind: http_bind.or(file.http_bind),
+            admin_bind: admin_bind.or(file.admin_bind),
+            admin_token: admin_token.or(file.admin_token),
+            admins: admins_from_env().unwrap_or(file.admins),
+            storage: storage.or(file.storage).unwrap_or_default(),
+            redis_url: redis_url
+                .or(file.redis_url)
+                .unwrap_or_else(|| "redis://:redis@127.0.0.1/".to_owned()),
+            db_path: db_path
+                .or(file.db_path)
+                .unwrap_or_else(|| PathBuf::from("chats.db")),
+            redis_timeout: Duration::from_secs(
+                redis_timeout_secs.or(file.redis_timeout_secs).unwrap_or(60),
+            ),
+            proxy_protocol: proxy_protocol || file.proxy_protocol,
+            command_prefix,
+            config_path,
+            chat_log: file.chat_log,
+            metrics,
+            runtime: file.runtime,
+        })
+    }
+}
+
+impl RuntimeConfig {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::config::RuntimeConfig;
+    ///
+    /// let config = RuntimeConfig::parse(
+    ///     r#"
+    ///     motd = "Be nice"
+    ///     history = 20
+    ///
+    ///     [limits]
+    ///     messages_per_window = 5
+    ///     "#,
+    /// )
+    /// .unwrap();
+    ///
+    /// assert_eq!(config.motd.as_deref(), Some("Be nice"));
+    /// assert_eq!(config.history, 20);
+    /// assert_eq!(config.limits.window_secs, 10);
+    ///
+    /// assert!(RuntimeConfig::parse("history = 0").is_err());
+    /// assert!(RuntimeConfig::parse("page_size = 0").is_err());
+    /// assert!(RuntimeConfig::parse("keepalive_grace_secs = 0").is_err());
+    /// assert!(RuntimeConfig::parse("keepalive_secs = 0\nkeepalive_grace_secs = 0").is_ok());
+    /// assert!(RuntimeConfig::parse("histroy = 5").is_err());
+    /// assert!(RuntimeConfig::parse("broker = { member_queue = 0 }").is_err());
+    ///
+    /// let config = RuntimeConfig::parse(r#"access = { allow = ["10.0.0.0/8", "2001:db8::/32"] }"#).unwrap();
+    /// assert_eq!(config.access.allow[1].to_string(), "2001:db8::/32");
+    /// assert!(config.access.deny.is_empty());
+    /// assert!(RuntimeConfig::parse(r#"access = { deny = ["10.0.0.0/33"] }"#).is_err());
+    /// ```
+    pub fn parse(s: &str) -> Result<Self, ConfigError> {
+        let config: RuntimeConfig =
+            toml::from_str(s).map_err(|e| ConfigError::FailedToParse(e.message().to_owned()))?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.history == 0 {
+            Err(ConfigError::Invalid("history must be at least 1"))?;
+        }
+
+        if self.page_size == 0 {
+            Err(ConfigError::Invalid("page_size must be at least 1"))?;
+        }
+
+        if self.keepalive_secs > 0 && self.keepalive_grace_secs == 0 {
+            Err(ConfigError::Invalid(
+                "keepalive_grace_secs must be positive",
+            ))?;
+        }
+
+        if self
+            .retention
+            .is_some_and(|retention| retention < self.history)
+        {
+            Err(ConfigError::Invalid("retention must be at least history"))?;
+        }
+
+        if self.limits.messages_per_window > 0 && self.limits.window_secs == 0 {
+            Err(ConfigError::Invalid("limits.window_secs must be positive"))?;
+        }
+
+        if self.limits.max_connections == Some(0) {
+            Err(ConfigError::Invalid(
+                "limits.max_connections must be positive",
+            ))?;
+        }
+
+        if self.limits.max_rooms == Some(0) {
+            Err(ConfigError::Invalid("limits.max_rooms must be positive"))?;
+        }
+
+        if self.broker.room_queue == 0 || self.broker.member_queue == 0 {
+            Err(ConfigError::Invalid("broker queues must be positive"))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MetricsConfig {
+    pub fn enabled(&self, exporter: Exporter) -> bool {
+        self.exporters.contains(&exporter)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self.enabled(Exporter::Statsd) {
+            return Ok(());
+        }
+
+        if self.statsd.addr.is_none() {
+            Err(ConfigError::Invalid(
+                "metrics.statsd.addr is needed for the statsd exporter",
+            ))?;
+        }
+
+        if self.statsd.flush_ms == 0 || self.statsd.max_packets == 0 {
+            Err(ConfigError::Invalid(
+                "metrics.statsd.flush_ms and max_packets must be positive",
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Re-reads the `[runtime]` section of the config file and swaps it in. The
+// current config is kept when the new one doesn't parse or validate.
+pub fn reload(path: &Path, shared: &SharedConfig) -> Result<(), ConfigError> {
+    let file = read_file(path)?;
+    file.runtime.validate()?;
+
+    shared.store(Arc::new(file.runtime));
+
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<FileConfig, ConfigError> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| ConfigError::FailedToRead(e.to_string()))?;
+
+    toml::from_str(&contents).map_err(|e| ConfigError::FailedToParse(e.message().to_owned()))
+}
+
+fn parse_prefix(prefix: String) -> Result<char, ConfigError> {
+    let mut chars = prefix.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(prefix), None) => Ok(prefix),
+        _ => Err(ConfigError::Invalid(
+            "command_prefix must be a single character",
+        )),
+    }
+}
+
+fn default_admin_bind() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8001))
+}
+
+// `CHATSAPP_ADMINS=alice,bob` takes precedence over `admins` in the file
+fn admins_from_env() -> Option<Vec<String>> {
+    let admins = std::env::var("CHATSAPP_ADMINS").ok()?;
+
+    Some(
+        admins
+            .split(',')
+            .map(str::trim)
+            .filter(|admin| !admin.is_empty())
+            .map(str::to_owned)
+            .collect(),
+    )
+}
+
+fn parse_storage(storage: String) -> Result<StorageKind, ConfigError> {
+    match storage.as_str() {
+        "redis" => Ok(StorageKind::Redis),
+        "memory" => Ok(StorageKind::Memory),
+        "sqlite" => Ok(StorageKind::Sqlite),
+        _ => Err(ConfigError::InvalidStorage(storage)),
+    }
+}
+
+fn parse_secs(secs: String) -> Result<u64, ConfigError> {
+    secs.parse().map_err(|_| ConfigError::InvalidNumber(secs))
+}
+
+fn parse_addr(addr: String) -> Result<SocketAddr, ConfigError> {
+    addr.parse().map_err(|_| ConfigError::InvalidAddr(addr))
+}
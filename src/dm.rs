@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use crate::room;
+use crate::store::{RoomStore, StoreError};
+
+// Whispers kept for someone offline, older ones are dropped as new ones arrive
+pub const MAX_OFFLINE: usize = 100;
+
+/// Stores the message in the pair's history, returning the formatted message.
+/// Both sides share one history, so it reads the same from either.
+pub async fn send(
+    store: &dyn RoomStore,
+    from: &str,
+    to: &str,
+    text: &str,
+    retention: Option<usize>,
+) -> Result<String, StoreError> {
+    let msg = format!("{}: {}\n", from, text);
+
+    store
+        .sorted_add(&key(from, to), &msg, room::get_time_in_ms(), retention)
+        .await?;
+
+    Ok(msg)
+}
+
+// The last `count` messages between `user` and `other`, oldest first. Takes
+// the user asking, so there's no way to read a conversation you weren't in.
+pub async fn history(
+    store: &dyn RoomStore,
+    user: &str,
+    other: &str,
+    count: usize,
+) -> Result<Vec<String>, StoreError> {
+    store.sorted_recent(&key(user, other), count).await
+}
+
+// `dm:alice:bob`, with the names sorted so both sides share a key
+pub fn key(a: &str, b: &str) -> String {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+
+    format!("dm:{}:{}", first, second)
+}
+
+/// Keeps a whisper, sent at `at` in ms, for when `to` next sets their name.
+/// Anything older than `offline_dm_ttl_secs` by then is dropped instead.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::time::Duration;
+///
+/// use chatsapp::dm::{self, MAX_OFFLINE};
+/// use chatsapp::room;
+/// use chatsapp::store::MemoryStore;
+///
+/// // Only so many are kept
+/// let store = MemoryStore::default();
+/// let now = room::get_time_in_ms();
+/// for n in 0..MAX_OFFLINE + 5 {
+///     dm::queue(&store, "erin", &format!("alice: {}\n", n), now).await.unwrap();
+/// }
+/// let week = Duration::from_secs(7 * 24 * 60 * 60);
+/// let kept = dm::take_offline(&store, "erin", week).await.unwrap();
+/// assert_eq!(kept.len(), MAX_OFFLINE);
+/// assert_eq!(kept[0], (now, "alice: 5\n".to_owned()));
+/// # }
+/// ```
+pub async fn queue(store: &dyn RoomStore, to: &str, msg: &str, at: i64) -> Result<(), StoreError> {
+    store
+        .list_push(&offline_key(to), &format!("{} {}", at, msg), MAX_OFFLINE)
+        .await
+}
+
+// Empties `user`'s queue, returning when each was sent and the message, oldest
+// first, less any older than `ttl`
+pub async fn take_offline(
+    store: &dyn RoomStore,
+    user: &str,
+    ttl: Duration,
+) -> Result<Vec<(i64, String)>, StoreError> {
+    let cutoff = room::get_time_in_ms() - ttl.as_millis() as i64;
+    let queued = store.list_take(&offline_key(user)).await?;
+
+    Ok(queued
+        .iter()
+        .filter_map(|item| {
+            let (at, msg) = item.split_once(' ')?;
+            Some((at.parse().ok()?, msg.to_owned()))
+        })
+        .filter(|(at, _)| *at >= cutoff)
+        .collect())
+}
+
+fn offline_key(user: &str) -> String {
+    format!("offline-dms:{}", user)
+}
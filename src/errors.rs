@@ -0,0 +1,244 @@
+use tokio::sync::mpsc::error::SendError;
+
+use crate::ban::InvalidNet;
+use crate::broker::BrokerEvent;
+use crate::command::ParseError;
+use crate::page::OutOfRange;
+use crate::quote::QuoteError;
+use crate::reactions::ReactionError;
+use crate::report::ReportError;
+use crate::room::RoomError;
+use crate::store::StoreError;
+use crate::username::UsernameError;
+
+/// What went wrong, for bots to match on rather than the text that comes
+/// with it. Codes are never renamed or reused, listed with `>help errors`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    RoomNotFound,
+    NotInRoom,
+    NameTaken,
+    Storage,
+    RateLimited,
+    Duplicate,
+    Forbidden,
+    UnknownCommand,
+    InvalidArgument,
+    InvalidUsername,
+    TooLong,
+    Blocked,
+    NeedsUsername,
+    NeedsLogin,
+    NotRegistered,
+    WrongPassword,
+    PasswordTooShort,
+    AlreadyLoggedIn,
+    SessionExpired,
+    ResumeNotFirst,
+    Reserved,
+    LimitReached,
+    RoomClosed,
+    ShuttingDown,
+    MessageNotFound,
+    EditExpired,
+    DoNotDisturb,
+}
+
+impl Code {
+    pub const ALL: [Code; 27] = [
+        Code::RoomNotFound,
+        Code::NotInRoom,
+        Code::NameTaken,
+        Code::Storage,
+        Code::RateLimited,
+        Code::Duplicate,
+        Code::Forbidden,
+        Code::UnknownCommand,
+        Code::InvalidArgument,
+        Code::InvalidUsername,
+        Code::TooLong,
+        Code::Blocked,
+        Code::NeedsUsername,
+        Code::NeedsLogin,
+        Code::NotRegistered,
+        Code::WrongPassword,
+        Code::PasswordTooShort,
+        Code::AlreadyLoggedIn,
+        Code::SessionExpired,
+        Code::ResumeNotFirst,
+        Code::Reserved,
+        Code::LimitReached,
+        Code::RoomClosed,
+        Code::ShuttingDown,
+        Code::MessageNotFound,
+        Code::EditExpired,
+        Code::DoNotDisturb,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Code::RoomNotFound => "E_ROOM_NOT_FOUND",
+            Code::NotInRoom => "E_NOT_IN_ROOM",
+            Code::NameTaken => "E_NAME_TAKEN",
+            Code::Storage => "E_STORAGE",
+            Code::RateLimited => "E_RATE_LIMITED",
+            Code::Duplicate => "E_DUPLICATE",
+            Code::Forbidden => "E_FORBIDDEN",
+            Code::UnknownCommand => "E_UNKNOWN_COMMAND",
+            Code::InvalidArgument => "E_INVALID_ARGUMENT",
+            Code::InvalidUsername => "E_INVALID_USERNAME",
+            Code::TooLong => "E_TOO_LONG",
+            Code::Blocked => "E_BLOCKED",
+            Code::NeedsUsername => "E_NEEDS_USERNAME",
+            Code::NeedsLogin => "E_NEEDS_LOGIN",
+            Code::NotRegistered => "E_NOT_REGISTERED",
+            Code::WrongPassword => "E_WRONG_PASSWORD",
+            Code::PasswordTooShort => "E_PASSWORD_TOO_SHORT",
+            Code::AlreadyLoggedIn => "E_ALREADY_LOGGED_IN",
+            Code::SessionExpired => "E_SESSION_EXPIRED",
+            Code::ResumeNotFirst => "E_RESUME_NOT_FIRST",
+            Code::Reserved => "E_RESERVED",
+            Code::LimitReached => "E_LIMIT_REACHED",
+            Code::RoomClosed => "E_ROOM_CLOSED",
+            Code::ShuttingDown => "E_SHUTTING_DOWN",
+            Code::MessageNotFound => "E_MESSAGE_NOT_FOUND",
+            Code::EditExpired => "E_EDIT_EXPIRED",
+            Code::DoNotDisturb => "E_DO_NOT_DISTURB",
+        }
+    }
+
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::errors::Code;
+    ///
+    /// for code in Code::ALL {
+    ///     assert_eq!(Code::parse(code.as_str()), Some(code));
+    /// }
+    /// assert_eq!(Code::parse("E_NOT_A_CODE"), None);
+    /// ```
+    pub fn parse(code: &str) -> Option<Code> {
+        Code::ALL.into_iter().find(|c| c.as_str() == code)
+    }
+
+    // For `>help errors`
+    pub fn description(&self) -> &'static str {
+        match self {
+            Code::RoomNotFound => "There's no room by that name",
+            Code::NotInRoom => "It needs you to be in a room",
+            Code::NameTaken => "The room or username is already taken",
+            Code::Storage => "The server couldn't reach its storage, try again",
+            Code::RateLimited => "Too many messages, wait and try again",
+            Code::Duplicate => "The same message was sent again",
+            Code::Forbidden => "You need to be an admin, or the room's owner or a moderator",
+            Code::UnknownCommand => "There's no command by that name",
+            Code::InvalidArgument => "An argument is missing or isn't valid",
+            Code::InvalidUsername => "Usernames can't be blank or mix alphabets",
+            Code::TooLong => "The message is over the room's length limit",
+            Code::Blocked => "The message was blocked by the word filter",
+            Code::NeedsUsername => "Pick a username first",
+            Code::NeedsLogin => "Log in to the registered name you've set first",
+            Code::NotRegistered => "The username isn't registered",
+            Code::WrongPassword => "The password is wrong",
+            Code::PasswordTooShort => "The password is too short",
+            Code::AlreadyLoggedIn => "The username is logged in elsewhere",
+            Code::SessionExpired => "The session token has expired",
+            Code::ResumeNotFirst => "Sessions can only be resumed as the first command",
+            Code::Reserved => "The name is reserved, eg for guests",
+            Code::LimitReached => "A limit on rooms or webhooks has been reached",
+            Code::RoomClosed => "The room closed while you were using it",
+            Code::ShuttingDown => "The server is shutting down, rooms can't be joined or created",
+            Code::MessageNotFound => "There's no message with that id in the room",
+            Code::EditExpired => {
+                "Messages can only be edited or removed for a while after they're sent"
+            }
+            Code::DoNotDisturb => {
+                "They're in do-not-disturb mode, so aren't taking direct messages"
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Errors written to connections, each with the code it's sent with
+pub trait UserError: std::error::Error {
+    fn code(&self) -> Code;
+}
+
+impl UserError for StoreError {
+    fn code(&self) -> Code {
+        Code::Storage
+    }
+}
+
+impl UserError for RoomError {
+    fn code(&self) -> Code {
+        match self {
+            RoomError::Store(e) => e.code(),
+            RoomError::RoomNameTaken => Code::NameTaken,
+            RoomError::InvalidTag(_) => Code::InvalidArgument,
+            RoomError::MessageNotFound(_) => Code::MessageNotFound,
+            RoomError::NotChat(_) | RoomError::AlreadyRemoved(_) => Code::InvalidArgument,
+        }
+    }
+}
+
+impl UserError for UsernameError {
+    fn code(&self) -> Code {
+        Code::InvalidUsername
+    }
+}
+
+impl UserError for OutOfRange {
+    fn code(&self) -> Code {
+        Code::InvalidArgument
+    }
+}
+
+impl UserError for InvalidNet {
+    fn code(&self) -> Code {
+        Code::InvalidArgument
+    }
+}
+
+impl UserError for ParseError {
+    fn code(&self) -> Code {
+        match self {
+            ParseError::UnknownCommand { .. } => Code::UnknownCommand,
+            _ => Code::InvalidArgument,
+        }
+    }
+}
+
+impl UserError for QuoteError {
+    fn code(&self) -> Code {
+        Code::InvalidArgument
+    }
+}
+
+impl UserError for ReactionError {
+    fn code(&self) -> Code {
+        Code::InvalidArgument
+    }
+}
+
+impl UserError for ReportError {
+    fn code(&self) -> Code {
+        match self {
+            ReportError::Store(e) => e.code(),
+            ReportError::RateLimited => Code::RateLimited,
+        }
+    }
+}
+
+// The broker has stopped, eg the room was deleted as the event was sent
+impl UserError for SendError<BrokerEvent> {
+    fn code(&self) -> Code {
+        Code::RoomClosed
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use crate::config::{FilterConfig, FilterMode};
+use crate::store::{RoomStore, StoreError};
+
+pub const FILTER_WORDS_KEY: &str = "server:filterwords";
+
+#[derive(Debug)]
+pub enum FilterError {
+    FailedToRead(String),
+    Store(StoreError),
+}
+
+impl std::fmt::Display for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterError::FailedToRead(e) => writeln!(f, "Error: Failed to read word list: {}", e),
+            FilterError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+impl From<StoreError> for FilterError {
+    fn from(e: StoreError) -> Self {
+        FilterError::Store(e)
+    }
+}
+
+/// Words are matched whole, ignoring case, look-alike digits and symbols, and
+/// repeated letters.
+#[derive(Debug, Default)]
+pub struct WordFilter {
+    // Collapsed form of each word to its shortest spelling, see `matches`
+    words: HashMap<String, usize>,
+}
+
+impl WordFilter {
+    pub fn new<I, S>(words: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut filter = Self::default();
+
+        for word in words {
+            let normalized = normalize(word.as_ref());
+            if normalized.is_empty() {
+                continue;
+            }
+
+            let len = normalized.chars().count();
+            let shortest = filter.words.entry(collapse(&normalized)).or_insert(len);
+            *shortest = (*shortest).min(len);
+        }
+
+        filter
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// The text with every matched word replaced by asterisks, or None if
+    /// nothing matched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::filter::WordFilter;
+    ///
+    /// let filter = WordFilter::new(["ass", "heck"]);
+    ///
+    /// assert_eq!(filter.mask("what the h3ckkk!").as_deref(), Some("what the *******"));
+    /// assert_eq!(filter.mask("HECK no, ASSS").as_deref(), Some("**** no, ****"));
+    ///
+    /// // Whole words only, and collapsing repeats never makes a word shorter
+    /// assert_eq!(filter.mask("as a class, check"), None);
+    /// ```
+    pub fn mask(&self, text: &str) -> Option<String> {
+        let mut masked = String::with_capacity(text.len());
+        let mut matched = false;
+
+        for (i, token) in text.split(' ').enumerate() {
+            if i > 0 {
+                masked.push(' ');
+            }
+
+            if self.matches(token) {
+                masked.extend(token.chars().map(|_| '*'));
+                matched = true;
+            } else {
+                masked.push_str(token);
+            }
+        }
+
+        matched.then_some(masked)
+    }
+
+    // Repeating letters only ever lengthens a word, so a token matches when
+    // it collapses to the same thing and is at least as long. Without the
+    // length check "ass" would match "as".
+    fn matches(&self, token: &str) -> bool {
+        let normalized = normalize(token);
+
+        self.words
+            .get(&collapse(&normalized))
+            .is_some_and(|&len| normalized.chars().count() >= len)
+    }
+}
+
+// Reads the word list for the current config, empty when the filter is off
+pub async fn load(config: &FilterConfig, store: &dyn RoomStore) -> Result<WordFilter, FilterError> {
+    if config.mode == FilterMode::Off {
+        return Ok(WordFilter::default());
+    }
+
+    match &config.words_file {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| FilterError::FailedToRead(e.to_string()))?;
+
+            // Blank lines and `#` comments are skipped
+            Ok(WordFilter::new(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.starts_with('#')),
+            ))
+        }
+        None => Ok(WordFilter::new(store.set_members(FILTER_WORDS_KEY).await?)),
+    }
+}
+
+// Lowercases, swaps look-alikes for the letter they stand in for and drops
+// punctuation
+fn normalize(word: &str) -> String {
+    word.chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            c => c,
+        })
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn collapse(word: &str) -> String {
+    let mut collapsed = String::with_capacity(word.len());
+
+    for c in word.chars() {
+        if !collapsed.ends_with(c) {
+            collapsed.push(c);
+        }
+    }
+
+    collapsed
+}
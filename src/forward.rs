@@ -0,0 +1,437 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, KeyInit, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{self as tokio_net, TcpStream};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::time;
+use tracing::warn;
+
+use crate::hash;
+use crate::metrics::metrics;
+use crate::store::RoomStore;
+use crate::webhook;
+
+// Messages waiting to be forwarded, newer ones are dropped once it's full
+const QUEUE_LEN: usize = 256;
+// Failed deliveries waiting to be retried, the oldest are dropped once it's full
+const MAX_RETRIES_QUEUED: usize = 100;
+const MAX_ATTEMPTS: u32 = 4;
+const TIMEOUT: Duration = Duration::from_secs(5);
+// Webhooks are re-read at most this often, so changes can take as long to apply
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+// Consecutive failures before an endpoint is left alone for a while
+const BREAKER_THRESHOLD: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+
+// What's POSTed as JSON for each chat message
+#[derive(Serialize)]
+pub struct Payload {
+    pub room: String,
+    pub user: String,
+    pub text: String,
+    // Milliseconds since the epoch
+    pub ts: i64,
+}
+
+/// Sends a room's chat messages to its outgoing webhooks, from its own task so
+/// a slow or dead endpoint never holds up the broker.
+///
+/// Failed deliveries are retried with backoff, and an endpoint that keeps
+/// failing is skipped for a minute. Each request carries an
+/// `X-Chatsapp-Signature: sha256=<hex>` header, the HMAC-SHA256 of the body
+/// with the webhook's secret. Endpoints that resolve to loopback or private
+/// addresses are only posted to with `allow_private`.
+#[derive(Clone)]
+pub struct Forwarder {
+    tx: Sender<Payload>,
+}
+
+impl Forwarder {
+    pub fn spawn(room: String, store: Arc<dyn RoomStore>, allow_private: bool) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(run(room, store, allow_private, rx));
+
+        Self { tx }
+    }
+
+    // Never waits, if the queue is full the message is dropped
+    pub fn forward(&self, payload: Payload) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(payload) {
+            metrics().webhooks_dropped.inc();
+        }
+    }
+}
+
+// An `http://` URL split up for connecting. There's no TLS support, so
+// `https://` isn't accepted.
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    host: String,
+    port: u16,
+    // As sent in the Host header
+    authority: String,
+    path: String,
+}
+
+impl Target {
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::forward::Target;
+    ///
+    /// assert!(Target::parse("http://example.com").is_some());
+    /// assert!(Target::parse("http://[::1]:8080/hooks/chat?room=rust").is_some());
+    /// assert!(Target::parse("https://example.com/hook").is_none());
+    /// assert!(Target::parse("http://example.com:http/").is_none());
+    /// ```
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+
+        // IPv6 addresses are bracketed, eg `[::1]:8080`
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(v6) => {
+                let (host, port) = v6.split_once(']')?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => 80,
+        };
+
+        if host.is_empty() || path.contains(char::is_whitespace) {
+            return None;
+        }
+
+        Some(Self {
+            host: host.to_owned(),
+            port,
+            authority: authority.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+
+    // Looked up again each time, so a name can't resolve to somewhere public
+    // when it's added and somewhere private later
+    pub async fn resolve(&self, allow_private: bool) -> io::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = tokio_net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .filter(|addr| allow_private || !is_private(addr.ip()))
+            .collect();
+        if addrs.is_empty() {
+            let msg = format!("{} only resolves to private addresses", self.host);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Addresses outgoing webhooks refuse by default, so a room's owner can't use
+/// them to reach the server itself or the network it's on: loopback,
+/// private, link-local, shared and unspecified ones.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::forward;
+///
+/// assert!(forward::is_private("127.0.0.1".parse().unwrap()));
+/// assert!(forward::is_private("10.1.2.3".parse().unwrap()));
+/// assert!(forward::is_private("169.254.169.254".parse().unwrap()));
+/// assert!(forward::is_private("::ffff:192.168.0.1".parse().unwrap()));
+/// assert!(forward::is_private("fd00::1".parse().unwrap()));
+/// assert!(!forward::is_private("93.184.216.34".parse().unwrap()));
+/// assert!(!forward::is_private("2606:2800:220:1::".parse().unwrap()));
+/// ```
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+/// The hex HMAC-SHA256 of `body`, as sent in `X-Chatsapp-Signature`.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::forward;
+///
+/// let body = "The quick brown fox jumps over the lazy dog";
+/// assert_eq!(
+///     forward::sign("key", body),
+///     "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+/// );
+/// ```
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+
+    hash::hex(&mac.finalize().into_bytes())
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    // Once the cooldown's up one more attempt is let through, and another
+    // failure opens it again straight away
+    fn allows(&self) -> bool {
+        self.open_until.is_none_or(|until| Instant::now() >= until)
+    }
+
+    fn succeeded(&mut self) {
+        self.failures = 0;
+        self.open_until = None;
+    }
+
+    // Returns true when this failure opened it
+    fn failed(&mut self) -> bool {
+        self.failures += 1;
+        if self.failures < BREAKER_THRESHOLD {
+            return false;
+        }
+
+        let was_closed = self.open_until.is_none();
+        self.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+
+        was_closed
+    }
+}
+
+struct Endpoint {
+    target: Target,
+    secret: String,
+    breaker: Breaker,
+}
+
+struct Retry {
+    url: String,
+    body: String,
+    attempts: u32,
+    due: Instant,
+}
+
+struct Forwarding {
+    room: String,
+    store: Arc<dyn RoomStore>,
+    allow_private: bool,
+    // <URL, Endpoint>
+    endpoints: HashMap<String, Endpoint>,
+    refreshed: Option<Instant>,
+    retries: VecDeque<Retry>,
+}
+
+// Stops once the broker, and so the sender, is gone
+async fn run(
+    room: String,
+    store: Arc<dyn RoomStore>,
+    allow_private: bool,
+    mut payloads: Receiver<Payload>,
+) {
+    let mut forwarding = Forwarding {
+        room,
+        store,
+        allow_private,
+        endpoints: HashMap::new(),
+        refreshed: None,
+        retries: VecDeque::new(),
+    };
+
+    loop {
+        let next_retry = forwarding.retries.iter().map(|retry| retry.due).min();
+
+        tokio::select! {
+            payload = payloads.recv() => {
+                let Some(payload) = payload else {
+                    break;
+                };
+                forwarding.forward(payload).await;
+            }
+            _ = time::sleep_until(next_retry.unwrap_or_else(Instant::now).into()),
+                if next_retry.is_some() => {
+                forwarding.retry_due().await;
+            }
+        }
+    }
+}
+
+impl Forwarding {
+    async fn forward(&mut self, payload: Payload) {
+        self.refresh().await;
+        if self.endpoints.is_empty() {
+            return;
+        }
+
+        let body = serde_json::to_string(&payload).unwrap();
+        let urls: Vec<String> = self.endpoints.keys().cloned().collect();
+        for url in urls {
+            self.deliver(url, body.clone(), 1).await;
+        }
+    }
+
+    async fn retry_due(&mut self) {
+        let now = Instant::now();
+        let (due, waiting) = self.retries.drain(..).partition(|retry| retry.due <= now);
+        self.retries = waiting;
+
+        for retry in due {
+            self.deliver(retry.url, retry.body, retry.attempts).await;
+        }
+    }
+
+    // Keeps each endpoint's breaker for as long as it's still configured
+    async fn refresh(&mut self) {
+        if self
+            .refreshed
+            .is_some_and(|refreshed| refreshed.elapsed() < REFRESH_INTERVAL)
+        {
+            return;
+        }
+
+        let hooks = match webhook::outgoing(&*self.store, &self.room).await {
+            Ok(hooks) => hooks,
+            // Keep using what we had, and try again next time
+            Err(_) => return,
+        };
+        self.refreshed = Some(Instant::now());
+
+        let mut endpoints = HashMap::new();
+        for hook in hooks {
+            let Some(target) = Target::parse(&hook.url) else {
+                continue;
+            };
+            let breaker = self
+                .endpoints
+                .remove(&hook.url)
+                .map(|endpoint| endpoint.breaker)
+                .unwrap_or_default();
+
+            endpoints.insert(
+                hook.url,
+                Endpoint {
+                    target,
+                    secret: hook.secret,
+                    breaker,
+                },
+            );
+        }
+        self.endpoints = endpoints;
+    }
+
+    async fn deliver(&mut self, url: String, body: String, attempts: u32) {
+        // Removed since
+        let Some(endpoint) = self.endpoints.get_mut(&url) else {
+            return;
+        };
+        if !endpoint.breaker.allows() {
+            metrics().webhooks_dropped.inc();
+            return;
+        }
+
+        let res = match time::timeout(TIMEOUT, post(endpoint, &body, self.allow_private)).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+        };
+        let Err(e) = res else {
+            endpoint.breaker.succeeded();
+            return;
+        };
+
+        if endpoint.breaker.failed() {
+            warn!(
+                "Webhook {} for {} keeps failing, pausing it for {:?}: {}",
+                url, self.room, BREAKER_COOLDOWN, e
+            );
+        }
+
+        if attempts >= MAX_ATTEMPTS {
+            metrics().webhooks_dropped.inc();
+            return;
+        }
+
+        if self.retries.len() >= MAX_RETRIES_QUEUED {
+            self.retries.pop_front();
+            metrics().webhooks_dropped.inc();
+        }
+        // 2s, 4s, 8s
+        self.retries.push_back(Retry {
+            url,
+            body,
+            attempts: attempts + 1,
+            due: Instant::now() + Duration::from_secs(1 << attempts),
+        });
+    }
+}
+
+// Succeeds on any 2xx response
+async fn post(endpoint: &Endpoint, body: &str, allow_private: bool) -> io::Result<()> {
+    let target = &endpoint.target;
+    let addrs = target.resolve(allow_private).await?;
+    let mut stream = TcpStream::connect(&addrs[..]).await?;
+
+    let req = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nX-Chatsapp-Signature: sha256={}\r\nConnection: close\r\n\r\n{}",
+        target.path,
+        target.authority,
+        body.len(),
+        sign(&endpoint.secret, body),
+        body
+    );
+    stream.write_all(req.as_bytes()).await?;
+
+    // Only the status line matters, eg `HTTP/1.1 204 No Content`
+    let mut buf = Vec::new();
+    let mut chunk = [0; 512];
+    while !buf.contains(&b'\n') && buf.len() < 1024 {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let res = String::from_utf8_lossy(&buf);
+    let status = res.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(io::Error::other(format!("responded with '{}'", status)));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::Rng;
+use sha1::Sha1;
+
+const SALT_LEN: usize = 16;
+
+// Hashes made before Argon2, as `pbkdf2-sha1$<iterations>$<salt>$<hash>` in
+// hex. They still verify, and are replaced on the next login.
+const LEGACY_SCHEME: &str = "pbkdf2-sha1$";
+const LEGACY_LEN: usize = 20;
+
+/// A salted Argon2id hash of the password, as a PHC string.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::hash;
+///
+/// let stored = hash::hash_password("hunter22");
+/// assert!(stored.starts_with("$argon2id$"));
+/// assert!(hash::verify_password("hunter22", &stored));
+/// assert!(!hash::verify_password("hunter23", &stored));
+///
+/// // Salted, so the same password never hashes the same way twice
+/// assert_ne!(hash::hash_password("hunter22"), stored);
+/// assert!(!hash::verify_password("hunter22", "not a hash"));
+/// ```
+pub fn hash_password(password: &str) -> String {
+    let salt: [u8; SALT_LEN] = rand::rng().random();
+    let salt = SaltString::encode_b64(&salt).expect("16 bytes is a valid salt");
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("the default params take any password")
+        .to_string()
+}
+
+// False for anything `hash_password` couldn't have made, other than the
+// hashes from before it used Argon2
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    if let Some(legacy) = stored.strip_prefix(LEGACY_SCHEME) {
+        return verify_legacy(password, legacy);
+    }
+
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return false;
+    };
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+/// Whether the hash is from before Argon2, so should be replaced once the
+/// password's known.
+pub fn is_legacy(stored: &str) -> bool {
+    stored.starts_with(LEGACY_SCHEME)
+}
+
+fn verify_legacy(password: &str, legacy: &str) -> bool {
+    let parts: Vec<&str> = legacy.split('$').collect();
+    let [iterations, salt, expected] = parts[..] else {
+        return false;
+    };
+    let (Ok(iterations), Some(salt), Some(expected)) =
+        (iterations.parse(), unhex(salt), unhex(expected))
+    else {
+        return false;
+    };
+    if expected.len() != LEGACY_LEN {
+        return false;
+    }
+
+    let mut derived = [0; LEGACY_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha1>(password.as_bytes(), &salt, iterations, &mut derived);
+
+    // Compared in full whatever differs, so timing gives nothing away
+    derived
+        .iter()
+        .zip(&expected)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    // An odd length leaves half a byte at the end, which `get` refuses
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,760 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::time;
+use tracing::error;
+
+use crate::admin;
+use crate::broker::BrokerEvent;
+use crate::client::ServerEvent;
+use crate::metrics::metrics;
+use crate::registry::NO_CONN;
+use crate::render::{ServerMessage, TextRenderer};
+use crate::room::{self, RoomEvent};
+use crate::server::ServerContext;
+use crate::session::{self, Session};
+use crate::shutdown::Shutdown;
+use crate::store::{RoomStore, StoreError};
+use crate::throttle::Throttle;
+use crate::webhook;
+
+const MAX_REQUEST: usize = 8 * 1024;
+// Webhook bodies larger than this are refused without being read
+const MAX_HOOK_BODY: usize = 4 * 1024;
+
+// Per token, so one noisy integration can't flood a room
+const HOOK_MESSAGES_PER_WINDOW: u32 = 20;
+const HOOK_WINDOW: Duration = Duration::from_secs(60);
+
+// Messages per page from `/api/rooms/<name>/messages`, when no `limit` is
+// given and at most
+const API_PAGE: usize = 50;
+const MAX_API_PAGE: usize = 100;
+
+// Open `/api/rooms/<name>/stream`s allowed from one IP
+const MAX_STREAMS_PER_IP: usize = 4;
+
+// Ids for streams to watch rooms by, apart from connections'
+static NEXT_VIEWER: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Default)]
+pub struct Health {
+    accepting: AtomicBool,
+    bootstrapped: AtomicBool,
+}
+
+impl Health {
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::SeqCst);
+    }
+
+    pub fn set_bootstrapped(&self) {
+        self.bootstrapped.store(true, Ordering::SeqCst);
+    }
+}
+
+pub struct HttpState {
+    pub store: Arc<dyn RoomStore>,
+    pub health: Arc<Health>,
+    // Set once rooms are bootstrapped, webhooks are refused until then
+    pub server: OnceLock<Arc<ServerContext>>,
+    // Accepted by the API as well as `>session` tokens
+    pub admin_token: Option<String>,
+    // Serves `GET /metrics`
+    pub prometheus: bool,
+    hook_throttles: Mutex<HashMap<String, Throttle>>,
+    // Open event streams per IP
+    streams: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl HttpState {
+    pub fn new(store: Arc<dyn RoomStore>, health: Arc<Health>) -> Self {
+        Self {
+            store,
+            health,
+            server: OnceLock::new(),
+            admin_token: None,
+            prometheus: true,
+            hook_throttles: Default::default(),
+            streams: Default::default(),
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    content_length: usize,
+    // `Bearer <token>`
+    authorization: Option<String>,
+    // The score of the last event a stream got, to pick up after
+    last_event_id: Option<i64>,
+    // Whatever of the body was read along with the headers
+    body: Vec<u8>,
+}
+
+// Who a request's token says it's from
+enum Caller {
+    Admin,
+    Session(Session),
+}
+
+impl Caller {
+    // As with `>history`, a session only reads the room it's in
+    fn can_read(&self, room: &str) -> bool {
+        match self {
+            Caller::Admin => true,
+            Caller::Session(session) => session.room.as_deref() == Some(room),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HookMessage {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct ApiRoom {
+    name: String,
+    members: usize,
+}
+
+#[derive(Serialize)]
+struct ApiMessage {
+    // When it was sent in ms, pass the oldest's as `before` for the next page
+    score: i64,
+    #[serde(flatten)]
+    event: ApiEvent,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum ApiEvent {
+    Chat { user: String, text: String },
+    Joined { user: String },
+    Left { user: String },
+    // A removed message, `by` being who removed it, the sender for `>undo`
+    Deleted { by: String },
+    Notice { text: String },
+}
+
+impl From<ServerMessage> for ApiEvent {
+    fn from(message: ServerMessage) -> Self {
+        match message {
+            ServerMessage::Chat { user, text, .. } => ApiEvent::Chat { user, text },
+            ServerMessage::Joined { user } => ApiEvent::Joined { user },
+            ServerMessage::Left { user } => ApiEvent::Left { user },
+            ServerMessage::Removed { by } => ApiEvent::Deleted { by },
+            ServerMessage::Retracted { user } => ApiEvent::Deleted { by: user },
+            ServerMessage::Info { text } => ApiEvent::Notice { text },
+            message => ApiEvent::Notice {
+                text: TextRenderer::text(message).trim_end().to_owned(),
+            },
+        }
+    }
+}
+
+// Counts a stream against its IP until it's dropped
+struct StreamSlot<'a> {
+    streams: &'a Mutex<HashMap<IpAddr, usize>>,
+    ip: IpAddr,
+}
+
+impl<'a> StreamSlot<'a> {
+    // None if the IP has as many as it's allowed
+    fn take(streams: &'a Mutex<HashMap<IpAddr, usize>>, ip: IpAddr) -> Option<Self> {
+        let mut counts = streams.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count >= MAX_STREAMS_PER_IP {
+            return None;
+        }
+        *count += 1;
+
+        Some(Self { streams, ip })
+    }
+}
+
+impl Drop for StreamSlot<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.streams.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+/// Serves the auxiliary HTTP endpoints until shutdown. Rooms and their
+/// history can be read under `/api` with the admin token or a `>session`
+/// token, a page at a time, and `/api/rooms/<name>/stream` follows a room as
+/// server-sent events, picking up after `Last-Event-ID` if it's given.
+pub async fn listen(
+    listener: TcpListener,
+    state: Arc<HttpState>,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = shutdown.recv() => return Ok(()),
+        };
+
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, addr, &state).await {
+                error!("{}", e)
+            };
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, addr: SocketAddr, state: &HttpState) -> io::Result<()> {
+    let mut request = match read_request(&mut stream).await? {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    let res = match (request.method.as_str(), request.path.as_str()) {
+        ("POST", path) if path.starts_with("/hooks/") => {
+            post_hook(&mut stream, state, &mut request).await?
+        }
+        ("GET", path) if room_path(path, "/stream").is_some() => {
+            match stream_room(&mut stream, addr.ip(), state, &request).await {
+                Some(res) => res,
+                None => return Ok(()),
+            }
+        }
+        ("GET", path) if path.starts_with("/api/") => api(state, &request).await,
+        ("GET", "/healthz") => health(state, false).await,
+        ("GET", "/readyz") => health(state, true).await,
+        ("GET", "/metrics") if state.prometheus => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: metrics().render(),
+        },
+        _ => Response::error("404 Not Found", "not found"),
+    };
+
+    stream.write_all(res.to_bytes().as_slice()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+// Reads until the end of the headers. Only the request line and
+// `Content-Length` matter, the body is left to the handler.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 1024];
+
+    let end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST {
+            return Ok(None);
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..end]);
+    let mut lines = head.lines();
+
+    // eg `GET /healthz HTTP/1.1`
+    let mut parts = lines.next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let headers: Vec<(&str, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+    let header = |wanted: &str| {
+        headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| *value)
+    };
+    let content_length = header("content-length")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0);
+    let authorization = header("authorization").map(str::to_owned);
+    let last_event_id = header("last-event-id").and_then(|value| value.parse().ok());
+
+    Ok(Some(Request {
+        method,
+        path,
+        content_length,
+        authorization,
+        last_event_id,
+        body: buf[end + 4..].to_vec(),
+    }))
+}
+
+// Posts `{"text": "..."}` into the webhook's room under its name
+async fn post_hook(
+    stream: &mut TcpStream,
+    state: &HttpState,
+    request: &mut Request,
+) -> io::Result<Response> {
+    let Some(server) = state.server.get() else {
+        return Ok(Response::error("503 Service Unavailable", "not ready"));
+    };
+
+    let token = request.path.trim_start_matches("/hooks/");
+    let hook = match webhook::find(&*server.store, token).await {
+        Ok(Some(hook)) => hook,
+        Ok(None) => return Ok(Response::error("404 Not Found", "not found")),
+        Err(e) => {
+            error!("{}", e.to_string().trim_end());
+            return Ok(Response::error("500 Internal Server Error", "storage"));
+        }
+    };
+
+    if request.content_length > MAX_HOOK_BODY {
+        return Ok(Response::error("413 Payload Too Large", "body too large"));
+    }
+
+    let throttled = state
+        .hook_throttles
+        .lock()
+        .unwrap()
+        .entry(hook.token.clone())
+        .or_default()
+        .check(HOOK_MESSAGES_PER_WINDOW, HOOK_WINDOW);
+    if throttled.is_err() {
+        return Ok(Response::error("429 Too Many Requests", "rate limited"));
+    }
+
+    while request.body.len() < request.content_length {
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        request.body.extend_from_slice(&chunk[..n]);
+    }
+    request.body.truncate(request.content_length);
+
+    let text = match serde_json::from_slice::<HookMessage>(&request.body) {
+        // Multiple lines would read as several messages
+        Ok(message) => message.text.lines().collect::<Vec<_>>().join(" "),
+        Err(_) => return Ok(Response::error("400 Bad Request", "invalid body")),
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(Response::error("400 Bad Request", "empty text"));
+    }
+
+    // Read each time, there's no connection to keep it up to date in
+    let ephemeral = match room::info(&*server.store, &hook.room).await {
+        Ok(info) => info.ephemeral,
+        Err(e) => {
+            error!("{}", e.to_string().trim_end());
+            return Ok(Response::error("500 Internal Server Error", "storage"));
+        }
+    };
+    let (msg, id) = room::record(
+        server,
+        RoomEvent::Chat(text.to_owned()),
+        &hook.room,
+        &hook.name,
+        ephemeral,
+    )
+    .await;
+
+    let tx = server.rooms.read().await.get(&hook.room).cloned();
+    if let Some(tx) = tx {
+        let event = BrokerEvent::Message {
+            conn: NO_CONN,
+            user: hook.name,
+            msg,
+            id: Some(id),
+        };
+        if tx.send(event).await.is_err() {
+            error!("Broker for {} has stopped", hook.room);
+        }
+    }
+
+    Ok(Response::json("200 OK", r#"{"status":"ok"}"#.into()))
+}
+
+// Read-only rooms and history, for anyone with an admin or `>session` token.
+// A session's history is only that of the room it's in.
+async fn api(state: &HttpState, request: &Request) -> Response {
+    let Some(server) = state.server.get() else {
+        return Response::error("503 Service Unavailable", "not ready");
+    };
+
+    let caller = match caller(state, server, request).await {
+        Ok(Some(caller)) => caller,
+        Ok(None) => return Response::error("401 Unauthorized", "unauthorized"),
+        Err(e) => return Response::storage_error(e),
+    };
+
+    let (path, query) = request
+        .path
+        .split_once('?')
+        .unwrap_or((request.path.as_str(), ""));
+    let res = match (path, room_path(path, "/messages")) {
+        ("/api/rooms", _) => api_rooms(server).await,
+        (_, Some(room)) => api_messages(server, &caller, &room, query).await,
+        _ => return Response::error("404 Not Found", "not found"),
+    };
+
+    res.unwrap_or_else(Response::storage_error)
+}
+
+// The room in `/api/rooms/<name><suffix>`, ignoring the query
+fn room_path(path: &str, suffix: &str) -> Option<String> {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+    path.strip_prefix("/api/rooms/")
+        .and_then(|rest| rest.strip_suffix(suffix))
+        .and_then(percent_decode)
+}
+
+// Follows a room as server-sent events, each `data` being a message as the
+// API gives them. `Last-Event-ID` replays what was missed since, as far back
+// as a page goes. None once it's been streamed, as there's nothing more to
+// send then.
+async fn stream_room(
+    stream: &mut TcpStream,
+    ip: IpAddr,
+    state: &HttpState,
+    request: &Request,
+) -> Option<Response> {
+    let Some(server) = state.server.get() else {
+        return Some(Response::error("503 Service Unavailable", "not ready"));
+    };
+
+    let caller = match caller(state, server, request).await {
+        Ok(Some(caller)) => caller,
+        Ok(None) => return Some(Response::error("401 Unauthorized", "unauthorized")),
+        Err(e) => return Some(Response::storage_error(e)),
+    };
+
+    let room = room_path(&request.path, "/stream")?;
+    let Some(tx) = server.rooms.read().await.get(&room).cloned() else {
+        return Some(Response::error("404 Not Found", "no such room"));
+    };
+    if !caller.can_read(&room) {
+        return Some(Response::error("403 Forbidden", "not in the room"));
+    }
+
+    let Some(_slot) = StreamSlot::take(&state.streams, ip) else {
+        return Some(Response::error("429 Too Many Requests", "too many streams"));
+    };
+
+    // Watched before anything's replayed, so nothing's missed in between
+    let config = server.config.load().broker;
+    let id = NEXT_VIEWER.fetch_add(1, Ordering::Relaxed);
+    let (viewer, messages) = mpsc::channel(config.member_queue);
+    if tx
+        .send(BrokerEvent::Watch { id, tx: viewer })
+        .await
+        .is_err()
+    {
+        return Some(Response::error("404 Not Found", "no such room"));
+    }
+
+    let write_timeout = Duration::from_secs(config.write_timeout_secs);
+    follow(
+        stream,
+        server,
+        &room,
+        request.last_event_id,
+        messages,
+        write_timeout,
+    )
+    .await;
+
+    // The broker lets go of it too once its sender's dropped, but not until
+    // the room's next message
+    let _ = tx.send(BrokerEvent::Unwatch { id }).await;
+
+    None
+}
+
+// Until the client goes, the room does or a write takes too long
+async fn follow(
+    stream: &mut TcpStream,
+    server: &ServerContext,
+    room: &str,
+    last_event_id: Option<i64>,
+    mut messages: Receiver<ServerMessage>,
+    write_timeout: Duration,
+) {
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if !write_within(stream, head.to_owned(), write_timeout).await {
+        return;
+    }
+
+    if let Some(after) = last_event_id {
+        let missed = match room::info(&*server.store, room).await {
+            Ok(info) => {
+                room::recent_before(&*server.store, room, info.ephemeral, None, MAX_API_PAGE).await
+            }
+            Err(e) => Err(e),
+        };
+        let missed = missed.unwrap_or_else(|e| {
+            error!("{}", e.to_string().trim_end());
+            vec![]
+        });
+
+        for (msg, score) in missed.into_iter().filter(|(_, score)| *score > after) {
+            let message = ServerEvent::parse(msg.strip_suffix('\n').unwrap_or(&msg)).into();
+            if !write_within(stream, sse_event(score, message), write_timeout).await {
+                return;
+            }
+        }
+    }
+
+    let mut buf = [0; 64];
+    loop {
+        let message = tokio::select! {
+            message = messages.recv() => match message {
+                Some(message) => message,
+                // The room's gone, or dropped the stream for falling behind
+                None => return,
+            },
+            // Nothing more's expected from the client, so this is usually it
+            // going
+            read = stream.read(&mut buf) => match read {
+                Ok(0) | Err(_) => return,
+                Ok(_) => continue,
+            },
+        };
+
+        // Live messages are given when they were relayed, as outgoing
+        // webhooks are, which is just after they were stored
+        let event = sse_event(room::get_time_in_ms(), message);
+        if !write_within(stream, event, write_timeout).await {
+            return;
+        }
+    }
+}
+
+fn sse_event(score: i64, message: ServerMessage) -> String {
+    let data = ApiMessage {
+        score,
+        event: message.into(),
+    };
+
+    format!(
+        "id: {}\ndata: {}\n\n",
+        score,
+        serde_json::to_string(&data).unwrap()
+    )
+}
+
+// Returns false if it failed or timed out
+async fn write_within(stream: &mut TcpStream, text: String, timeout: Duration) -> bool {
+    matches!(
+        time::timeout(timeout, stream.write_all(text.as_bytes())).await,
+        Ok(Ok(()))
+    )
+}
+
+// None if there's no token, or it isn't the admin one or a session's
+async fn caller(
+    state: &HttpState,
+    server: &ServerContext,
+    request: &Request,
+) -> Result<Option<Caller>, StoreError> {
+    let Some(token) = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return Ok(None);
+    };
+
+    if let Some(admin_token) = &state.admin_token {
+        if admin::constant_time_eq(token.as_bytes(), admin_token.as_bytes()) {
+            return Ok(Some(Caller::Admin));
+        }
+    }
+
+    Ok(session::find(&*server.store, token)
+        .await?
+        .map(Caller::Session))
+}
+
+// Every room by name, with how many people are in each
+async fn api_rooms(server: &ServerContext) -> Result<Response, StoreError> {
+    let mut names = server.store.list().await?;
+    names.sort();
+
+    let conns = server.registry.snapshot();
+    let rooms: Vec<ApiRoom> = names
+        .into_iter()
+        .map(|name| {
+            // People rather than connections, as `>join-room` counts them
+            let mut members: Vec<&str> = conns
+                .iter()
+                .filter(|conn| conn.room.as_deref() == Some(name.as_str()))
+                .filter_map(|conn| conn.username.as_deref())
+                .collect();
+            members.sort();
+            members.dedup();
+
+            ApiRoom {
+                members: members.len(),
+                name,
+            }
+        })
+        .collect();
+
+    Ok(Response::json(
+        "200 OK",
+        serde_json::to_string(&rooms).unwrap(),
+    ))
+}
+
+// `?limit=n&before=score`, the newest page first and oldest first within it
+async fn api_messages(
+    server: &ServerContext,
+    caller: &Caller,
+    room: &str,
+    query: &str,
+) -> Result<Response, StoreError> {
+    let mut limit = API_PAGE;
+    let mut before = None;
+    for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match (name, value.parse()) {
+            ("limit", Ok(n)) if n > 0 => limit = (n as usize).min(MAX_API_PAGE),
+            ("before", Ok(score)) => before = Some(score),
+            ("limit" | "before", _) => {
+                let error = format!("invalid {}", name);
+                return Ok(Response::error("400 Bad Request", &error));
+            }
+            _ => {}
+        }
+    }
+
+    if server.store.meta(room).await?.is_none() {
+        return Ok(Response::error("404 Not Found", "no such room"));
+    }
+    if !caller.can_read(room) {
+        return Ok(Response::error("403 Forbidden", "not in the room"));
+    }
+
+    let ephemeral = room::info(&*server.store, room).await?.ephemeral;
+    let msgs = room::recent_before(&*server.store, room, ephemeral, before, limit).await?;
+    let msgs: Vec<ApiMessage> = msgs
+        .into_iter()
+        .map(|(msg, score)| ApiMessage {
+            score,
+            event: ServerMessage::from(ServerEvent::parse(msg.strip_suffix('\n').unwrap_or(&msg)))
+                .into(),
+        })
+        .collect();
+
+    Ok(Response::json(
+        "200 OK",
+        serde_json::to_string(&msgs).unwrap(),
+    ))
+}
+
+// `%20` and the like in a path segment, None if it isn't UTF-8 after
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail) {
+            (b'%', [hi, lo, tail @ ..]) => {
+                let hex = std::str::from_utf8(&[*hi, *lo]).ok()?.to_owned();
+                bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+                rest = tail;
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+async fn health(state: &HttpState, readiness: bool) -> Response {
+    let mut failed = Vec::new();
+
+    if !state.health.accepting.load(Ordering::SeqCst) {
+        failed.push("listener");
+    }
+
+    if state.store.ping().await.is_err() {
+        failed.push("storage");
+    }
+
+    if readiness && !state.health.bootstrapped.load(Ordering::SeqCst) {
+        failed.push("bootstrap");
+    }
+
+    if failed.is_empty() {
+        return Response::json("200 OK", r#"{"status":"ok"}"#.into());
+    }
+
+    let failed = failed
+        .iter()
+        .map(|f| format!("\"{}\"", f))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    Response::json(
+        "503 Service Unavailable",
+        format!(r#"{{"status":"unavailable","failed":[{}]}}"#, failed),
+    )
+}
+
+impl Response {
+    fn json(status: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn error(status: &'static str, error: &str) -> Self {
+        Self::json(status, format!(r#"{{"error":"{}"}}"#, error))
+    }
+
+    fn storage_error(e: StoreError) -> Self {
+        error!("{}", e.to_string().trim_end());
+        Self::error("500 Internal Server Error", "storage")
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
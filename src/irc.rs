@@ -0,0 +1,608 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{error, info};
+
+use crate::access;
+use crate::app::App;
+use crate::client::ServerEvent;
+use crate::command::Command;
+use crate::keepalive::{Due, Keepalive};
+use crate::metrics;
+use crate::registry::ConnectionRegistry;
+use crate::render::{Renderer, ServerMessage, TextRenderer};
+use crate::room;
+use crate::server::ServerContext;
+use crate::shutdown::Shutdown;
+
+// Used as the server's name and every user's host
+const SERVER_NAME: &str = "chatsapp";
+
+/// Accepts IRC connections and runs each one as a regular `App`, translating
+/// enough of the protocol for clients like irssi and WeeChat: `NICK`, `USER`,
+/// `JOIN`, `PART`, `PRIVMSG` to a channel or nick, `NAMES`, `LIST`, `TOPIC`,
+/// `WHO`, `MODE`, `PING` and `QUIT`. Channels are rooms with a `#` in front,
+/// and only one can be joined at a time.
+pub async fn listen(
+    listener: TcpListener,
+    ctx: Arc<ServerContext>,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    let local_addr = format!("irc://{}", listener.local_addr()?);
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = shutdown.recv() => return Ok(()),
+        };
+
+        let ctx = Arc::clone(&ctx);
+        let local_addr = local_addr.clone();
+
+        tokio::spawn(async move {
+            let _connection = metrics::connection();
+
+            if let Err(e) = handle(stream, addr, &ctx, local_addr).await {
+                error!("{}", e)
+            };
+        });
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    ctx: &Arc<ServerContext>,
+    local_addr: String,
+) -> io::Result<()> {
+    if !ctx.permits(addr.ip()).await {
+        info!("Refused connection from {}, not allowed by access", addr);
+        let denied = format!("ERROR :{}\r\n", access::DENIED);
+        return stream.write_all(denied.as_bytes()).await;
+    }
+
+    if let Some(ban) = ctx.ban_for(addr.ip()).await {
+        let banned = format!("ERROR :{}\r\n", ban.notice().trim_end());
+        return stream.write_all(banned.as_bytes()).await;
+    }
+
+    if ctx.is_full() {
+        return stream
+            .write_all(b"ERROR :Server is full, try again later\r\n")
+            .await;
+    }
+
+    // Like WebSocket connections, the app speaks the line protocol on one end
+    // of the pipe while the bridge translates on the other
+    let (client, server) = io::duplex(64 * 1024);
+    tokio::spawn(bridge(stream, server, Irc::new(Arc::clone(ctx))));
+
+    let conn = ConnectionRegistry::register(&ctx.registry, addr.to_string(), local_addr);
+
+    App::new(client, addr, conn, Arc::clone(ctx))
+        .without_keepalive()
+        .run()
+        .await
+}
+
+// Sends a PING after `keepalive_secs` without writing anything, and closes the
+// connection if nothing at all comes back within `keepalive_grace_secs`
+async fn bridge(stream: TcpStream, server: DuplexStream, mut irc: Irc) {
+    let (reader, mut socket) = stream.into_split();
+    let mut messages = BufReader::new(reader).lines();
+    let (reader, mut app) = io::split(server);
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut keepalive = Keepalive::new();
+
+    loop {
+        let check = keepalive.next_check(&irc.ctx.config.load());
+
+        let out = tokio::select! {
+            message = messages.next_line() => match message {
+                Ok(Some(message)) => {
+                    keepalive.heard();
+                    let commands = irc.handle(&message).await;
+                    for command in commands {
+                        let line = format!("{}\n", irc.ctx.commands.format(&command));
+                        if app.write_all(line.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+
+                    irc.take_replies()
+                }
+                Ok(None) | Err(_) => break,
+            },
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => irc.render(ServerEvent::parse(&line).into()),
+                // The app has finished, eg after `QUIT`
+                _ => break,
+            },
+            _ = time::sleep_until(check.unwrap_or_else(Instant::now).into()), if check.is_some() => {
+                match keepalive.due(&irc.ctx.config.load()) {
+                    Some(Due::Probe) => {
+                        keepalive.probed();
+                        vec![format!("PING :{}", SERVER_NAME)]
+                    }
+                    // Peer never answered the last ping
+                    Some(Due::Dead) => break,
+                    None => Vec::new(),
+                }
+            }
+        };
+        if out.is_empty() {
+            continue;
+        }
+
+        let out: String = out.iter().map(|line| format!("{}\r\n", line)).collect();
+        if socket.write_all(out.as_bytes()).await.is_err() {
+            break;
+        }
+        keepalive.wrote();
+    }
+
+    // Dropping both halves of the pipe ends `App::run`, which leaves any room
+    let _ = socket.shutdown().await;
+}
+
+/// Splits an IRC message into its command, uppercased, and parameters, the
+/// last of which may contain spaces.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::irc;
+///
+/// assert_eq!(
+///     irc::parse(":bob PRIVMSG #rust :hi there"),
+///     Some(("PRIVMSG".to_owned(), vec!["#rust".to_owned(), "hi there".to_owned()]))
+/// );
+/// assert_eq!(irc::parse("nick bob"), Some(("NICK".to_owned(), vec!["bob".to_owned()])));
+/// assert_eq!(irc::parse(""), None);
+/// ```
+pub fn parse(message: &str) -> Option<(String, Vec<String>)> {
+    let mut message = message.trim_end_matches(['\r', '\n']);
+
+    // The client's own prefix is ignored
+    if message.starts_with(':') {
+        message = message.split_once(' ')?.1;
+    }
+
+    let (head, trailing) = match message.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (message, None),
+    };
+
+    let mut words = head.split_whitespace();
+    let command = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<String> = words.map(str::to_owned).collect();
+    params.extend(trailing.map(str::to_owned));
+
+    Some((command, params))
+}
+
+// One connection's side of the conversation, translating IRC messages into
+// commands and what the app writes back into IRC messages
+struct Irc {
+    ctx: Arc<ServerContext>,
+    nick: Option<String>,
+    // Sent `USER`, which along with `NICK` completes registration
+    user: bool,
+    registered: bool,
+    // The room, without the `#`
+    channel: Option<String>,
+    // From `PASS`, used to log in as the nick if it's registered
+    password: Option<String>,
+    // Sent straight back to the client, rather than through the app
+    replies: Vec<String>,
+}
+
+impl Irc {
+    fn new(ctx: Arc<ServerContext>) -> Self {
+        Self {
+            ctx,
+            nick: None,
+            user: false,
+            registered: false,
+            channel: None,
+            password: None,
+            replies: Vec::new(),
+        }
+    }
+
+    fn take_replies(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.replies)
+    }
+
+    fn nick(&self) -> &str {
+        self.nick.as_deref().unwrap_or("*")
+    }
+
+    fn numeric(&mut self, code: &str, params: &str) {
+        let reply = format!(":{} {} {} {}", SERVER_NAME, code, self.nick(), params);
+        self.replies.push(reply);
+    }
+
+    // As if sent by the user, eg `:bob!bob@chatsapp JOIN #rust`
+    fn from_user(user: &str, message: &str) -> String {
+        format!(":{}!{}@{} {}", user, user, SERVER_NAME, message)
+    }
+
+    // The commands to run, any replies are left in `replies`
+    async fn handle(&mut self, message: &str) -> Vec<Command> {
+        let Some((command, params)) = parse(message) else {
+            return Vec::new();
+        };
+        let param = |i: usize| params.get(i).map(String::as_str).unwrap_or_default();
+
+        match command.as_str() {
+            // No capabilities, but answering lets clients that ask carry on
+            "CAP" if param(0).eq_ignore_ascii_case("LS") => {
+                self.replies.push(format!(":{} CAP * LS :", SERVER_NAME));
+                return Vec::new();
+            }
+            "PASS" => {
+                self.password = Some(param(0).to_owned());
+                return Vec::new();
+            }
+            "CAP" => return Vec::new(),
+            // Like anything else, it answers the bridge's PING
+            "PONG" => return Vec::new(),
+            "PING" => {
+                let pong = format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, param(0));
+                self.replies.push(pong);
+                return Vec::new();
+            }
+            "QUIT" => {
+                self.replies.push("ERROR :Closing link".to_owned());
+                return vec![Command::Exit];
+            }
+            "NICK" => return self.handle_nick(param(0)),
+            "USER" => {
+                self.user = true;
+                self.register();
+                return Vec::new();
+            }
+            _ => {}
+        }
+
+        if !self.registered {
+            self.numeric("451", ":You have not registered");
+            return Vec::new();
+        }
+
+        match command.as_str() {
+            "JOIN" => self.handle_join(param(0)).await,
+            "PART" => self.handle_part(param(0)),
+            "PRIVMSG" | "NOTICE" => self.handle_privmsg(param(0), param(1)),
+            "NAMES" => {
+                let channel = param(0).split(',').next().unwrap_or_default().to_owned();
+                self.write_names(&channel);
+                Vec::new()
+            }
+            "LIST" => {
+                self.write_list().await;
+                Vec::new()
+            }
+            "TOPIC" => {
+                self.write_topic(param(0)).await;
+                Vec::new()
+            }
+            "WHO" => {
+                self.write_who(param(0));
+                Vec::new()
+            }
+            "MODE" if param(0).starts_with('#') => {
+                let mode = format!("{} +", param(0));
+                self.numeric("324", &mode);
+                Vec::new()
+            }
+            "MODE" => {
+                self.numeric("221", "+");
+                Vec::new()
+            }
+            _ => {
+                self.numeric("421", &format!("{} :Unknown command", command));
+                Vec::new()
+            }
+        }
+    }
+
+    fn handle_nick(&mut self, nick: &str) -> Vec<Command> {
+        if nick.is_empty() || nick.starts_with(['#', ':']) {
+            self.numeric("432", &format!("{} :Erroneous nickname", nick));
+            return Vec::new();
+        }
+
+        if self.registered {
+            let old = self.nick().to_owned();
+            self.replies
+                .push(Self::from_user(&old, &format!("NICK :{}", nick)));
+        }
+        self.nick = Some(nick.to_owned());
+        self.register();
+
+        match &self.password {
+            Some(password) => vec![
+                Command::SetUsername(nick.to_owned()),
+                Command::Login(password.clone()),
+            ],
+            None => vec![Command::SetUsername(nick.to_owned())],
+        }
+    }
+
+    // Once both `NICK` and `USER` have been sent
+    fn register(&mut self) {
+        if self.registered || !self.user || self.nick.is_none() {
+            return;
+        }
+        self.registered = true;
+
+        let nick = self.nick().to_owned();
+        self.numeric("001", &format!(":Welcome to ChatsApp, {}", nick));
+        self.numeric("002", &format!(":Your host is {}", SERVER_NAME));
+        self.numeric(
+            "004",
+            &format!("{} {} o o", SERVER_NAME, env!("CARGO_PKG_VERSION")),
+        );
+        self.numeric(
+            "005",
+            "CHANTYPES=# CHANLIMIT=#:1 PREFIX=() :are supported by this server",
+        );
+
+        match self.ctx.config.load().motd.clone() {
+            Some(motd) => {
+                self.numeric("375", &format!(":- {} Message of the day -", SERVER_NAME));
+                for line in motd.lines() {
+                    self.numeric("372", &format!(":- {}", line));
+                }
+                self.numeric("376", ":End of /MOTD command");
+            }
+            None => self.numeric("422", ":MOTD File is missing"),
+        }
+    }
+
+    async fn handle_join(&mut self, channels: &str) -> Vec<Command> {
+        // Only one room at a time, so only the first counts
+        let channel = channels.split(',').next().unwrap_or_default();
+
+        // `JOIN 0` leaves every channel
+        if channel == "0" {
+            return match self.channel.clone() {
+                Some(current) => self.handle_part(&format!("#{}", current)),
+                None => Vec::new(),
+            };
+        }
+
+        let Some(room) = channel.strip_prefix('#').filter(|room| !room.is_empty()) else {
+            self.numeric("403", &format!("{} :No such channel", channel));
+            return Vec::new();
+        };
+        if self.channel.as_deref() == Some(room) {
+            return Vec::new();
+        }
+        if !self.ctx.rooms.read().await.contains_key(room) {
+            self.numeric("403", &format!("{} :No such channel", channel));
+            return Vec::new();
+        }
+
+        // Joining another room leaves the current one
+        let nick = self.nick().to_owned();
+        if let Some(current) = self.channel.take() {
+            let part = format!("PART #{}", current);
+            self.replies.push(Self::from_user(&nick, &part));
+        }
+        let join = format!("JOIN {}", channel);
+        self.replies.push(Self::from_user(&nick, &join));
+        self.channel = Some(room.to_owned());
+
+        self.write_topic(channel).await;
+        self.write_names(channel);
+
+        vec![Command::JoinRoom(room.to_owned())]
+    }
+
+    fn handle_part(&mut self, channels: &str) -> Vec<Command> {
+        let channel = channels.split(',').next().unwrap_or_default();
+        if self.channel.as_deref() != channel.strip_prefix('#') {
+            self.numeric("442", &format!("{} :You're not on that channel", channel));
+            return Vec::new();
+        }
+
+        self.channel = None;
+        let part = format!("PART {}", channel);
+        self.replies.push(Self::from_user(self.nick(), &part));
+
+        vec![Command::Leave]
+    }
+
+    fn handle_privmsg(&mut self, target: &str, text: &str) -> Vec<Command> {
+        // CTCP, eg `\x01VERSION\x01`, isn't supported
+        if text.is_empty() || text.starts_with('\x01') {
+            return Vec::new();
+        }
+
+        let Some(room) = target.strip_prefix('#') else {
+            return vec![Command::Dm {
+                to: target.to_owned(),
+                text: text.to_owned(),
+            }];
+        };
+
+        if self.channel.as_deref() != Some(room) {
+            self.numeric("404", &format!("{} :Cannot send to channel", target));
+            return Vec::new();
+        }
+
+        vec![Command::Message(text.to_owned())]
+    }
+
+    fn write_names(&mut self, channel: &str) {
+        let room = channel.strip_prefix('#').unwrap_or(channel);
+
+        let mut names: Vec<String> = self
+            .ctx
+            .registry
+            .snapshot()
+            .into_iter()
+            .filter(|conn| conn.room.as_deref() == Some(room))
+            .filter_map(|conn| conn.username)
+            .collect();
+        // We may not be in the registry's copy yet when we've only just joined
+        if self.channel.as_deref() == Some(room) {
+            names.push(self.nick().to_owned());
+        }
+        names.sort();
+        names.dedup();
+
+        if !names.is_empty() {
+            self.numeric("353", &format!("= {} :{}", channel, names.join(" ")));
+        }
+        self.numeric("366", &format!("{} :End of /NAMES list", channel));
+    }
+
+    async fn write_list(&mut self) {
+        let rooms = match self.ctx.store.list().await {
+            Ok(rooms) => rooms,
+            Err(e) => {
+                self.notice(&e.to_string());
+                return;
+            }
+        };
+        let conns = self.ctx.registry.snapshot();
+
+        self.numeric("321", "Channel :Users  Name");
+        // Names with spaces can't be channels
+        for room in rooms.iter().filter(|room| !room.contains(' ')) {
+            let members = conns
+                .iter()
+                .filter(|conn| conn.room.as_deref() == Some(room.as_str()))
+                .count();
+            self.numeric("322", &format!("#{} {} :", room, members));
+        }
+        self.numeric("323", ":End of /LIST");
+    }
+
+    async fn write_topic(&mut self, channel: &str) {
+        let room = channel.strip_prefix('#').unwrap_or(channel);
+
+        match room::info(&*self.ctx.store, room).await {
+            Ok(info) => match info.topic {
+                Some(topic) => self.numeric("332", &format!("{} :{}", channel, topic)),
+                None => self.numeric("331", &format!("{} :No topic is set", channel)),
+            },
+            Err(e) => self.notice(&e.to_string()),
+        }
+    }
+
+    fn write_who(&mut self, channel: &str) {
+        let room = channel.strip_prefix('#').unwrap_or(channel);
+
+        for conn in self.ctx.registry.snapshot() {
+            let Some(user) = conn.username else {
+                continue;
+            };
+            if conn.room.as_deref() == Some(room) {
+                let who = format!(
+                    "{} {} {} {} {} H :0 {}",
+                    channel, user, SERVER_NAME, SERVER_NAME, user, user
+                );
+                self.numeric("352", &who);
+            }
+        }
+        self.numeric("315", &format!("{} :End of /WHO list", channel));
+    }
+
+    fn notice(&mut self, text: &str) {
+        let notice = format!(
+            ":{} NOTICE {} :{}",
+            SERVER_NAME,
+            self.nick(),
+            text.trim_end()
+        );
+        self.replies.push(notice);
+    }
+}
+
+impl Renderer for Irc {
+    fn render(&mut self, message: ServerMessage) -> Vec<String> {
+        // The greeting is replaced by the welcome numerics
+        if !self.registered {
+            return Vec::new();
+        }
+
+        let channel = self.channel.clone();
+        match (message, channel) {
+            (ServerMessage::Chat { user, text, .. }, Some(room)) => {
+                let privmsg = format!("PRIVMSG #{} :{}", room, text);
+                vec![Self::from_user(&user, &privmsg)]
+            }
+            (ServerMessage::Joined { user }, Some(room)) if user != self.nick() => {
+                vec![Self::from_user(&user, &format!("JOIN #{}", room))]
+            }
+            (ServerMessage::Left { user }, Some(room)) if user != self.nick() => {
+                vec![Self::from_user(&user, &format!("PART #{}", room))]
+            }
+            (ServerMessage::Joined { .. } | ServerMessage::Left { .. }, _) => Vec::new(),
+            // The app has taken us out of the room
+            (ServerMessage::Info { text: line }, Some(room))
+                if line == "You have been removed from the room by an admin"
+                    || line == "The room has been deleted by an admin" =>
+            {
+                self.channel = None;
+                let kick = format!(":{} KICK #{} {} :{}", SERVER_NAME, room, self.nick(), line);
+                vec![kick]
+            }
+            (ServerMessage::Info { text: line }, _) => {
+                // Direct messages, eg `[dm] alice: hi`. Our own are echoed by
+                // the client already.
+                if let Some((user, text)) = line
+                    .strip_prefix("[dm] ")
+                    .and_then(|dm| dm.split_once(": "))
+                {
+                    let privmsg = format!("PRIVMSG {} :{}", self.nick(), text);
+                    return vec![Self::from_user(user, &privmsg)];
+                }
+                // Nick changes are echoed as `NICK` already, and joins and
+                // parts as `JOIN` and `PART`
+                if line.is_empty()
+                    || line.starts_with("[dm to ")
+                    || line.starts_with("Username set to '")
+                    || line.starts_with("Username changed from '")
+                    || line.starts_with("Joined '")
+                    || line.starts_with("You left '")
+                {
+                    return Vec::new();
+                }
+
+                // Someone else in the channel picking a new name
+                if let Some((old, new)) = line.split_once(" is now known as ") {
+                    let nick = format!("NICK :{}", new);
+                    return vec![Self::from_user(old, &nick)];
+                }
+
+                // Not logging in as a registered nick in time
+                if let Some((_, guest)) = line.split_once(", you're now ") {
+                    let nick = format!("NICK :{}", guest);
+                    self.replies.push(Self::from_user(self.nick(), &nick));
+                    self.nick = Some(guest.to_owned());
+                }
+
+                self.notice(&line);
+                self.take_replies()
+            }
+            // Anything else as it reads in the line protocol
+            (message, _) => {
+                for line in TextRenderer.render(message) {
+                    self.notice(&line);
+                }
+                self.take_replies()
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{self, AsyncWrite};
+
+use crate::config::RuntimeConfig;
+
+/// What the line protocol is sent after `keepalive_secs` without anything
+/// else being written. Any line back keeps the connection, and `Client`
+/// answers with a blank one, which the server otherwise ignores. WebSocket
+/// and IRC connections are pinged the way their protocol does it instead.
+pub const PROBE: &str = "# keepalive";
+
+// Where a connection's keepalive is at
+#[derive(Debug, PartialEq)]
+pub enum Due {
+    // Quiet for long enough that it's time to check on the peer
+    Probe,
+    // Checked on and never answered
+    Dead,
+}
+
+// When a connection last wrote or was answered, see `keepalive_secs`
+pub struct Keepalive {
+    // Shared with whatever writes to the connection, eg its room
+    last_write: Arc<Mutex<Instant>>,
+    // When the unanswered probe was sent
+    probed: Option<Instant>,
+}
+
+impl Keepalive {
+    pub fn new() -> Self {
+        Self {
+            last_write: Arc::new(Mutex::new(Instant::now())),
+            probed: None,
+        }
+    }
+
+    // So every write through it counts as traffic
+    pub fn writer<W>(&self, inner: W) -> Stamped<W> {
+        Stamped {
+            inner,
+            last_write: Arc::clone(&self.last_write),
+        }
+    }
+
+    // For writes that don't go through `writer`
+    pub fn wrote(&self) {
+        *self.last_write.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    pub fn probed(&mut self) {
+        self.probed = Some(Instant::now());
+    }
+
+    // Anything at all from the peer
+    pub fn heard(&mut self) {
+        self.probed = None;
+    }
+
+    // When to next check on the peer, None while it's off
+    pub fn next_check(&self, config: &RuntimeConfig) -> Option<Instant> {
+        if config.keepalive_secs == 0 {
+            return None;
+        }
+
+        Some(match self.probed {
+            Some(probed) => probed + Duration::from_secs(config.keepalive_grace_secs),
+            None => {
+                let last_write = *self.last_write.lock().unwrap_or_else(|e| e.into_inner());
+                last_write + Duration::from_secs(config.keepalive_secs)
+            }
+        })
+    }
+
+    // Something written since `next_check` pushes it back
+    pub fn due(&self, config: &RuntimeConfig) -> Option<Due> {
+        match self.next_check(config) {
+            Some(at) if at <= Instant::now() && self.probed.is_some() => Some(Due::Dead),
+            Some(at) if at <= Instant::now() => Some(Due::Probe),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A writer that notes when it was last written to
+pub struct Stamped<W> {
+    inner: W,
+    last_write: Arc<Mutex<Instant>>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Stamped<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = written {
+            if n > 0 {
+                *self.last_write.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+            }
+        }
+
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -1,4 +1,50 @@
+pub mod access;
+pub mod account;
+pub mod admin;
 pub mod app;
+pub mod audit;
+pub mod backup;
+pub mod ban;
 pub mod broker;
+pub mod caps;
+pub mod chatlog;
+pub mod client;
 pub mod command;
+pub mod compact;
+pub mod config;
+pub mod dm;
+pub mod errors;
+pub mod filter;
+pub mod forward;
+pub mod hash;
+pub mod http;
+pub mod irc;
+pub mod keepalive;
+pub mod mention;
+pub mod metrics;
+pub mod page;
+pub mod pending;
+pub mod proxy;
+pub mod quote;
+pub mod reactions;
+pub mod registry;
+pub mod render;
+pub mod report;
+pub mod roles;
 pub mod room;
+pub mod server;
+pub mod session;
+pub mod shutdown;
+pub mod spam;
+#[cfg(feature = "sqlite-backend")]
+pub mod sqlite;
+pub mod statsd;
+pub mod store;
+pub mod systemd;
+pub mod telemetry;
+pub mod telnet;
+pub mod themes;
+pub mod throttle;
+pub mod username;
+pub mod webhook;
+pub mod ws;
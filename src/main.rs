@@ -1,32 +1,312 @@
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use chatsapp::{app::App, broker};
+use arc_swap::ArcSwap;
+use chatsapp::{
+    admin::{self, AdminContext},
+    backup::{self, Task},
+    broker::{self, RoomMap},
+    chatlog::ChatLog,
+    command::CommandParser,
+    compact,
+    config::{self, AppConfig, BrokerConfig, Exporter, StorageKind},
+    http::{self, Health, HttpState},
+    irc, pending,
+    registry::ConnectionRegistry,
+    roles,
+    server::{self, ServerContext},
+    shutdown, statsd,
+    store::{MemoryStore, RedisStore, RoomStore, TimedStore},
+    systemd, telemetry, ws,
+};
 use redis::Client as RedisClient;
-use tokio::{io, net::TcpListener};
+use tokio::{
+    io, signal,
+    task::JoinSet,
+    time::{self, Instant},
+};
+use tracing::{error, info, warn};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:8000").await?;
+    // Uptime counts from here, not from once Redis is reachable
+    let (started, started_at) = (std::time::Instant::now(), SystemTime::now());
+    let _telemetry = telemetry::init();
 
-    let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
-    let redis = Arc::new(redis);
-
-    let rooms = match broker::bootstrap_rooms(&redis).await {
-        Ok(r) => r,
+    // `backup` and `restore` use the same flags to find storage
+    let (task, args) = match Task::from_args(std::env::args().skip(1).collect()) {
+        Ok(parsed) => parsed,
+        Err(e) => panic!("{}", e),
+    };
+    let config = match AppConfig::from_args(args) {
+        Ok(c) => c,
         Err(e) => panic!("{}", e),
     };
 
-    loop {
-        let redis = Arc::clone(&redis);
-        let rooms = Arc::clone(&rooms);
+    // Named in logs, with the address only since the URL may hold a password
+    let (store, store_name): (Arc<dyn RoomStore>, String) = match config.storage {
+        StorageKind::Redis => match RedisClient::open(config.redis_url.as_str()) {
+            Ok(r) => {
+                let name = format!("Redis at {}", r.get_connection_info().addr);
+                let slow = match config.metrics.slow_redis_ms {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms)),
+                };
+                (Arc::new(TimedStore::new(RedisStore::new(r), slow)), name)
+            }
+            Err(e) => {
+                error!("Invalid Redis URL: {}", e);
+                std::process::exit(1);
+            }
+        },
+        StorageKind::Memory => {
+            warn!("Using in-memory storage, rooms won't survive a restart");
+            (
+                Arc::new(MemoryStore::default()),
+                "memory storage".to_owned(),
+            )
+        }
+        #[cfg(feature = "sqlite-backend")]
+        StorageKind::Sqlite => match chatsapp::sqlite::SqliteStore::open(&config.db_path) {
+            Ok(db) => {
+                let name = format!("SQLite at {}", config.db_path.display());
+                (Arc::new(db), name)
+            }
+            Err(e) => {
+                error!(
+                    "Failed to open {}: {}",
+                    config.db_path.display(),
+                    e.to_string().trim_end()
+                );
+                std::process::exit(1);
+            }
+        },
+        #[cfg(not(feature = "sqlite-backend"))]
+        StorageKind::Sqlite => {
+            error!("SQLite storage needs building with --features sqlite-backend");
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(task) = task {
+        match backup::run(task, &*store).await {
+            Ok(summary) => println!("{}", summary),
+            Err(e) => {
+                error!("{}", e.to_string().trim_end());
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // Prefer listeners handed over by systemd socket activation
+    let listeners = match systemd::listeners(&config.binds).await {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let (trigger, mut shutdown) = shutdown::channel();
+    let trigger = Arc::new(trigger);
+    let health = Arc::new(Health::default());
+
+    if config.metrics.enabled(Exporter::Statsd) {
+        statsd::start(&config.metrics.statsd, shutdown.clone()).await?;
+    }
+
+    let mut http_state = None;
+
+    // Started before bootstrapping so probes can see the server isn't ready yet
+    if let Some(http_bind) = config.http_bind {
+        let http_listener = server::bind(http_bind).await?;
+        let mut state = HttpState::new(Arc::clone(&store), Arc::clone(&health));
+        state.admin_token = config.admin_token.clone();
+        state.prometheus = config.metrics.enabled(Exporter::Prometheus);
+        let state = Arc::new(state);
+        http_state = Some(Arc::clone(&state));
+
+        tokio::spawn(http::listen(http_listener, state, shutdown.clone()));
+    }
+
+    let rooms = bootstrap(
+        &store,
+        &store_name,
+        &config.runtime.broker,
+        config.redis_timeout,
+    )
+    .await;
+    if let Err(e) = roles::seed(&*store, &config.admins).await {
+        error!("Failed to seed admins: {}", e.to_string().trim_end());
+    }
+    health.set_bootstrapped();
+    systemd::notify_ready();
+    systemd::spawn_watchdog(shutdown.clone());
+
+    let chat_log = match (config.chat_log.enabled, &config.chat_log.dir) {
+        (true, Some(dir)) => {
+            info!("Logging chat to {}", dir.display());
+            ChatLog::start(dir.clone(), config.chat_log.dms)?
+        }
+        _ => ChatLog::default(),
+    };
+
+    let ctx = Arc::new(ServerContext {
+        store,
+        rooms,
+        registry: Arc::new(ConnectionRegistry::default()),
+        proxy_protocol: config.proxy_protocol,
+        config: Arc::new(ArcSwap::from_pointee(config.runtime)),
+        shutdown: Arc::clone(&trigger),
+        bans: Default::default(),
+        access: Default::default(),
+        filter: Default::default(),
+        commands: CommandParser::new(config.command_prefix),
+        mutes: Default::default(),
+        pending: Default::default(),
+        chat_log,
+        started,
+        started_at,
+    });
+    tokio::spawn(pending::retry(Arc::clone(&ctx), shutdown.clone()));
+    tokio::spawn(compact::periodic(Arc::clone(&ctx), shutdown.clone()));
+
+    if let Err(e) = ctx.reload_filter().await {
+        error!("{}, the word filter is empty", e.to_string().trim_end());
+    }
+
+    // Webhooks need the room brokers, so are only accepted from here on
+    if let Some(state) = http_state {
+        let _ = state.server.set(Arc::clone(&ctx));
+    }
+
+    // Re-read the config file on SIGHUP
+    #[cfg(unix)]
+    if let Some(path) = config.config_path.clone() {
+        use signal::unix::{signal, SignalKind};
+
+        let mut hangups = signal(SignalKind::hangup())?;
+        let ctx = Arc::clone(&ctx);
 
-        let (stream, addr) = listener.accept().await?;
         tokio::spawn(async move {
-            let app = App::new(stream, addr, redis);
+            while hangups.recv().await.is_some() {
+                match config::reload(&path, &ctx.config) {
+                    Ok(()) => info!("Reloaded config from {}", path.display()),
+                    Err(e) => {
+                        error!("{}, keeping the current config", e);
+                        continue;
+                    }
+                }
 
-            if let Err(e) = app.run(rooms).await {
-                eprintln!("{}", e)
-            };
+                if let Err(e) = ctx.reload_filter().await {
+                    error!("{}, keeping the old word list", e.to_string().trim_end());
+                }
+            }
         });
     }
+
+    let mut accept_loops = JoinSet::new();
+
+    if let Some(ws_bind) = config.ws_bind {
+        let ws_listener = server::bind(ws_bind).await?;
+        accept_loops.spawn(ws::listen(ws_listener, Arc::clone(&ctx), shutdown.clone()));
+    }
+
+    if let Some(irc_bind) = config.irc_bind {
+        let irc_listener = server::bind(irc_bind).await?;
+        accept_loops.spawn(irc::listen(
+            irc_listener,
+            Arc::clone(&ctx),
+            shutdown.clone(),
+        ));
+    }
+
+    for listener in listeners {
+        accept_loops.spawn(server::listen(listener, Arc::clone(&ctx), shutdown.clone()));
+    }
+
+    if let Some(admin_bind) = config.admin_bind {
+        let admin_listener = server::bind(admin_bind).await?;
+        let admin = AdminContext {
+            server: Arc::clone(&ctx),
+            token: config.admin_token,
+            config_path: config.config_path,
+        };
+
+        accept_loops.spawn(admin::listen(
+            admin_listener,
+            Arc::new(admin),
+            shutdown.clone(),
+        ));
+    }
+
+    tokio::spawn(async move {
+        if signal::ctrl_c().await.is_ok() {
+            trigger.trigger();
+        }
+    });
+
+    health.set_accepting(true);
+
+    // Runs until shutdown, or until any accept loop fails
+    tokio::select! {
+        _ = shutdown.recv() => {}
+        Some(res) = accept_loops.join_next() => {
+            if let Ok(Err(e)) = res {
+                error!("{}", e);
+            }
+        }
+    }
+
+    health.set_accepting(false);
+    systemd::notify_stopping();
+
+    // Stops the other accept loops too if one of them failed
+    ctx.shutdown.trigger();
+    ctx.drain().await;
+
+    Ok(())
+}
+
+// Redis may well still be starting (eg under docker-compose), so keep trying
+// with exponential backoff until `timeout` runs out.
+async fn bootstrap(
+    store: &Arc<dyn RoomStore>,
+    name: &str,
+    broker: &BrokerConfig,
+    timeout: Duration,
+) -> RoomMap {
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_millis(250);
+    let mut attempt = 1;
+
+    loop {
+        let e = match broker::bootstrap_rooms(store, broker).await {
+            Ok(rooms) => return rooms,
+            Err(e) => e,
+        };
+
+        if Instant::now() + backoff > deadline {
+            error!(
+                "Giving up on {} after {} attempts: {}",
+                name,
+                attempt,
+                e.to_string().trim_end()
+            );
+            std::process::exit(1);
+        }
+
+        warn!(
+            "{} isn't available (attempt {}), retrying in {:?}: {}",
+            name,
+            attempt,
+            backoff,
+            e.to_string().trim_end()
+        );
+
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(5));
+        attempt += 1;
+    }
 }
@@ -0,0 +1,72 @@
+use crate::store::{RoomStore, StoreError};
+
+// Older mentions are dropped as new ones arrive
+pub const MAX_UNREAD: usize = 100;
+
+/// Everyone `@name`d in a chat message, in order and without repeats.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::mention;
+///
+/// let names = mention::parse("@bob, have you seen @alice? @bob!");
+/// assert_eq!(names, ["bob", "alice"]);
+/// assert!(mention::parse("alice@example.com").is_empty());
+/// ```
+pub fn parse(text: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+
+    for word in text.split_whitespace() {
+        let Some(name) = word.strip_prefix('@') else {
+            continue;
+        };
+
+        // So `@bob,` and `@bob?` still count
+        let name = name.trim_end_matches(|c: char| c.is_ascii_punctuation());
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    names
+}
+
+/// Adds the line to the user's inbox, for when they weren't around to see it.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::mention::{self, MAX_UNREAD};
+/// use chatsapp::store::MemoryStore;
+///
+/// let store = MemoryStore::default();
+/// for i in 0..MAX_UNREAD + 5 {
+///     mention::deliver(&store, "bob", &format!("[rust] alice: @bob {}\n", i)).await.unwrap();
+/// }
+///
+/// assert_eq!(mention::unread(&store, "bob").await.unwrap(), MAX_UNREAD);
+/// let inbox = mention::take(&store, "bob").await.unwrap();
+/// assert_eq!(inbox[0], "[rust] alice: @bob 5\n");
+/// assert_eq!(mention::unread(&store, "bob").await.unwrap(), 0);
+/// # }
+/// ```
+pub async fn deliver(store: &dyn RoomStore, user: &str, line: &str) -> Result<(), StoreError> {
+    store.list_push(&key(user), line, MAX_UNREAD).await
+}
+
+/// Mentions are only kept for users who aren't in the room at the time.
+pub async fn unread(store: &dyn RoomStore, user: &str) -> Result<usize, StoreError> {
+    store.list_len(&key(user)).await
+}
+
+// Empties the inbox, returning what was in it oldest first
+pub async fn take(store: &dyn RoomStore, user: &str) -> Result<Vec<String>, StoreError> {
+    store.list_take(&key(user)).await
+}
+
+fn key(user: &str) -> String {
+    format!("mentions:{}", user)
+}
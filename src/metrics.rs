@@ -0,0 +1,518 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use tracing::error;
+
+use crate::statsd::Sink;
+
+// Only the busiest rooms get a member gauge so label cardinality stays bounded
+const TOP_ROOMS: usize = 20;
+
+// Minutes of Redis timings kept for the percentiles in `>stats`
+pub const WINDOW_MINUTES: u64 = 5;
+
+// Buckets each time the latency doubles, so percentiles are within about 19%
+const SUB_BUCKETS: f64 = 4.0;
+
+// Enough for over an hour in µs
+const BUCKETS: usize = 128;
+
+pub struct Metrics {
+    registry: Registry,
+    pub connected_clients: IntGauge,
+    pub rooms: IntGauge,
+    pub messages_relayed: IntCounter,
+    pub joins: IntCounter,
+    pub webhooks_dropped: IntCounter,
+    pub pending_dropped: IntCounter,
+    pub chat_log_dropped: IntCounter,
+    pub statsd_dropped: IntCounter,
+    pub compacted: IntCounter,
+    pub last_compacted: IntGauge,
+    pub redis_latency: HistogramVec,
+    pub command_latency: HistogramVec,
+    pub commands: IntCounterVec,
+    pub queue_full: IntCounterVec,
+    room_members: IntGaugeVec,
+    room_queue_full: IntGaugeVec,
+    user_queue_full: IntGaugeVec,
+    // <Room, Members>, kept up to date by the brokers
+    members: Mutex<HashMap<String, i64>>,
+    // Sends that found a queue full, by room and by user
+    full: Mutex<FullQueues>,
+    // Set by `statsd::start`, timings are sent to it as they're taken
+    statsd: OnceLock<Sink>,
+    // Redis timings by op, for `>stats`
+    redis_window: Mutex<LatencyWindow>,
+    created: Instant,
+}
+
+#[derive(Default)]
+struct FullQueues {
+    rooms: HashMap<String, i64>,
+    users: HashMap<String, i64>,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+// Decrements the connected clients gauge when the connection ends
+pub struct ConnectionGuard(());
+
+pub fn connection() -> ConnectionGuard {
+    metrics().connected_clients.inc();
+
+    ConnectionGuard(())
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        metrics().connected_clients.dec();
+    }
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let connected_clients =
+            IntGauge::new("chatsapp_connected_clients", "Currently connected clients").unwrap();
+        let rooms = IntGauge::new("chatsapp_rooms", "Rooms with a running broker").unwrap();
+        let messages_relayed = IntCounter::new(
+            "chatsapp_messages_relayed_total",
+            "Messages delivered to room members",
+        )
+        .unwrap();
+        let joins = IntCounter::new(
+            "chatsapp_joins_total",
+            "Rooms joined, not counting resumed sessions",
+        )
+        .unwrap();
+        let webhooks_dropped = IntCounter::new(
+            "chatsapp_webhooks_dropped_total",
+            "Outgoing webhook deliveries given up on",
+        )
+        .unwrap();
+        let pending_dropped = IntCounter::new(
+            "chatsapp_pending_writes_dropped_total",
+            "Messages held while storage was down that were dropped unsaved",
+        )
+        .unwrap();
+        let chat_log_dropped = IntCounter::new(
+            "chatsapp_chat_log_dropped_total",
+            "Lines left out of the chat log because it fell behind",
+        )
+        .unwrap();
+        let statsd_dropped = IntCounter::new(
+            "chatsapp_statsd_dropped_total",
+            "StatsD lines dropped because the exporter fell behind",
+        )
+        .unwrap();
+        let compacted = IntCounter::new(
+            "chatsapp_compacted_total",
+            "Messages removed from room histories by compaction",
+        )
+        .unwrap();
+        let last_compacted = IntGauge::new(
+            "chatsapp_compacted_last_run",
+            "Messages removed by the latest compaction run",
+        )
+        .unwrap();
+        let redis_latency = HistogramVec::new(
+            HistogramOpts::new("chatsapp_redis_op_seconds", "Latency of Redis operations"),
+            &["op"],
+        )
+        .unwrap();
+        let command_latency = HistogramVec::new(
+            HistogramOpts::new("chatsapp_command_seconds", "Time taken to handle commands"),
+            &["command"],
+        )
+        .unwrap();
+        let commands = IntCounterVec::new(
+            Opts::new("chatsapp_commands_total", "Commands handled by type"),
+            &["command"],
+        )
+        .unwrap();
+        let queue_full = IntCounterVec::new(
+            Opts::new(
+                "chatsapp_queue_full_total",
+                "Sends that found a room's or member's queue full",
+            ),
+            &["queue"],
+        )
+        .unwrap();
+        let room_members = IntGaugeVec::new(
+            Opts::new("chatsapp_room_members", "Members of the busiest rooms"),
+            &["room"],
+        )
+        .unwrap();
+        let room_queue_full = IntGaugeVec::new(
+            Opts::new(
+                "chatsapp_room_queue_full",
+                "Full queues in the rooms that hit them most",
+            ),
+            &["room"],
+        )
+        .unwrap();
+        let user_queue_full = IntGaugeVec::new(
+            Opts::new(
+                "chatsapp_user_queue_full",
+                "Full queues for the users that hit them most",
+            ),
+            &["user"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(connected_clients.clone()))
+            .unwrap();
+        registry.register(Box::new(rooms.clone())).unwrap();
+        registry
+            .register(Box::new(messages_relayed.clone()))
+            .unwrap();
+        registry.register(Box::new(joins.clone())).unwrap();
+        registry
+            .register(Box::new(webhooks_dropped.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pending_dropped.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(chat_log_dropped.clone()))
+            .unwrap();
+        registry.register(Box::new(statsd_dropped.clone())).unwrap();
+        registry.register(Box::new(compacted.clone())).unwrap();
+        registry.register(Box::new(last_compacted.clone())).unwrap();
+        registry.register(Box::new(redis_latency.clone())).unwrap();
+        registry
+            .register(Box::new(command_latency.clone()))
+            .unwrap();
+        registry.register(Box::new(commands.clone())).unwrap();
+        registry.register(Box::new(queue_full.clone())).unwrap();
+        registry.register(Box::new(room_members.clone())).unwrap();
+        registry
+            .register(Box::new(room_queue_full.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(user_queue_full.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            connected_clients,
+            rooms,
+            messages_relayed,
+            joins,
+            webhooks_dropped,
+            pending_dropped,
+            chat_log_dropped,
+            statsd_dropped,
+            compacted,
+            last_compacted,
+            redis_latency,
+            command_latency,
+            commands,
+            queue_full,
+            room_members,
+            room_queue_full,
+            user_queue_full,
+            members: Mutex::new(HashMap::new()),
+            full: Mutex::new(FullQueues::default()),
+            statsd: OnceLock::new(),
+            redis_window: Mutex::new(LatencyWindow::default()),
+            created: Instant::now(),
+        }
+    }
+
+    // `queue` is "room" or "member", `user` the sender or the member
+    pub fn record_queue_full(&self, queue: &str, room: &str, user: &str) {
+        self.queue_full.with_label_values(&[queue]).inc();
+
+        let mut full = self.full.lock().unwrap();
+        *full.rooms.entry(room.to_owned()).or_default() += 1;
+        *full.users.entry(user.to_owned()).or_default() += 1;
+    }
+
+    // <Room, Full queues>, the most first
+    pub fn full_rooms(&self, n: usize) -> Vec<(String, i64)> {
+        top(&self.full.lock().unwrap().rooms, n)
+    }
+
+    // <User, Full queues>, the most first
+    pub fn full_users(&self, n: usize) -> Vec<(String, i64)> {
+        top(&self.full.lock().unwrap().users, n)
+    }
+
+    pub fn set_room_members(&self, room: &str, members: usize) {
+        let mut map = self.members.lock().unwrap();
+        map.insert(room.to_owned(), members as i64);
+    }
+
+    pub fn observe_redis(&self, op: &str, start: Instant) {
+        let elapsed = start.elapsed();
+        self.redis_latency
+            .with_label_values(&[op])
+            .observe(elapsed.as_secs_f64());
+        self.redis_window
+            .lock()
+            .unwrap()
+            .record(op, elapsed, self.created.elapsed());
+
+        if let Some(sink) = self.statsd.get() {
+            sink.timing("chatsapp_redis_op_ms", ("op", op), elapsed);
+        }
+    }
+
+    // Redis ops over the last `WINDOW_MINUTES`, by name
+    pub fn redis_summary(&self) -> Vec<OpLatency> {
+        self.redis_window
+            .lock()
+            .unwrap()
+            .summary(self.created.elapsed())
+    }
+
+    pub fn observe_command(&self, command: &str, start: Instant) {
+        let elapsed = start.elapsed();
+        self.command_latency
+            .with_label_values(&[command])
+            .observe(elapsed.as_secs_f64());
+
+        if let Some(sink) = self.statsd.get() {
+            sink.timing("chatsapp_command_ms", ("command", command), elapsed);
+        }
+    }
+
+    // Only the first one is kept
+    pub fn set_statsd(&self, sink: Sink) {
+        let _ = self.statsd.set(sink);
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// use std::sync::Arc;
+    ///
+    /// use chatsapp::app::App;
+    /// use chatsapp::metrics::metrics;
+    /// use chatsapp::registry::ConnectionRegistry;
+    /// use chatsapp::server::ServerContext;
+    /// use chatsapp::shutdown;
+    /// use chatsapp::store::MemoryStore;
+    /// use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    /// use tokio::net::{TcpListener, TcpStream};
+    ///
+    /// let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    /// let addr = listener.local_addr().unwrap();
+    /// tokio::spawn(async move {
+    ///     let (stream, addr) = listener.accept().await.unwrap();
+    ///     let (trigger, _) = shutdown::channel();
+    ///     let ctx = Arc::new(ServerContext::new(Arc::new(MemoryStore::default()), trigger));
+    ///     let conn = ConnectionRegistry::register(&ctx.registry, addr.to_string(), "test".into());
+    ///     App::new(stream, addr, conn, ctx).run().await
+    /// });
+    ///
+    /// let mut stream = TcpStream::connect(addr).await.unwrap();
+    /// stream.write_all(b">help\n>help\n>exit\n").await.unwrap();
+    /// let mut lines = BufReader::new(stream).lines();
+    /// while lines.next_line().await.unwrap().is_some() {}
+    ///
+    /// let scrape = metrics().render();
+    /// assert!(scrape.contains(r#"chatsapp_commands_total{command="help"} 2"#));
+    /// assert!(scrape.contains(r#"chatsapp_commands_total{command="exit"} 1"#));
+    /// # }
+    /// ```
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        let encoder = TextEncoder::new();
+
+        if let Err(e) = encoder.encode(&self.gather(), &mut buf) {
+            error!("{}", e);
+        }
+
+        String::from_utf8(buf).unwrap_or_default()
+    }
+
+    // Every metric as it stands, for exporters
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.update_top_rooms();
+
+        self.registry.gather()
+    }
+
+    fn update_top_rooms(&self) {
+        let rooms = top(&self.members.lock().unwrap(), TOP_ROOMS);
+
+        self.room_members.reset();
+        for (room, members) in rooms {
+            self.room_members.with_label_values(&[&room]).set(members);
+        }
+
+        self.room_queue_full.reset();
+        for (room, full) in self.full_rooms(TOP_ROOMS) {
+            self.room_queue_full.with_label_values(&[&room]).set(full);
+        }
+
+        self.user_queue_full.reset();
+        for (user, full) in self.full_users(TOP_ROOMS) {
+            self.user_queue_full.with_label_values(&[&user]).set(full);
+        }
+    }
+}
+
+// The `n` largest, ties broken by name
+fn top(counts: &HashMap<String, i64>, n: usize) -> Vec<(String, i64)> {
+    let mut counts: Vec<(String, i64)> = counts
+        .iter()
+        .map(|(name, count)| (name.clone(), *count))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(n);
+
+    counts
+}
+
+/// Latencies by op over the last `WINDOW_MINUTES`, without keeping every
+/// sample. As in hdrhistogram, each is counted in a bucket a fraction wider
+/// than the one before, and a percentile is the top of the bucket it falls
+/// in. `now` is how long the process has been up.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use chatsapp::metrics::LatencyWindow;
+///
+/// let mut window = LatencyWindow::default();
+/// let minute = Duration::from_secs(60);
+/// for ms in 1..=100 {
+///     window.record("zadd", Duration::from_millis(ms), minute);
+/// }
+/// window.record("hget", Duration::from_millis(2), 3 * minute);
+///
+/// let summary = window.summary(3 * minute);
+/// assert_eq!(summary[0].op, "hget");
+/// let zadd = &summary[1];
+/// assert_eq!(zadd.count, 100);
+/// assert!(zadd.p50 >= Duration::from_millis(50) && zadd.p50 < Duration::from_millis(60));
+/// assert!(zadd.p99 >= Duration::from_millis(99) && zadd.p99 < Duration::from_millis(120));
+///
+/// // Gone once they're 5 minutes old
+/// let summary = window.summary(6 * minute);
+/// assert_eq!(summary.len(), 1);
+/// assert_eq!(summary[0].op, "hget");
+/// ```
+#[derive(Default)]
+pub struct LatencyWindow {
+    // One a minute, reused once it's out of the window
+    slots: Vec<Slot>,
+}
+
+struct Slot {
+    minute: u64,
+    // <Op, Count in each bucket>
+    ops: HashMap<String, Vec<u64>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OpLatency {
+    pub op: String,
+    pub count: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+impl LatencyWindow {
+    pub fn record(&mut self, op: &str, elapsed: Duration, now: Duration) {
+        let minute = now.as_secs() / 60;
+
+        if self.slots.is_empty() {
+            self.slots = (0..WINDOW_MINUTES)
+                .map(|_| Slot {
+                    minute: u64::MAX,
+                    ops: HashMap::new(),
+                })
+                .collect();
+        }
+
+        let slot = &mut self.slots[(minute % WINDOW_MINUTES) as usize];
+        if slot.minute != minute {
+            slot.minute = minute;
+            slot.ops.clear();
+        }
+
+        let buckets = slot
+            .ops
+            .entry(op.to_owned())
+            .or_insert_with(|| vec![0; BUCKETS]);
+        buckets[bucket(elapsed)] += 1;
+    }
+
+    // By op name
+    pub fn summary(&self, now: Duration) -> Vec<OpLatency> {
+        let minute = now.as_secs() / 60;
+        let mut merged: HashMap<&str, Vec<u64>> = HashMap::new();
+
+        for slot in &self.slots {
+            if slot.minute > minute || minute - slot.minute >= WINDOW_MINUTES {
+                continue;
+            }
+
+            for (op, buckets) in &slot.ops {
+                let total = merged.entry(op).or_insert_with(|| vec![0; BUCKETS]);
+                for (total, count) in total.iter_mut().zip(buckets) {
+                    *total += count;
+                }
+            }
+        }
+
+        let mut summary: Vec<OpLatency> = merged
+            .into_iter()
+            .map(|(op, buckets)| OpLatency {
+                op: op.to_owned(),
+                count: buckets.iter().sum(),
+                p50: percentile(&buckets, 0.5),
+                p99: percentile(&buckets, 0.99),
+            })
+            .collect();
+        summary.sort_by(|a, b| a.op.cmp(&b.op));
+
+        summary
+    }
+}
+
+fn bucket(elapsed: Duration) -> usize {
+    let micros = elapsed.as_micros() as f64 + 1.0;
+
+    ((micros.log2() * SUB_BUCKETS) as usize).min(BUCKETS - 1)
+}
+
+// The top of the bucket the `q`th sample is in
+fn percentile(buckets: &[u64], q: f64) -> Duration {
+    let total: u64 = buckets.iter().sum();
+    let rank = ((total as f64 * q).ceil() as u64).max(1);
+
+    let mut seen = 0;
+    for (i, count) in buckets.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            let micros = 2f64.powf((i + 1) as f64 / SUB_BUCKETS) - 1.0;
+            return Duration::from_micros(micros as u64);
+        }
+    }
+
+    Duration::ZERO
+}
@@ -0,0 +1,75 @@
+// A page of a longer listing, eg `>list page 3`. Pages count from 1.
+#[derive(Debug, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: usize,
+    pub pages: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OutOfRange {
+    pub page: usize,
+    pub pages: usize,
+}
+
+impl std::fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.pages {
+            1 => writeln!(f, "Error: There's no page {}, there's only 1", self.page),
+            pages => writeln!(
+                f,
+                "Error: There's no page {}, pick one from 1 to {}",
+                self.page, pages
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+/// Splits `items`, already in the order they're listed, into pages of `size`
+/// and keeps the one asked for. An empty listing still has a page 1.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::page::{self, OutOfRange, Page};
+///
+/// let second = page::paginate(vec![1, 2, 3, 4, 5], 2, 2);
+/// assert_eq!(second, Ok(Page { items: vec![3, 4], page: 2, pages: 3 }));
+/// assert_eq!(page::paginate(vec![1, 2, 3, 4, 5], 3, 2).unwrap().items, [5]);
+/// let past = page::paginate(vec![1, 2, 3, 4], 3, 2);
+/// assert_eq!(past, Err(OutOfRange { page: 3, pages: 2 }));
+/// assert_eq!(page::paginate(Vec::<u8>::new(), 1, 2).unwrap().pages, 1);
+/// ```
+pub fn paginate<T>(items: Vec<T>, page: usize, size: usize) -> Result<Page<T>, OutOfRange> {
+    let pages = items.len().div_ceil(size).max(1);
+    if page == 0 || page > pages {
+        return Err(OutOfRange { page, pages });
+    }
+
+    let items = items
+        .into_iter()
+        .skip((page - 1) * size)
+        .take(size)
+        .collect();
+
+    Ok(Page { items, page, pages })
+}
+
+impl<T> Page<T> {
+    // Only worth showing when there's more than one, `next` being how to ask
+    // for the page after
+    pub fn footer(&self, next: impl FnOnce(usize) -> String) -> Option<String> {
+        match self.pages {
+            1 => None,
+            pages if self.page == pages => Some(format!("Page {}/{}", self.page, pages)),
+            pages => Some(format!(
+                "Page {}/{} — '{}' for more",
+                self.page,
+                pages,
+                next(self.page + 1)
+            )),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::metrics::metrics;
+use crate::server::ServerContext;
+use crate::shutdown::Shutdown;
+use crate::store::RoomStore;
+
+// Unsaved messages held for each room, past this the oldest are dropped
+pub const MAX_PENDING: usize = 200;
+
+// How often held messages are retried, for rooms nobody's written to since
+// storage came back
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Messages that were sent live while storage was down, held until it's back
+/// so history has no hole. `room::event` holds them, and writes them with
+/// their original timestamps once a write goes through again.
+#[derive(Default)]
+pub struct PendingWrites {
+    // <Room, Held messages>
+    rooms: Mutex<HashMap<String, Held>>,
+}
+
+#[derive(Default)]
+struct Held {
+    // <Message, Score>, oldest first
+    msgs: VecDeque<(String, i64)>,
+    // As of the newest message
+    retention: Option<usize>,
+}
+
+impl PendingWrites {
+    pub fn push(&self, room: &str, msg: &str, ts: i64, retention: Option<usize>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let held = rooms.entry(room.to_owned()).or_default();
+
+        held.msgs.push_back((msg.to_owned(), ts));
+        held.retention = retention;
+        drop_oldest(room, held);
+    }
+
+    // Held messages across every room
+    pub fn len(&self) -> usize {
+        let rooms = self.rooms.lock().unwrap();
+
+        rooms.values().map(|held| held.msgs.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes every room's held messages, one round trip each, returning how
+    /// many were written. Rooms that still can't be written keep theirs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// use chatsapp::pending::{PendingWrites, MAX_PENDING};
+    /// use chatsapp::store::{MemoryStore, RoomStore};
+    ///
+    /// let pending = PendingWrites::default();
+    /// for n in 0..MAX_PENDING + 5 {
+    ///     pending.push("rust", &format!("bob: {}\n", n), n as i64, None);
+    /// }
+    /// pending.push("go", "bob: hi\n", 0, None);
+    ///
+    /// // The oldest go first
+    /// let store = MemoryStore::default();
+    /// assert_eq!(pending.flush(&store).await, MAX_PENDING + 1);
+    /// assert!(pending.is_empty());
+    /// let history = store.recent("rust", MAX_PENDING + 5).await.unwrap();
+    /// assert_eq!(history.len(), MAX_PENDING);
+    /// assert_eq!(history[0], "bob: 5\n");
+    /// assert_eq!(store.recent("go", 10).await.unwrap(), ["bob: hi\n"]);
+    /// # }
+    /// ```
+    pub async fn flush(&self, store: &dyn RoomStore) -> usize {
+        let taken: Vec<(String, Held)> = self.rooms.lock().unwrap().drain().collect();
+
+        let mut written = 0;
+        for (room, held) in taken {
+            let msgs: Vec<(String, i64)> = held.msgs.iter().cloned().collect();
+
+            match store.append_many(&room, &msgs, held.retention).await {
+                Ok(()) => written += msgs.len(),
+                Err(_) => self.put_back(room, held),
+            }
+        }
+
+        if written > 0 {
+            info!("Saved {} message(s) held while storage was down", written);
+        }
+
+        written
+    }
+
+    // Ahead of anything held since it was taken
+    fn put_back(&self, room: String, mut held: Held) {
+        let mut rooms = self.rooms.lock().unwrap();
+
+        if let Some(newer) = rooms.remove(&room) {
+            held.msgs.extend(newer.msgs);
+            held.retention = newer.retention;
+        }
+        drop_oldest(&room, &mut held);
+
+        rooms.insert(room, held);
+    }
+}
+
+fn drop_oldest(room: &str, held: &mut Held) {
+    let over = held.msgs.len().saturating_sub(MAX_PENDING);
+    if over == 0 {
+        return;
+    }
+
+    held.msgs.drain(..over);
+    metrics().pending_dropped.inc_by(over as u64);
+    warn!(
+        room,
+        "Storage is still down, dropped {} unsaved message(s) past the {} held", over, MAX_PENDING
+    );
+}
+
+// Retries held messages until shutdown
+pub async fn retry(ctx: Arc<ServerContext>, mut shutdown: Shutdown) {
+    let mut interval = time::interval(RETRY_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.recv() => return,
+        }
+
+        if !ctx.pending.is_empty() {
+            ctx.pending.flush(&*ctx.store).await;
+        }
+    }
+}
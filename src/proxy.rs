@@ -0,0 +1,219 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time;
+
+// PROXY protocol headers, as sent by HAProxy in front of the server
+// https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq)]
+pub enum ProxyError {
+    Missing,
+    Truncated,
+    Malformed,
+    TimedOut,
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::Missing => write!(f, "Error: Missing PROXY header"),
+            ProxyError::Truncated => write!(f, "Error: Truncated PROXY header"),
+            ProxyError::Malformed => write!(f, "Error: Malformed PROXY header"),
+            ProxyError::TimedOut => write!(f, "Error: Timed out reading PROXY header"),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+// Reads exactly the header off the stream, leaving the client's own bytes
+// untouched. `None` means the proxy didn't know the source (eg health checks),
+// in which case the socket's own address should be used.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    match time::timeout(HEADER_TIMEOUT, read(stream)).await {
+        Ok(res) => res,
+        Err(_) => Err(ProxyError::TimedOut),
+    }
+}
+
+async fn read<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = vec![0; V2_SIGNATURE.len()];
+    read_exact(stream, &mut header).await?;
+
+    if header == V2_SIGNATURE {
+        // Version/command, family, then the length of the addresses
+        let mut fixed = [0; 4];
+        read_exact(stream, &mut fixed).await?;
+
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut rest = vec![0; len];
+        read_exact(stream, &mut rest).await?;
+
+        header.extend_from_slice(&fixed);
+        header.extend_from_slice(&rest);
+
+        return parse_v2(&header);
+    }
+
+    if !header.starts_with(b"PROXY ") {
+        return Err(ProxyError::Missing);
+    }
+
+    // v1 is a single line, read a byte at a time so we stop right at `\r\n`
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            return Err(ProxyError::Malformed);
+        }
+
+        let mut byte = [0; 1];
+        read_exact(stream, &mut byte).await?;
+        header.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&header).map_err(|_| ProxyError::Malformed)?;
+
+    parse_v1(line)
+}
+
+async fn read_exact<S>(stream: &mut S, buf: &mut [u8]) -> Result<(), ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    stream
+        .read_exact(buf)
+        .await
+        .map(|_| ())
+        .map_err(|_| ProxyError::Truncated)
+}
+
+/// Parses a v1 header line, returning the advertised source address.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::proxy::{parse_v1, ProxyError};
+///
+/// let tcp4 = parse_v1("PROXY TCP4 203.0.113.7 10.0.0.1 51234 8000\r\n");
+/// let tcp6 = parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 51234 8000\r\n");
+/// let unknown = parse_v1("PROXY UNKNOWN\r\n");
+///
+/// assert_eq!(tcp4.unwrap().unwrap().to_string(), "203.0.113.7:51234");
+/// assert_eq!(tcp6.unwrap().unwrap().to_string(), "[2001:db8::1]:51234");
+/// assert_eq!(unknown, Ok(None));
+///
+/// assert_eq!(parse_v1("PROXY TCP4 203.0.113.7\r\n"), Err(ProxyError::Truncated));
+/// assert_eq!(parse_v1("PROXY TCP4 203.0.113.7 10.0.0.1 51234 8000"), Err(ProxyError::Truncated));
+/// assert_eq!(parse_v1("PROXY TCP4 2001:db8::1 10.0.0.1 1 2\r\n"), Err(ProxyError::Malformed));
+/// ```
+pub fn parse_v1(line: &str) -> Result<Option<SocketAddr>, ProxyError> {
+    let line = line.strip_suffix("\r\n").ok_or(ProxyError::Truncated)?;
+    let mut parts = line.split(' ');
+
+    if parts.next() != Some("PROXY") {
+        return Err(ProxyError::Missing);
+    }
+
+    let family = parts.next().ok_or(ProxyError::Truncated)?;
+    if family == "UNKNOWN" {
+        return Ok(None);
+    }
+
+    let fields: Vec<&str> = parts.collect();
+    if fields.len() < 4 {
+        return Err(ProxyError::Truncated);
+    }
+    if fields.len() > 4 {
+        return Err(ProxyError::Malformed);
+    }
+
+    let ip: IpAddr = match family {
+        "TCP4" => fields[0]
+            .parse::<Ipv4Addr>()
+            .map_err(|_| ProxyError::Malformed)?
+            .into(),
+        "TCP6" => fields[0]
+            .parse::<Ipv6Addr>()
+            .map_err(|_| ProxyError::Malformed)?
+            .into(),
+        _ => return Err(ProxyError::Malformed),
+    };
+    let port: u16 = fields[2].parse().map_err(|_| ProxyError::Malformed)?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Parses a complete v2 header, returning the advertised source address.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::proxy::{parse_v2, ProxyError};
+///
+/// let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+/// // PROXY command over TCP4, 12 bytes of addresses
+/// header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+/// header.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+/// header.extend_from_slice(&51234u16.to_be_bytes());
+/// header.extend_from_slice(&8000u16.to_be_bytes());
+///
+/// assert_eq!(parse_v2(&header).unwrap().unwrap().to_string(), "203.0.113.7:51234");
+/// assert_eq!(parse_v2(&header[..20]), Err(ProxyError::Truncated));
+/// ```
+pub fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>, ProxyError> {
+    if header.len() < 16 {
+        return Err(ProxyError::Truncated);
+    }
+    if &header[..12] != V2_SIGNATURE {
+        return Err(ProxyError::Missing);
+    }
+
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    let family = header[13];
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    if version != 2 {
+        return Err(ProxyError::Malformed);
+    }
+
+    let addrs = header.get(16..16 + len).ok_or(ProxyError::Truncated)?;
+
+    // LOCAL connections come from the proxy itself
+    if command == 0 {
+        return Ok(None);
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            let addrs = addrs.get(..12).ok_or(ProxyError::Truncated)?;
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP over IPv6
+        0x21 => {
+            let addrs = addrs.get(..36).ok_or(ProxyError::Truncated)?;
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // UDP or unix sockets, nothing useful to report
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::render::ServerMessage;
+
+// Messages kept for each connection to quote from
+pub const MAX_DELIVERED: usize = 20;
+
+#[derive(Debug, PartialEq)]
+pub enum QuoteError {
+    // `len` is how many there are to pick from
+    OutOfRange { n: usize, len: usize },
+    // Eg a join, only chat can be quoted
+    NotChat(usize),
+}
+
+impl std::fmt::Display for QuoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuoteError::OutOfRange { len: 0, .. } => {
+                writeln!(f, "Error: There's nothing to quote yet")
+            }
+            QuoteError::OutOfRange { n, len } => writeln!(
+                f,
+                "Error: There's no message {} to quote, 1 is the latest and {} the oldest",
+                n, len
+            ),
+            QuoteError::NotChat(n) => writeln!(f, "Error: Message {} isn't chat", n),
+        }
+    }
+}
+
+impl std::error::Error for QuoteError {}
+
+/// The last messages a connection was sent in its room, history included,
+/// for `>quote`. Joins and the like take a number too, so counting back from
+/// the bottom of the screen finds the right one, but only chat can be quoted.
+#[derive(Default)]
+pub struct Delivered {
+    // Oldest first
+    messages: Mutex<VecDeque<ServerMessage>>,
+}
+
+impl Delivered {
+    pub fn push(&self, message: ServerMessage) {
+        let mut messages = self.messages.lock().unwrap();
+
+        if messages.len() == MAX_DELIVERED {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// The reply as it's sent, quoting message `n` where 1 is the latest.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::quote::{Delivered, QuoteError, MAX_DELIVERED};
+    /// use chatsapp::render::ServerMessage;
+    ///
+    /// let delivered = Delivered::default();
+    /// assert_eq!(delivered.quote(1, "hi"), Err(QuoteError::OutOfRange { n: 1, len: 0 }));
+    ///
+    /// for i in 0..MAX_DELIVERED + 1 {
+    ///     let chat = ServerMessage::Chat { user: "carol".into(), text: i.to_string(), ts: None };
+    ///     delivered.push(chat);
+    /// }
+    /// delivered.push(ServerMessage::Left { user: "carol".into() });
+    ///
+    /// assert_eq!(delivered.quote(1, "bye"), Err(QuoteError::NotChat(1)));
+    /// assert_eq!(delivered.quote(2, "ok").unwrap(), "> carol: 20 \u{21b5} ok");
+    /// // The first two have made way
+    /// assert_eq!(delivered.quote(MAX_DELIVERED, "ok").unwrap(), "> carol: 2 \u{21b5} ok");
+    /// let out_of_range = QuoteError::OutOfRange { n: MAX_DELIVERED + 1, len: MAX_DELIVERED };
+    /// assert_eq!(delivered.quote(MAX_DELIVERED + 1, "ok"), Err(out_of_range));
+    /// assert!(delivered.quote(0, "ok").is_err());
+    /// ```
+    pub fn quote(&self, n: usize, reply: &str) -> Result<String, QuoteError> {
+        match self.get(n)? {
+            ServerMessage::Chat { user, text, .. } => {
+                Ok(format!("> {}: {} \u{21b5} {}", user, text, reply))
+            }
+            _ => Err(QuoteError::NotChat(n)),
+        }
+    }
+
+    // Message `n`, 1 being the latest
+    pub fn get(&self, n: usize) -> Result<ServerMessage, QuoteError> {
+        let messages = self.messages.lock().unwrap();
+
+        let len = messages.len();
+        n.checked_sub(1)
+            .and_then(|back| messages.iter().rev().nth(back))
+            .cloned()
+            .ok_or(QuoteError::OutOfRange { n, len })
+    }
+}
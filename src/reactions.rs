@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use crate::quote::{Delivered, QuoteError};
+use crate::render::ServerMessage;
+use crate::room::{self, RoomError};
+use crate::store::{RoomStore, StoreError};
+
+// Longest reaction, in characters, enough for a word or a few emoji
+pub const MAX_LEN: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum ReactionError {
+    // `len` is how many there are to pick from
+    OutOfRange { n: usize, len: usize },
+    // Eg a join, only chat has reactions
+    NotChat(usize),
+}
+
+impl std::fmt::Display for ReactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReactionError::OutOfRange { len: 0, .. } => {
+                writeln!(f, "Error: There's nothing to react to yet")
+            }
+            ReactionError::OutOfRange { n, len } => writeln!(
+                f,
+                "Error: There's no message {}, 1 is the latest and {} the oldest",
+                n, len
+            ),
+            ReactionError::NotChat(n) => {
+                writeln!(
+                    f,
+                    "Error: Message {} isn't chat, only chat has reactions",
+                    n
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReactionError {}
+
+/// <Sender, Id> of message `n` of those the connection was sent, counted as
+/// `>quote` counts them.
+pub fn target(delivered: &Delivered, n: usize) -> Result<(String, i64), ReactionError> {
+    match delivered.get(n) {
+        Ok(ServerMessage::Chat {
+            user, ts: Some(id), ..
+        }) => Ok((user, id)),
+        Ok(_) => Err(ReactionError::NotChat(n)),
+        Err(QuoteError::OutOfRange { n, len }) => Err(ReactionError::OutOfRange { n, len }),
+        Err(QuoteError::NotChat(n)) => Err(ReactionError::NotChat(n)),
+    }
+}
+
+/// Sets `user`'s reaction to `author`'s message `id` in the room, replacing
+/// theirs if they'd already reacted. Reactions are kept apart from the
+/// message, so history replays it as it was sent, and go when it's removed.
+/// In rooms whose messages expire, `ttl` has them go too.
+pub async fn react(
+    store: &dyn RoomStore,
+    room: &str,
+    id: i64,
+    author: &str,
+    user: &str,
+    reaction: &str,
+    ttl: Option<Duration>,
+) -> Result<(), RoomError> {
+    // Not once it's removed, or gone
+    room::find_chat(store, room, id, Some(author)).await?;
+
+    let key = key(room, id);
+    store.hash_set(&key, user, reaction).await?;
+    store.set_add(&index_key(room), &id.to_string()).await?;
+    if let Some(ttl) = ttl {
+        store.hash_expire(&key, ttl).await?;
+    }
+
+    Ok(())
+}
+
+/// Reactions to message `id`, each with who reacted with it, the most
+/// popular first.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::reactions;
+/// use chatsapp::room::{self, RoomError};
+/// use chatsapp::store::{MemoryStore, RoomStore};
+///
+/// let store = MemoryStore::default();
+/// store.append("rust", "carol: hi\n", 1, None).await.unwrap();
+/// store.append("rust", "bob: hey\n", 2, None).await.unwrap();
+/// assert!(reactions::list(&store, "rust", 1).await.unwrap().is_empty());
+///
+/// for (user, reaction) in [("bob", "+1"), ("alice", "tada"), ("dave", "+1"), ("alice", "+1")] {
+///     reactions::react(&store, "rust", 1, "carol", user, reaction, None).await.unwrap();
+/// }
+/// reactions::react(&store, "rust", 2, "bob", "carol", "tada", None).await.unwrap();
+///
+/// let listed = reactions::list(&store, "rust", 1).await.unwrap();
+/// assert_eq!(listed, [("+1".to_owned(), vec!["alice".to_owned(), "bob".into(), "dave".into()])]);
+///
+/// // They go with the message, and it can't be reacted to after
+/// room::remove_message(&store, "rust", 1, "alice").await.unwrap();
+/// assert!(reactions::list(&store, "rust", 1).await.unwrap().is_empty());
+/// let removed = reactions::react(&store, "rust", 1, "carol", "bob", "+1", None).await;
+/// assert!(matches!(removed, Err(RoomError::AlreadyRemoved(1))));
+///
+/// // And with the room
+/// room::delete(&store, "rust").await.unwrap();
+/// assert!(reactions::list(&store, "rust", 2).await.unwrap().is_empty());
+/// # }
+/// ```
+pub async fn list(
+    store: &dyn RoomStore,
+    room: &str,
+    id: i64,
+) -> Result<Vec<(String, Vec<String>)>, StoreError> {
+    let mut reactions: Vec<(String, Vec<String>)> = vec![];
+    for (user, reaction) in store.hash_get_all(&key(room, id)).await? {
+        match reactions.iter_mut().find(|(known, _)| *known == reaction) {
+            Some((_, users)) => users.push(user),
+            None => reactions.push((reaction, vec![user])),
+        }
+    }
+
+    for (_, users) in &mut reactions {
+        users.sort();
+    }
+    reactions.sort_by(|(a, a_users), (b, b_users)| {
+        b_users.len().cmp(&a_users.len()).then_with(|| a.cmp(b))
+    });
+
+    Ok(reactions)
+}
+
+// Drops message `id`'s reactions, for when it's removed
+pub async fn clear(store: &dyn RoomStore, room: &str, id: i64) -> Result<(), StoreError> {
+    store.hash_delete(&key(room, id)).await?;
+    store.set_remove(&index_key(room), &id.to_string()).await?;
+
+    Ok(())
+}
+
+// Drops the reactions to messages that have gone, eg trimmed or expired
+pub async fn prune(store: &dyn RoomStore, room: &str) -> Result<(), StoreError> {
+    for id in store.set_members(&index_key(room)).await? {
+        let id = id.parse().unwrap_or_default();
+        match room::find_chat(store, room, id, None).await {
+            Ok(_) => {}
+            Err(RoomError::Store(e)) => return Err(e),
+            Err(_) => clear(store, room, id).await?,
+        }
+    }
+
+    Ok(())
+}
+
+// Drops every reaction in the room, for when it's deleted
+pub async fn clear_room(store: &dyn RoomStore, room: &str) -> Result<(), StoreError> {
+    for id in store.set_members(&index_key(room)).await? {
+        store
+            .hash_delete(&key(room, id.parse().unwrap_or_default()))
+            .await?;
+    }
+    store.set_delete(&index_key(room)).await?;
+
+    Ok(())
+}
+
+// Each message's reactions, by who reacted
+fn key(room: &str, id: i64) -> String {
+    format!("reactions:{}:{}", room, id)
+}
+
+// The ids of the room's messages with reactions, so they can be found to be
+// dropped with the room
+fn index_key(room: &str) -> String {
+    format!("reacted:{}", room)
+}
@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use crate::account::MultiLogin;
+use crate::username;
+
+pub type ConnId = u64;
+
+// Never given to a connection, for events from elsewhere, eg webhooks
+pub const NO_CONN: ConnId = 0;
+
+// Sent to a connection from outside, eg by the admin listener
+#[derive(Clone, Debug, PartialEq)]
+pub enum Control {
+    Disconnect,
+    LeaveRoom,
+    // The room is gone, so there's no one left to tell about leaving
+    RoomDeleted,
+    // Written to the connection as is
+    Notice(String),
+    // The room's settings, or someone's role in it, changed, so anyone in it
+    // re-reads them
+    SettingsChanged { room: String },
+    // Their username's been logged in to from a newer connection
+    Replaced,
+    // Their room's broker gave up writing to them, so they're dropped
+    WriteTimedOut,
+    // The server's shutting down, so the connection is closed
+    Goodbye,
+}
+
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub id: ConnId,
+    pub addr: String,
+    // Local address of the listener that accepted the connection
+    pub listener: String,
+    pub username: Option<String>,
+    pub room: Option<String>,
+    // When they joined `room`
+    pub joined_at: Option<Instant>,
+    pub connected_at: SystemTime,
+    // When they last sent a line
+    pub last_active: Instant,
+    // Set after `away_after_secs` in a room without a line, never announced
+    pub away: bool,
+    // With `>dnd on`, so direct messages to them are refused
+    pub dnd: bool,
+    pub control: Sender<Control>,
+}
+
+// Every live connection on the server, touched on each join/leave so it's
+// sharded rather than behind a single lock.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: DashMap<ConnId, Connection>,
+    // <Folded username, Connection> for names that aren't registered, so
+    // only one connection at a time can have each
+    claims: DashMap<String, ConnId>,
+}
+
+// Removes the connection from the registry when dropped
+pub struct Registration {
+    id: ConnId,
+    registry: Arc<ConnectionRegistry>,
+    control: Receiver<Control>,
+    control_tx: Sender<Control>,
+}
+
+impl ConnectionRegistry {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use chatsapp::registry::ConnectionRegistry;
+    ///
+    /// let registry = Arc::new(ConnectionRegistry::default());
+    ///
+    /// let conn = ConnectionRegistry::register(&registry, "127.0.0.1:5000".into(), "0.0.0.0:8000".into());
+    /// registry.set_username(conn.id(), Some("bob".into()));
+    /// registry.set_room(conn.id(), Some("rust".into()));
+    ///
+    /// let bob = registry.find_by_username("bob").unwrap();
+    /// assert_eq!(bob.room.as_deref(), Some("rust"));
+    /// assert_eq!(registry.snapshot().len(), 1);
+    ///
+    /// // Names that fold the same are the same user
+    /// assert_eq!(registry.find_by_username("BOB").unwrap().id, conn.id());
+    /// assert!(registry.find_by_username("b\u{43e}b").is_none());
+    ///
+    /// drop(conn);
+    /// assert!(registry.find_by_username("bob").is_none());
+    /// ```
+    pub fn register(registry: &Arc<Self>, addr: String, listener: String) -> Registration {
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (control_tx, control) = mpsc::channel(8);
+
+        registry.connections.insert(
+            id,
+            Connection {
+                id,
+                addr,
+                listener,
+                username: None,
+                room: None,
+                joined_at: None,
+                connected_at: SystemTime::now(),
+                last_active: Instant::now(),
+                away: false,
+                dnd: false,
+                control: control_tx.clone(),
+            },
+        );
+
+        Registration {
+            id,
+            registry: Arc::clone(registry),
+            control,
+            control_tx,
+        }
+    }
+
+    // Gives up whatever name the connection had claimed, unless it's the
+    // same one
+    pub fn set_username(&self, id: ConnId, username: Option<String>) {
+        let folded = username.as_deref().map(username::fold);
+        self.claims
+            .retain(|name, holder| *holder != id || Some(name) == folded.as_ref());
+
+        if let Some(mut conn) = self.connections.get_mut(&id) {
+            conn.username = username;
+        }
+    }
+
+    /// Sets a username that isn't registered, unless another connection has
+    /// one that folds the same. Registered names are kept to whoever logs in
+    /// as them instead, see `log_in`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use chatsapp::registry::ConnectionRegistry;
+    ///
+    /// let registry = Arc::new(ConnectionRegistry::default());
+    /// let bob = ConnectionRegistry::register(&registry, "127.0.0.1:5000".into(), "0.0.0.0:8000".into());
+    /// let mallory = ConnectionRegistry::register(&registry, "127.0.0.1:5001".into(), "0.0.0.0:8000".into());
+    ///
+    /// assert!(registry.claim_username(bob.id(), "bob"));
+    /// assert!(!registry.claim_username(mallory.id(), "Bob"));
+    /// assert!(registry.claim_username(bob.id(), "Bob"));
+    ///
+    /// // Free again once bob's moved on
+    /// registry.set_username(bob.id(), Some("robert".into()));
+    /// assert!(registry.claim_username(mallory.id(), "bob"));
+    /// ```
+    pub fn claim_username(&self, id: ConnId, username: &str) -> bool {
+        match self.claims.entry(username::fold(username)) {
+            Entry::Occupied(entry) if *entry.get() != id => return false,
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+            }
+        }
+        self.set_username(id, Some(username.to_owned()));
+
+        true
+    }
+
+    pub fn set_room(&self, id: ConnId, room: Option<String>) {
+        if let Some(mut conn) = self.connections.get_mut(&id) {
+            conn.joined_at = room.as_ref().map(|_| Instant::now());
+            conn.room = room;
+        }
+    }
+
+    // They've sent a line, so they're back if they were away
+    pub fn set_active(&self, id: ConnId) {
+        if let Some(mut conn) = self.connections.get_mut(&id) {
+            conn.last_active = Instant::now();
+            conn.away = false;
+        }
+    }
+
+    pub fn set_away(&self, id: ConnId) {
+        if let Some(mut conn) = self.connections.get_mut(&id) {
+            conn.away = true;
+        }
+    }
+
+    // On every connection using `username`, so they all agree
+    pub fn set_dnd(&self, username: &str, dnd: bool) {
+        let username = username::fold(username);
+        for mut conn in self.connections.iter_mut() {
+            if is_named(&conn, &username) {
+                conn.dnd = dnd;
+            }
+        }
+    }
+
+    pub fn get(&self, id: ConnId) -> Option<Connection> {
+        self.connections.get(&id).map(|conn| conn.value().clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    // Copies every connection out so no shard stays locked, ordered by id
+    pub fn snapshot(&self) -> Vec<Connection> {
+        let mut conns: Vec<Connection> = self
+            .connections
+            .iter()
+            .map(|conn| conn.value().clone())
+            .collect();
+        conns.sort_by_key(|conn| conn.id);
+
+        conns
+    }
+
+    // Returns false if there's no such connection, or it isn't keeping up
+    pub fn send_control(&self, id: ConnId, control: Control) -> bool {
+        match self.connections.get(&id) {
+            Some(conn) => conn.control.try_send(control).is_ok(),
+            None => false,
+        }
+    }
+
+    // To every connection, skipping any that aren't keeping up
+    pub fn send_control_all(&self, control: Control) {
+        for conn in self.connections.iter() {
+            let _ = conn.control.try_send(control.clone());
+        }
+    }
+
+    // <Listener, Connections>, ordered by listener
+    pub fn per_listener(&self) -> Vec<(String, usize)> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for conn in self.connections.iter() {
+            *counts.entry(conn.listener.clone()).or_default() += 1;
+        }
+
+        let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+        counts.sort();
+
+        counts
+    }
+
+    // Whether connection `id` may log in as `username` under its policy,
+    // closing the other connections using it with `KickOld`
+    pub fn log_in(&self, id: ConnId, username: &str, policy: MultiLogin) -> bool {
+        let username = username::fold(username);
+        let others: Vec<ConnId> = self
+            .connections
+            .iter()
+            .filter(|conn| conn.id != id && is_named(conn, &username))
+            .map(|conn| conn.id)
+            .collect();
+
+        match policy {
+            MultiLogin::Allow => true,
+            MultiLogin::KickOld => {
+                for other in others {
+                    self.send_control(other, Control::Replaced);
+                }
+                true
+            }
+            MultiLogin::Deny => others.is_empty(),
+        }
+    }
+
+    // The oldest connection using `username`, or a name that folds the same
+    pub fn find_by_username(&self, username: &str) -> Option<Connection> {
+        let username = username::fold(username);
+        self.connections
+            .iter()
+            .filter(|conn| is_named(conn, &username))
+            .min_by_key(|conn| conn.id)
+            .map(|conn| conn.value().clone())
+    }
+}
+
+impl Connection {
+    /// How long they've been idle for, once they're away. Shown by `>users`,
+    /// and to anyone sending them a direct message.
+    pub fn idle(&self) -> Option<Duration> {
+        self.away.then(|| self.last_active.elapsed())
+    }
+}
+
+// `folded` is already folded, so it's only done once per lookup
+fn is_named(conn: &Connection, folded: &str) -> bool {
+    conn.username
+        .as_deref()
+        .is_some_and(|name| username::fold(name) == folded)
+}
+
+impl Registration {
+    pub fn id(&self) -> ConnId {
+        self.id
+    }
+
+    pub fn registry(&self) -> &ConnectionRegistry {
+        &self.registry
+    }
+
+    pub async fn recv_control(&mut self) -> Option<Control> {
+        self.control.recv().await
+    }
+
+    // For the connection's room to reach it without going through the registry
+    pub fn control_sender(&self) -> Sender<Control> {
+        self.control_tx.clone()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.remove(&self.id);
+        self.registry.claims.retain(|_, holder| *holder != self.id);
+    }
+}
+
+/// `*` matches any run of characters and `?` any single one, everything else
+/// matches itself.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::registry::glob_match;
+///
+/// assert!(glob_match("bo*", "bob"));
+/// assert!(glob_match("bo*", "bo"));
+/// assert!(glob_match("*ob", "bob"));
+/// assert!(glob_match("b?b", "bob"));
+/// assert!(!glob_match("bo*", "alice"));
+/// assert!(!glob_match("b?b", "bb"));
+/// ```
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Where to resume after the last `*`, which can be retried one
+    // character further along
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
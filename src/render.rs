@@ -0,0 +1,485 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::client::ServerEvent;
+use crate::command::ParseError;
+use crate::errors::{Code, UserError};
+use crate::mention;
+use crate::room;
+use crate::themes::{Theme, THEMES};
+
+/// Everything the server tells a connection, before it's given a format.
+/// `TextRenderer` gives the line protocol, other protocols get their own
+/// renderer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerMessage {
+    // `ts` is when it was sent in ms, if known
+    Chat {
+        user: String,
+        text: String,
+        ts: Option<i64>,
+    },
+    Joined {
+        user: String,
+    },
+    Left {
+        user: String,
+    },
+    // In place of a message a room owner or moderator removed
+    Removed {
+        by: String,
+    },
+    // In place of a message its sender removed with `>undo`
+    Retracted {
+        user: String,
+    },
+    // Someone else in the room, for those who asked to see it
+    Typing {
+        user: String,
+    },
+    // `code` is for bots to match on, `text` is for people
+    Error {
+        code: Code,
+        text: String,
+    },
+    // Any number of lines
+    Info {
+        text: String,
+    },
+    RoomList {
+        rooms: Vec<String>,
+    },
+    // Lines already in the line protocol, eg a room's history. Each may end
+    // in `\n` or not.
+    Lines {
+        lines: Vec<String>,
+    },
+    Greeting {
+        prefix: char,
+        guest: Option<String>,
+        motd: Option<String>,
+    },
+    Invalid {
+        error: ParseError,
+        prefix: char,
+    },
+}
+
+impl ServerMessage {
+    pub fn info(text: impl Into<String>) -> Self {
+        ServerMessage::Info { text: text.into() }
+    }
+
+    /// Errors display as `Error: text`, that text is sent with their code.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::errors::Code;
+    /// use chatsapp::render::{ServerMessage, TextRenderer};
+    /// use chatsapp::room::RoomError;
+    /// use chatsapp::store::StoreError;
+    ///
+    /// let taken = ServerMessage::error(&RoomError::RoomNameTaken);
+    /// let error = ServerMessage::Error { code: Code::NameTaken, text: "Room name taken".into() };
+    /// assert_eq!(taken, error);
+    /// assert_eq!(TextRenderer::text(taken), "[E_NAME_TAKEN] Room name taken\n");
+    ///
+    /// // Store errors are the same whatever they happened during
+    /// let failed = ServerMessage::error(&RoomError::Store(StoreError::Read));
+    /// assert_eq!(failed, ServerMessage::error(&StoreError::Read));
+    /// assert_eq!(TextRenderer::text(failed), "[E_STORAGE] Failed to fetch\n");
+    /// ```
+    pub fn error(error: &impl UserError) -> Self {
+        let display = error.to_string();
+        let text = display.strip_suffix('\n').unwrap_or(&display);
+        let text = text.strip_prefix("Error: ").unwrap_or(text);
+
+        ServerMessage::Error {
+            code: error.code(),
+            text: text.to_owned(),
+        }
+    }
+}
+
+// What clients parse back out of the line protocol
+impl From<ServerEvent> for ServerMessage {
+    fn from(event: ServerEvent) -> Self {
+        match event {
+            ServerEvent::Chat { user, text } => ServerMessage::Chat {
+                user,
+                text,
+                ts: None,
+            },
+            ServerEvent::Joined(user) => ServerMessage::Joined { user },
+            ServerEvent::Left(user) => ServerMessage::Left { user },
+            ServerEvent::Removed(by) => ServerMessage::Removed { by },
+            ServerEvent::Retracted(user) => ServerMessage::Retracted { user },
+            ServerEvent::Error { code, text } => ServerMessage::Error { code, text },
+            ServerEvent::Info(text) => ServerMessage::Info { text },
+        }
+    }
+}
+
+/// Turns messages into what a connection's protocol expects. The app writes
+/// the line protocol, so listeners for other protocols parse each line it
+/// writes and pass it through one of these on the way out.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::client::ServerEvent;
+/// use chatsapp::render::{Renderer, ServerMessage, TextRenderer};
+///
+/// let hi = ServerEvent::Chat { user: "bob".into(), text: "hi".into() };
+/// assert_eq!(TextRenderer.render(hi.into()), ["bob: hi"]);
+/// let users = ServerMessage::info("Users:\n  alice\n  bob");
+/// assert_eq!(TextRenderer.render(users), ["Users:", "  alice", "  bob"]);
+/// ```
+pub trait Renderer: Send {
+    // Any number of lines, without line endings
+    fn render(&mut self, message: ServerMessage) -> Vec<String>;
+}
+
+// The line protocol, eg for TCP connections and WebSocket frames
+pub struct TextRenderer;
+
+impl TextRenderer {
+    /// The bytes written to text connections, each line ending in `\n`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::command::ParseError;
+    /// use chatsapp::errors::Code;
+    /// use chatsapp::render::{ServerMessage, TextRenderer};
+    ///
+    /// let golden = [
+    ///     (ServerMessage::Chat { user: "bob".into(), text: "hi: there".into(), ts: Some(1) }, "bob: hi: there\n"),
+    ///     (ServerMessage::Joined { user: "bob".into() }, "bob has joined the room\n"),
+    ///     (ServerMessage::Left { user: "bob".into() }, "bob has left the room\n"),
+    ///     (ServerMessage::Removed { by: "carol".into() }, "message removed by carol\n"),
+    ///     (ServerMessage::Typing { user: "bob".into() }, "* bob is typing\n"),
+    ///     (ServerMessage::Error { code: Code::Storage, text: "Failed to fetch".into() }, "[E_STORAGE] Failed to fetch\n"),
+    ///     (ServerMessage::info("Password changed"), "Password changed\n"),
+    ///     (ServerMessage::info("Users:\n  bob"), "Users:\n  bob\n"),
+    ///     (ServerMessage::info(""), "\n"),
+    ///     (ServerMessage::RoomList { rooms: vec!["go".into(), "rust".into()] }, "go\nrust\n"),
+    ///     (ServerMessage::RoomList { rooms: vec![] }, ""),
+    ///     (ServerMessage::Lines { lines: vec!["Start of chat\n".into(), "bob: hi\n".into()] }, "Start of chat\nbob: hi\n"),
+    ///     (ServerMessage::Lines { lines: vec!["10.0.0.0/8".into(), "rust (2)\n".into()] }, "10.0.0.0/8\nrust (2)\n"),
+    /// ];
+    /// for (message, text) in golden {
+    ///     assert_eq!(TextRenderer::text(message), text);
+    /// }
+    ///
+    /// let greeting = ServerMessage::Greeting { prefix: '>', guest: None, motd: None };
+    /// let text = "Welcome to ChatsApp!\nEnter \">help\" for a list of commands and their usage.\n\n\n";
+    /// assert_eq!(TextRenderer::text(greeting), text);
+    /// let greeting = ServerMessage::Greeting {
+    ///     prefix: '/',
+    ///     guest: Some("guest-1a2b".into()),
+    ///     motd: Some("Be nice".into()),
+    /// };
+    /// let text = "Welcome to ChatsApp!
+    /// Enter \"/help\" for a list of commands and their usage.
+    /// You're guest-1a2b until you pick a name with /set-username name
+    ///
+    /// Be nice
+    ///
+    ///
+    /// ";
+    /// assert_eq!(TextRenderer::text(greeting), text);
+    ///
+    /// let error = ParseError::UnknownCommand { input: ">hepl".into(), suggestion: Some("help"), prefix: '>' };
+    /// let text = "[E_UNKNOWN_COMMAND] Unknown command '>hepl'. Did you mean '>help'?
+    /// Enter \">help\" for a list of commands and their usage.\n";
+    /// assert_eq!(TextRenderer::text(ServerMessage::Invalid { error, prefix: '>' }), text);
+    /// ```
+    pub fn text(message: ServerMessage) -> String {
+        TextRenderer
+            .render(message)
+            .into_iter()
+            .map(|line| line + "\n")
+            .collect()
+    }
+}
+
+impl Renderer for TextRenderer {
+    fn render(&mut self, message: ServerMessage) -> Vec<String> {
+        match message {
+            ServerMessage::Chat { user, text, .. } => vec![format!("{}: {}", user, text)],
+            ServerMessage::Joined { user } => vec![format!("{} has joined the room", user)],
+            ServerMessage::Left { user } => vec![format!("{} has left the room", user)],
+            ServerMessage::Removed { by } => vec![format!("message removed by {}", by)],
+            ServerMessage::Retracted { user } => vec![format!("{} removed their message", user)],
+            ServerMessage::Typing { user } => vec![format!("* {} is typing", user)],
+            ServerMessage::Error { code, text } => lines(&format!("[{}] {}", code, text)),
+            ServerMessage::Info { text } => lines(&text),
+            ServerMessage::RoomList { rooms } => rooms,
+            ServerMessage::Lines { lines: text } => text
+                .iter()
+                .flat_map(|line| lines(line.strip_suffix('\n').unwrap_or(line)))
+                .collect(),
+            ServerMessage::Greeting {
+                prefix,
+                guest,
+                motd,
+            } => {
+                let mut greeting = vec![
+                    "Welcome to ChatsApp!".to_owned(),
+                    format!(
+                        "Enter \"{}help\" for a list of commands and their usage.",
+                        prefix
+                    ),
+                ];
+                if let Some(guest) = guest {
+                    greeting.push(format!(
+                        "You're {} until you pick a name with {}set-username name",
+                        guest, prefix
+                    ));
+                }
+                if let Some(motd) = motd {
+                    greeting.push(String::new());
+                    greeting.extend(lines(&motd));
+                }
+                greeting.extend([String::new(), String::new()]);
+
+                greeting
+            }
+            ServerMessage::Invalid { error, prefix } => {
+                let text = error.to_string();
+                let text = text.strip_suffix('\n').unwrap_or(&text);
+                let mut invalid = lines(&format!("[{}] {}", error.code(), text));
+                invalid.push(format!(
+                    "Enter \"{}help\" for a list of commands and their usage.",
+                    prefix
+                ));
+
+                invalid
+            }
+        }
+    }
+}
+
+// Dim, then back to normal, around system messages for `>set-ansi on`
+pub const DIM: &str = "\x1b[2m";
+// Around chat that mentions whoever has `caps::Cap::Mentions`
+pub const BOLD: &str = "\x1b[1m";
+pub const RESET: &str = "\x1b[0m";
+
+/// How a connection is shown what its room sends, live or replayed on
+/// joining. Joins, leaves, removed messages and the room's notices are
+/// system messages, set apart from chat as `-- text --`, dimmed too with
+/// `>set-ansi on`. Joins and leaves are left out with `>set-quiet on`,
+/// they're still stored.
+#[derive(Debug, Default)]
+pub struct RoomView {
+    // `>set-ansi on`
+    ansi: AtomicBool,
+    // `>set-quiet on`
+    quiet: AtomicBool,
+    // `>set-theme`, an index into `THEMES`
+    theme: AtomicUsize,
+    // Their name, when chat mentioning it is picked out
+    mention: Mutex<Option<String>>,
+}
+
+impl RoomView {
+    pub fn set_ansi(&self, on: bool) {
+        self.ansi.store(on, Ordering::Relaxed);
+    }
+
+    pub fn set_mention(&self, name: Option<String>) {
+        *self.mention.lock().unwrap_or_else(|e| e.into_inner()) = name;
+    }
+
+    fn mentions(&self, text: &str) -> bool {
+        let name = self.mention.lock().unwrap_or_else(|e| e.into_inner());
+        name.as_deref()
+            .is_some_and(|name| mention::parse(text).contains(&name))
+    }
+
+    pub fn set_quiet(&self, on: bool) {
+        self.quiet.store(on, Ordering::Relaxed);
+    }
+
+    pub fn set_theme(&self, theme: &'static Theme) {
+        let index = THEMES
+            .iter()
+            .position(|known| std::ptr::eq(known, theme))
+            .unwrap_or_default();
+        self.theme.store(index, Ordering::Relaxed);
+    }
+
+    pub fn theme(&self) -> &'static Theme {
+        &THEMES[self.theme.load(Ordering::Relaxed)]
+    }
+
+    /// The bytes written for something the room sent, None if it's hidden.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::render::{RoomView, ServerMessage};
+    ///
+    /// let joined = || ServerMessage::Joined { user: "bob".into() };
+    /// let hi = || ServerMessage::Chat { user: "bob".into(), text: "hi".into(), ts: None };
+    /// let notice = || ServerMessage::info("Slow mode is on\nEvery 5s");
+    ///
+    /// let view = RoomView::default();
+    /// assert_eq!(view.render(joined()).unwrap(), "-- bob has joined the room --\n");
+    /// assert_eq!(view.render(notice()).unwrap(), "-- Slow mode is on --\n-- Every 5s --\n");
+    /// assert_eq!(view.render(hi()).unwrap(), "bob: hi\n");
+    ///
+    /// view.set_ansi(true);
+    /// let dimmed = "\x1b[2m-- bob has joined the room --\x1b[0m\n";
+    /// assert_eq!(view.render(joined()).unwrap(), dimmed);
+    /// assert_eq!(view.render(hi()).unwrap(), "bob: hi\n");
+    ///
+    /// // Only joins and leaves are hidden
+    /// view.set_quiet(true);
+    /// assert_eq!(view.render(joined()), None);
+    /// assert_eq!(view.render(ServerMessage::Left { user: "bob".into() }), None);
+    /// assert!(view.render(notice()).is_some());
+    /// assert_eq!(view.render(hi()).unwrap(), "bob: hi\n");
+    /// ```
+    pub fn render(&self, message: ServerMessage) -> Option<String> {
+        let at = match &message {
+            ServerMessage::Chat { ts: Some(ts), .. } => *ts,
+            // Live, so it's only just been sent
+            _ => room::get_time_in_ms(),
+        };
+
+        self.render_at(message, at)
+    }
+
+    /// As `render`, for something sent at `at` in ms, eg from a room's
+    /// history.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::render::{RoomView, ServerMessage};
+    /// use chatsapp::themes;
+    ///
+    /// // 2024-01-31 12:01:05 UTC
+    /// let at = 1_706_702_465_000;
+    /// let hi = || ServerMessage::Chat { user: "bob".into(), text: "hi".into(), ts: None };
+    /// let joined = || ServerMessage::Joined { user: "bob".into() };
+    ///
+    /// let view = RoomView::default();
+    /// assert_eq!(view.theme().name, "plain");
+    /// view.set_theme(themes::find("compact").unwrap());
+    /// assert_eq!(view.render_at(hi(), at).unwrap(), "[12:01] <bob> hi\n");
+    ///
+    /// // `>set-ansi` dims whatever the theme makes of it
+    /// view.set_ansi(true);
+    /// let dimmed = "\x1b[2m[12:01] * bob has joined the room\x1b[0m\n";
+    /// assert_eq!(view.render_at(joined(), at).unwrap(), dimmed);
+    /// ```
+    pub fn render_at(&self, message: ServerMessage, at: i64) -> Option<String> {
+        let system = match &message {
+            ServerMessage::Joined { .. } | ServerMessage::Left { .. } => {
+                if self.quiet.load(Ordering::Relaxed) {
+                    return None;
+                }
+                true
+            }
+            ServerMessage::Info { .. }
+            | ServerMessage::Removed { .. }
+            | ServerMessage::Retracted { .. } => true,
+            _ => false,
+        };
+
+        let (start, end) = match &message {
+            _ if system && self.ansi.load(Ordering::Relaxed) => (DIM, RESET),
+            ServerMessage::Chat { text, .. } if self.mentions(text) => (BOLD, RESET),
+            _ => ("", ""),
+        };
+        let text = self
+            .theme()
+            .render(message, at)
+            .into_iter()
+            .map(|line| format!("{}{}{}\n", start, line, end))
+            .collect();
+
+        Some(text)
+    }
+}
+
+// Blank text is still a line
+fn lines(text: &str) -> Vec<String> {
+    text.split('\n').map(str::to_owned).collect()
+}
+
+/// Durations as they're shown to people, eg in `>users` and `>me`, to the
+/// largest whole unit.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use chatsapp::render::format_duration;
+///
+/// let cases = [
+///     (0, "0s"),
+///     (59, "59s"),
+///     (60, "1m"),
+///     (22 * 60 + 30, "22m"),
+///     (60 * 60 - 1, "59m"),
+///     (60 * 60, "1h"),
+///     (3 * 60 * 60 + 5, "3h"),
+///     (24 * 60 * 60, "1d"),
+///     (9 * 24 * 60 * 60, "9d"),
+/// ];
+/// for (secs, shown) in cases {
+///     assert_eq!(format_duration(Duration::from_secs(secs)), shown, "{}", secs);
+/// }
+/// assert_eq!(format_duration(Duration::from_millis(1999)), "1s");
+/// ```
+pub fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        secs @ 0..60 => format!("{}s", secs),
+        secs @ 60..3600 => format!("{}m", secs / 60),
+        secs @ 3600..86_400 => format!("{}h", secs / 3600),
+        secs => format!("{}d", secs / 86_400),
+    }
+}
+
+/// Uptimes as `>uptime` shows them, in days, hours and minutes, or seconds
+/// for the first minute.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use chatsapp::render::format_uptime;
+///
+/// let cases = [
+///     (0, "0s"),
+///     (59, "59s"),
+///     (60, "1m"),
+///     (22 * 60 + 59, "22m"),
+///     (60 * 60, "1h 0m"),
+///     (3 * 24 * 60 * 60 + 4 * 60 * 60 + 12 * 60, "3d 4h 12m"),
+///     (24 * 60 * 60, "1d 0h 0m"),
+/// ];
+/// for (secs, shown) in cases {
+///     assert_eq!(format_uptime(Duration::from_secs(secs)), shown, "{}", secs);
+/// }
+/// ```
+pub fn format_uptime(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins) = (secs / 86_400, secs % 86_400 / 3600, secs % 3600 / 60);
+
+    match (days, hours) {
+        (0, 0) if mins == 0 => format!("{}s", secs),
+        (0, 0) => format!("{}m", mins),
+        (0, _) => format!("{}h {}m", hours, mins),
+        _ => format!("{}d {}h {}m", days, hours, mins),
+    }
+}
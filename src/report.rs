@@ -0,0 +1,196 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::audit::{clean, format_time};
+use crate::room;
+use crate::store::{RoomStore, StoreError};
+use crate::username;
+
+// Open reports, a record per id
+pub const REPORTS_KEY: &str = "server:reports";
+
+// The last id given out
+const IDS_KEY: &str = "server:reportids";
+
+// Shown by `>reports` without a count
+pub const DEFAULT_COUNT: usize = 20;
+
+// What the reported user said lately, kept with the report
+const CONTEXT_LINES: usize = 3;
+
+// How far back in the room to look for it
+const CONTEXT_SCAN: usize = 200;
+
+// Reports each user can make within the window, so they can't be used to
+// flood the admins
+pub const MAX_PER_WINDOW: i64 = 3;
+const WINDOW: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub id: u64,
+    pub reporter: String,
+    pub target: String,
+    // Where the reporter was at the time
+    pub room: Option<String>,
+    pub reason: String,
+    // The target's last few messages in `room`, oldest first
+    pub context: Vec<String>,
+    // Unix time in seconds
+    pub timestamp: i64,
+}
+
+#[derive(Debug)]
+pub enum ReportError {
+    Store(StoreError),
+    // `MAX_PER_WINDOW` has been reached
+    RateLimited,
+}
+
+impl std::fmt::Display for ReportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportError::Store(e) => write!(f, "{}", e),
+            ReportError::RateLimited => {
+                writeln!(
+                    f,
+                    "Error: You've sent a lot of reports lately, try again later"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReportError {}
+
+impl From<StoreError> for ReportError {
+    fn from(e: StoreError) -> Self {
+        ReportError::Store(e)
+    }
+}
+
+impl Report {
+    // Tab separated, with the context lines last
+    fn to_record(&self) -> String {
+        let mut fields = vec![
+            self.timestamp.to_string(),
+            clean(&self.reporter),
+            clean(&self.target),
+            clean(self.room.as_deref().unwrap_or_default()),
+            clean(&self.reason),
+        ];
+        fields.extend(self.context.iter().map(|line| clean(line)));
+
+        fields.join("\t")
+    }
+
+    fn from_record(id: u64, record: &str) -> Option<Self> {
+        let mut fields = record.split('\t');
+        let mut next = || fields.next();
+
+        Some(Self {
+            id,
+            timestamp: next()?.parse().ok()?,
+            reporter: next()?.to_owned(),
+            target: next()?.to_owned(),
+            room: Some(next()?)
+                .filter(|room| !room.is_empty())
+                .map(str::to_owned),
+            reason: next()?.to_owned(),
+            context: fields.map(str::to_owned).collect(),
+        })
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} {} reported {}",
+            self.id,
+            format_time(self.timestamp),
+            self.reporter,
+            self.target
+        )?;
+
+        if let Some(room) = &self.room {
+            write!(f, " in {}", room)?;
+        }
+        write!(f, ": {}", self.reason)?;
+        for line in &self.context {
+            write!(f, "\n  {}: {}", self.target, line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Files a report about `target` for the admins, with what they said lately
+/// in `room` if the reporter's in one, returning its id. Each reporter can
+/// make `MAX_PER_WINDOW` every 10 minutes.
+pub async fn file(
+    store: &dyn RoomStore,
+    reporter: &str,
+    target: &str,
+    room: Option<&str>,
+    reason: &str,
+) -> Result<u64, ReportError> {
+    // Counted before anything else, so being limited costs nothing
+    let limit_key = format!("reportlimit:{}", username::fold(reporter));
+    let sent = store.hash_incr(&limit_key, "sent", 1).await?;
+    if sent == 1 {
+        store.hash_expire(&limit_key, WINDOW).await?;
+    }
+    if sent > MAX_PER_WINDOW {
+        return Err(ReportError::RateLimited);
+    }
+
+    let context = match room {
+        Some(room) => room::said_by(store, room, target, CONTEXT_LINES, CONTEXT_SCAN).await?,
+        None => vec![],
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() as i64)
+        .unwrap_or_default();
+
+    let id = store.hash_incr(IDS_KEY, "last", 1).await? as u64;
+    let report = Report {
+        id,
+        reporter: reporter.to_owned(),
+        target: target.to_owned(),
+        room: room.map(str::to_owned),
+        reason: reason.to_owned(),
+        context,
+        timestamp,
+    };
+    store
+        .hash_set(REPORTS_KEY, &id.to_string(), &report.to_record())
+        .await?;
+
+    Ok(id)
+}
+
+// The latest `count` open reports, oldest first
+pub async fn open(store: &dyn RoomStore, count: usize) -> Result<Vec<Report>, StoreError> {
+    let mut reports: Vec<Report> = store
+        .hash_get_all(REPORTS_KEY)
+        .await?
+        .iter()
+        .filter_map(|(id, record)| Report::from_record(id.parse().ok()?, record))
+        .collect();
+    reports.sort_by_key(|report| report.id);
+    reports.drain(..reports.len().saturating_sub(count));
+
+    Ok(reports)
+}
+
+// Returns the report, or None if there's no open report with that id
+pub async fn close(store: &dyn RoomStore, id: u64) -> Result<Option<Report>, StoreError> {
+    let field = id.to_string();
+    let Some(record) = store.hash_get(REPORTS_KEY, &field).await? else {
+        return Ok(None);
+    };
+    store.hash_remove(REPORTS_KEY, &field).await?;
+
+    Ok(Report::from_record(id, &record))
+}
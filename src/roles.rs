@@ -0,0 +1,35 @@
+use crate::store::{RoomStore, StoreError};
+use crate::username;
+
+pub const ADMINS_KEY: &str = "server:admins";
+
+/// Admin commands are refused unless the username is in `server:admins` at
+/// the time they're run, and the connection's logged in as it. Names are
+/// kept folded, so `Alice` and `alice` are the same admin.
+pub async fn is_admin(store: &dyn RoomStore, username: &str) -> Result<bool, StoreError> {
+    store
+        .set_contains(ADMINS_KEY, &username::fold(username))
+        .await
+}
+
+// Returns false if they already were an admin
+pub async fn grant(store: &dyn RoomStore, username: &str) -> Result<bool, StoreError> {
+    store.set_add(ADMINS_KEY, &username::fold(username)).await
+}
+
+// Returns false if they weren't an admin
+pub async fn revoke(store: &dyn RoomStore, username: &str) -> Result<bool, StoreError> {
+    store
+        .set_remove(ADMINS_KEY, &username::fold(username))
+        .await
+}
+
+// Makes sure the admins from the config are in the store, anyone granted
+// since is left alone
+pub async fn seed(store: &dyn RoomStore, admins: &[String]) -> Result<(), StoreError> {
+    for admin in admins {
+        grant(store, admin).await?;
+    }
+
+    Ok(())
+}
@@ -1,177 +1,1411 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use redis::{AsyncCommands, Client};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use tracing::warn;
+
+use crate::client::ServerEvent;
+use crate::reactions;
+use crate::render::{ServerMessage, TextRenderer};
+use crate::server::ServerContext;
+use crate::store::{RoomStore, StoreError};
+use crate::username;
+use crate::webhook;
+
+// <Tag, Rooms with it>, kept up to date as tags change and rooms are deleted
+pub const TAG_COUNTS_KEY: &str = "server:tags";
+
+const MAX_TAG_LEN: usize = 32;
+
+// `>find` shows at most this many rooms
+pub const MAX_FIND_RESULTS: usize = 20;
+
+// <Unit, Milliseconds in it>, smallest first
+const TTL_UNITS: [(&str, u64); 5] = [
+    ("ms", 1),
+    ("s", 1000),
+    ("m", 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("d", 24 * 60 * 60 * 1000),
+];
+
+// Messages `purge_user` reads and rewrites at a time
+const PURGE_BATCH: usize = 500;
+
+// Messages `compact` reads at a time looking for removal notes
+const COMPACT_BATCH: usize = 500;
+
+// Messages sent in the same millisecond as the one being looked for, ids
+// being when they were sent, that are read to tell it apart
+const MAX_SAME_ID: usize = 16;
+
+// Bounds how many rooms `find` reads metadata for
+const MAX_FIND_CANDIDATES: usize = 100;
+
+// Rooms with someone in them are this many times as likely to be picked by
+// `>random-room` as empty ones
+const OCCUPIED_WEIGHT: usize = 4;
+
+// `>my-rooms` shows at most this many in each section
+pub const MAX_MY_ROOMS: usize = 20;
+
+// Rooms `sort` reads at once, so a long listing doesn't open a connection for
+// every room together
+const SORT_CONCURRENCY: usize = 8;
 
 pub enum RoomEvent {
     Chat(String),
     Join,
     Leave,
+    // Announcements like slow mode changes, stored as is
+    Notice(String),
+}
+
+// Settings kept alongside a room's history, in `roominfo:{name}`
+#[derive(Debug, Default, PartialEq)]
+pub struct RoomInfo {
+    // Whoever created the room, if they had a username at the time
+    pub owner: Option<String>,
+    // Time each member has to wait between messages, zero when off
+    pub slow_mode: Duration,
+    pub tags: Vec<String>,
+    pub topic: Option<String>,
+    // Overrides `limits.max_message_len`, 0 disables it
+    pub max_message_len: Option<usize>,
+    // How long messages are kept for, when they expire
+    pub ephemeral: Option<Duration>,
+    // When `compact` last got to it, in ms
+    pub last_compacted: Option<i64>,
+    // In ms, None for rooms from before it was kept
+    pub created: Option<i64>,
+}
+
+// A room from a user's `owns:` or `memberof:` index, for `>my-rooms`
+#[derive(Debug, PartialEq)]
+pub struct MyRoom {
+    pub name: String,
+    // Score of the newest message, None if the room's since been deleted
+    pub last_activity: Option<i64>,
+}
+
+// How `>list` orders rooms, ties going to the most recently active
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SortBy {
+    Name,
+    // Most members online first
+    Members,
+    #[default]
+    Activity,
+    // Newest first, those without a creation time last
+    Created,
+}
+
+impl SortBy {
+    pub const ALL: [SortBy; 4] = [
+        SortBy::Name,
+        SortBy::Members,
+        SortBy::Activity,
+        SortBy::Created,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortBy::Name => "name",
+            SortBy::Members => "members",
+            SortBy::Activity => "activity",
+            SortBy::Created => "created",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|by| by.name() == name)
+    }
+}
+
+// Options given to `>create-room`, the settings among them stored with the room
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CreateRoomOpts {
+    // Join it straight after
+    pub join: bool,
+    pub ephemeral: Option<Duration>,
+    pub topic: Option<String>,
+    pub max_message_len: Option<usize>,
+}
+
+// What someone may do in a room, each role can do everything the ones before
+// it can
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Member,
+    // Can manage slow mode, but not the room itself
+    Moderator,
+    Owner,
 }
 
 #[derive(Debug)]
 pub enum RoomError {
-    FailedToConnect,
-    FailedToSend,
-    FailedToFetch,
-    FailedToCheckRoomExists,
+    Store(StoreError),
     RoomNameTaken,
+    InvalidTag(String),
+    // Message ids are their scores
+    MessageNotFound(i64),
+    NotChat(i64),
+    AlreadyRemoved(i64),
 }
 
 impl std::fmt::Display for RoomError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RoomError::FailedToConnect => write!(f, "Error: Failed to connect\n"),
-            RoomError::FailedToSend => write!(f, "Error: Failed to send\n"),
-            RoomError::FailedToFetch => write!(f, "Error: Failed to fetch\n"),
-            RoomError::FailedToCheckRoomExists => {
-                write!(f, "Error: Failed to check if room exists\n")
+            RoomError::Store(e) => write!(f, "{}", e),
+            RoomError::RoomNameTaken => writeln!(f, "Error: Room name taken"),
+            RoomError::InvalidTag(tag) => writeln!(
+                f,
+                "Error: Invalid tag '{}', tags are up to {} letters, numbers, - or _",
+                tag, MAX_TAG_LEN
+            ),
+            RoomError::MessageNotFound(id) => writeln!(f, "Error: No message {} in this room", id),
+            RoomError::NotChat(id) => {
+                writeln!(
+                    f,
+                    "Error: Message {} isn't chat, only chat can be removed",
+                    id
+                )
+            }
+            RoomError::AlreadyRemoved(id) => {
+                writeln!(f, "Error: Message {} has already been removed", id)
             }
-            RoomError::RoomNameTaken => write!(f, "Error: Room name taken\n"),
         }
     }
 }
 
 impl std::error::Error for RoomError {}
 
-pub async fn new(redis: &Client, room: &str) -> Result<(), RoomError> {
-    let mut conn = redis.get_async_connection().await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToConnect
-    })?;
+impl From<StoreError> for RoomError {
+    fn from(e: StoreError) -> Self {
+        RoomError::Store(e)
+    }
+}
+
+/// Creates the room with the settings in `opts`, failing if the name's taken.
+/// Its broker's only started once it has been, so a taken name leaves the
+/// room there alone.
+pub async fn create(
+    store: &dyn RoomStore,
+    room: &str,
+    owner: Option<&str>,
+    opts: &CreateRoomOpts,
+) -> Result<(), RoomError> {
+    if !store.create(room).await? {
+        Err(RoomError::RoomNameTaken)?;
+    }
 
-    let key = gen_key(room);
+    // Clear anything left over from a deleted room of the same name
+    store.hash_delete(&info_key(room)).await?;
+    store.set_delete(&mods_key(room)).await?;
+    reactions::clear_room(store, room).await?;
+    let now = get_time_in_ms().to_string();
+    store.hash_set(&info_key(room), "created", &now).await?;
+    if let Some(owner) = owner {
+        store.hash_set(&info_key(room), "owner", owner).await?;
+        store.hash_incr(&count_key(owner), "rooms", 1).await?;
+        store.set_add(&owns_key(owner), room).await?;
+    }
+    if opts.ephemeral.is_some() {
+        set_ephemeral(store, room, opts.ephemeral).await?;
+    }
+    if let Some(topic) = &opts.topic {
+        set_topic(store, room, Some(topic)).await?;
+    }
+    if opts.max_message_len.is_some() {
+        set_max_message_len(store, room, opts.max_message_len).await?;
+    }
 
-    let exists: u8 = conn.exists(&key).await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToCheckRoomExists
-    })?;
+    Ok(())
+}
 
-    if exists == 1 {
-        Err(RoomError::RoomNameTaken)?;
+// Removes the room's history, settings, moderators, tags, webhooks and
+// reactions, returns false if it didn't exist
+pub async fn delete(store: &dyn RoomStore, room: &str) -> Result<bool, StoreError> {
+    let info = info(store, room).await?;
+    for tag in &info.tags {
+        untag(store, room, tag).await?;
+    }
+    // Rooms from before counts were kept don't count against anyone
+    if let Some(owner) = &info.owner {
+        if owned_by(store, owner).await? > 0 {
+            store.hash_incr(&count_key(owner), "rooms", -1).await?;
+        }
+        store.set_remove(&owns_key(owner), room).await?;
+    }
+    store.hash_delete(&info_key(room)).await?;
+    store.set_delete(&mods_key(room)).await?;
+    webhook::revoke_all(store, room).await?;
+    reactions::clear_room(store, room).await?;
+
+    store.delete(room).await
+}
+
+// Everything in `roominfo:{name}` as it's stored, sorted by field, for backups
+pub async fn settings(
+    store: &dyn RoomStore,
+    room: &str,
+) -> Result<Vec<(String, String)>, StoreError> {
+    let mut fields = store.hash_get_all(&info_key(room)).await?;
+    fields.sort();
+
+    Ok(fields)
+}
+
+// Puts back what `settings` read, and moderators, keeping the owner's room
+// count and the tag index as `create` and `set_tags` would. Fields the room
+// already has are kept.
+pub async fn restore_settings(
+    store: &dyn RoomStore,
+    room: &str,
+    fields: &[(String, String)],
+    mods: &[String],
+) -> Result<(), StoreError> {
+    let key = info_key(room);
+    for (field, value) in fields {
+        if store.hash_get(&key, field).await?.is_some() {
+            continue;
+        }
+        store.hash_set(&key, field, value).await?;
+
+        match field.as_str() {
+            "owner" => {
+                store.hash_incr(&count_key(value), "rooms", 1).await?;
+                store.set_add(&owns_key(value), room).await?;
+            }
+            "tags" => {
+                for tag in value.split(',').filter(|tag| !tag.is_empty()) {
+                    if store.set_add(&tag_key(tag), room).await? {
+                        store.hash_incr(TAG_COUNTS_KEY, tag, 1).await?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for user in mods {
+        add_moderator(store, room, user).await?;
+    }
+
+    Ok(())
+}
+
+/// How many rooms the user owns, checked against `limits.rooms_per_user`
+/// before they can create another.
+pub async fn owned_by(store: &dyn RoomStore, user: &str) -> Result<usize, StoreError> {
+    let count = store.hash_get(&count_key(user), "rooms").await?;
+
+    Ok(count.and_then(|count| count.parse().ok()).unwrap_or(0))
+}
+
+pub async fn info(store: &dyn RoomStore, room: &str) -> Result<RoomInfo, StoreError> {
+    let key = info_key(room);
+    let owner = store.hash_get(&key, "owner").await?;
+    let slow_mode = store
+        .hash_get(&key, "slowmode")
+        .await?
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(0);
+    let tags = store
+        .hash_get(&key, "tags")
+        .await?
+        .map(|tags| {
+            tags.split(',')
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let topic = store
+        .hash_get(&key, "topic")
+        .await?
+        .filter(|topic| !topic.is_empty());
+    let max_message_len = store
+        .hash_get(&key, "maxlen")
+        .await?
+        .and_then(|len| len.parse().ok());
+    let ephemeral = store
+        .hash_get(&key, "ephemeral")
+        .await?
+        .and_then(|ms| ms.parse().ok())
+        .map(Duration::from_millis);
+    let last_compacted = store
+        .hash_get(&key, "compacted")
+        .await?
+        .and_then(|ms| ms.parse().ok());
+    let created = store
+        .hash_get(&key, "created")
+        .await?
+        .and_then(|ms| ms.parse().ok());
+
+    Ok(RoomInfo {
+        owner,
+        slow_mode: Duration::from_secs(slow_mode),
+        tags,
+        topic,
+        max_message_len,
+        ephemeral,
+        last_compacted,
+        created,
+    })
+}
+
+/// Every moderation command checks this before doing anything, so changes
+/// apply straight away. Names are compared folded, moderators are kept that
+/// way.
+pub async fn permission(store: &dyn RoomStore, room: &str, user: &str) -> Result<Role, StoreError> {
+    let user = username::fold(user);
+    let owner = store.hash_get(&info_key(room), "owner").await?;
+    if owner.is_some_and(|owner| username::fold(&owner) == user) {
+        return Ok(Role::Owner);
+    }
+
+    if store.set_contains(&mods_key(room), &user).await? {
+        return Ok(Role::Moderator);
+    }
+
+    Ok(Role::Member)
+}
+
+/// Hands the room to `to`, returning who owned it before. The owner field and
+/// both owners' `owns:` indexes change together, and every owner-only
+/// command reads the owner afresh, so the old owner loses them straight
+/// away.
+pub async fn transfer(
+    store: &dyn RoomStore,
+    room: &str,
+    to: &str,
+) -> Result<Option<String>, StoreError> {
+    let key = info_key(room);
+    let from = store.hash_get(&key, "owner").await?;
+
+    match &from {
+        Some(from) => {
+            store
+                .hash_set_move(&key, "owner", to, &owns_key(from), &owns_key(to), room)
+                .await?;
+            if owned_by(store, from).await? > 0 {
+                store.hash_incr(&count_key(from), "rooms", -1).await?;
+            }
+        }
+        // A guest made it
+        None => {
+            store.hash_set(&key, "owner", to).await?;
+            store.set_add(&owns_key(to), room).await?;
+        }
+    }
+    store.hash_incr(&count_key(to), "rooms", 1).await?;
+
+    Ok(from)
+}
+
+// Returns false if they already were a moderator
+pub async fn add_moderator(
+    store: &dyn RoomStore,
+    room: &str,
+    user: &str,
+) -> Result<bool, StoreError> {
+    store.set_add(&mods_key(room), &username::fold(user)).await
+}
+
+// Returns false if they weren't a moderator
+pub async fn remove_moderator(
+    store: &dyn RoomStore,
+    room: &str,
+    user: &str,
+) -> Result<bool, StoreError> {
+    store
+        .set_remove(&mods_key(room), &username::fold(user))
+        .await
+}
+
+pub async fn moderators(store: &dyn RoomStore, room: &str) -> Result<Vec<String>, StoreError> {
+    store.set_members(&mods_key(room)).await
+}
+
+/// Set by the room's owner or moderators with `>slowmode`, who aren't held
+/// back by it.
+pub async fn set_slow_mode(store: &dyn RoomStore, room: &str, secs: u64) -> Result<(), StoreError> {
+    store
+        .hash_set(&info_key(room), "slowmode", &secs.to_string())
+        .await
+}
+
+/// Replaces the room's tags, set by its owner with `>room-set tags`. Tags are
+/// lowercased, and an empty list clears them. Returns the tags as stored.
+pub async fn set_tags(
+    store: &dyn RoomStore,
+    room: &str,
+    tags: &[String],
+) -> Result<Vec<String>, RoomError> {
+    let mut new: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.to_lowercase();
+        if !valid_tag(&tag) {
+            Err(RoomError::InvalidTag(tag))?;
+        } else if !new.contains(&tag) {
+            new.push(tag);
+        }
+    }
+
+    let old = info(store, room).await?.tags;
+    for tag in old.iter().filter(|tag| !new.contains(tag)) {
+        untag(store, room, tag).await?;
+    }
+    for tag in new.iter().filter(|tag| !old.contains(tag)) {
+        if store.set_add(&tag_key(tag), room).await? {
+            store.hash_incr(TAG_COUNTS_KEY, tag, 1).await?;
+        }
+    }
+
+    store
+        .hash_set(&info_key(room), "tags", &new.join(","))
+        .await?;
+
+    Ok(new)
+}
+
+// None clears it
+pub async fn set_topic(
+    store: &dyn RoomStore,
+    room: &str,
+    topic: Option<&str>,
+) -> Result<(), StoreError> {
+    store
+        .hash_set(&info_key(room), "topic", topic.unwrap_or_default())
+        .await
+}
+
+/// Overrides the server's message length limit in this room, None goes back
+/// to it. Counted in characters rather than bytes, so a message in Japanese
+/// can be as long as one in English.
+pub async fn set_max_message_len(
+    store: &dyn RoomStore,
+    room: &str,
+    len: Option<usize>,
+) -> Result<(), StoreError> {
+    let len = len.map(|len| len.to_string()).unwrap_or_default();
+
+    store.hash_set(&info_key(room), "maxlen", &len).await
+}
+
+/// Makes messages in the room expire once they're `ttl` old, None keeps them
+/// again. Expired messages are removed whenever the room's written to, and
+/// never replayed to anyone joining.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::time::Duration;
+///
+/// use chatsapp::room;
+/// use chatsapp::store::MemoryStore;
+///
+/// let store = MemoryStore::default();
+/// room::create(&store, "scratch", None, &Default::default()).await.unwrap();
+/// let ttl = Some(Duration::from_secs(2 * 60 * 60));
+/// room::set_ephemeral(&store, "scratch", ttl).await.unwrap();
+/// assert_eq!(room::info(&store, "scratch").await.unwrap().ephemeral, ttl);
+///
+/// room::set_ephemeral(&store, "scratch", None).await.unwrap();
+/// assert_eq!(room::info(&store, "scratch").await.unwrap().ephemeral, None);
+/// # }
+/// ```
+pub async fn set_ephemeral(
+    store: &dyn RoomStore,
+    room: &str,
+    ttl: Option<Duration>,
+) -> Result<(), StoreError> {
+    let ttl = ttl
+        .map(|ttl| ttl.as_millis().to_string())
+        .unwrap_or_default();
+
+    store.hash_set(&info_key(room), "ephemeral", &ttl).await
+}
+
+/// A TTL for ephemeral rooms, a whole number of `ms`, `s`, `m`, `h` or `d`.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use chatsapp::room;
+///
+/// assert_eq!(room::parse_ttl("90m"), Some(Duration::from_secs(90 * 60)));
+/// assert_eq!(room::parse_ttl("250ms"), Some(Duration::from_millis(250)));
+/// assert_eq!(room::format_ttl(Duration::from_secs(2 * 60 * 60)), "2h");
+/// assert_eq!(room::format_ttl(Duration::from_millis(1500)), "1500ms");
+/// for invalid in ["0s", "1", "h", "1.5h", "-1m", "1w"] {
+///     assert_eq!(room::parse_ttl(invalid), None);
+/// }
+/// ```
+pub fn parse_ttl(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (count, unit) = s.split_at(split);
+    let count: u64 = count.parse().ok()?;
+    let (_, ms) = TTL_UNITS.iter().find(|(name, _)| *name == unit)?;
+
+    match count.checked_mul(*ms)? {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+// In the biggest unit it's a whole number of
+pub fn format_ttl(ttl: Duration) -> String {
+    let ms = ttl.as_millis() as u64;
+    let (unit, per) = TTL_UNITS
+        .iter()
+        .rev()
+        .find(|(_, per)| ms.is_multiple_of(*per))
+        .unwrap_or(&TTL_UNITS[0]);
+
+    format!("{}{}", ms / per, unit)
+}
+
+/// Rooms whose name or topic contains `query`, ignoring case, most recently
+/// active first along with their topics.
+///
+/// Names are checked first from the room list alone. Topics are only read for
+/// up to 100 other rooms when there aren't enough name matches, so a search
+/// costs a bounded number of round trips however many rooms there are.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::sync::Arc;
+///
+/// use chatsapp::server::ServerContext;
+/// use chatsapp::store::MemoryStore;
+/// use chatsapp::{room, shutdown};
+///
+/// let (trigger, _) = shutdown::channel();
+/// let ctx = ServerContext::new(Arc::new(MemoryStore::default()), trigger);
+/// let store = &*ctx.store;
+/// for name in ["rust", "rustaceans", "go", "chess"] {
+///     room::create(store, name, None, &Default::default()).await.unwrap();
+/// }
+/// room::set_topic(store, "go", Some("Go, and some Rust on Fridays")).await.unwrap();
+/// for name in ["go", "rust"] {
+///     room::event(&ctx, room::RoomEvent::Join, name, "alice", None).await;
+/// }
+///
+/// let found = room::find(store, "RUST").await.unwrap();
+/// let names: Vec<&str> = found.iter().map(|(name, _)| name.as_str()).collect();
+/// assert_eq!(names, ["rust", "go", "rustaceans"]);
+/// assert_eq!(found[1].1.as_deref(), Some("Go, and some Rust on Fridays"));
+///
+/// assert!(room::find(store, "draughts").await.unwrap().is_empty());
+/// # }
+/// ```
+pub async fn find(
+    store: &dyn RoomStore,
+    query: &str,
+) -> Result<Vec<(String, Option<String>)>, StoreError> {
+    let query = query.to_lowercase();
+    let (mut candidates, others): (Vec<String>, Vec<String>) = store
+        .list()
+        .await?
+        .into_iter()
+        .partition(|room| room.to_lowercase().contains(&query));
+    candidates.truncate(MAX_FIND_CANDIDATES);
+
+    let mut topics = HashMap::new();
+    if candidates.len() < MAX_FIND_RESULTS {
+        for room in others.into_iter().take(MAX_FIND_CANDIDATES) {
+            let topic = info(store, &room).await?.topic;
+            if topic
+                .as_ref()
+                .is_some_and(|topic| topic.to_lowercase().contains(&query))
+            {
+                topics.insert(room.clone(), topic);
+                candidates.push(room);
+            }
+        }
+    }
+
+    let mut found = Vec::new();
+    for room in by_activity(store, candidates)
+        .await?
+        .into_iter()
+        .take(MAX_FIND_RESULTS)
+    {
+        let topic = match topics.remove(&room) {
+            Some(topic) => topic,
+            None => info(store, &room).await?.topic,
+        };
+        found.push((room, topic));
     }
 
-    // Key, member, score
-    conn.zadd(key, "Start of chat\n", 0).await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToSend
-    })?;
+    Ok(found)
+}
+
+pub async fn tagged(store: &dyn RoomStore, tag: &str) -> Result<Vec<String>, StoreError> {
+    store.set_members(&tag_key(&tag.to_lowercase())).await
+}
+
+// <Tag, Rooms>, ordered by tag
+pub async fn tag_counts(store: &dyn RoomStore) -> Result<Vec<(String, i64)>, StoreError> {
+    let mut counts: Vec<(String, i64)> = store
+        .hash_get_all(TAG_COUNTS_KEY)
+        .await?
+        .into_iter()
+        .filter_map(|(tag, count)| Some((tag, count.parse().ok()?)))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    counts.sort();
+
+    Ok(counts)
+}
+
+// Most recently active first, leaving out any that no longer exist
+pub async fn by_activity(
+    store: &dyn RoomStore,
+    rooms: Vec<String>,
+) -> Result<Vec<String>, StoreError> {
+    sort(store, rooms, SortBy::Activity, &HashMap::new()).await
+}
+
+/// `rooms` in the order `>list` shows them, leaving out any that no longer
+/// exist. `members` is how many are in each running room. Each room's
+/// metadata is read a few at a time, rather than one after the other or all at
+/// once.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::collections::HashMap;
+///
+/// use chatsapp::room::{self, SortBy};
+/// use chatsapp::store::{MemoryStore, RoomStore};
+///
+/// let store = MemoryStore::default();
+/// // <Room, Created, Last message>
+/// for (name, created, active) in [("cello", 3, 10), ("alto", 1, 30), ("bass", 2, 20)] {
+///     room::create(&store, name, None, &Default::default()).await.unwrap();
+///     let key = format!("roominfo:{}", name);
+///     store.hash_set(&key, "created", &created.to_string()).await.unwrap();
+///     store.append(name, "bob: hi\n", active, None).await.unwrap();
+/// }
+///
+/// let rooms = || vec!["alto".into(), "bass".into(), "cello".into(), "gone".into()];
+/// let members = HashMap::from([("cello".to_owned(), 2), ("bass".to_owned(), 1)]);
+/// for (by, expected) in [
+///     (SortBy::Name, ["alto", "bass", "cello"]),
+///     (SortBy::Members, ["cello", "bass", "alto"]),
+///     (SortBy::Activity, ["alto", "bass", "cello"]),
+///     (SortBy::Created, ["cello", "bass", "alto"]),
+/// ] {
+///     let sorted = room::sort(&store, rooms(), by, &members).await.unwrap();
+///     assert_eq!(sorted, expected, "{:?}", by);
+/// }
+/// # }
+/// ```
+pub async fn sort(
+    store: &dyn RoomStore,
+    rooms: Vec<String>,
+    by: SortBy,
+    members: &HashMap<String, usize>,
+) -> Result<Vec<String>, StoreError> {
+    let read = stream::iter(rooms)
+        .map(|room| async move {
+            let Some(meta) = store.meta(&room).await? else {
+                return Ok(None);
+            };
+            let created = match by {
+                SortBy::Created => store
+                    .hash_get(&info_key(&room), "created")
+                    .await?
+                    .and_then(|ms| ms.parse().ok()),
+                _ => None,
+            };
+
+            Ok::<_, StoreError>(Some((meta.last_activity, room, created)))
+        })
+        .buffered(SORT_CONCURRENCY);
+    let mut active: Vec<(i64, String, Option<i64>)> = read
+        .try_filter_map(|room| async move { Ok(room) })
+        .try_collect()
+        .await?;
+
+    active.sort_by(|a, b| b.cmp(a));
+    match by {
+        SortBy::Name => active.sort_by(|a, b| a.1.cmp(&b.1)),
+        SortBy::Members => active
+            .sort_by_key(|(_, room, _)| Reverse(members.get(room).copied().unwrap_or_default())),
+        SortBy::Activity => {}
+        SortBy::Created => active.sort_by_key(|(_, _, created)| Reverse(*created)),
+    }
+
+    Ok(active.into_iter().map(|(_, room, _)| room).collect())
+}
+
+// Kept for `>my-rooms` each time someone joins a room
+pub async fn add_member(store: &dyn RoomStore, room: &str, user: &str) -> Result<(), StoreError> {
+    store.set_add(&member_key(user), room).await?;
 
     Ok(())
 }
 
-pub async fn list(redis: &Client) -> Result<Vec<String>, RoomError> {
-    let mut conn = redis.get_async_connection().await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToConnect
-    })?;
+/// The rooms `user` owns, then the others they've joined, each most recently
+/// active first. Rooms that have since been deleted come last, and are taken
+/// out of the index as they're found, so they're only shown the once.
+pub async fn my_rooms(
+    store: &dyn RoomStore,
+    user: &str,
+) -> Result<(Vec<MyRoom>, Vec<MyRoom>), StoreError> {
+    let owned = indexed(store, &owns_key(user)).await?;
+    let mut joined = indexed(store, &member_key(user)).await?;
+    joined.retain(|room| !owned.iter().any(|owned| owned.name == room.name));
 
-    let rooms: Vec<String> = conn.keys("room*").await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToFetch
-    })?;
+    Ok((owned, joined))
+}
+
+async fn indexed(store: &dyn RoomStore, key: &str) -> Result<Vec<MyRoom>, StoreError> {
+    let mut rooms = vec![];
+    for name in store.set_members(key).await? {
+        let last_activity = store.meta(&name).await?.map(|meta| meta.last_activity);
+        if last_activity.is_none() {
+            store.set_remove(key, &name).await?;
+        }
+        rooms.push(MyRoom {
+            name,
+            last_activity,
+        });
+    }
+    // None sorts first, so deleted rooms end up last
+    rooms.sort_by_key(|room| Reverse(room.last_activity));
 
     Ok(rooms)
 }
 
-pub async fn event(
-    redis: &Client,
-    event: RoomEvent,
+/// A room for `>random-room`, out of `rooms` with how many are in each. Empty
+/// rooms can still come up, but ones with someone in them are more likely, so
+/// there's usually someone to talk to.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::room;
+///
+/// assert_eq!(room::pick_random(&[]), None);
+/// assert_eq!(room::pick_random(&[("rust".to_owned(), 0)]), Some("rust"));
+///
+/// let rooms = [("busy".to_owned(), 3), ("empty".to_owned(), 0)];
+/// let busy = (0..1000)
+///     .filter(|_| room::pick_random(&rooms) == Some("busy"))
+///     .count();
+/// assert!(busy > 600 && busy < 1000);
+/// ```
+pub fn pick_random(rooms: &[(String, usize)]) -> Option<&str> {
+    let weight = |members: usize| if members > 0 { OCCUPIED_WEIGHT } else { 1 };
+    let total: usize = rooms.iter().map(|(_, members)| weight(*members)).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let mut pick = rand::random_range(0..total);
+    for (room, members) in rooms {
+        if pick < weight(*members) {
+            return Some(room);
+        }
+        pick -= weight(*members);
+    }
+
+    None
+}
+
+/// Stores the join message and fetches the last `history` messages before it
+/// in a single round trip, or two when the room's ephemeral. None if the room
+/// doesn't exist.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::sync::Arc;
+/// use std::time::{Duration, Instant};
+///
+/// use chatsapp::room::{self, RoomEvent};
+/// use chatsapp::server::ServerContext;
+/// use chatsapp::shutdown;
+/// use chatsapp::store::{MemoryStore, RoomStore, SlowStore};
+///
+/// let latency = Duration::from_millis(100);
+/// let store = Arc::new(SlowStore::new(MemoryStore::default(), latency));
+/// let (trigger, _) = shutdown::channel();
+/// let ctx = ServerContext::new(store.clone(), trigger);
+/// store.create("rust").await.unwrap();
+///
+/// let start = Instant::now();
+/// let (msg, history) = room::join(&*store, "rust", "bob", None, None, 10).await.unwrap().unwrap();
+/// assert!(start.elapsed() < latency * 2);
+/// assert_eq!(msg, "bob has joined the room\n");
+/// assert_eq!(history.len(), 1);
+/// assert_eq!(history[0].0, "Start of chat\n");
+///
+/// // Storing the event then reading the history takes twice as long
+/// let start = Instant::now();
+/// room::event(&ctx, RoomEvent::Join, "rust", "alice", None).await;
+/// store.recent("rust", 10).await.unwrap();
+/// assert!(start.elapsed() >= latency * 2);
+///
+/// assert_eq!(room::join(&*store, "go", "bob", None, None, 10).await.unwrap(), None);
+/// assert!(!store.list().await.unwrap().contains(&"go".to_owned()));
+/// # }
+/// ```
+pub async fn join(
+    store: &dyn RoomStore,
     room: &str,
     username: &str,
-) -> Result<String, RoomError> {
-    let mut conn = redis.get_async_connection().await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToConnect
-    })?;
+    retention: Option<usize>,
+    ephemeral: Option<Duration>,
+    history: usize,
+) -> Result<Option<(String, Vec<(String, i64)>)>, RoomError> {
+    expire(store, room, ephemeral).await?;
+
+    let msg = TextRenderer::text(ServerMessage::Joined {
+        user: username.to_owned(),
+    });
+    let recent = store
+        .append_recent(room, &msg, get_time_in_ms(), retention, history + 1)
+        .await?;
+
+    // Their own join would only be noise to them
+    Ok(recent.map(|mut recent| {
+        recent.retain(|(line, _)| *line != msg);
+        let extra = recent.len().saturating_sub(history);
+        recent.drain(..extra);
 
-    let key = gen_key(room);
-    let score = get_time_in_ms();
+        (msg, recent)
+    }))
+}
+
+// The last `history` messages with their scores, leaving out any that have
+// expired
+pub async fn recent(
+    store: &dyn RoomStore,
+    room: &str,
+    ephemeral: Option<Duration>,
+    history: usize,
+) -> Result<Vec<(String, i64)>, StoreError> {
+    expire(store, room, ephemeral).await?;
 
-    let msg = match event {
-        RoomEvent::Chat(message) => {
-            let chat = gen_chat(username, &message);
+    store.recent_before(room, None, history).await
+}
+
+// `recent` a page at a time with scores, for the HTTP API
+pub async fn recent_before(
+    store: &dyn RoomStore,
+    room: &str,
+    ephemeral: Option<Duration>,
+    before: Option<i64>,
+    count: usize,
+) -> Result<Vec<(String, i64)>, StoreError> {
+    expire(store, room, ephemeral).await?;
+
+    store.recent_before(room, before, count).await
+}
+
+/// Replaces the chat message with id `id` by a note of who removed it, so
+/// it keeps its place in history. Ids are the scores messages are stored
+/// with, shown by `>history --ids` and the HTTP API. Returns <Removed
+/// message, Note>.
+pub async fn remove_message(
+    store: &dyn RoomStore,
+    room: &str,
+    id: i64,
+    by: &str,
+) -> Result<(String, String), RoomError> {
+    let (old, _) = find_chat(store, room, id, None).await?;
+    let note = TextRenderer::text(ServerMessage::Removed { by: by.to_owned() });
+    // Gone since it was read, eg expired or removed by someone else
+    if !store.replace(room, id, &old, &note).await? {
+        return Err(RoomError::MessageNotFound(id));
+    }
+    reactions::clear(store, room, id).await?;
+
+    Ok((old, note))
+}
+
+/// Replaces `user`'s chat message `id` by a note that they removed it, as
+/// `remove_message` does for moderators, returning the note. `>undo` uses it
+/// on someone's latest message in the room, within `edit_window_secs` of
+/// sending it.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::room::{self, RoomError};
+/// use chatsapp::store::{MemoryStore, RoomStore};
+///
+/// // Sent in the same millisecond, so with the same id
+/// let store = MemoryStore::default();
+/// store.append("rust", "alice: hi\n", 5, None).await.unwrap();
+/// store.append("rust", "bob: hey\n", 5, None).await.unwrap();
+///
+/// let note = room::retract_message(&store, "rust", 5, "alice").await.unwrap();
+/// assert_eq!(note, "alice removed their message\n");
+/// let mut stored = store.recent("rust", 2).await.unwrap();
+/// stored.sort();
+/// assert_eq!(stored, ["alice removed their message\n", "bob: hey\n"]);
+///
+/// let again = room::retract_message(&store, "rust", 5, "alice").await;
+/// assert!(matches!(again, Err(RoomError::AlreadyRemoved(5))));
+/// let missing = room::retract_message(&store, "rust", 5, "carol").await;
+/// assert!(matches!(missing, Err(RoomError::MessageNotFound(5))));
+/// # }
+/// ```
+pub async fn retract_message(
+    store: &dyn RoomStore,
+    room: &str,
+    id: i64,
+    user: &str,
+) -> Result<String, RoomError> {
+    let (old, user) = find_chat(store, room, id, Some(user)).await?;
+    let note = TextRenderer::text(ServerMessage::Retracted { user });
+    if !store.replace(room, id, &old, &note).await? {
+        return Err(RoomError::MessageNotFound(id));
+    }
+    reactions::clear(store, room, id).await?;
+
+    Ok(note)
+}
 
-            conn.zadd(key, &chat, score).await.map_err(|e| {
-                dbg!("{}", e);
-                RoomError::FailedToSend
-            })?;
+// <Stored line, Sender> of chat message `id`, the newest sent then by
+// `author` when given, as others' can share the id
+pub async fn find_chat(
+    store: &dyn RoomStore,
+    room: &str,
+    id: i64,
+    author: Option<&str>,
+) -> Result<(String, String), RoomError> {
+    let found = store
+        .recent_before(room, Some(id.saturating_add(1)), MAX_SAME_ID)
+        .await?;
 
-            chat
+    let (mut removed, mut other) = (false, false);
+    for (msg, _) in found.into_iter().rev().filter(|(_, score)| *score == id) {
+        match ServerEvent::parse(msg.strip_suffix('\n').unwrap_or(&msg)) {
+            ServerEvent::Chat { user, .. } if author.is_none_or(|author| author == user) => {
+                return Ok((msg, user));
+            }
+            ServerEvent::Chat { .. } => {}
+            ServerEvent::Retracted(user) if author.is_some_and(|author| author != user) => {}
+            ServerEvent::Removed(_) | ServerEvent::Retracted(_) => removed = true,
+            _ => other = true,
         }
-        RoomEvent::Join => {
-            let join = gen_join_msg(username);
+    }
+
+    match (removed, other) {
+        (true, _) => Err(RoomError::AlreadyRemoved(id)),
+        (false, true) => Err(RoomError::NotChat(id)),
+        (false, false) => Err(RoomError::MessageNotFound(id)),
+    }
+}
+
+/// Rewrites `user`'s chat message `id` with `text`, marked `(edited)` so
+/// it's replayed that way too, returning the new line. `>edit` uses it on
+/// someone's latest message in the room, within `edit_window_secs` of
+/// sending it.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::room::{self, RoomError};
+/// use chatsapp::store::{MemoryStore, RoomStore};
+///
+/// // Sent in the same millisecond, so with the same id
+/// let store = MemoryStore::default();
+/// store.append("rust", "alice: hi\n", 5, None).await.unwrap();
+/// store.append("rust", "bob: hey\n", 5, None).await.unwrap();
+///
+/// let edited = room::edit_message(&store, "rust", 5, "alice", "hi all").await.unwrap();
+/// assert_eq!(edited, "alice: hi all (edited)\n");
+/// let mut stored = store.recent("rust", 2).await.unwrap();
+/// stored.sort();
+/// assert_eq!(stored, ["alice: hi all (edited)\n", "bob: hey\n"]);
+///
+/// let missing = room::edit_message(&store, "rust", 5, "carol", "hi").await;
+/// assert!(matches!(missing, Err(RoomError::MessageNotFound(5))));
+/// # }
+/// ```
+pub async fn edit_message(
+    store: &dyn RoomStore,
+    room: &str,
+    id: i64,
+    user: &str,
+    text: &str,
+) -> Result<String, RoomError> {
+    let (old, user) = find_chat(store, room, id, Some(user)).await?;
+    let new = TextRenderer::text(ServerMessage::Chat {
+        user,
+        text: format!("{} (edited)", text),
+        ts: None,
+    });
+    if !store.replace(room, id, &old, &new).await? {
+        return Err(RoomError::MessageNotFound(id));
+    }
+
+    Ok(new)
+}
 
-            conn.zadd(key, &join, score).await.map_err(|e| {
-                dbg!("{}", e);
-                RoomError::FailedToSend
-            })?;
+/// Replaces every chat message `user` sent in the room, as `remove_message`
+/// does, a page at a time from the newest so storage isn't held up for long.
+/// Returns how many there were. Admins run it with `>purge-user`, in the
+/// background, for one room or all of them.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::room;
+/// use chatsapp::store::{MemoryStore, RoomStore};
+///
+/// // More than a page, with lines that only look like bob's among them
+/// let store = MemoryStore::default();
+/// store.create("rust").await.unwrap();
+/// for n in 1..=1200 {
+///     let msg = match n % 4 {
+///         0 => format!("bob: {}\n", n),
+///         1 => format!("bobby: {}\n", n),
+///         2 => format!("alice: bob: {}\n", n),
+///         _ => "bob has joined the room\n".to_owned(),
+///     };
+///     store.append("rust", &msg, n, None).await.unwrap();
+/// }
+///
+/// assert_eq!(room::purge_user(&store, "rust", "bob", "alice").await.unwrap(), 300);
+/// let history = store.recent("rust", 2000).await.unwrap();
+/// assert_eq!(history.len(), 1201);
+/// assert!(!history.iter().any(|msg| msg.starts_with("bob: ")));
+/// let removed = history.iter().filter(|msg| *msg == "message removed by alice\n");
+/// assert_eq!(removed.count(), 300);
+///
+/// // Nothing left to purge
+/// assert_eq!(room::purge_user(&store, "rust", "bob", "alice").await.unwrap(), 0);
+/// # }
+/// ```
+pub async fn purge_user(
+    store: &dyn RoomStore,
+    room: &str,
+    user: &str,
+    by: &str,
+) -> Result<usize, StoreError> {
+    let note = TextRenderer::text(ServerMessage::Removed { by: by.to_owned() });
+    let mut before = None;
+    let mut purged = 0;
+
+    loop {
+        let page = store.recent_before(room, before, PURGE_BATCH).await?;
+        // Anything sharing the oldest score with the page before is skipped,
+        // which only happens for messages sent in the same ms
+        let Some((_, oldest)) = page.first() else {
+            return Ok(purged);
+        };
+        before = Some(*oldest);
+        let last = page.len() < PURGE_BATCH;
 
-            join
+        let theirs: Vec<(String, i64)> = page
+            .into_iter()
+            .filter(|(msg, _)| {
+                let line = msg.strip_suffix('\n').unwrap_or(msg);
+                matches!(ServerEvent::parse(line), ServerEvent::Chat { user: from, .. } if from == user)
+            })
+            .collect();
+        purged += store.replace_many(room, &theirs, &note).await?;
+        for (_, id) in &theirs {
+            reactions::clear(store, room, *id).await?;
         }
-        RoomEvent::Leave => {
-            let leave = gen_leave_msg(username);
 
-            conn.zadd(key, &leave, score).await.map_err(|e| {
-                dbg!("{}", e);
-                RoomError::FailedToSend
-            })?;
+        if last {
+            return Ok(purged);
+        }
+    }
+}
+
+// What `user` said in the room lately, oldest first, looking back through at
+// most `scan` messages and keeping the last `count`
+pub async fn said_by(
+    store: &dyn RoomStore,
+    room: &str,
+    user: &str,
+    count: usize,
+    scan: usize,
+) -> Result<Vec<String>, StoreError> {
+    let mut said: Vec<String> = store
+        .recent(room, scan)
+        .await?
+        .into_iter()
+        .filter_map(|msg| {
+            let line = msg.strip_suffix('\n').unwrap_or(&msg);
+            match ServerEvent::parse(line) {
+                ServerEvent::Chat { user: from, text } if from == user => Some(text),
+                _ => None,
+            }
+        })
+        .collect();
+    said.drain(..said.len().saturating_sub(count));
 
-            leave
+    Ok(said)
+}
+
+/// Applies the room's retention to its history without waiting for someone to
+/// write to it: expired messages go if it's ephemeral, then all but the newest
+/// `retention`, then every removal note followed by another, so each run of
+/// removed messages leaves only its newest note. Reactions to messages that
+/// went are dropped too. Returns how many messages went, and notes the time
+/// in the room's `compacted` field. Rooms that don't
+/// exist are left alone. `compact::run` goes through every room with it.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::time::Duration;
+///
+/// use chatsapp::store::{MemoryStore, RoomStore};
+/// use chatsapp::{reactions, room};
+///
+/// let store = MemoryStore::default();
+/// store.create("rust").await.unwrap();
+/// let now = room::get_time_in_ms();
+/// let mut msgs = vec![("bob: old\n".to_owned(), now - 60_000)];
+/// for (n, msg) in ["alice: hi", "message removed by carol", "message removed by dave", "message removed by carol", "bob: hello", "message removed by dave"]
+///     .into_iter()
+///     .enumerate()
+/// {
+///     msgs.push((format!("{}\n", msg), now + n as i64));
+/// }
+/// store.append_many("rust", &msgs, None).await.unwrap();
+/// reactions::react(&store, "rust", now - 60_000, "bob", "alice", "+1", None).await.unwrap();
+/// reactions::react(&store, "rust", now + 4, "bob", "alice", "+1", None).await.unwrap();
+///
+/// // The run of three notes is down to the newest
+/// assert_eq!(room::compact(&store, "rust", None).await.unwrap(), 2);
+/// let history = store.recent("rust", 10).await.unwrap();
+/// assert_eq!(
+///     history,
+///     ["Start of chat\n", "bob: old\n", "alice: hi\n", "message removed by carol\n", "bob: hello\n", "message removed by dave\n"],
+/// );
+/// let compacted = room::info(&store, "rust").await.unwrap().last_compacted.unwrap();
+/// assert!(compacted >= now);
+///
+/// // Expiry and retention as writes would apply them
+/// room::set_ephemeral(&store, "rust", Some(Duration::from_secs(30))).await.unwrap();
+/// assert_eq!(room::compact(&store, "rust", None).await.unwrap(), 1);
+/// assert!(reactions::list(&store, "rust", now - 60_000).await.unwrap().is_empty());
+/// assert_eq!(reactions::list(&store, "rust", now + 4).await.unwrap().len(), 1);
+/// assert_eq!(room::compact(&store, "rust", Some(3)).await.unwrap(), 2);
+/// assert_eq!(store.recent("rust", 10).await.unwrap(), ["message removed by carol\n", "bob: hello\n", "message removed by dave\n"]);
+/// assert_eq!(room::compact(&store, "rust", Some(3)).await.unwrap(), 0);
+///
+/// assert_eq!(room::compact(&store, "nowhere", None).await.unwrap(), 0);
+/// assert!(room::info(&store, "nowhere").await.unwrap().last_compacted.is_none());
+/// # }
+/// ```
+pub async fn compact(
+    store: &dyn RoomStore,
+    room: &str,
+    retention: Option<usize>,
+) -> Result<usize, StoreError> {
+    if store.meta(room).await?.is_none() {
+        return Ok(0);
+    }
+
+    let ephemeral = info(store, room).await?.ephemeral;
+    let mut removed = match ephemeral {
+        Some(ttl) => {
+            let before = get_time_in_ms() - ttl.as_millis() as i64;
+            store.expire(room, before).await?
         }
+        None => 0,
     };
+    if let Some(retention) = retention {
+        removed += store.trim(room, retention).await?;
+    }
+
+    // Newest first, so a note is dropped when the one after it is a note too
+    let mut before = None;
+    let mut after_note = false;
+    loop {
+        let page = store.recent_before(room, before, COMPACT_BATCH).await?;
+        let Some((_, oldest)) = page.first() else {
+            break;
+        };
+        before = Some(*oldest);
+        let last = page.len() < COMPACT_BATCH;
+
+        let mut runs = vec![];
+        for (msg, score) in page.into_iter().rev() {
+            let line = msg.strip_suffix('\n').unwrap_or(&msg);
+            let note = matches!(
+                ServerEvent::parse(line),
+                ServerEvent::Removed(_) | ServerEvent::Retracted(_)
+            );
+            if note && after_note {
+                runs.push((msg, score));
+            }
+            after_note = note;
+        }
+        removed += store.remove_many(room, &runs).await?;
+
+        if last {
+            break;
+        }
+    }
+
+    // Messages trimmed or expired above take their reactions with them
+    reactions::prune(store, room).await?;
+
+    let now = get_time_in_ms().to_string();
+    store.hash_set(&info_key(room), "compacted", &now).await?;
 
-    Ok(msg)
+    Ok(removed)
 }
 
-pub async fn recent_msgs(redis: &Client, room: &str) -> Result<Vec<String>, RoomError> {
-    let mut conn = redis.get_async_connection().await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToConnect
-    })?;
+// Stores the event in the room's history, and the chat log if there is one,
+// returning the formatted message. Anything older than `ephemeral` is removed
+// at the same time. While storage is down it's held in `ctx.pending` instead,
+// so it can still be sent live.
+pub async fn event(
+    ctx: &ServerContext,
+    event: RoomEvent,
+    room: &str,
+    username: &str,
+    ephemeral: Option<Duration>,
+) -> String {
+    record(ctx, event, room, username, ephemeral).await.0
+}
 
-    let key = gen_key(room);
+// As `event`, with the message's id too
+pub async fn record(
+    ctx: &ServerContext,
+    event: RoomEvent,
+    room: &str,
+    username: &str,
+    ephemeral: Option<Duration>,
+) -> (String, i64) {
+    let store = &*ctx.store;
+    let pending = &ctx.pending;
+    let retention = ctx.config.load().retention;
+    let ts = get_time_in_ms();
+    let user = username.to_owned();
+    let message = match event {
+        RoomEvent::Chat(text) => ServerMessage::Chat {
+            user,
+            text,
+            ts: Some(ts),
+        },
+        RoomEvent::Join => ServerMessage::Joined { user },
+        RoomEvent::Leave => ServerMessage::Left { user },
+        RoomEvent::Notice(text) => ServerMessage::Info { text },
+    };
+    // History is kept as it's sent to text connections
+    let msg = TextRenderer::text(message);
+    ctx.chat_log.room(room, &msg, ts);
 
-    let mut offset: u128 = conn.zcount(&key, 0, "inf").await.map_err(|e| {
-        dbg!(e);
-        RoomError::FailedToFetch
-    })?;
+    let stored = match expire(store, room, ephemeral).await {
+        Ok(()) => store.append(room, &msg, ts, retention).await,
+        Err(e) => Err(e),
+    };
+    match stored {
+        // Storage is back, so anything held can go too
+        Ok(()) if !pending.is_empty() => {
+            pending.flush(store).await;
+        }
+        Ok(()) => {}
+        Err(e) => {
+            warn!(
+                room,
+                "Holding a message until storage is back: {}",
+                e.to_string().trim_end()
+            );
+            pending.push(room, &msg, ts, retention);
+        }
+    }
+
+    (msg, ts)
+}
 
-    if offset < 10 {
-        offset = 0
-    } else {
-        offset -= 10
+async fn expire(
+    store: &dyn RoomStore,
+    room: &str,
+    ephemeral: Option<Duration>,
+) -> Result<(), StoreError> {
+    match ephemeral {
+        Some(ttl) => {
+            let before = get_time_in_ms() - ttl.as_millis() as i64;
+            store.expire(room, before).await.map(|_| ())
+        }
+        None => Ok(()),
     }
+}
 
-    let msgs: Vec<String> = conn
-        .zrangebyscore_limit(key, 0, "inf", offset as isize, 10)
-        .await
-        .map_err(|e| {
-            dbg!(e);
-            RoomError::FailedToFetch
-        })?;
+fn info_key(room: &str) -> String {
+    format!("roominfo:{}", room)
+}
 
-    Ok(msgs)
+fn mods_key(room: &str) -> String {
+    format!("roommods:{}", room)
 }
 
-fn gen_key(name: &str) -> String {
-    format!("room:{}", name)
+fn count_key(user: &str) -> String {
+    format!("roomcount:{}", user)
 }
 
-fn gen_chat(username: &str, message: &str) -> String {
-    format!("{}: {}\n", username, message)
+fn owns_key(user: &str) -> String {
+    format!("owns:{}", user)
 }
 
-fn gen_join_msg(username: &str) -> String {
-    format!("{} has joined the room\n", username)
+fn member_key(user: &str) -> String {
+    format!("memberof:{}", user)
+}
+
+fn tag_key(tag: &str) -> String {
+    format!("roomtag:{}", tag)
+}
+
+async fn untag(store: &dyn RoomStore, room: &str, tag: &str) -> Result<(), StoreError> {
+    if store.set_remove(&tag_key(tag), room).await? {
+        store.hash_incr(TAG_COUNTS_KEY, tag, -1).await?;
+    }
+
+    Ok(())
 }
 
-fn gen_leave_msg(username: &str) -> String {
-    format!("{} has left the room\n", username)
+fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.chars().count() <= MAX_TAG_LEN
+        && tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
 }
 
-fn get_time_in_ms() -> isize {
+pub fn get_time_in_ms() -> i64 {
     let start = SystemTime::now();
     let since_epoch = start.duration_since(UNIX_EPOCH).unwrap();
 
-    since_epoch.as_millis() as isize
+    since_epoch.as_millis() as i64
 }
@@ -0,0 +1,234 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time;
+use tracing::{error, info, warn};
+
+use crate::access::{self, AccessList};
+use crate::app::App;
+use crate::ban::{BanList, IpBan};
+use crate::broker::RoomMap;
+use crate::chatlog::ChatLog;
+use crate::command::CommandParser;
+use crate::config::SharedConfig;
+use crate::filter::{self, FilterError, WordFilter};
+use crate::metrics;
+use crate::pending::PendingWrites;
+use crate::proxy;
+use crate::registry::{ConnectionRegistry, Control};
+use crate::shutdown::{Shutdown, ShutdownTrigger};
+use crate::store::RoomStore;
+
+// How long connections get to close once they've been told to
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Everything the accept loops share, whichever address they're bound to
+pub struct ServerContext {
+    pub store: Arc<dyn RoomStore>,
+    pub rooms: RoomMap,
+    pub registry: Arc<ConnectionRegistry>,
+    pub proxy_protocol: bool,
+    pub config: SharedConfig,
+    pub shutdown: Arc<ShutdownTrigger>,
+    pub bans: BanList,
+    pub access: AccessList,
+    // Loaded from the `[runtime.filter]` config, see `reload_filter`
+    pub filter: ArcSwap<WordFilter>,
+    pub commands: CommandParser,
+    // Usernames muted for repeating themselves, until when
+    pub mutes: DashMap<String, Instant>,
+    // Messages sent while storage was down, to be saved once it's back
+    pub pending: PendingWrites,
+    // Disabled unless `[chat_log]` is configured
+    pub chat_log: ChatLog,
+    // When the server started, the `Instant` to measure uptime with and the
+    // `SystemTime` to show
+    pub started: Instant,
+    pub started_at: SystemTime,
+}
+
+impl ServerContext {
+    // Default settings and no rooms, for tests and embedding
+    pub fn new(store: Arc<dyn RoomStore>, shutdown: ShutdownTrigger) -> Self {
+        Self {
+            store,
+            rooms: Default::default(),
+            registry: Default::default(),
+            proxy_protocol: false,
+            config: Default::default(),
+            shutdown: Arc::new(shutdown),
+            bans: Default::default(),
+            access: Default::default(),
+            filter: Default::default(),
+            commands: Default::default(),
+            mutes: Default::default(),
+            pending: Default::default(),
+            chat_log: Default::default(),
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+        }
+    }
+
+    /// Warns everyone connected that the server's shutting down, waits out
+    /// the countdown `>shutdown` gave or `shutdown_countdown_secs`, then says
+    /// goodbye and closes every connection. Call it once shutdown's been triggered, so nothing new is
+    /// accepted and rooms can't be joined or created in the meantime.
+    pub async fn drain(&self) {
+        let request = self.shutdown.request();
+        let countdown = request
+            .countdown_secs
+            .unwrap_or_else(|| self.config.load().shutdown_countdown_secs);
+        if countdown > 0 && !self.registry.is_empty() {
+            let mut notice = format!(
+                "[SERVER] Shutting down in {} second{}",
+                countdown,
+                if countdown == 1 { "" } else { "s" },
+            );
+            if let Some(reason) = request.reason {
+                notice = format!("{}: {}", notice, reason);
+            }
+            self.registry.send_control_all(Control::Notice(notice));
+
+            time::sleep(Duration::from_secs(countdown)).await;
+        }
+
+        self.registry.send_control_all(Control::Goodbye);
+
+        // Giving them a moment to leave their rooms, but not forever
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while !self.registry.is_empty() && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        if !self.registry.is_empty() {
+            warn!(
+                "Shutting down with {} connection(s) still open",
+                self.registry.len()
+            );
+        }
+    }
+
+    // Whether `limits.max_connections` has been reached
+    pub fn is_full(&self) -> bool {
+        let max_connections = self.config.load().limits.max_connections;
+
+        max_connections.is_some_and(|max| self.registry.len() >= max)
+    }
+
+    // Re-reads the word list for the current config, returning how many
+    // words it has. The current list is kept if it can't be read.
+    pub async fn reload_filter(&self) -> Result<usize, FilterError> {
+        let config = &self.config.load().filter;
+        let words = filter::load(config, &*self.store).await?;
+        let len = words.len();
+
+        self.filter.store(Arc::new(words));
+
+        Ok(len)
+    }
+
+    /// Whether `addr` is let in by `[runtime.access]` along with the
+    /// `server:ipallow` and `server:ipdeny` sets, as `access::permits` decides.
+    /// Every listener checks it before anything else, and behind a proxy it's
+    /// the address from the PROXY header that's checked. Only the config's
+    /// lists apply while the sets can't be read.
+    pub async fn permits(&self, addr: IpAddr) -> bool {
+        let config = &self.config.load().access;
+
+        match self.access.permits(&*self.store, config, addr).await {
+            Ok(permitted) => permitted,
+            Err(e) => {
+                warn!(
+                    "Failed to check the access sets for {}: {}",
+                    addr,
+                    e.to_string().trim_end()
+                );
+                access::permits(&config.allow, &config.deny, addr)
+            }
+        }
+    }
+
+    // Lets connections through if the ban list can't be read, rather than
+    // locking everyone out while storage is down
+    pub async fn ban_for(&self, addr: IpAddr) -> Option<IpBan> {
+        match self.bans.find(&*self.store, addr).await {
+            Ok(ban) => ban,
+            Err(e) => {
+                warn!(
+                    "Failed to check bans for {}: {}",
+                    addr,
+                    e.to_string().trim_end()
+                );
+                None
+            }
+        }
+    }
+}
+
+pub async fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind {}: {}", addr, e)))
+}
+
+pub async fn listen(
+    listener: TcpListener,
+    ctx: Arc<ServerContext>,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    let local_addr = listener.local_addr()?.to_string();
+
+    loop {
+        let (mut stream, mut addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = shutdown.recv() => return Ok(()),
+        };
+
+        let ctx = Arc::clone(&ctx);
+        let local_addr = local_addr.clone();
+
+        tokio::spawn(async move {
+            if ctx.proxy_protocol {
+                match proxy::read_header(&mut stream).await {
+                    Ok(Some(source)) => addr = source,
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Dropping connection from {}: {}", addr, e);
+                        return;
+                    }
+                }
+            }
+
+            // After the PROXY header, so it's the client that's checked
+            if !ctx.permits(addr.ip()).await {
+                info!("Refused connection from {}, not allowed by access", addr);
+                let _ = stream
+                    .write_all(format!("{}\n", access::DENIED).as_bytes())
+                    .await;
+                return;
+            }
+
+            if let Some(ban) = ctx.ban_for(addr.ip()).await {
+                let _ = stream.write_all(ban.notice().as_bytes()).await;
+                return;
+            }
+
+            if ctx.is_full() {
+                let _ = stream.write_all(b"Server is full, try again later\n").await;
+                return;
+            }
+
+            let _connection = metrics::connection();
+            let conn = ConnectionRegistry::register(&ctx.registry, addr.to_string(), local_addr);
+            let app = App::new(stream, addr, conn, Arc::clone(&ctx));
+
+            if let Err(e) = app.run().await {
+                error!("{}", e)
+            };
+        });
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::registry::ConnId;
+use crate::room;
+use crate::store::{RoomStore, StoreError};
+
+// Reconnecting within this long puts the user back in their room without
+// telling it they'd gone
+pub const RESUME_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub username: String,
+    // Whether they'd logged in as the name, rather than just set it
+    pub authenticated: bool,
+    // The connection that was in the room, for a resumed one to take over
+    pub conn: Option<ConnId>,
+    // Where they were last, to rejoin on resume
+    pub room: Option<String>,
+    // When the connection dropped in ms, unset until the server notices
+    pub away: Option<i64>,
+}
+
+impl Session {
+    // Whether the room still thinks they're there, so can be rejoined quietly
+    pub fn still_in_room(&self) -> bool {
+        self.away
+            .is_none_or(|away| room::get_time_in_ms() - away < RESUME_GRACE.as_millis() as i64)
+    }
+}
+
+/// Issues a token for `>resume`, kept for `session_ttl_secs` from the last
+/// time it was touched. Each can be resumed once, giving a new one.
+pub async fn create(
+    store: &dyn RoomStore,
+    conn: ConnId,
+    username: &str,
+    authenticated: bool,
+    room: Option<&str>,
+    ttl: Duration,
+) -> Result<String, StoreError> {
+    let token = format!("{:032x}", rand::rng().random::<u128>());
+
+    let key = key(&token);
+    store.hash_set(&key, "username", username).await?;
+    store.hash_set(&key, "conn", &conn.to_string()).await?;
+    if authenticated {
+        store.hash_set(&key, "authenticated", "1").await?;
+    }
+    set_room(store, &token, room, ttl).await?;
+
+    Ok(token)
+}
+
+// Refreshes the TTL too
+pub async fn set_room(
+    store: &dyn RoomStore,
+    token: &str,
+    room: Option<&str>,
+    ttl: Duration,
+) -> Result<(), StoreError> {
+    let key = key(token);
+    match room {
+        Some(room) => store.hash_set(&key, "room", room).await?,
+        None => {
+            store.hash_remove(&key, "room").await?;
+        }
+    }
+
+    store.hash_expire(&key, ttl).await
+}
+
+// Returns false if the session's gone, eg it's been resumed already
+pub async fn set_away(store: &dyn RoomStore, token: &str) -> Result<bool, StoreError> {
+    let key = key(token);
+    if store.hash_get(&key, "username").await?.is_none() {
+        return Ok(false);
+    }
+
+    let now = room::get_time_in_ms().to_string();
+    store.hash_set(&key, "away", &now).await?;
+
+    Ok(true)
+}
+
+pub async fn find(store: &dyn RoomStore, token: &str) -> Result<Option<Session>, StoreError> {
+    let key = key(token);
+    let Some(username) = store.hash_get(&key, "username").await? else {
+        return Ok(None);
+    };
+
+    Ok(Some(Session {
+        username,
+        authenticated: store.hash_get(&key, "authenticated").await?.is_some(),
+        conn: store
+            .hash_get(&key, "conn")
+            .await?
+            .and_then(|conn| conn.parse().ok()),
+        room: store.hash_get(&key, "room").await?,
+        away: store
+            .hash_get(&key, "away")
+            .await?
+            .and_then(|away| away.parse().ok()),
+    }))
+}
+
+// Looks up the session and revokes it, so it can't be resumed twice
+pub async fn take(store: &dyn RoomStore, token: &str) -> Result<Option<Session>, StoreError> {
+    let session = find(store, token).await?;
+    if session.is_some() && !revoke(store, token).await? {
+        // Someone else got there first
+        return Ok(None);
+    }
+
+    Ok(session)
+}
+
+pub async fn revoke(store: &dyn RoomStore, token: &str) -> Result<bool, StoreError> {
+    store.hash_delete(&key(token)).await
+}
+
+fn key(token: &str) -> String {
+    format!("session:{}", token)
+}
@@ -0,0 +1,115 @@
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+
+// Held by whoever decides the server should stop (eg the ctrl-c handler)
+pub struct ShutdownTrigger {
+    tx: watch::Sender<bool>,
+    request: Mutex<ShutdownRequest>,
+}
+
+// Cloned into every long running task so they can stop together
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+/// What an admin gave with `>shutdown [seconds] [reason]`. Without a
+/// countdown the config's `shutdown_countdown_secs` is used.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShutdownRequest {
+    pub countdown_secs: Option<u64>,
+    pub reason: Option<String>,
+}
+
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (tx, rx) = watch::channel(false);
+    let request = Mutex::default();
+
+    (ShutdownTrigger { tx, request }, Shutdown { rx })
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    // Only the first request counts, a countdown can't be restarted
+    pub fn trigger_with(&self, request: ShutdownRequest) {
+        if !self.is_triggered() {
+            *self.request.lock().unwrap() = request;
+        }
+        self.trigger();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    pub fn request(&self) -> ShutdownRequest {
+        self.request.lock().unwrap().clone()
+    }
+}
+
+impl ShutdownRequest {
+    /// A leading number is the countdown, anything else is the reason.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::shutdown::ShutdownRequest;
+    ///
+    /// let request = ShutdownRequest::parse("60 upgrading redis");
+    /// assert_eq!(request.countdown_secs, Some(60));
+    /// assert_eq!(request.reason.as_deref(), Some("upgrading redis"));
+    /// assert_eq!(request.to_string(), "60 upgrading redis");
+    ///
+    /// let request = ShutdownRequest::parse("upgrading redis");
+    /// assert_eq!(request.countdown_secs, None);
+    /// assert_eq!(request.reason.as_deref(), Some("upgrading redis"));
+    ///
+    /// assert_eq!(ShutdownRequest::parse("0").countdown_secs, Some(0));
+    /// assert_eq!(ShutdownRequest::parse(""), ShutdownRequest::default());
+    /// ```
+    pub fn parse(args: &str) -> Self {
+        let args = args.trim();
+        let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+
+        let (countdown_secs, reason) = match first.parse() {
+            Ok(secs) => (Some(secs), rest.trim()),
+            Err(_) => (None, args),
+        };
+
+        Self {
+            countdown_secs,
+            reason: (!reason.is_empty()).then(|| reason.to_owned()),
+        }
+    }
+}
+
+// As typed after `>shutdown`
+impl std::fmt::Display for ShutdownRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.countdown_secs, &self.reason) {
+            (Some(secs), Some(reason)) => write!(f, "{} {}", secs, reason),
+            (Some(secs), None) => write!(f, "{}", secs),
+            (None, Some(reason)) => write!(f, "{}", reason),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn is_shutdown(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    // Resolves once shutdown has been triggered, or the trigger was dropped
+    pub async fn recv(&mut self) {
+        while !*self.rx.borrow_and_update() {
+            if self.rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
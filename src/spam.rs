@@ -0,0 +1,101 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+// Messages remembered at most, however many fall within the window
+const MAX_TRACKED: usize = 50;
+
+// How long someone who keeps repeating themselves is muted for
+pub const MUTE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Repeat {
+    Fresh,
+    // Sent more than the limit within the window
+    Suppressed,
+    // Suppressed as many times again, so it's time for a mute
+    Abuse,
+}
+
+// One user's recent messages in a room, as hashes, so copy-paste spam can be
+// told apart from someone saying "ok" now and then
+#[derive(Default)]
+pub struct Repeats {
+    recent: VecDeque<(u64, Instant)>,
+    suppressed: VecDeque<Instant>,
+}
+
+impl Repeats {
+    /// Records the message unless it's been sent `limit` times within the
+    /// window already. Case and surrounding whitespace are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    ///
+    /// use chatsapp::spam::{Repeat, Repeats};
+    ///
+    /// let window = Duration::from_secs(60);
+    /// let start = Instant::now();
+    /// let at = |secs| start + Duration::from_secs(secs);
+    ///
+    /// // Someone saying "ok" every half a minute is never held back
+    /// let mut innocent = Repeats::default();
+    /// for i in 0..10 {
+    ///     assert_eq!(innocent.check("ok", 3, window, at(i * 30)), Repeat::Fresh);
+    /// }
+    ///
+    /// // A spammer gets 3 through, then is suppressed, then muted
+    /// let mut spammer = Repeats::default();
+    /// let verdicts: Vec<_> = (0..6)
+    ///     .map(|i| spammer.check("BUY NOW ", 3, window, at(i)))
+    ///     .collect();
+    /// assert_eq!(&verdicts[..3], [Repeat::Fresh; 3]);
+    /// assert_eq!(&verdicts[3..5], [Repeat::Suppressed; 2]);
+    /// assert_eq!(verdicts[5], Repeat::Abuse);
+    ///
+    /// // Something else is fine, and so is the same thing once the window's passed
+    /// assert_eq!(spammer.check("sorry", 3, window, at(6)), Repeat::Fresh);
+    /// assert_eq!(spammer.check("buy now", 3, window, at(61)), Repeat::Fresh);
+    ///
+    /// // A limit of 0 disables it
+    /// assert_eq!(spammer.check("buy now", 0, window, at(62)), Repeat::Fresh);
+    /// ```
+    pub fn check(&mut self, text: &str, limit: u32, window: Duration, now: Instant) -> Repeat {
+        if limit == 0 {
+            return Repeat::Fresh;
+        }
+
+        let expired = |at: &Instant| now.saturating_duration_since(*at) >= window;
+        while self.recent.front().is_some_and(|(_, at)| expired(at)) {
+            self.recent.pop_front();
+        }
+        while self.suppressed.front().is_some_and(expired) {
+            self.suppressed.pop_front();
+        }
+
+        let mut hasher = DefaultHasher::new();
+        text.trim().to_lowercase().hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let sent = self.recent.iter().filter(|(h, _)| *h == hash).count();
+        if sent >= limit as usize {
+            self.suppressed.push_back(now);
+            if self.suppressed.len() >= limit as usize {
+                self.suppressed.clear();
+                return Repeat::Abuse;
+            }
+
+            return Repeat::Suppressed;
+        }
+
+        if self.recent.len() >= MAX_TRACKED {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((hash, now));
+
+        Repeat::Fresh
+    }
+}
@@ -0,0 +1,968 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::task;
+use tracing::error;
+
+use crate::client::ServerEvent;
+use crate::room::get_time_in_ms;
+use crate::store::{RoomMeta, RoomStore, StoreError};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+
+    CREATE TABLE IF NOT EXISTS rooms (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+
+    -- `text` is the line as it was stored, `user` and `kind` are read from it.
+    -- Removed messages keep their place as `deleted`, `user` being who
+    -- removed them, whoever sent them if it was with `>undo`.
+    CREATE TABLE IF NOT EXISTS messages (
+        room_id INTEGER NOT NULL REFERENCES rooms (id),
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        ts INTEGER NOT NULL,
+        user TEXT,
+        kind TEXT NOT NULL,
+        text TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_room_seq ON messages (room_id, seq);
+    CREATE INDEX IF NOT EXISTS messages_room_ts ON messages (room_id, ts);
+
+    CREATE TABLE IF NOT EXISTS sets (
+        key TEXT NOT NULL,
+        member TEXT NOT NULL,
+        PRIMARY KEY (key, member)
+    );
+
+    CREATE TABLE IF NOT EXISTS hashes (
+        key TEXT NOT NULL,
+        field TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (key, field)
+    );
+
+    -- When each hash goes, in ms since the epoch
+    CREATE TABLE IF NOT EXISTS hash_expiries (
+        key TEXT PRIMARY KEY,
+        at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS lists (
+        key TEXT NOT NULL,
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        item TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS lists_key_seq ON lists (key, seq);
+
+    CREATE TABLE IF NOT EXISTS sorted (
+        key TEXT NOT NULL,
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        score INTEGER NOT NULL,
+        member TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS sorted_key_seq ON sorted (key, seq);
+";
+
+/// Keeps everything in a SQLite file, for running without Redis. Queries run
+/// on the blocking pool, one at a time over a single connection, so
+/// brokers writing at once can't interleave. Messages are ordered by the
+/// `seq` they were written in rather than by their timestamps.
+///
+/// # Examples
+///
+/// Writers racing each other each get their own place in the room:
+///
+/// ```
+/// # #[tokio::main]
+/// # async fn main() {
+/// use std::sync::Arc;
+///
+/// use chatsapp::sqlite::SqliteStore;
+/// use chatsapp::store::RoomStore;
+///
+/// let store = Arc::new(SqliteStore::open(":memory:").unwrap());
+/// assert!(store.create("rust").await.unwrap());
+/// assert!(!store.create("rust").await.unwrap());
+///
+/// // The same ms for everyone, order comes from `seq`
+/// let mut writers = Vec::new();
+/// for writer in 0..4 {
+///     let store = Arc::clone(&store);
+///     writers.push(tokio::spawn(async move {
+///         for n in 0..25 {
+///             let msg = format!("w{}: {}\n", writer, n);
+///             store.append("rust", &msg, 1000, Some(50)).await.unwrap();
+///         }
+///     }));
+/// }
+/// for writer in writers {
+///     writer.await.unwrap();
+/// }
+///
+/// let history = store.recent("rust", 100).await.unwrap();
+/// assert_eq!(history.len(), 50);
+/// for writer in 0..4 {
+///     let mine: Vec<usize> = history
+///         .iter()
+///         .filter_map(|msg| msg.strip_prefix(&format!("w{}: ", writer)))
+///         .map(|n| n.trim_end().parse().unwrap())
+///         .collect();
+///     assert!(mine.windows(2).all(|pair| pair[0] + 1 == pair[1]));
+/// }
+/// let meta = store.meta("rust").await.unwrap().unwrap();
+/// assert_eq!((meta.messages, meta.last_activity), (50, 1000));
+///
+/// // Paging and expiry go by the timestamp
+/// store.append("rust", "bob: later\n", 2000, None).await.unwrap();
+/// let page = store.recent_before("rust", Some(2000), 1).await.unwrap();
+/// assert_eq!(page[0].1, 1000);
+/// store.expire("rust", 1500).await.unwrap();
+/// assert_eq!(store.recent("rust", 100).await.unwrap(), ["bob: later\n"]);
+///
+/// // Removed messages keep their place
+/// let note = "message removed by carol\n";
+/// assert!(store.replace("rust", 2000, "bob: later\n", note).await.unwrap());
+/// assert!(!store.replace("rust", 2000, "bob: later\n", note).await.unwrap());
+/// assert_eq!(store.recent_before("rust", None, 1).await.unwrap(), [(note.to_owned(), 2000)]);
+///
+/// // Compaction removes them outright
+/// store.append_many("rust", &[("bob: a\n".into(), 3000), ("bob: b\n".into(), 4000)], None).await.unwrap();
+/// assert_eq!(store.remove_many("rust", &[(note.to_owned(), 2000), (note.to_owned(), 2000)]).await.unwrap(), 1);
+/// assert_eq!(store.trim("rust", 1).await.unwrap(), 1);
+/// assert_eq!(store.recent("rust", 100).await.unwrap(), ["bob: b\n"]);
+///
+/// assert!(store.append_recent("go", "bob has joined the room\n", 1, None, 10).await.unwrap().is_none());
+/// assert_eq!(store.list().await.unwrap(), ["rust"]);
+/// assert!(store.delete("rust").await.unwrap());
+/// assert!(store.meta("rust").await.unwrap().is_none());
+///
+/// // And the plain structures behave as they do in Redis
+/// assert!(store.set_add("server:admins", "alice").await.unwrap());
+/// assert!(!store.set_add("server:admins", "alice").await.unwrap());
+/// assert_eq!(store.set_members("server:admins").await.unwrap(), ["alice"]);
+/// assert_eq!(store.hash_incr("count:alice", "rooms", 2).await.unwrap(), 2);
+/// store.hash_set("count:alice", "name", "alice").await.unwrap();
+/// assert!(store.hash_incr("count:alice", "name", 1).await.is_err());
+/// assert!(store.hash_remove("count:alice", "rooms").await.unwrap());
+/// assert!(store.hash_remove("count:alice", "name").await.unwrap());
+/// assert!(!store.hash_delete("count:alice").await.unwrap());
+/// for n in 0..5 {
+///     store.list_push("server:auditlog", &n.to_string(), 3).await.unwrap();
+/// }
+/// assert_eq!(store.list_recent("server:auditlog", 2).await.unwrap(), ["3", "4"]);
+/// assert_eq!(store.list_take("server:auditlog").await.unwrap(), ["2", "3", "4"]);
+/// assert_eq!(store.list_len("server:auditlog").await.unwrap(), 0);
+/// # }
+/// ```
+///
+/// Backups move rooms between backends, messages that share a ms keeping
+/// their order:
+///
+/// ```
+/// # #[tokio::main]
+/// # async fn main() {
+/// use chatsapp::backup::{self, RestoreMode};
+/// use chatsapp::sqlite::SqliteStore;
+/// use chatsapp::store::RoomStore;
+///
+/// let from = SqliteStore::open(":memory:").unwrap();
+/// from.create("rust").await.unwrap();
+/// // More than a page in each ms
+/// let msgs: Vec<(String, i64)> = (0..1200).map(|n| (format!("bob: {}\n", n), 1000 + n / 700)).collect();
+/// from.append_many("rust", &msgs, None).await.unwrap();
+///
+/// let mut dump = vec![];
+/// backup::backup(&from, &mut dump).await.unwrap();
+/// let to = SqliteStore::open(":memory:").unwrap();
+/// let summary = backup::restore(&to, &dump[..], RestoreMode::Skip).await.unwrap();
+/// assert_eq!(summary.to_string(), "1 room(s), 1201 message(s)");
+/// let history = from.recent_before("rust", None, 2000).await.unwrap();
+/// assert_eq!(to.recent_before("rust", None, 2000).await.unwrap(), history);
+/// # }
+/// ```
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    // Creates the file and tables if need be, `:memory:` keeps nothing
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let conn = Connection::open(path).map_err(|e| {
+            error!("{}", e);
+            StoreError::Unavailable
+        })?;
+
+        conn.execute_batch(SCHEMA).map_err(|e| {
+            error!("{}", e);
+            StoreError::Unavailable
+        })?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    async fn read<T, F>(&self, query: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        self.run(query, StoreError::Read).await
+    }
+
+    async fn write<T, F>(&self, query: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        self.run(query, StoreError::Write).await
+    }
+
+    // Off the async threads, since SQLite blocks on the disk
+    async fn run<T, F>(&self, query: F, failed: StoreError) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+
+        let res = task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            query(&mut conn)
+        })
+        .await
+        .map_err(|_| StoreError::Unavailable)?;
+
+        res.map_err(|e| {
+            error!("{}", e);
+            failed
+        })
+    }
+}
+
+fn room_id(conn: &Connection, room: &str) -> rusqlite::Result<Option<i64>> {
+    conn.query_row("SELECT id FROM rooms WHERE name = ?1", [room], |row| {
+        row.get(0)
+    })
+    .optional()
+}
+
+// Like a `ZADD`, adding to a room that doesn't exist creates it
+fn room_id_or_create(conn: &Connection, room: &str) -> rusqlite::Result<i64> {
+    conn.execute("INSERT OR IGNORE INTO rooms (name) VALUES (?1)", [room])?;
+
+    conn.query_row("SELECT id FROM rooms WHERE name = ?1", [room], |row| {
+        row.get(0)
+    })
+}
+
+fn insert_message(
+    conn: &Connection,
+    room_id: i64,
+    msg: &str,
+    ts: i64,
+    retention: Option<usize>,
+) -> rusqlite::Result<()> {
+    let (kind, user) = kind(msg);
+
+    conn.execute(
+        "INSERT INTO messages (room_id, ts, user, kind, text) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![room_id, ts, user, kind, msg],
+    )?;
+
+    if let Some(retention) = retention {
+        conn.execute(
+            "DELETE FROM messages WHERE room_id = ?1 AND seq <= (
+                SELECT seq FROM messages WHERE room_id = ?1 ORDER BY seq DESC LIMIT 1 OFFSET ?2
+            )",
+            params![room_id, retention as i64],
+        )?;
+    }
+
+    Ok(())
+}
+
+// <Kind, User> for the `messages` table
+fn kind(msg: &str) -> (&'static str, Option<String>) {
+    match ServerEvent::parse(msg.trim_end_matches('\n')) {
+        ServerEvent::Chat { user, .. } => ("chat", Some(user)),
+        ServerEvent::Joined(user) => ("joined", Some(user)),
+        ServerEvent::Left(user) => ("left", Some(user)),
+        ServerEvent::Removed(by) | ServerEvent::Retracted(by) => ("deleted", Some(by)),
+        _ => ("notice", None),
+    }
+}
+
+// The last `count`, oldest first
+// <Message, Score>, oldest first
+fn last_messages(
+    conn: &Connection,
+    room_id: i64,
+    count: usize,
+) -> rusqlite::Result<Vec<(String, i64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT text, ts FROM (
+            SELECT seq, ts, text FROM messages WHERE room_id = ?1 ORDER BY seq DESC LIMIT ?2
+        ) ORDER BY seq",
+    )?;
+    let msgs = stmt.query_map(params![room_id, count as i64], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+
+    msgs.collect()
+}
+
+// Hashes past their TTL are dropped whenever any are looked at
+fn drop_expired(conn: &Connection) -> rusqlite::Result<()> {
+    let now = get_time_in_ms();
+
+    conn.execute(
+        "DELETE FROM hashes WHERE key IN (SELECT key FROM hash_expiries WHERE at <= ?1)",
+        [now],
+    )?;
+    conn.execute("DELETE FROM hash_expiries WHERE at <= ?1", [now])?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl RoomStore for SqliteStore {
+    async fn ping(&self) -> Result<(), StoreError> {
+        self.read(|conn| conn.query_row("SELECT 1", [], |_| Ok(())))
+            .await
+    }
+
+    async fn create(&self, room: &str) -> Result<bool, StoreError> {
+        let room = room.to_owned();
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            if room_id(&tx, &room)?.is_some() {
+                return Ok(false);
+            }
+
+            let id = room_id_or_create(&tx, &room)?;
+            insert_message(&tx, id, "Start of chat\n", 0, None)?;
+            tx.commit()?;
+
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn append(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let (room, msg) = (room.to_owned(), msg.to_owned());
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let id = room_id_or_create(&tx, &room)?;
+            insert_message(&tx, id, &msg, score, retention)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn append_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let (room, msgs) = (room.to_owned(), msgs.to_vec());
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let id = room_id_or_create(&tx, &room)?;
+            for (msg, score) in &msgs {
+                insert_message(&tx, id, msg, *score, retention)?;
+            }
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn recent(&self, room: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        let room = room.to_owned();
+
+        self.read(move |conn| match room_id(conn, &room)? {
+            Some(id) => Ok(last_messages(conn, id, count)?
+                .into_iter()
+                .map(|(msg, _)| msg)
+                .collect()),
+            None => Ok(vec![]),
+        })
+        .await
+    }
+
+    async fn recent_before(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        count: usize,
+    ) -> Result<Vec<(String, i64)>, StoreError> {
+        let room = room.to_owned();
+
+        self.read(move |conn| {
+            let Some(id) = room_id(conn, &room)? else {
+                return Ok(vec![]);
+            };
+
+            let mut stmt = conn.prepare_cached(
+                "SELECT text, ts FROM (
+                    SELECT seq, text, ts FROM messages WHERE room_id = ?1 AND ts < ?2
+                    ORDER BY seq DESC LIMIT ?3
+                ) ORDER BY seq",
+            )?;
+            let before = before.unwrap_or(i64::MAX);
+            let page = stmt.query_map(params![id, before, count as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+
+            page.collect()
+        })
+        .await
+    }
+
+    async fn expire(&self, room: &str, before: i64) -> Result<usize, StoreError> {
+        let room = room.to_owned();
+
+        self.write(move |conn| {
+            conn.execute(
+                "DELETE FROM messages
+                WHERE room_id = (SELECT id FROM rooms WHERE name = ?1) AND ts >= 1 AND ts < ?2",
+                params![room, before],
+            )
+        })
+        .await
+    }
+
+    async fn trim(&self, room: &str, keep: usize) -> Result<usize, StoreError> {
+        let room = room.to_owned();
+
+        self.write(move |conn| {
+            conn.execute(
+                "DELETE FROM messages WHERE room_id = (SELECT id FROM rooms WHERE name = ?1) AND seq <= (
+                    SELECT seq FROM messages WHERE room_id = (SELECT id FROM rooms WHERE name = ?1)
+                    ORDER BY seq DESC LIMIT 1 OFFSET ?2
+                )",
+                params![room, keep as i64],
+            )
+        })
+        .await
+    }
+
+    async fn remove_many(&self, room: &str, msgs: &[(String, i64)]) -> Result<usize, StoreError> {
+        let (room, msgs) = (room.to_owned(), msgs.to_vec());
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let mut removed = 0;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "DELETE FROM messages WHERE seq = (
+                        SELECT seq FROM messages
+                        WHERE room_id = (SELECT id FROM rooms WHERE name = ?1) AND ts = ?2 AND text = ?3
+                        ORDER BY seq LIMIT 1
+                    )",
+                )?;
+                for (msg, score) in &msgs {
+                    removed += stmt.execute(params![room, score, msg])?;
+                }
+            }
+            tx.commit()?;
+
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn replace(
+        &self,
+        room: &str,
+        score: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<bool, StoreError> {
+        let msgs = [(old.to_owned(), score)];
+
+        Ok(self.replace_many(room, &msgs, new).await? > 0)
+    }
+
+    async fn replace_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        new: &str,
+    ) -> Result<usize, StoreError> {
+        let (room, msgs, new) = (room.to_owned(), msgs.to_vec(), new.to_owned());
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let (kind, user) = kind(&new);
+            let mut replaced = 0;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "UPDATE messages SET kind = ?1, user = ?2, text = ?3 WHERE seq = (
+                        SELECT seq FROM messages
+                        WHERE room_id = (SELECT id FROM rooms WHERE name = ?4) AND ts = ?5 AND text = ?6
+                        ORDER BY seq LIMIT 1
+                    )",
+                )?;
+                for (old, score) in &msgs {
+                    replaced += stmt.execute(params![kind, user, new, room, score, old])?;
+                }
+            }
+            tx.commit()?;
+
+            Ok(replaced)
+        })
+        .await
+    }
+
+    async fn append_recent(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+        count: usize,
+    ) -> Result<Option<Vec<(String, i64)>>, StoreError> {
+        let (room, msg) = (room.to_owned(), msg.to_owned());
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let Some(id) = room_id(&tx, &room)? else {
+                return Ok(None);
+            };
+
+            insert_message(&tx, id, &msg, score, retention)?;
+            let msgs = last_messages(&tx, id, count)?;
+            tx.commit()?;
+
+            Ok(Some(msgs))
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StoreError> {
+        self.read(|conn| {
+            let mut stmt = conn.prepare_cached("SELECT name FROM rooms ORDER BY name")?;
+            let rooms = stmt.query_map([], |row| row.get(0))?;
+
+            rooms.collect()
+        })
+        .await
+    }
+
+    async fn delete(&self, room: &str) -> Result<bool, StoreError> {
+        let room = room.to_owned();
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            let Some(id) = room_id(&tx, &room)? else {
+                return Ok(false);
+            };
+
+            tx.execute("DELETE FROM messages WHERE room_id = ?1", [id])?;
+            tx.execute("DELETE FROM rooms WHERE id = ?1", [id])?;
+            tx.commit()?;
+
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn meta(&self, room: &str) -> Result<Option<RoomMeta>, StoreError> {
+        let room = room.to_owned();
+
+        self.read(move |conn| {
+            let Some(id) = room_id(conn, &room)? else {
+                return Ok(None);
+            };
+
+            conn.query_row(
+                "SELECT COUNT(*), (SELECT ts FROM messages WHERE room_id = ?1 ORDER BY seq DESC LIMIT 1)
+                FROM messages WHERE room_id = ?1",
+                [id],
+                |row| {
+                    let messages: i64 = row.get(0)?;
+                    let newest: Option<i64> = row.get(1)?;
+
+                    Ok(newest.map(|last_activity| RoomMeta {
+                        messages: messages as usize,
+                        last_activity,
+                    }))
+                },
+            )
+        })
+        .await
+    }
+
+    async fn set_add(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let (key, member) = (key.to_owned(), member.to_owned());
+
+        self.write(move |conn| {
+            let added = conn.execute(
+                "INSERT OR IGNORE INTO sets (key, member) VALUES (?1, ?2)",
+                [key, member],
+            )?;
+
+            Ok(added == 1)
+        })
+        .await
+    }
+
+    async fn set_remove(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let (key, member) = (key.to_owned(), member.to_owned());
+
+        self.write(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM sets WHERE key = ?1 AND member = ?2",
+                [key, member],
+            )?;
+
+            Ok(removed == 1)
+        })
+        .await
+    }
+
+    async fn set_contains(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let (key, member) = (key.to_owned(), member.to_owned());
+
+        self.read(move |conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM sets WHERE key = ?1 AND member = ?2)",
+                [key, member],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let key = key.to_owned();
+
+        self.read(move |conn| {
+            let mut stmt =
+                conn.prepare_cached("SELECT member FROM sets WHERE key = ?1 ORDER BY member")?;
+            let members = stmt.query_map([key], |row| row.get(0))?;
+
+            members.collect()
+        })
+        .await
+    }
+
+    async fn set_delete(&self, key: &str) -> Result<bool, StoreError> {
+        let key = key.to_owned();
+
+        self.write(move |conn| {
+            let removed = conn.execute("DELETE FROM sets WHERE key = ?1", [key])?;
+
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn hash_set(&self, key: &str, field: &str, value: &str) -> Result<(), StoreError> {
+        let (key, field, value) = (key.to_owned(), field.to_owned(), value.to_owned());
+
+        self.write(move |conn| {
+            drop_expired(conn)?;
+            conn.execute(
+                "INSERT INTO hashes (key, field, value) VALUES (?1, ?2, ?3)
+                ON CONFLICT (key, field) DO UPDATE SET value = excluded.value",
+                [key, field, value],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn hash_set_move(
+        &self,
+        key: &str,
+        field: &str,
+        value: &str,
+        from: &str,
+        to: &str,
+        member: &str,
+    ) -> Result<(), StoreError> {
+        let (key, field, value) = (key.to_owned(), field.to_owned(), value.to_owned());
+        let (from, to, member) = (from.to_owned(), to.to_owned(), member.to_owned());
+
+        self.write(move |conn| {
+            drop_expired(conn)?;
+            let tx = conn.transaction()?;
+
+            tx.execute(
+                "INSERT INTO hashes (key, field, value) VALUES (?1, ?2, ?3)
+                ON CONFLICT (key, field) DO UPDATE SET value = excluded.value",
+                [key, field, value],
+            )?;
+            tx.execute(
+                "DELETE FROM sets WHERE key = ?1 AND member = ?2",
+                [&from, &member],
+            )?;
+            tx.execute(
+                "INSERT OR IGNORE INTO sets (key, member) VALUES (?1, ?2)",
+                [&to, &member],
+            )?;
+
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        let (key, field) = (key.to_owned(), field.to_owned());
+
+        self.write(move |conn| {
+            drop_expired(conn)?;
+            conn.query_row(
+                "SELECT value FROM hashes WHERE key = ?1 AND field = ?2",
+                [key, field],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn hash_delete(&self, key: &str) -> Result<bool, StoreError> {
+        let key = key.to_owned();
+
+        self.write(move |conn| {
+            drop_expired(conn)?;
+            let removed = conn.execute("DELETE FROM hashes WHERE key = ?1", [&key])?;
+            conn.execute("DELETE FROM hash_expiries WHERE key = ?1", [&key])?;
+
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn hash_incr(&self, key: &str, field: &str, by: i64) -> Result<i64, StoreError> {
+        let (key, field) = (key.to_owned(), field.to_owned());
+
+        self.write(move |conn| {
+            drop_expired(conn)?;
+            let tx = conn.transaction()?;
+
+            let current: Option<String> = tx
+                .query_row(
+                    "SELECT value FROM hashes WHERE key = ?1 AND field = ?2",
+                    [&key, &field],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            // Redis refuses to increment anything that isn't an integer
+            let current = match current.as_deref().map(str::parse::<i64>) {
+                None => 0,
+                Some(Ok(current)) => current,
+                Some(Err(_)) => {
+                    let reason = format!("{} {} is not an integer", key, field);
+                    return Err(rusqlite::Error::FromSqlConversionFailure(
+                        0,
+                        Type::Text,
+                        reason.into(),
+                    ));
+                }
+            };
+
+            tx.execute(
+                "INSERT INTO hashes (key, field, value) VALUES (?1, ?2, ?3)
+                ON CONFLICT (key, field) DO UPDATE SET value = excluded.value",
+                params![key, field, (current + by).to_string()],
+            )?;
+            tx.commit()?;
+
+            Ok(current + by)
+        })
+        .await
+    }
+
+    async fn hash_get_all(&self, key: &str) -> Result<Vec<(String, String)>, StoreError> {
+        let key = key.to_owned();
+
+        self.write(move |conn| {
+            drop_expired(conn)?;
+            let mut stmt = conn.prepare_cached("SELECT field, value FROM hashes WHERE key = ?1")?;
+            let fields = stmt.query_map([key], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+            fields.collect()
+        })
+        .await
+    }
+
+    async fn hash_remove(&self, key: &str, field: &str) -> Result<bool, StoreError> {
+        let (key, field) = (key.to_owned(), field.to_owned());
+
+        self.write(move |conn| {
+            drop_expired(conn)?;
+            let removed = conn.execute(
+                "DELETE FROM hashes WHERE key = ?1 AND field = ?2",
+                [&key, &field],
+            )?;
+
+            // Like Redis, a hash goes away with its last field
+            conn.execute(
+                "DELETE FROM hash_expiries
+                WHERE key = ?1 AND NOT EXISTS (SELECT 1 FROM hashes WHERE key = ?1)",
+                [&key],
+            )?;
+
+            Ok(removed == 1)
+        })
+        .await
+    }
+
+    async fn hash_expire(&self, key: &str, ttl: Duration) -> Result<(), StoreError> {
+        let key = key.to_owned();
+        let at = get_time_in_ms() + ttl.as_millis() as i64;
+
+        self.write(move |conn| {
+            drop_expired(conn)?;
+
+            // Like Redis, there's nothing to expire until the hash exists
+            conn.execute(
+                "INSERT INTO hash_expiries (key, at)
+                SELECT ?1, ?2 WHERE EXISTS (SELECT 1 FROM hashes WHERE key = ?1)
+                ON CONFLICT (key) DO UPDATE SET at = excluded.at",
+                params![key, at],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_push(&self, key: &str, item: &str, cap: usize) -> Result<(), StoreError> {
+        let (key, item) = (key.to_owned(), item.to_owned());
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO lists (key, item) VALUES (?1, ?2)",
+                [&key, &item],
+            )?;
+            tx.execute(
+                "DELETE FROM lists WHERE key = ?1 AND seq <= (
+                    SELECT seq FROM lists WHERE key = ?1 ORDER BY seq DESC LIMIT 1 OFFSET ?2
+                )",
+                params![key, cap as i64],
+            )?;
+
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn list_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        let key = key.to_owned();
+
+        self.read(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT item FROM (
+                    SELECT seq, item FROM lists WHERE key = ?1 ORDER BY seq DESC LIMIT ?2
+                ) ORDER BY seq",
+            )?;
+            let items = stmt.query_map(params![key, count as i64], |row| row.get(0))?;
+
+            items.collect()
+        })
+        .await
+    }
+
+    async fn list_len(&self, key: &str) -> Result<usize, StoreError> {
+        let key = key.to_owned();
+
+        self.read(move |conn| {
+            let len: i64 =
+                conn.query_row("SELECT COUNT(*) FROM lists WHERE key = ?1", [key], |row| {
+                    row.get(0)
+                })?;
+
+            Ok(len as usize)
+        })
+        .await
+    }
+
+    async fn list_take(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let key = key.to_owned();
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+
+            let items = {
+                let mut stmt =
+                    tx.prepare_cached("SELECT item FROM lists WHERE key = ?1 ORDER BY seq")?;
+                let items = stmt.query_map([&key], |row| row.get(0))?;
+                items.collect::<rusqlite::Result<Vec<String>>>()?
+            };
+            tx.execute("DELETE FROM lists WHERE key = ?1", [&key])?;
+            tx.commit()?;
+
+            Ok(items)
+        })
+        .await
+    }
+
+    async fn sorted_add(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let (key, member) = (key.to_owned(), member.to_owned());
+
+        self.write(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO sorted (key, score, member) VALUES (?1, ?2, ?3)",
+                params![key, score, member],
+            )?;
+            if let Some(retention) = retention {
+                tx.execute(
+                    "DELETE FROM sorted WHERE key = ?1 AND seq <= (
+                        SELECT seq FROM sorted WHERE key = ?1 ORDER BY seq DESC LIMIT 1 OFFSET ?2
+                    )",
+                    params![key, retention as i64],
+                )?;
+            }
+
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn sorted_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        let key = key.to_owned();
+
+        self.read(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT member FROM (
+                    SELECT seq, member FROM sorted WHERE key = ?1 ORDER BY seq DESC LIMIT ?2
+                ) ORDER BY seq",
+            )?;
+            let members = stmt.query_map(params![key, count as i64], |row| row.get(0))?;
+
+            members.collect()
+        })
+        .await
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use prometheus::proto::{MetricFamily, MetricType};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::config::{StatsdConfig, StatsdFlavor};
+use crate::metrics::metrics;
+use crate::shutdown::Shutdown;
+
+// Timings waiting to be sent, past this they're dropped rather than hold up
+// chat
+const QUEUE: usize = 10_000;
+
+// Keeps datagrams under a typical MTU so they aren't fragmented
+pub const MAX_DATAGRAM: usize = 1432;
+
+// Where timings are sent from chat paths, see `Metrics::observe_redis`
+pub struct Sink {
+    tx: Sender<Timing>,
+}
+
+struct Timing {
+    name: &'static str,
+    // <Label, Value>, as the Prometheus histogram has it
+    tag: (&'static str, String),
+    ms: f64,
+}
+
+impl Sink {
+    pub fn timing(&self, name: &'static str, tag: (&'static str, &str), elapsed: Duration) {
+        let timing = Timing {
+            name,
+            tag: (tag.0, tag.1.to_owned()),
+            ms: elapsed.as_secs_f64() * 1000.0,
+        };
+
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(timing) {
+            metrics().statsd_dropped.inc();
+        }
+    }
+}
+
+/// Sends metrics to StatsD over UDP until shutdown. Every `flush_ms`
+/// counters go as what they've gone up by since the last flush, gauges as
+/// they stand and Redis and command timings as they were taken, all named
+/// as in Prometheus (timings in ms, eg `chatsapp_redis_op_ms`). At most
+/// `max_packets` datagrams go each flush, anything past them is dropped and
+/// counted in `chatsapp_statsd_dropped_total`.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::time::Instant;
+///
+/// use chatsapp::config::StatsdConfig;
+/// use chatsapp::metrics::{self, metrics};
+/// use chatsapp::{shutdown, statsd};
+/// use tokio::net::UdpSocket;
+///
+/// let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+/// let config = StatsdConfig {
+///     addr: Some(socket.local_addr().unwrap()),
+///     flush_ms: 10,
+///     ..Default::default()
+/// };
+/// let (_trigger, shutdown) = shutdown::channel();
+/// statsd::start(&config, shutdown).await.unwrap();
+///
+/// async fn receive(socket: &UdpSocket, until: &str) -> Vec<String> {
+///     let mut lines = Vec::new();
+///     let mut buf = [0; statsd::MAX_DATAGRAM];
+///     while !lines.iter().any(|line: &String| line.starts_with(until)) {
+///         let n = socket.recv(&mut buf).await.unwrap();
+///         let packet = std::str::from_utf8(&buf[..n]).unwrap();
+///         lines.extend(packet.lines().map(str::to_owned));
+///     }
+///     lines
+/// }
+///
+/// metrics().messages_relayed.inc_by(3);
+/// metrics().commands.with_label_values(&["help"]).inc();
+/// let _conn = metrics::connection();
+/// metrics().observe_redis("zadd", Instant::now());
+///
+/// let lines = receive(&socket, "chatsapp_redis_op_ms:").await;
+/// assert!(lines.contains(&"chatsapp_messages_relayed_total:3|c".to_owned()));
+/// assert!(lines.contains(&"chatsapp_commands_total:1|c|#command:help".to_owned()));
+/// assert!(lines.contains(&"chatsapp_connected_clients:1|g".to_owned()));
+/// let timing = lines.iter().find(|line| line.starts_with("chatsapp_redis_op_ms:")).unwrap();
+/// assert!(timing.ends_with("|ms|#op:zadd"));
+///
+/// // Counters only send what's new
+/// metrics().messages_relayed.inc_by(2);
+/// let lines = receive(&socket, "chatsapp_messages_relayed_total:").await;
+/// assert!(lines.contains(&"chatsapp_messages_relayed_total:2|c".to_owned()));
+/// # }
+/// ```
+///
+/// Plain StatsD has no tags, so labels go on the end of the name instead.
+/// Timings that don't fit are dropped rather than sent late:
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use std::time::{Duration, Instant};
+///
+/// use chatsapp::config::{StatsdConfig, StatsdFlavor};
+/// use chatsapp::metrics::metrics;
+/// use chatsapp::{shutdown, statsd};
+/// use tokio::net::UdpSocket;
+///
+/// let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+/// let config = StatsdConfig {
+///     addr: Some(socket.local_addr().unwrap()),
+///     flavor: StatsdFlavor::Plain,
+///     flush_ms: 10,
+///     max_packets: 1,
+/// };
+/// let (_trigger, shutdown) = shutdown::channel();
+/// statsd::start(&config, shutdown).await.unwrap();
+///
+/// for _ in 0..100 {
+///     metrics().observe_redis("zadd", Instant::now());
+/// }
+///
+/// let mut buf = [0; 2 * statsd::MAX_DATAGRAM];
+/// let n = socket.recv(&mut buf).await.unwrap();
+/// assert!(n <= statsd::MAX_DATAGRAM);
+/// let packet = std::str::from_utf8(&buf[..n]).unwrap();
+/// let timings = packet.lines().filter(|line| line.starts_with("chatsapp_redis_op_ms.zadd:"));
+/// assert!(timings.count() < 100);
+///
+/// while metrics().statsd_dropped.get() == 0 {
+///     tokio::time::sleep(Duration::from_millis(10)).await;
+/// }
+/// # }
+/// ```
+pub async fn start(config: &StatsdConfig, shutdown: Shutdown) -> io::Result<()> {
+    let addr = config
+        .addr
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No StatsD address"))?;
+
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0; 8], 0)),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+
+    let (tx, rx) = mpsc::channel(QUEUE);
+    metrics().set_statsd(Sink { tx });
+
+    let emitter = Emitter {
+        socket,
+        flavor: config.flavor,
+        max_packets: config.max_packets,
+        counters: HashMap::new(),
+    };
+    let interval = Duration::from_millis(config.flush_ms);
+    tokio::spawn(run(emitter, rx, interval, shutdown));
+
+    info!("Sending metrics to StatsD at {}", addr);
+
+    Ok(())
+}
+
+struct Emitter {
+    socket: UdpSocket,
+    flavor: StatsdFlavor,
+    max_packets: usize,
+    // <Name and labels, Value> as of the last flush
+    counters: HashMap<String, f64>,
+}
+
+// Flushes until shutdown, and once more then
+async fn run(
+    mut emitter: Emitter,
+    mut rx: Receiver<Timing>,
+    interval: Duration,
+    mut shutdown: Shutdown,
+) {
+    let mut interval = time::interval(interval);
+
+    loop {
+        let stopping = tokio::select! {
+            _ = interval.tick() => false,
+            _ = shutdown.recv() => true,
+        };
+
+        let mut timings = Vec::new();
+        while let Ok(timing) = rx.try_recv() {
+            timings.push(timing);
+        }
+
+        let lines = emitter.lines(&metrics().gather(), timings);
+        emitter.send(lines).await;
+
+        if stopping {
+            return;
+        }
+    }
+}
+
+impl Emitter {
+    // Counters and gauges first, so timings are what's dropped when over
+    fn lines(&mut self, families: &[MetricFamily], timings: Vec<Timing>) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for family in families {
+            let name = family.name();
+
+            for metric in family.get_metric() {
+                let labels: Vec<(&str, &str)> = metric
+                    .get_label()
+                    .iter()
+                    .map(|label| (label.name(), label.value()))
+                    .collect();
+
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let value = metric.get_counter().get_value();
+                        let key = format!("{}{:?}", name, labels);
+                        let last = self.counters.insert(key, value).unwrap_or_default();
+
+                        if value > last {
+                            lines.push(line(self.flavor, name, &labels, value - last, "c"));
+                        }
+                    }
+                    MetricType::GAUGE => {
+                        let value = metric.get_gauge().get_value();
+                        lines.push(line(self.flavor, name, &labels, value, "g"));
+                    }
+                    // Histograms go as timings when they're taken
+                    _ => {}
+                }
+            }
+        }
+
+        for timing in timings {
+            let tag = [(timing.tag.0, timing.tag.1.as_str())];
+            let ms = format!("{:.3}", timing.ms);
+            lines.push(line(self.flavor, timing.name, &tag, ms, "ms"));
+        }
+
+        lines
+    }
+
+    async fn send(&self, lines: Vec<String>) {
+        let mut packets = pack(lines);
+
+        let dropped: usize = packets
+            .iter()
+            .skip(self.max_packets)
+            .map(|(_, lines)| lines)
+            .sum();
+        packets.truncate(self.max_packets);
+
+        for (packet, _) in packets {
+            // Nobody listening isn't worth a warning every flush
+            let _ = self.socket.send(packet.as_bytes()).await;
+        }
+
+        if dropped > 0 {
+            metrics().statsd_dropped.inc_by(dropped as u64);
+            warn!(
+                "StatsD is limited to {} datagram(s) each flush, dropped {} line(s)",
+                self.max_packets, dropped
+            );
+        }
+    }
+}
+
+// <Datagram, Lines in it>, each as full as it can be
+fn pack(lines: Vec<String>) -> Vec<(String, usize)> {
+    let mut packets: Vec<(String, usize)> = Vec::new();
+
+    for line in lines {
+        match packets.last_mut() {
+            Some((packet, n)) if packet.len() + 1 + line.len() <= MAX_DATAGRAM => {
+                packet.push('\n');
+                packet.push_str(&line);
+                *n += 1;
+            }
+            _ => packets.push((line, 1)),
+        }
+    }
+
+    packets
+}
+
+// Eg `chatsapp_commands_total:1|c|#command:help`, or
+// `chatsapp_commands_total.help:1|c` for plain StatsD
+fn line(
+    flavor: StatsdFlavor,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: impl Display,
+    kind: &str,
+) -> String {
+    match flavor {
+        StatsdFlavor::Dogstatsd => {
+            let mut line = format!("{}:{}|{}", name, value, kind);
+            for (i, (label, value)) in labels.iter().enumerate() {
+                line.push_str(if i == 0 { "|#" } else { "," });
+                line.push_str(&format!("{}:{}", label, clean(value)));
+            }
+            line
+        }
+        StatsdFlavor::Plain => {
+            let mut name = name.to_owned();
+            for (_, value) in labels {
+                name.push('.');
+                name.push_str(&clean(value));
+            }
+            format!("{}:{}|{}", name, value, kind)
+        }
+    }
+}
+
+// Room and user names can't break the line up
+fn clean(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
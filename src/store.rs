@@ -0,0 +1,1820 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use redis::{AsyncCommands, Client};
+use tokio::time;
+use tracing::{error, warn};
+
+use crate::metrics::metrics;
+
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum StoreError {
+    Unavailable,
+    Read,
+    Write,
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Unavailable => writeln!(f, "Error: Failed to connect"),
+            StoreError::Read => writeln!(f, "Error: Failed to fetch"),
+            StoreError::Write => writeln!(f, "Error: Failed to send"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+#[derive(Debug, PartialEq)]
+pub struct RoomMeta {
+    pub messages: usize,
+    // Score of the newest message
+    pub last_activity: i64,
+}
+
+// Where rooms and their history are kept, so nothing above this depends on
+// a particular backend. Messages are scored by the time they were sent in ms.
+#[async_trait]
+pub trait RoomStore: Send + Sync {
+    async fn ping(&self) -> Result<(), StoreError>;
+
+    // Returns false if the room already exists
+    async fn create(&self, room: &str) -> Result<bool, StoreError>;
+
+    // Keeps only the newest `retention` messages when set
+    async fn append(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError>;
+
+    // `append` for several <Message, Score> at once, in one round trip
+    async fn append_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        retention: Option<usize>,
+    ) -> Result<(), StoreError>;
+
+    // The last `count` messages, oldest first
+    async fn recent(&self, room: &str, count: usize) -> Result<Vec<String>, StoreError>;
+
+    // The last `count` <Message, Score> scored below `before`, or the newest
+    // without it, oldest first
+    async fn recent_before(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        count: usize,
+    ) -> Result<Vec<(String, i64)>, StoreError>;
+
+    // Removes messages scored below `before`, keeping the start of chat so
+    // the room itself stays. Returns how many went.
+    async fn expire(&self, room: &str, before: i64) -> Result<usize, StoreError>;
+
+    // Keeps only the newest `keep` messages, as `append` does with retention.
+    // Returns how many went.
+    async fn trim(&self, room: &str, keep: usize) -> Result<usize, StoreError>;
+
+    // Removes each <Message, Score> that's still there, returning how many were
+    async fn remove_many(&self, room: &str, msgs: &[(String, i64)]) -> Result<usize, StoreError>;
+
+    // Swaps `old`, scored `score`, for `new` at the same score. Returns false,
+    // changing nothing, if `old` isn't there.
+    async fn replace(
+        &self,
+        room: &str,
+        score: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<bool, StoreError>;
+
+    // `replace` for several <Message, Score> at once, each swapped for `new`.
+    // Returns how many were still there.
+    async fn replace_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        new: &str,
+    ) -> Result<usize, StoreError>;
+
+    // `append` then `recent` in one round trip, for joining a room, with each
+    // message's score. None, with nothing appended, if the room doesn't exist.
+    async fn append_recent(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+        count: usize,
+    ) -> Result<Option<Vec<(String, i64)>>, StoreError>;
+
+    async fn list(&self) -> Result<Vec<String>, StoreError>;
+
+    // Returns false if the room didn't exist
+    async fn delete(&self, room: &str) -> Result<bool, StoreError>;
+
+    // None if the room doesn't exist
+    async fn meta(&self, room: &str) -> Result<Option<RoomMeta>, StoreError>;
+
+    // Plain sets for server wide state, eg `server:admins`. Returns false if
+    // the member was already there.
+    async fn set_add(&self, key: &str, member: &str) -> Result<bool, StoreError>;
+
+    // Returns false if the member wasn't there
+    async fn set_remove(&self, key: &str, member: &str) -> Result<bool, StoreError>;
+
+    async fn set_contains(&self, key: &str, member: &str) -> Result<bool, StoreError>;
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError>;
+
+    // Removes every member, returns false if the set didn't exist
+    async fn set_delete(&self, key: &str) -> Result<bool, StoreError>;
+
+    // Plain hashes for per room settings, eg `roominfo:rust`
+    async fn hash_set(&self, key: &str, field: &str, value: &str) -> Result<(), StoreError>;
+
+    // `hash_set`, and moves `member` from the set `from` to `to`, all at once
+    // so nothing sees one without the others, eg handing a room to a new owner
+    async fn hash_set_move(
+        &self,
+        key: &str,
+        field: &str,
+        value: &str,
+        from: &str,
+        to: &str,
+        member: &str,
+    ) -> Result<(), StoreError>;
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError>;
+
+    // Removes every field, returns false if the hash didn't exist
+    async fn hash_delete(&self, key: &str) -> Result<bool, StoreError>;
+
+    // Adds `by` to a numeric field, starting from 0, returning the new value
+    async fn hash_incr(&self, key: &str, field: &str, by: i64) -> Result<i64, StoreError>;
+
+    // <Field, Value>, in no particular order
+    async fn hash_get_all(&self, key: &str) -> Result<Vec<(String, String)>, StoreError>;
+
+    // Returns false if the field wasn't set
+    async fn hash_remove(&self, key: &str, field: &str) -> Result<bool, StoreError>;
+
+    // Deletes the hash once `ttl` has passed, unless it's deleted or given
+    // another TTL first. Redis only counts whole seconds.
+    async fn hash_expire(&self, key: &str, ttl: Duration) -> Result<(), StoreError>;
+
+    // Plain lists for logs, eg `server:auditlog`. Only the newest `cap` items
+    // are kept.
+    async fn list_push(&self, key: &str, item: &str, cap: usize) -> Result<(), StoreError>;
+
+    // The last `count` items, oldest first
+    async fn list_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError>;
+
+    async fn list_len(&self, key: &str) -> Result<usize, StoreError>;
+
+    // Removes and returns every item at once, oldest first
+    async fn list_take(&self, key: &str) -> Result<Vec<String>, StoreError>;
+
+    // Plain sorted sets for histories kept outside rooms, eg `dm:alice:bob`.
+    // Scored and trimmed the same way as room messages.
+    async fn sorted_add(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError>;
+
+    // The last `count` members, oldest first
+    async fn sorted_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError>;
+}
+
+pub struct RedisStore {
+    redis: Client,
+}
+
+/// Everything is lost on restart, meant for tests and demos. A server backed
+/// by it needs nothing else running.
+#[derive(Default)]
+pub struct MemoryStore {
+    // <Room, <Score, Message>>
+    rooms: Mutex<HashMap<String, BTreeMap<i64, String>>>,
+    sets: Mutex<HashMap<String, BTreeSet<String>>>,
+    hashes: Mutex<HashMap<String, HashMap<String, String>>>,
+    // <Hash, When it's deleted>
+    expiries: Mutex<HashMap<String, Instant>>,
+    lists: Mutex<HashMap<String, VecDeque<String>>>,
+    sorted: Mutex<HashMap<String, BTreeMap<i64, String>>>,
+}
+
+impl RedisStore {
+    pub fn new(redis: Client) -> Self {
+        Self { redis }
+    }
+
+    async fn connect(&self) -> Result<redis::aio::Connection, StoreError> {
+        let start = Instant::now();
+        let conn = self.redis.get_async_connection().await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Unavailable
+        })?;
+        metrics().observe_redis("connect", start);
+
+        Ok(conn)
+    }
+}
+
+#[async_trait]
+impl RoomStore for RedisStore {
+    async fn ping(&self) -> Result<(), StoreError> {
+        let ping = async {
+            let mut conn = self.connect().await?;
+            redis::cmd("PING")
+                .query_async::<_, String>(&mut conn)
+                .await
+                .map_err(|_| StoreError::Read)
+        };
+
+        match time::timeout(PING_TIMEOUT, ping).await {
+            Ok(res) => res.map(|_| ()),
+            Err(_) => Err(StoreError::Unavailable),
+        }
+    }
+
+    async fn create(&self, room: &str) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let key = gen_key(room);
+
+        let exists: u8 = conn.exists(&key).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })?;
+
+        if exists == 1 {
+            return Ok(false);
+        }
+
+        // Key, member, score
+        conn.zadd::<_, _, _, ()>(key, "Start of chat\n", 0)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })?;
+
+        Ok(true)
+    }
+
+    async fn append(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        self.sorted_add(&gen_key(room), msg, score, retention).await
+    }
+
+    async fn append_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.connect().await?;
+        let key = gen_key(room);
+
+        let mut pipe = redis::pipe();
+        for (msg, score) in msgs {
+            pipe.zadd(&key, msg, *score).ignore();
+        }
+        if let Some(retention) = retention {
+            pipe.zremrangebyrank(&key, 0, -(retention as isize) - 1)
+                .ignore();
+        }
+
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        Ok(())
+    }
+
+    async fn recent(&self, room: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        self.sorted_recent(&gen_key(room), count).await
+    }
+
+    async fn recent_before(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        count: usize,
+    ) -> Result<Vec<(String, i64)>, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let max = match before {
+            Some(before) => format!("({}", before),
+            None => "+inf".to_owned(),
+        };
+
+        let mut msgs: Vec<(String, i64)> = conn
+            .zrevrangebyscore_limit_withscores(gen_key(room), max, "-inf", 0, count as isize)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Read
+            })?;
+
+        msgs.reverse();
+        Ok(msgs)
+    }
+
+    async fn expire(&self, room: &str, before: i64) -> Result<usize, StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.zrembyscore(gen_key(room), 1, format!("({}", before))
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })
+    }
+
+    async fn trim(&self, room: &str, keep: usize) -> Result<usize, StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.zremrangebyrank(gen_key(room), 0, -(keep as isize) - 1)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })
+    }
+
+    // By member alone, which only ever has the one score
+    async fn remove_many(&self, room: &str, msgs: &[(String, i64)]) -> Result<usize, StoreError> {
+        if msgs.is_empty() {
+            return Ok(0);
+        }
+
+        let mut conn = self.connect().await?;
+        let members: Vec<&str> = msgs.iter().map(|(msg, _)| msg.as_str()).collect();
+
+        conn.zrem(gen_key(room), members).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })
+    }
+
+    // Members are unique, so the same line replacing two messages only keeps
+    // the later one
+    async fn replace(
+        &self,
+        room: &str,
+        score: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+        let key = gen_key(room);
+
+        let current: Option<f64> = conn.zscore(&key, old).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })?;
+        if current != Some(score as f64) {
+            return Ok(false);
+        }
+
+        redis::pipe()
+            .atomic()
+            .zrem(&key, old)
+            .ignore()
+            .zadd(&key, new, score)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })?;
+
+        Ok(true)
+    }
+
+    // Two round trips, so only what was removed is replaced
+    async fn replace_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        new: &str,
+    ) -> Result<usize, StoreError> {
+        if msgs.is_empty() {
+            return Ok(0);
+        }
+
+        let mut conn = self.connect().await?;
+        let key = gen_key(room);
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (msg, _) in msgs {
+            pipe.zrem(&key, msg);
+        }
+        let removed: Vec<u8> = pipe.query_async(&mut conn).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        let mut pipe = redis::pipe();
+        let mut replaced = 0;
+        for ((_, score), removed) in msgs.iter().zip(removed) {
+            if removed == 1 {
+                pipe.zadd(&key, new, *score).ignore();
+                replaced += 1;
+            }
+        }
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        Ok(replaced)
+    }
+
+    async fn append_recent(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+        count: usize,
+    ) -> Result<Option<Vec<(String, i64)>>, StoreError> {
+        let mut conn = self.connect().await?;
+        let key = gen_key(room);
+
+        let mut pipe = redis::pipe();
+        pipe.exists(&key).zadd(&key, msg, score).ignore();
+        if let Some(retention) = retention {
+            pipe.zremrangebyrank(&key, 0, -(retention as isize) - 1)
+                .ignore();
+        }
+        // `0 -1` would be everything, a start past the stop is nothing
+        match count {
+            0 => pipe.zrevrange_withscores(&key, 1, 0),
+            count => pipe.zrevrange_withscores(&key, 0, count as isize - 1),
+        };
+
+        let (exists, mut msgs): (bool, Vec<(String, i64)>) =
+            pipe.query_async(&mut conn).await.map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })?;
+
+        // The room was created by the `ZADD`, so is taken away again
+        if !exists {
+            conn.del::<_, ()>(&key).await.map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })?;
+            return Ok(None);
+        }
+
+        msgs.reverse();
+        Ok(Some(msgs))
+    }
+
+    // `SCAN` rather than `KEYS`, so Redis isn't blocked while it walks them
+    async fn list(&self) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let mut keys = conn
+            .scan_match::<_, String>(gen_key("*"))
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Read
+            })?;
+
+        // Remove `room:`
+        let mut rooms = Vec::new();
+        while let Some(key) = keys.next_item().await {
+            if let Some(room) = key.strip_prefix("room:") {
+                rooms.push(room.to_owned());
+            }
+        }
+        // A scan can come across the same key twice
+        rooms.sort_unstable();
+        rooms.dedup();
+
+        Ok(rooms)
+    }
+
+    async fn delete(&self, room: &str) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let deleted: u8 = conn.del(gen_key(room)).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        Ok(deleted == 1)
+    }
+
+    async fn meta(&self, room: &str) -> Result<Option<RoomMeta>, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let key = gen_key(room);
+
+        let messages: usize = conn.zcard(&key).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })?;
+
+        let newest: Vec<(String, i64)> =
+            conn.zrevrange_withscores(&key, 0, 0).await.map_err(|e| {
+                error!("{}", e);
+                StoreError::Read
+            })?;
+
+        Ok(newest.first().map(|(_, score)| RoomMeta {
+            messages,
+            last_activity: *score,
+        }))
+    }
+
+    async fn set_add(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let added: u8 = conn.sadd(key, member).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        Ok(added == 1)
+    }
+
+    async fn set_remove(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let removed: u8 = conn.srem(key, member).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        Ok(removed == 1)
+    }
+
+    async fn set_contains(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.sismember(key, member).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let mut members: Vec<String> = conn.smembers(key).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })?;
+        members.sort();
+
+        Ok(members)
+    }
+
+    async fn set_delete(&self, key: &str) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let deleted: u8 = conn.del(key).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        Ok(deleted == 1)
+    }
+
+    async fn hash_set(&self, key: &str, field: &str, value: &str) -> Result<(), StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.hset(key, field, value).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })
+    }
+
+    async fn hash_set_move(
+        &self,
+        key: &str,
+        field: &str,
+        value: &str,
+        from: &str,
+        to: &str,
+        member: &str,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.connect().await?;
+
+        redis::pipe()
+            .atomic()
+            .hset(key, field, value)
+            .ignore()
+            .srem(from, member)
+            .ignore()
+            .sadd(to, member)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.hget(key, field).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })
+    }
+
+    async fn hash_delete(&self, key: &str) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let deleted: u8 = conn.del(key).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        Ok(deleted == 1)
+    }
+
+    async fn hash_incr(&self, key: &str, field: &str, by: i64) -> Result<i64, StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.hincr(key, field, by).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })
+    }
+
+    async fn hash_get_all(&self, key: &str) -> Result<Vec<(String, String)>, StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.hgetall(key).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })
+    }
+
+    async fn hash_remove(&self, key: &str, field: &str) -> Result<bool, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let removed: u8 = conn.hdel(key, field).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Write
+        })?;
+
+        Ok(removed == 1)
+    }
+
+    async fn hash_expire(&self, key: &str, ttl: Duration) -> Result<(), StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.expire(key, ttl.as_secs().max(1) as usize)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })
+    }
+
+    async fn list_push(&self, key: &str, item: &str, cap: usize) -> Result<(), StoreError> {
+        let mut conn = self.connect().await?;
+
+        redis::pipe()
+            .atomic()
+            .rpush(key, item)
+            .ignore()
+            .ltrim(key, -(cap as isize), -1)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })
+    }
+
+    async fn list_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut conn = self.connect().await?;
+
+        conn.lrange(key, -(count as isize), -1).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })
+    }
+
+    async fn list_len(&self, key: &str) -> Result<usize, StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.llen(key).await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })
+    }
+
+    async fn list_take(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let (items,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .lrange(key, 0, -1)
+            .del(key)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })?;
+
+        Ok(items)
+    }
+
+    async fn sorted_add(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.connect().await?;
+
+        conn.zadd::<_, _, _, ()>(key, member, score)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Write
+            })?;
+
+        if let Some(retention) = retention {
+            conn.zremrangebyrank::<_, ()>(key, 0, -(retention as isize) - 1)
+                .await
+                .map_err(|e| {
+                    error!("{}", e);
+                    StoreError::Write
+                })?;
+        }
+
+        Ok(())
+    }
+
+    async fn sorted_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        let mut conn = self.connect().await?;
+
+        let mut offset: usize = conn.zcount(key, 0, "inf").await.map_err(|e| {
+            error!("{}", e);
+            StoreError::Read
+        })?;
+
+        offset = offset.saturating_sub(count);
+
+        let msgs: Vec<String> = conn
+            .zrangebyscore_limit(key, 0, "inf", offset as isize, count as isize)
+            .await
+            .map_err(|e| {
+                error!("{}", e);
+                StoreError::Read
+            })?;
+
+        Ok(msgs)
+    }
+}
+
+impl MemoryStore {
+    fn rooms(&self) -> std::sync::MutexGuard<'_, HashMap<String, BTreeMap<i64, String>>> {
+        // Nothing panics while holding the lock, but don't take the server down if it did
+        self.rooms.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sets(&self) -> std::sync::MutexGuard<'_, HashMap<String, BTreeSet<String>>> {
+        self.sets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Expired hashes are dropped whenever any are looked at
+    fn hashes(&self) -> std::sync::MutexGuard<'_, HashMap<String, HashMap<String, String>>> {
+        let mut hashes = self.hashes.lock().unwrap_or_else(|e| e.into_inner());
+
+        let now = Instant::now();
+        self.expiries().retain(|key, at| {
+            if *at > now {
+                return true;
+            }
+            hashes.remove(key);
+            false
+        });
+
+        hashes
+    }
+
+    fn expiries(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        self.expiries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lists(&self) -> std::sync::MutexGuard<'_, HashMap<String, VecDeque<String>>> {
+        self.lists.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sorted(&self) -> std::sync::MutexGuard<'_, HashMap<String, BTreeMap<i64, String>>> {
+        self.sorted.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl RoomStore for MemoryStore {
+    async fn ping(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    async fn create(&self, room: &str) -> Result<bool, StoreError> {
+        let mut rooms = self.rooms();
+
+        if rooms.contains_key(room) {
+            return Ok(false);
+        }
+
+        rooms.insert(
+            room.to_owned(),
+            BTreeMap::from([(0, "Start of chat\n".into())]),
+        );
+
+        Ok(true)
+    }
+
+    async fn append(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let mut rooms = self.rooms();
+        insert_scored(
+            rooms.entry(room.to_owned()).or_default(),
+            msg,
+            score,
+            retention,
+        );
+
+        Ok(())
+    }
+
+    async fn append_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let mut rooms = self.rooms();
+        let members = rooms.entry(room.to_owned()).or_default();
+        for (msg, score) in msgs {
+            insert_scored(members, msg, *score, retention);
+        }
+
+        Ok(())
+    }
+
+    async fn recent(&self, room: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        Ok(self
+            .rooms()
+            .get(room)
+            .map(|msgs| {
+                last_scored(msgs, count)
+                    .into_iter()
+                    .map(|(msg, _)| msg)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn recent_before(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        count: usize,
+    ) -> Result<Vec<(String, i64)>, StoreError> {
+        let rooms = self.rooms();
+        let Some(msgs) = rooms.get(room) else {
+            return Ok(vec![]);
+        };
+
+        let mut page: Vec<(String, i64)> = msgs
+            .range(..before.unwrap_or(i64::MAX))
+            .rev()
+            .take(count)
+            .map(|(score, msg)| (msg.clone(), *score))
+            .collect();
+        page.reverse();
+
+        Ok(page)
+    }
+
+    async fn expire(&self, room: &str, before: i64) -> Result<usize, StoreError> {
+        let mut rooms = self.rooms();
+        let Some(msgs) = rooms.get_mut(room) else {
+            return Ok(0);
+        };
+
+        let len = msgs.len();
+        msgs.retain(|score, _| *score < 1 || *score >= before);
+
+        Ok(len - msgs.len())
+    }
+
+    async fn trim(&self, room: &str, keep: usize) -> Result<usize, StoreError> {
+        let mut rooms = self.rooms();
+        let Some(msgs) = rooms.get_mut(room) else {
+            return Ok(0);
+        };
+
+        let over = msgs.len().saturating_sub(keep);
+        for _ in 0..over {
+            msgs.pop_first();
+        }
+
+        Ok(over)
+    }
+
+    async fn remove_many(&self, room: &str, msgs: &[(String, i64)]) -> Result<usize, StoreError> {
+        let mut rooms = self.rooms();
+        let Some(members) = rooms.get_mut(room) else {
+            return Ok(0);
+        };
+
+        let mut removed = 0;
+        for (msg, score) in msgs {
+            if members.get(score) == Some(msg) {
+                members.remove(score);
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    async fn replace(
+        &self,
+        room: &str,
+        score: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<bool, StoreError> {
+        let mut rooms = self.rooms();
+        let msg = rooms.get_mut(room).and_then(|msgs| msgs.get_mut(&score));
+
+        match msg {
+            Some(msg) if msg == old => {
+                *msg = new.to_owned();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn replace_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        new: &str,
+    ) -> Result<usize, StoreError> {
+        let mut rooms = self.rooms();
+        let Some(members) = rooms.get_mut(room) else {
+            return Ok(0);
+        };
+
+        let mut replaced = 0;
+        for (old, score) in msgs {
+            if let Some(msg) = members.get_mut(score).filter(|msg| *msg == old) {
+                *msg = new.to_owned();
+                replaced += 1;
+            }
+        }
+
+        Ok(replaced)
+    }
+
+    async fn append_recent(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+        count: usize,
+    ) -> Result<Option<Vec<(String, i64)>>, StoreError> {
+        let mut rooms = self.rooms();
+        let Some(msgs) = rooms.get_mut(room) else {
+            return Ok(None);
+        };
+        insert_scored(msgs, msg, score, retention);
+
+        Ok(Some(last_scored(msgs, count)))
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StoreError> {
+        Ok(self.rooms().keys().cloned().collect())
+    }
+
+    async fn delete(&self, room: &str) -> Result<bool, StoreError> {
+        Ok(self.rooms().remove(room).is_some())
+    }
+
+    async fn meta(&self, room: &str) -> Result<Option<RoomMeta>, StoreError> {
+        let rooms = self.rooms();
+
+        let meta = rooms.get(room).and_then(|msgs| {
+            msgs.last_key_value().map(|(score, _)| RoomMeta {
+                messages: msgs.len(),
+                last_activity: *score,
+            })
+        });
+
+        Ok(meta)
+    }
+
+    async fn set_add(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        Ok(self
+            .sets()
+            .entry(key.to_owned())
+            .or_default()
+            .insert(member.to_owned()))
+    }
+
+    async fn set_remove(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let mut sets = self.sets();
+
+        Ok(sets.get_mut(key).is_some_and(|set| set.remove(member)))
+    }
+
+    async fn set_contains(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        Ok(self.sets().get(key).is_some_and(|set| set.contains(member)))
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let sets = self.sets();
+
+        Ok(sets
+            .get(key)
+            .map(|set| set.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn set_delete(&self, key: &str) -> Result<bool, StoreError> {
+        Ok(self.sets().remove(key).is_some())
+    }
+
+    async fn hash_set(&self, key: &str, field: &str, value: &str) -> Result<(), StoreError> {
+        self.hashes()
+            .entry(key.to_owned())
+            .or_default()
+            .insert(field.to_owned(), value.to_owned());
+
+        Ok(())
+    }
+
+    async fn hash_set_move(
+        &self,
+        key: &str,
+        field: &str,
+        value: &str,
+        from: &str,
+        to: &str,
+        member: &str,
+    ) -> Result<(), StoreError> {
+        // Both locks are held throughout, hashes first
+        let mut hashes = self.hashes();
+        let mut sets = self.sets();
+
+        hashes
+            .entry(key.to_owned())
+            .or_default()
+            .insert(field.to_owned(), value.to_owned());
+        if let Some(set) = sets.get_mut(from) {
+            set.remove(member);
+        }
+        sets.entry(to.to_owned())
+            .or_default()
+            .insert(member.to_owned());
+
+        Ok(())
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        let hashes = self.hashes();
+
+        Ok(hashes.get(key).and_then(|hash| hash.get(field)).cloned())
+    }
+
+    async fn hash_delete(&self, key: &str) -> Result<bool, StoreError> {
+        let deleted = self.hashes().remove(key).is_some();
+        self.expiries().remove(key);
+
+        Ok(deleted)
+    }
+
+    async fn hash_incr(&self, key: &str, field: &str, by: i64) -> Result<i64, StoreError> {
+        let mut hashes = self.hashes();
+        let value = hashes
+            .entry(key.to_owned())
+            .or_default()
+            .entry(field.to_owned())
+            .or_insert_with(|| "0".to_owned());
+
+        // Redis refuses to increment anything that isn't an integer
+        let Ok(current) = value.parse::<i64>() else {
+            error!("{} {} is not an integer", key, field);
+            return Err(StoreError::Write);
+        };
+        *value = (current + by).to_string();
+
+        Ok(current + by)
+    }
+
+    async fn hash_get_all(&self, key: &str) -> Result<Vec<(String, String)>, StoreError> {
+        let hashes = self.hashes();
+
+        Ok(hashes
+            .get(key)
+            .map(|hash| {
+                hash.iter()
+                    .map(|(field, value)| (field.clone(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn hash_remove(&self, key: &str, field: &str) -> Result<bool, StoreError> {
+        let mut hashes = self.hashes();
+        let Some(hash) = hashes.get_mut(key) else {
+            return Ok(false);
+        };
+        let removed = hash.remove(field).is_some();
+
+        // Like Redis, a hash goes away with its last field
+        if hash.is_empty() {
+            hashes.remove(key);
+            self.expiries().remove(key);
+        }
+
+        Ok(removed)
+    }
+
+    async fn hash_expire(&self, key: &str, ttl: Duration) -> Result<(), StoreError> {
+        // Like Redis, there's nothing to expire until the hash exists
+        if self.hashes().contains_key(key) {
+            self.expiries().insert(key.to_owned(), Instant::now() + ttl);
+        }
+
+        Ok(())
+    }
+
+    async fn list_push(&self, key: &str, item: &str, cap: usize) -> Result<(), StoreError> {
+        let mut lists = self.lists();
+        let list = lists.entry(key.to_owned()).or_default();
+
+        list.push_back(item.to_owned());
+        while list.len() > cap {
+            list.pop_front();
+        }
+
+        Ok(())
+    }
+
+    async fn list_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        let lists = self.lists();
+
+        Ok(lists
+            .get(key)
+            .map(|list| {
+                list.iter()
+                    .skip(list.len().saturating_sub(count))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn list_len(&self, key: &str) -> Result<usize, StoreError> {
+        Ok(self.lists().get(key).map_or(0, VecDeque::len))
+    }
+
+    async fn list_take(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        Ok(self.lists().remove(key).map(Vec::from).unwrap_or_default())
+    }
+
+    async fn sorted_add(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let mut sorted = self.sorted();
+        insert_scored(
+            sorted.entry(key.to_owned()).or_default(),
+            member,
+            score,
+            retention,
+        );
+
+        Ok(())
+    }
+
+    async fn sorted_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        Ok(self
+            .sorted()
+            .get(key)
+            .map(|members| {
+                last_scored(members, count)
+                    .into_iter()
+                    .map(|(member, _)| member)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+// Waits before every call as if the store were far away, for seeing how
+// round trips add up, eg in `room::join`. Can be taken down, so every call
+// fails as if Redis were unreachable.
+pub struct SlowStore<S> {
+    inner: S,
+    latency: Duration,
+    down: AtomicBool,
+}
+
+impl<S: RoomStore> SlowStore<S> {
+    pub fn new(inner: S, latency: Duration) -> Self {
+        Self {
+            inner,
+            latency,
+            down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::Relaxed);
+    }
+
+    async fn round_trip(&self) -> Result<(), StoreError> {
+        time::sleep(self.latency).await;
+
+        if self.down.load(Ordering::Relaxed) {
+            return Err(StoreError::Unavailable);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: RoomStore> RoomStore for SlowStore<S> {
+    async fn ping(&self) -> Result<(), StoreError> {
+        self.round_trip().await?;
+        self.inner.ping().await
+    }
+
+    async fn create(&self, room: &str) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.create(room).await
+    }
+
+    async fn append(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        self.round_trip().await?;
+        self.inner.append(room, msg, score, retention).await
+    }
+
+    async fn append_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        self.round_trip().await?;
+        self.inner.append_many(room, msgs, retention).await
+    }
+
+    async fn recent(&self, room: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        self.round_trip().await?;
+        self.inner.recent(room, count).await
+    }
+
+    async fn recent_before(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        count: usize,
+    ) -> Result<Vec<(String, i64)>, StoreError> {
+        self.round_trip().await?;
+        self.inner.recent_before(room, before, count).await
+    }
+
+    async fn expire(&self, room: &str, before: i64) -> Result<usize, StoreError> {
+        self.round_trip().await?;
+        self.inner.expire(room, before).await
+    }
+
+    async fn trim(&self, room: &str, keep: usize) -> Result<usize, StoreError> {
+        self.round_trip().await?;
+        self.inner.trim(room, keep).await
+    }
+
+    async fn remove_many(&self, room: &str, msgs: &[(String, i64)]) -> Result<usize, StoreError> {
+        self.round_trip().await?;
+        self.inner.remove_many(room, msgs).await
+    }
+
+    async fn replace(
+        &self,
+        room: &str,
+        score: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.replace(room, score, old, new).await
+    }
+
+    async fn replace_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        new: &str,
+    ) -> Result<usize, StoreError> {
+        self.round_trip().await?;
+        self.inner.replace_many(room, msgs, new).await
+    }
+
+    async fn append_recent(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+        count: usize,
+    ) -> Result<Option<Vec<(String, i64)>>, StoreError> {
+        self.round_trip().await?;
+        self.inner
+            .append_recent(room, msg, score, retention, count)
+            .await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StoreError> {
+        self.round_trip().await?;
+        self.inner.list().await
+    }
+
+    async fn delete(&self, room: &str) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.delete(room).await
+    }
+
+    async fn meta(&self, room: &str) -> Result<Option<RoomMeta>, StoreError> {
+        self.round_trip().await?;
+        self.inner.meta(room).await
+    }
+
+    async fn set_add(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.set_add(key, member).await
+    }
+
+    async fn set_remove(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.set_remove(key, member).await
+    }
+
+    async fn set_contains(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.set_contains(key, member).await
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        self.round_trip().await?;
+        self.inner.set_members(key).await
+    }
+
+    async fn set_delete(&self, key: &str) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.set_delete(key).await
+    }
+
+    async fn hash_set(&self, key: &str, field: &str, value: &str) -> Result<(), StoreError> {
+        self.round_trip().await?;
+        self.inner.hash_set(key, field, value).await
+    }
+
+    async fn hash_set_move(
+        &self,
+        key: &str,
+        field: &str,
+        value: &str,
+        from: &str,
+        to: &str,
+        member: &str,
+    ) -> Result<(), StoreError> {
+        self.round_trip().await?;
+        self.inner
+            .hash_set_move(key, field, value, from, to, member)
+            .await
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        self.round_trip().await?;
+        self.inner.hash_get(key, field).await
+    }
+
+    async fn hash_delete(&self, key: &str) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.hash_delete(key).await
+    }
+
+    async fn hash_incr(&self, key: &str, field: &str, by: i64) -> Result<i64, StoreError> {
+        self.round_trip().await?;
+        self.inner.hash_incr(key, field, by).await
+    }
+
+    async fn hash_get_all(&self, key: &str) -> Result<Vec<(String, String)>, StoreError> {
+        self.round_trip().await?;
+        self.inner.hash_get_all(key).await
+    }
+
+    async fn hash_remove(&self, key: &str, field: &str) -> Result<bool, StoreError> {
+        self.round_trip().await?;
+        self.inner.hash_remove(key, field).await
+    }
+
+    async fn hash_expire(&self, key: &str, ttl: Duration) -> Result<(), StoreError> {
+        self.round_trip().await?;
+        self.inner.hash_expire(key, ttl).await
+    }
+
+    async fn list_push(&self, key: &str, item: &str, cap: usize) -> Result<(), StoreError> {
+        self.round_trip().await?;
+        self.inner.list_push(key, item, cap).await
+    }
+
+    async fn list_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        self.round_trip().await?;
+        self.inner.list_recent(key, count).await
+    }
+
+    async fn list_len(&self, key: &str) -> Result<usize, StoreError> {
+        self.round_trip().await?;
+        self.inner.list_len(key).await
+    }
+
+    async fn list_take(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        self.round_trip().await?;
+        self.inner.list_take(key).await
+    }
+
+    async fn sorted_add(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        self.round_trip().await?;
+        self.inner.sorted_add(key, member, score, retention).await
+    }
+
+    async fn sorted_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        self.round_trip().await?;
+        self.inner.sorted_recent(key, count).await
+    }
+}
+
+/// Times every call, by the Redis command it comes down to, into
+/// `chatsapp_redis_op_seconds` and the last few minutes for `>stats`, so a
+/// slow Redis can be told apart from a slow broker. Calls that take longer
+/// than `slow` log a warning with the op and the room or key.
+pub struct TimedStore<S> {
+    inner: S,
+    // None never warns
+    slow: Option<Duration>,
+}
+
+impl<S: RoomStore> TimedStore<S> {
+    pub fn new(inner: S, slow: Option<Duration>) -> Self {
+        Self { inner, slow }
+    }
+
+    async fn time<T>(
+        &self,
+        op: &'static str,
+        room: Option<&str>,
+        key: Option<&str>,
+        call: impl Future<Output = T>,
+    ) -> T {
+        let start = Instant::now();
+        let output = call.await;
+
+        metrics().observe_redis(op, start);
+
+        let elapsed = start.elapsed();
+        if self.slow.is_some_and(|slow| elapsed >= slow) {
+            warn!(
+                op,
+                room,
+                key,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "slow Redis op"
+            );
+        }
+
+        output
+    }
+}
+
+#[async_trait]
+impl<S: RoomStore> RoomStore for TimedStore<S> {
+    async fn ping(&self) -> Result<(), StoreError> {
+        self.time("ping", None, None, self.inner.ping()).await
+    }
+
+    async fn create(&self, room: &str) -> Result<bool, StoreError> {
+        self.time("create", Some(room), None, self.inner.create(room))
+            .await
+    }
+
+    async fn append(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let call = self.inner.append(room, msg, score, retention);
+        self.time("zadd", Some(room), None, call).await
+    }
+
+    async fn append_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let call = self.inner.append_many(room, msgs, retention);
+        self.time("zadd_many", Some(room), None, call).await
+    }
+
+    async fn recent(&self, room: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        let call = self.inner.recent(room, count);
+        self.time("zrevrange", Some(room), None, call).await
+    }
+
+    async fn recent_before(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        count: usize,
+    ) -> Result<Vec<(String, i64)>, StoreError> {
+        let call = self.inner.recent_before(room, before, count);
+        self.time("zrevrangebyscore", Some(room), None, call).await
+    }
+
+    async fn expire(&self, room: &str, before: i64) -> Result<usize, StoreError> {
+        let call = self.inner.expire(room, before);
+        self.time("zremrangebyscore", Some(room), None, call).await
+    }
+
+    async fn trim(&self, room: &str, keep: usize) -> Result<usize, StoreError> {
+        let call = self.inner.trim(room, keep);
+        self.time("zremrangebyrank", Some(room), None, call).await
+    }
+
+    async fn remove_many(&self, room: &str, msgs: &[(String, i64)]) -> Result<usize, StoreError> {
+        let call = self.inner.remove_many(room, msgs);
+        self.time("zrem", Some(room), None, call).await
+    }
+
+    async fn replace(
+        &self,
+        room: &str,
+        score: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<bool, StoreError> {
+        let call = self.inner.replace(room, score, old, new);
+        self.time("replace", Some(room), None, call).await
+    }
+
+    async fn replace_many(
+        &self,
+        room: &str,
+        msgs: &[(String, i64)],
+        new: &str,
+    ) -> Result<usize, StoreError> {
+        let call = self.inner.replace_many(room, msgs, new);
+        self.time("replace_many", Some(room), None, call).await
+    }
+
+    async fn append_recent(
+        &self,
+        room: &str,
+        msg: &str,
+        score: i64,
+        retention: Option<usize>,
+        count: usize,
+    ) -> Result<Option<Vec<(String, i64)>>, StoreError> {
+        let call = self.inner.append_recent(room, msg, score, retention, count);
+        self.time("join", Some(room), None, call).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StoreError> {
+        self.time("scan", None, None, self.inner.list()).await
+    }
+
+    async fn delete(&self, room: &str) -> Result<bool, StoreError> {
+        self.time("del", Some(room), None, self.inner.delete(room))
+            .await
+    }
+
+    async fn meta(&self, room: &str) -> Result<Option<RoomMeta>, StoreError> {
+        self.time("meta", Some(room), None, self.inner.meta(room))
+            .await
+    }
+
+    async fn set_add(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let call = self.inner.set_add(key, member);
+        self.time("sadd", None, Some(key), call).await
+    }
+
+    async fn set_remove(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let call = self.inner.set_remove(key, member);
+        self.time("srem", None, Some(key), call).await
+    }
+
+    async fn set_contains(&self, key: &str, member: &str) -> Result<bool, StoreError> {
+        let call = self.inner.set_contains(key, member);
+        self.time("sismember", None, Some(key), call).await
+    }
+
+    async fn set_members(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let call = self.inner.set_members(key);
+        self.time("smembers", None, Some(key), call).await
+    }
+
+    async fn set_delete(&self, key: &str) -> Result<bool, StoreError> {
+        let call = self.inner.set_delete(key);
+        self.time("del", None, Some(key), call).await
+    }
+
+    async fn hash_set(&self, key: &str, field: &str, value: &str) -> Result<(), StoreError> {
+        let call = self.inner.hash_set(key, field, value);
+        self.time("hset", None, Some(key), call).await
+    }
+
+    async fn hash_set_move(
+        &self,
+        key: &str,
+        field: &str,
+        value: &str,
+        from: &str,
+        to: &str,
+        member: &str,
+    ) -> Result<(), StoreError> {
+        let call = self
+            .inner
+            .hash_set_move(key, field, value, from, to, member);
+        self.time("multi", None, Some(key), call).await
+    }
+
+    async fn hash_get(&self, key: &str, field: &str) -> Result<Option<String>, StoreError> {
+        let call = self.inner.hash_get(key, field);
+        self.time("hget", None, Some(key), call).await
+    }
+
+    async fn hash_delete(&self, key: &str) -> Result<bool, StoreError> {
+        let call = self.inner.hash_delete(key);
+        self.time("del", None, Some(key), call).await
+    }
+
+    async fn hash_incr(&self, key: &str, field: &str, by: i64) -> Result<i64, StoreError> {
+        let call = self.inner.hash_incr(key, field, by);
+        self.time("hincrby", None, Some(key), call).await
+    }
+
+    async fn hash_get_all(&self, key: &str) -> Result<Vec<(String, String)>, StoreError> {
+        let call = self.inner.hash_get_all(key);
+        self.time("hgetall", None, Some(key), call).await
+    }
+
+    async fn hash_remove(&self, key: &str, field: &str) -> Result<bool, StoreError> {
+        let call = self.inner.hash_remove(key, field);
+        self.time("hdel", None, Some(key), call).await
+    }
+
+    async fn hash_expire(&self, key: &str, ttl: Duration) -> Result<(), StoreError> {
+        let call = self.inner.hash_expire(key, ttl);
+        self.time("expire", None, Some(key), call).await
+    }
+
+    async fn list_push(&self, key: &str, item: &str, cap: usize) -> Result<(), StoreError> {
+        let call = self.inner.list_push(key, item, cap);
+        self.time("lpush", None, Some(key), call).await
+    }
+
+    async fn list_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        let call = self.inner.list_recent(key, count);
+        self.time("lrange", None, Some(key), call).await
+    }
+
+    async fn list_len(&self, key: &str) -> Result<usize, StoreError> {
+        let call = self.inner.list_len(key);
+        self.time("llen", None, Some(key), call).await
+    }
+
+    async fn list_take(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let call = self.inner.list_take(key);
+        self.time("list_take", None, Some(key), call).await
+    }
+
+    async fn sorted_add(
+        &self,
+        key: &str,
+        member: &str,
+        score: i64,
+        retention: Option<usize>,
+    ) -> Result<(), StoreError> {
+        let call = self.inner.sorted_add(key, member, score, retention);
+        self.time("zadd", None, Some(key), call).await
+    }
+
+    async fn sorted_recent(&self, key: &str, count: usize) -> Result<Vec<String>, StoreError> {
+        let call = self.inner.sorted_recent(key, count);
+        self.time("zrevrange", None, Some(key), call).await
+    }
+}
+
+fn insert_scored(
+    members: &mut BTreeMap<i64, String>,
+    member: &str,
+    mut score: i64,
+    retention: Option<usize>,
+) {
+    // Members added in the same ms keep their order
+    while members.contains_key(&score) {
+        score += 1;
+    }
+    members.insert(score, member.to_owned());
+
+    if let Some(retention) = retention {
+        while members.len() > retention {
+            members.pop_first();
+        }
+    }
+}
+
+// <Member, Score>, oldest first
+fn last_scored(members: &BTreeMap<i64, String>, count: usize) -> Vec<(String, i64)> {
+    let skip = members.len().saturating_sub(count);
+
+    members
+        .iter()
+        .skip(skip)
+        .map(|(score, member)| (member.clone(), *score))
+        .collect()
+}
+
+fn gen_key(name: &str) -> String {
+    format!("room:{}", name)
+}
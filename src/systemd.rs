@@ -0,0 +1,100 @@
+// Socket activation and service notifications. Everything here is a no-op
+// unless built with the `systemd` feature and started by systemd.
+
+use std::net::SocketAddr;
+
+use tokio::io;
+use tokio::net::TcpListener;
+
+use crate::server;
+use crate::shutdown::Shutdown;
+
+/// The listeners systemd handed over via socket activation, or failing that,
+/// one bound to each of `binds`.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::systemd;
+///
+/// // Not started by systemd, so it's bound here
+/// let listeners = systemd::listeners(&["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+/// assert_eq!(listeners.len(), 1);
+/// # }
+/// ```
+pub async fn listeners(binds: &[SocketAddr]) -> io::Result<Vec<TcpListener>> {
+    let listeners = take_listeners()?;
+    if !listeners.is_empty() {
+        return Ok(listeners);
+    }
+
+    let mut listeners = Vec::with_capacity(binds.len());
+    for addr in binds {
+        listeners.push(server::bind(*addr).await?);
+    }
+
+    Ok(listeners)
+}
+
+// The listeners systemd bound for us via `LISTEN_FDS`, if any
+#[cfg(feature = "systemd")]
+pub fn take_listeners() -> io::Result<Vec<TcpListener>> {
+    let mut fds = listenfd::ListenFd::from_env();
+    let mut listeners = Vec::new();
+
+    for idx in 0..fds.len() {
+        if let Some(listener) = fds.take_tcp_listener(idx)? {
+            listener.set_nonblocking(true)?;
+            listeners.push(TcpListener::from_std(listener)?);
+        }
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn take_listeners() -> io::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+pub fn notify_ready() {
+    #[cfg(feature = "systemd")]
+    notify(&[sd_notify::NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+    #[cfg(feature = "systemd")]
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+// Pings the watchdog at half the interval systemd asked for via `WATCHDOG_USEC`
+pub fn spawn_watchdog(shutdown: Shutdown) {
+    #[cfg(feature = "systemd")]
+    if let Some(interval) = sd_notify::watchdog_enabled() {
+        tokio::spawn(watchdog(interval / 2, shutdown));
+    }
+
+    #[cfg(not(feature = "systemd"))]
+    drop(shutdown);
+}
+
+#[cfg(feature = "systemd")]
+async fn watchdog(interval: std::time::Duration, mut shutdown: Shutdown) {
+    let mut ticks = tokio::time::interval(interval);
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => notify(&[sd_notify::NotifyState::Watchdog]),
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+#[cfg(feature = "systemd")]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(state) {
+        tracing::warn!("Failed to notify systemd: {}", e);
+    }
+}
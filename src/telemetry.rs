@@ -0,0 +1,143 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tracing::{warn, Instrument, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+// Commands taking longer than this get a warning with their timing breakdown
+pub const SLOW_COMMAND: Duration = Duration::from_millis(500);
+
+pub enum Stage {
+    Redis,
+    Broker,
+    Write,
+}
+
+// Time spent in each stage while handling a single command, in microseconds
+#[derive(Default)]
+pub struct Timings {
+    redis: AtomicU64,
+    broker: AtomicU64,
+    write: AtomicU64,
+}
+
+impl Timings {
+    pub fn reset(&self) {
+        self.redis.store(0, Ordering::Relaxed);
+        self.broker.store(0, Ordering::Relaxed);
+        self.write.store(0, Ordering::Relaxed);
+    }
+
+    // Runs `fut` inside `span`, recording its duration on the span's
+    // `elapsed_ms` field and adding it to the stage total.
+    pub async fn time<F: Future>(&self, stage: Stage, span: Span, fut: F) -> F::Output {
+        let start = Instant::now();
+        let output = fut.instrument(span.clone()).await;
+        let elapsed = start.elapsed();
+
+        span.record("elapsed_ms", elapsed.as_millis() as u64);
+
+        let total = match stage {
+            Stage::Redis => &self.redis,
+            Stage::Broker => &self.broker,
+            Stage::Write => &self.write,
+        };
+        total.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+
+        output
+    }
+
+    pub fn finish(&self, span: &Span, command: &str, elapsed: Duration) {
+        span.record("elapsed_ms", elapsed.as_millis() as u64);
+
+        if elapsed < SLOW_COMMAND {
+            return;
+        }
+
+        warn!(
+            parent: span,
+            command,
+            elapsed_ms = elapsed.as_millis() as u64,
+            redis_ms = self.redis.load(Ordering::Relaxed) / 1000,
+            broker_ms = self.broker.load(Ordering::Relaxed) / 1000,
+            write_ms = self.write.load(Ordering::Relaxed) / 1000,
+            "slow command"
+        );
+    }
+}
+
+// Flushes pending spans when dropped
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+// Logs go to stderr filtered by `RUST_LOG`. With the `otel` feature, spans
+// are also exported when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init() -> TelemetryGuard {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr));
+
+    #[cfg(feature = "otel")]
+    {
+        let provider = otel_provider();
+        let layer = provider.as_ref().map(|provider| {
+            use opentelemetry::trace::TracerProvider;
+
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("chatsapp"))
+        });
+
+        registry.with(layer).init();
+
+        TelemetryGuard { provider }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+
+        TelemetryGuard {}
+    }
+}
+
+#[cfg(feature = "otel")]
+fn otel_provider() -> Option<opentelemetry_sdk::trace::SdkTracerProvider> {
+    use opentelemetry_otlp::SpanExporter;
+
+    // The exporter reads the endpoint and the rest of the standard `OTEL_*`
+    // variables itself
+    std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")?;
+
+    let exporter = match SpanExporter::builder().with_http().build() {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("{}", e);
+            return None;
+        }
+    };
+
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name("chatsapp")
+        .build();
+
+    Some(
+        opentelemetry_sdk::trace::SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource)
+            .build(),
+    )
+}
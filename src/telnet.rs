@@ -0,0 +1,213 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, BufReader, ReadBuf};
+
+// Telnet commands, RFC 854
+const SE: u8 = 240;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum State {
+    #[default]
+    Data,
+    // After a CR, where a NUL is only padding
+    Cr,
+    Iac,
+    // After WILL, WONT, DO or DONT, before the option
+    Negotiation,
+    // Between IAC SB and IAC SE
+    Sub,
+    SubIac,
+}
+
+/// Strips what telnet clients send besides text: option negotiation,
+/// subnegotiations and other commands. None of it is answered, so clients
+/// carry on with their defaults. Sequences split across reads are picked up
+/// where they left off.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::telnet::Telnet;
+///
+/// fn filter(telnet: &mut Telnet, bytes: &[u8]) -> Vec<u8> {
+///     let mut buf = bytes.to_vec();
+///     let kept = telnet.filter(&mut buf);
+///     buf.truncate(kept);
+///     buf
+/// }
+///
+/// // Captured from netkit telnet and PuTTY connecting, the first then
+/// // answering the server's DO NAWS with its window size
+/// let netkit = b"\xff\xfd\x03\xff\xfb\x18\xff\xfb\x1f\xff\xfb\x20\xff\xfb\x21\xff\xfb\x22\xff\xfb\x27\xff\xfd\x05>me\r\n";
+/// let putty = b"\xff\xfb\x1f\xff\xfb\x20\xff\xfb\x18\xff\xfb\x27\xff\xfd\x01\xff\xfb\x03\xff\xfd\x03>me\r\n";
+/// let naws = b"\xff\xfa\x1f\x00\x50\x00\x18\xff\xf0>me\r\n";
+/// for handshake in [&netkit[..], putty, naws] {
+///     assert_eq!(filter(&mut Telnet::default(), handshake), b">me\r\n");
+/// }
+///
+/// // A byte at a time, as a slow link might deliver it
+/// let mut telnet = Telnet::default();
+/// let mut text = vec![];
+/// for byte in netkit.iter().chain(naws) {
+///     text.extend(filter(&mut telnet, &[*byte]));
+/// }
+/// assert_eq!(text, b">me\r\n>me\r\n");
+///
+/// // Terminal type, and a window 255 wide, whose width has its IAC doubled
+/// let ttype = b"\xff\xfa\x18\x00xterm-256color\xff\xf0";
+/// let wide = b"\xff\xfa\x1f\x00\xff\xff\x00\x18\xff\xf0";
+/// assert_eq!(filter(&mut Telnet::default(), &[&ttype[..], wide, b"hi\r\n"].concat()), b"hi\r\n");
+///
+/// // Ctrl-C and "are you there" mid-message, and a bare CR padded with NUL
+/// let mut telnet = Telnet::default();
+/// assert_eq!(filter(&mut telnet, b"hel\xff\xf4lo\xff\xf6\r\n"), b"hello\r\n");
+/// assert_eq!(filter(&mut telnet, b"a\r\0b\r\n"), b"a\rb\r\n");
+///
+/// // A doubled IAC is the byte itself, which isn't text, but is kept for
+/// // `LossyLines` to replace
+/// assert_eq!(filter(&mut Telnet::default(), b"caf\xff\xff\n"), b"caf\xff\n");
+///
+/// // Anything that isn't telnet goes through untouched
+/// let utf8 = "héllo wörld ✓\n".as_bytes();
+/// assert_eq!(filter(&mut Telnet::default(), utf8), utf8);
+/// ```
+#[derive(Debug, Default)]
+pub struct Telnet {
+    state: State,
+}
+
+impl Telnet {
+    // Strips `buf` in place, returning how much of the start of it is text
+    pub fn filter(&mut self, buf: &mut [u8]) -> usize {
+        let mut kept = 0;
+
+        for i in 0..buf.len() {
+            let byte = buf[i];
+            let (state, keep) = match (self.state, byte) {
+                (State::Data | State::Cr, IAC) => (State::Iac, false),
+                (State::Cr, 0) => (State::Data, false),
+                (State::Data | State::Cr, b'\r') => (State::Cr, true),
+                (State::Data | State::Cr, _) => (State::Data, true),
+                (State::Iac, IAC) => (State::Data, true),
+                (State::Iac, WILL | WONT | DO | DONT) => (State::Negotiation, false),
+                (State::Iac, SB) => (State::Sub, false),
+                // Every other command is the one byte
+                (State::Iac, _) => (State::Data, false),
+                (State::Negotiation, _) => (State::Data, false),
+                (State::Sub, IAC) => (State::SubIac, false),
+                (State::Sub, _) => (State::Sub, false),
+                (State::SubIac, SE) => (State::Data, false),
+                // A doubled IAC in the parameters
+                (State::SubIac, _) => (State::Sub, false),
+            };
+
+            self.state = state;
+            if keep {
+                buf[kept] = byte;
+                kept += 1;
+            }
+        }
+
+        kept
+    }
+}
+
+// A connection's input with telnet's commands taken out, see `Telnet`
+pub struct TelnetReader<R> {
+    inner: R,
+    telnet: Telnet,
+}
+
+impl<R> TelnetReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            telnet: Telnet::default(),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TelnetReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            let start = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            if buf.filled().len() == start {
+                return Poll::Ready(Ok(()));
+            }
+
+            let kept = this.telnet.filter(&mut buf.filled_mut()[start..]);
+            buf.set_filled(start + kept);
+
+            // Reading nothing would look like the end of the stream, so a read
+            // that was all commands is followed by another
+            if kept > 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+/// Lines without their `\n` or `\r\n`, like `AsyncBufReadExt::lines`, but
+/// with anything that isn't UTF-8 replaced by U+FFFD rather than failing.
+/// Cancel safe, so it can be raced in `select!`.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::telnet::{LossyLines, TelnetReader};
+///
+/// let input: &[u8] = b"\xff\xfb\x1f>join-room caf\xe9\r\nna\xc3\xafve\nlast";
+/// let mut lines = LossyLines::new(TelnetReader::new(input));
+/// assert_eq!(lines.next_line().await.unwrap().as_deref(), Some(">join-room caf\u{fffd}"));
+/// assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("naïve"));
+/// assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("last"));
+/// assert_eq!(lines.next_line().await.unwrap(), None);
+/// # }
+/// ```
+pub struct LossyLines<R> {
+    reader: BufReader<R>,
+    // What's been read of the next line, kept if a read is cancelled
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> LossyLines<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            buf: Vec::new(),
+        }
+    }
+
+    pub async fn next_line(&mut self) -> io::Result<Option<String>> {
+        let read = self.reader.read_until(b'\n', &mut self.buf).await?;
+        if read == 0 && self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let mut line = std::mem::take(&mut self.buf);
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+}
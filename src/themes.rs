@@ -0,0 +1,255 @@
+use crate::audit::civil_date;
+use crate::render::{Renderer, ServerMessage, TextRenderer, RESET};
+
+// How `{time}` is shown, always in UTC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeFormat {
+    // `12:01`
+    Clock,
+    // `2024-01-31 12:01:05`
+    Date,
+}
+
+// How one kind of message looks. `{user}`, `{text}` and `{time}` in `format`
+// are filled in, `prefix` and `suffix` go around it as they are, eg for ANSI
+// styling.
+#[derive(Clone, Copy, Debug)]
+pub struct Format {
+    pub prefix: &'static str,
+    pub format: &'static str,
+    pub suffix: &'static str,
+}
+
+#[derive(Debug)]
+pub struct Theme {
+    pub name: &'static str,
+    pub chat: Format,
+    // Joins, leaves, removed messages and the room's notices, a line at a
+    // time, `{text}` being the line `plain` shows between its dashes
+    pub system: Format,
+    pub typing: Format,
+    pub time: TimeFormat,
+}
+
+/// The looks `>set-theme` picks from, the first being everyone's to start
+/// with. Each only changes how rooms are shown to whoever picked it, what's
+/// stored and sent to everyone else stays the same.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::render::ServerMessage;
+/// use chatsapp::themes;
+///
+/// // 2024-01-31 12:01:05 UTC
+/// let at = 1_706_702_465_000;
+/// let kinds = [
+///     ServerMessage::Chat { user: "bob".into(), text: "hi {user}".into(), ts: None },
+///     ServerMessage::Joined { user: "bob".into() },
+///     ServerMessage::Left { user: "bob".into() },
+///     ServerMessage::Removed { by: "carol".into() },
+///     ServerMessage::info("Slow mode is on\nEvery 5s"),
+///     ServerMessage::Typing { user: "bob".into() },
+/// ];
+/// let golden: [(&str, [&[&str]; 6]); 4] = [
+///     ("plain", [
+///         &["bob: hi {user}"],
+///         &["-- bob has joined the room --"],
+///         &["-- bob has left the room --"],
+///         &["-- message removed by carol --"],
+///         &["-- Slow mode is on --", "-- Every 5s --"],
+///         &["* bob is typing"],
+///     ]),
+///     ("compact", [
+///         &["[12:01] <bob> hi {user}"],
+///         &["[12:01] * bob has joined the room"],
+///         &["[12:01] * bob has left the room"],
+///         &["[12:01] * message removed by carol"],
+///         &["[12:01] * Slow mode is on", "[12:01] * Every 5s"],
+///         &["* bob is typing"],
+///     ]),
+///     ("verbose", [
+///         &["[2024-01-31 12:01:05 UTC] bob: hi {user}"],
+///         &["[2024-01-31 12:01:05 UTC] -- bob has joined the room --"],
+///         &["[2024-01-31 12:01:05 UTC] -- bob has left the room --"],
+///         &["[2024-01-31 12:01:05 UTC] -- message removed by carol --"],
+///         &["[2024-01-31 12:01:05 UTC] -- Slow mode is on --", "[2024-01-31 12:01:05 UTC] -- Every 5s --"],
+///         &["* bob is typing"],
+///     ]),
+///     ("high-contrast", [
+///         &["\x1b[97m\x1b[1;93mbob\x1b[22;97m: hi {user}\x1b[0m"],
+///         &["\x1b[1;96m-- bob has joined the room --\x1b[0m"],
+///         &["\x1b[1;96m-- bob has left the room --\x1b[0m"],
+///         &["\x1b[1;96m-- message removed by carol --\x1b[0m"],
+///         &["\x1b[1;96m-- Slow mode is on --\x1b[0m", "\x1b[1;96m-- Every 5s --\x1b[0m"],
+///         &["\x1b[93m* bob is typing\x1b[0m"],
+///     ]),
+/// ];
+///
+/// assert_eq!(themes::THEMES.len(), golden.len());
+/// for (name, expected) in golden {
+///     let theme = themes::find(name).unwrap();
+///     for (kind, lines) in kinds.iter().zip(expected) {
+///         assert_eq!(theme.render(kind.clone(), at), lines, "{} {:?}", name, kind);
+///     }
+/// }
+/// assert!(themes::find("neon").is_none());
+///
+/// // Anything that isn't from a room is left as it is
+/// let error = ServerMessage::Lines { lines: vec!["Start of chat\n".into()] };
+/// assert_eq!(themes::find("compact").unwrap().render(error, at), ["Start of chat"]);
+/// ```
+pub static THEMES: [Theme; 4] = [
+    Theme {
+        name: "plain",
+        chat: Format {
+            prefix: "",
+            format: "{user}: {text}",
+            suffix: "",
+        },
+        system: Format {
+            prefix: "-- ",
+            format: "{text}",
+            suffix: " --",
+        },
+        typing: Format {
+            prefix: "",
+            format: "* {user} is typing",
+            suffix: "",
+        },
+        time: TimeFormat::Clock,
+    },
+    Theme {
+        name: "compact",
+        chat: Format {
+            prefix: "",
+            format: "[{time}] <{user}> {text}",
+            suffix: "",
+        },
+        system: Format {
+            prefix: "",
+            format: "[{time}] * {text}",
+            suffix: "",
+        },
+        typing: Format {
+            prefix: "",
+            format: "* {user} is typing",
+            suffix: "",
+        },
+        time: TimeFormat::Clock,
+    },
+    Theme {
+        name: "verbose",
+        chat: Format {
+            prefix: "",
+            format: "[{time} UTC] {user}: {text}",
+            suffix: "",
+        },
+        system: Format {
+            prefix: "",
+            format: "[{time} UTC] -- {text} --",
+            suffix: "",
+        },
+        typing: Format {
+            prefix: "",
+            format: "* {user} is typing",
+            suffix: "",
+        },
+        time: TimeFormat::Date,
+    },
+    Theme {
+        name: "high-contrast",
+        chat: Format {
+            prefix: "\x1b[97m",
+            format: "\x1b[1;93m{user}\x1b[22;97m: {text}",
+            suffix: RESET,
+        },
+        system: Format {
+            prefix: "\x1b[1;96m",
+            format: "-- {text} --",
+            suffix: RESET,
+        },
+        typing: Format {
+            prefix: "\x1b[93m",
+            format: "* {user} is typing",
+            suffix: RESET,
+        },
+        time: TimeFormat::Clock,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Theme> {
+    THEMES.iter().find(|theme| theme.name == name)
+}
+
+impl Theme {
+    // Lines without line endings, `at` being when it was sent in ms
+    pub fn render(&self, message: ServerMessage, at: i64) -> Vec<String> {
+        let (format, user, lines) = match message {
+            ServerMessage::Chat { user, text, .. } => (&self.chat, user, vec![text]),
+            ServerMessage::Typing { user } => (&self.typing, user, vec![String::new()]),
+            message @ (ServerMessage::Joined { .. }
+            | ServerMessage::Left { .. }
+            | ServerMessage::Removed { .. }
+            | ServerMessage::Retracted { .. }
+            | ServerMessage::Info { .. }) => {
+                (&self.system, String::new(), TextRenderer.render(message))
+            }
+            message => return TextRenderer.render(message),
+        };
+        let time = format_time(at, self.time);
+
+        lines
+            .iter()
+            .map(|text| {
+                let line = fill(format.format, &user, text, &time);
+                format!("{}{}{}", format.prefix, line, format.suffix)
+            })
+            .collect()
+    }
+}
+
+// In one pass, so a placeholder in someone's message is left as it is
+fn fill(format: &str, user: &str, text: &str, time: &str) -> String {
+    let mut filled = String::with_capacity(format.len() + text.len());
+    let mut rest = format;
+
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let (value, len) = match rest {
+            _ if rest.starts_with("{user}") => (user, 6),
+            _ if rest.starts_with("{text}") => (text, 6),
+            _ if rest.starts_with("{time}") => (time, 6),
+            _ => ("{", 1),
+        };
+        filled.push_str(value);
+        rest = &rest[len..];
+    }
+    filled.push_str(rest);
+
+    filled
+}
+
+fn format_time(at: i64, format: TimeFormat) -> String {
+    let secs = at.div_euclid(1000);
+    let of_day = secs.rem_euclid(86_400);
+    let (hours, mins) = (of_day / 3600, of_day % 3600 / 60);
+
+    match format {
+        TimeFormat::Clock => format!("{:02}:{:02}", hours, mins),
+        TimeFormat::Date => {
+            let (year, month, day) = civil_date(secs.div_euclid(86_400));
+            format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                year,
+                month,
+                day,
+                hours,
+                mins,
+                of_day % 60
+            )
+        }
+    }
+}
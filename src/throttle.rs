@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+// Fixed window counter. The limit is passed on every check rather than stored
+// so a config reload takes effect immediately.
+pub struct Throttle {
+    window_start: Instant,
+    count: u32,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+}
+
+impl Throttle {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use chatsapp::throttle::Throttle;
+    ///
+    /// let mut throttle = Throttle::default();
+    /// let window = Duration::from_secs(10);
+    ///
+    /// assert!(throttle.check(2, window).is_ok());
+    /// assert!(throttle.check(2, window).is_ok());
+    /// assert!(throttle.check(2, window).is_err());
+    ///
+    /// // A limit of 0 disables throttling
+    /// assert!(throttle.check(0, window).is_ok());
+    /// ```
+    pub fn check(&mut self, limit: u32, window: Duration) -> Result<(), Duration> {
+        if limit == 0 {
+            return Ok(());
+        }
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= window {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+
+        if self.count >= limit {
+            // Time left until the window resets
+            return Err(window.saturating_sub(elapsed));
+        }
+
+        self.count += 1;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_script::{Script, UnicodeScript};
+
+// Allowed alongside letters and numbers
+const PUNCTUATION: [char; 3] = ['-', '_', '.'];
+
+#[derive(Debug, PartialEq)]
+pub enum UsernameError {
+    Blank,
+    Disallowed(char),
+    // Eg Cyrillic letters among Latin ones, to pass for someone else
+    MixedScripts,
+}
+
+impl std::fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameError::Blank => writeln!(f, "Error: Usernames can't be blank"),
+            UsernameError::Disallowed(c) => writeln!(
+                f,
+                "Error: Usernames can't contain '{}', only letters, numbers, - _ and .",
+                c
+            ),
+            UsernameError::MixedScripts => writeln!(
+                f,
+                "Error: Usernames can't mix alphabets, eg Latin and Cyrillic letters"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+/// The name as it's stored and shown, NFKC normalized so the same name
+/// always has the same bytes however it was typed. Only letters, numbers,
+/// `-`, `_` and `.` are allowed, and letters all have to come from one
+/// script, so `bob` can't be imitated with a Cyrillic `Ь`.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::username::{self, UsernameError};
+///
+/// // Composed and decomposed é are the same name
+/// let composed = username::normalize("Ren\u{e9}").unwrap();
+/// let decomposed = username::normalize("Rene\u{301}").unwrap();
+/// assert_eq!(composed, decomposed);
+/// // So are full width letters and their usual form
+/// assert_eq!(username::normalize("ｂｏｂ").unwrap(), "bob");
+/// assert_eq!(username::normalize("  alice ").unwrap(), "alice");
+///
+/// // Homoglyphs of Latin letters
+/// for imitation in ["\u{42c}ob", "b\u{43e}b", "p\u{430}ypal"] {
+///     assert_eq!(username::normalize(imitation), Err(UsernameError::MixedScripts));
+/// }
+/// // One script at a time is fine, as is Japanese's mix of kanji and kana
+/// for name in ["Борис", "ελένη", "山田たろう", "o_brien.99"] {
+///     assert!(username::normalize(name).is_ok(), "{}", name);
+/// }
+///
+/// assert_eq!(username::normalize("bob smith"), Err(UsernameError::Disallowed(' ')));
+/// assert_eq!(username::normalize("bob\u{200b}"), Err(UsernameError::Disallowed('\u{200b}')));
+/// assert_eq!(username::normalize("bob\u{200d}"), Err(UsernameError::Disallowed('\u{200d}')));
+/// assert_eq!(username::normalize(" "), Err(UsernameError::Blank));
+/// ```
+pub fn normalize(name: &str) -> Result<String, UsernameError> {
+    let name: String = name.trim().nfkc().collect();
+    if name.is_empty() {
+        return Err(UsernameError::Blank);
+    }
+
+    if let Some(c) = name
+        .chars()
+        .find(|c| !c.is_alphanumeric() && !is_mark(*c) && !PUNCTUATION.contains(c))
+    {
+        return Err(UsernameError::Disallowed(c));
+    }
+
+    let mut scripts = name.chars().map(|c| c.script()).filter_map(script_group);
+    if let Some(first) = scripts.next() {
+        if scripts.any(|script| script != first) {
+            return Err(UsernameError::MixedScripts);
+        }
+    }
+
+    Ok(name)
+}
+
+/// What names are compared by, so `Bob` and `bob` are the same user when
+/// registering and logging in.
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::username;
+///
+/// assert_eq!(username::fold("Bob"), username::fold("bob"));
+/// assert_eq!(username::fold("ＢＯＢ"), "bob");
+/// assert_eq!(username::fold("René"), username::fold("RENE\u{301}"));
+/// assert_ne!(username::fold("bob"), username::fold("b\u{43e}b"));
+/// ```
+pub fn fold(name: &str) -> String {
+    name.nfkc().collect::<String>().to_lowercase()
+}
+
+// Combining diacritics left over once composed. Not everything of the
+// Inherited script, which includes invisible joiners and variation selectors.
+fn is_mark(c: char) -> bool {
+    matches!(
+        c,
+        '\u{300}'..='\u{36f}'
+            | '\u{1ab0}'..='\u{1aff}'
+            | '\u{1dc0}'..='\u{1dff}'
+            | '\u{20d0}'..='\u{20ff}'
+            | '\u{fe20}'..='\u{fe2f}'
+    )
+}
+
+// None for characters shared between scripts, eg digits. Han and the
+// Japanese and Korean scripts are written together, so count as one.
+fn script_group(script: Script) -> Option<Script> {
+    match script {
+        Script::Common | Script::Inherited | Script::Unknown => None,
+        Script::Hiragana | Script::Katakana | Script::Hangul | Script::Bopomofo => {
+            Some(Script::Han)
+        }
+        script => Some(script),
+    }
+}
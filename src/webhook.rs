@@ -0,0 +1,195 @@
+use rand::Rng;
+
+use crate::store::{RoomStore, StoreError};
+
+// Used when `>webhook create` isn't given a name
+pub const DEFAULT_NAME: &str = "webhook";
+pub const MAX_NAME_LEN: usize = 32;
+// Outgoing webhooks each room can have
+pub const MAX_OUTGOING: usize = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Webhook {
+    pub token: String,
+    pub room: String,
+    // Who messages posted through it appear to come from
+    pub name: String,
+}
+
+// Where a room's messages are sent, see `forward`
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutgoingWebhook {
+    pub url: String,
+    // Payloads are signed with it so the receiver can check where they came from
+    pub secret: String,
+}
+
+/// Creates a webhook posting into `room` as `name`, returning its token.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::store::MemoryStore;
+/// use chatsapp::webhook;
+///
+/// let store = MemoryStore::default();
+/// let token = webhook::create(&store, "rust", "ci").await.unwrap();
+///
+/// let hook = webhook::find(&store, &token).await.unwrap().unwrap();
+/// assert_eq!((hook.room.as_str(), hook.name.as_str()), ("rust", "ci"));
+/// assert_eq!(webhook::list(&store, "rust").await.unwrap(), [hook]);
+///
+/// // Tokens can only be revoked from their own room
+/// assert!(!webhook::revoke(&store, "go", &token).await.unwrap());
+/// assert!(webhook::revoke(&store, "rust", &token).await.unwrap());
+/// assert_eq!(webhook::find(&store, &token).await.unwrap(), None);
+/// # }
+/// ```
+pub async fn create(store: &dyn RoomStore, room: &str, name: &str) -> Result<String, StoreError> {
+    let token = random_hex();
+
+    store.hash_set(&key(&token), "room", room).await?;
+    store.hash_set(&key(&token), "name", name).await?;
+    store.set_add(&room_key(room), &token).await?;
+
+    Ok(token)
+}
+
+/// Looked up for each `POST /hooks/<token>` to the HTTP listener, whose JSON
+/// body's `text` is sent to the room as if the webhook's name had said it.
+pub async fn find(store: &dyn RoomStore, token: &str) -> Result<Option<Webhook>, StoreError> {
+    let key = key(token);
+    let (Some(room), Some(name)) = (
+        store.hash_get(&key, "room").await?,
+        store.hash_get(&key, "name").await?,
+    ) else {
+        return Ok(None);
+    };
+
+    Ok(Some(Webhook {
+        token: token.to_owned(),
+        room,
+        name,
+    }))
+}
+
+// Sorted by name, then token
+pub async fn list(store: &dyn RoomStore, room: &str) -> Result<Vec<Webhook>, StoreError> {
+    let mut hooks = Vec::new();
+    for token in store.set_members(&room_key(room)).await? {
+        if let Some(hook) = find(store, &token).await? {
+            hooks.push(hook);
+        }
+    }
+    hooks.sort_by(|a, b| (&a.name, &a.token).cmp(&(&b.name, &b.token)));
+
+    Ok(hooks)
+}
+
+// Returns false if the token doesn't belong to the room
+pub async fn revoke(store: &dyn RoomStore, room: &str, token: &str) -> Result<bool, StoreError> {
+    if !store.set_remove(&room_key(room), token).await? {
+        return Ok(false);
+    }
+    store.hash_delete(&key(token)).await?;
+
+    Ok(true)
+}
+
+// For when the room is deleted, both incoming and outgoing
+pub async fn revoke_all(store: &dyn RoomStore, room: &str) -> Result<(), StoreError> {
+    for token in store.set_members(&room_key(room)).await? {
+        store.hash_delete(&key(&token)).await?;
+    }
+    store.set_delete(&room_key(room)).await?;
+    store.hash_delete(&outgoing_key(room)).await?;
+
+    Ok(())
+}
+
+/// Starts sending the room's messages to `url`, returning the secret they'll
+/// be signed with, or None if the URL was already added.
+///
+/// # Examples
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use chatsapp::store::MemoryStore;
+/// use chatsapp::webhook;
+///
+/// let store = MemoryStore::default();
+/// let url = "http://example.com/hook";
+/// let secret = webhook::add_outgoing(&store, "rust", url).await.unwrap().unwrap();
+/// assert_eq!(webhook::add_outgoing(&store, "rust", url).await.unwrap(), None);
+///
+/// let hooks = webhook::outgoing(&store, "rust").await.unwrap();
+/// assert_eq!((hooks[0].url.as_str(), &hooks[0].secret), (url, &secret));
+///
+/// assert!(webhook::remove_outgoing(&store, "rust", url).await.unwrap());
+/// assert!(webhook::outgoing(&store, "rust").await.unwrap().is_empty());
+/// # }
+/// ```
+pub async fn add_outgoing(
+    store: &dyn RoomStore,
+    room: &str,
+    url: &str,
+) -> Result<Option<String>, StoreError> {
+    let key = outgoing_key(room);
+    if store.hash_get(&key, url).await?.is_some() {
+        return Ok(None);
+    }
+
+    let secret = random_hex();
+    store.hash_set(&key, url, &secret).await?;
+
+    Ok(Some(secret))
+}
+
+// Sorted by URL
+pub async fn outgoing(
+    store: &dyn RoomStore,
+    room: &str,
+) -> Result<Vec<OutgoingWebhook>, StoreError> {
+    let mut hooks: Vec<_> = store
+        .hash_get_all(&outgoing_key(room))
+        .await?
+        .into_iter()
+        .map(|(url, secret)| OutgoingWebhook { url, secret })
+        .collect();
+    hooks.sort_by(|a, b| a.url.cmp(&b.url));
+
+    Ok(hooks)
+}
+
+// Returns false if the URL wasn't added
+pub async fn remove_outgoing(
+    store: &dyn RoomStore,
+    room: &str,
+    url: &str,
+) -> Result<bool, StoreError> {
+    store.hash_remove(&outgoing_key(room), url).await
+}
+
+// A single word, so messages from it read like anyone else's
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().count() <= MAX_NAME_LEN && !name.contains(char::is_whitespace)
+}
+
+fn random_hex() -> String {
+    format!("{:032x}", rand::rng().random::<u128>())
+}
+
+fn key(token: &str) -> String {
+    format!("webhook:{}", token)
+}
+
+fn room_key(room: &str) -> String {
+    format!("roomhooks:{}", room)
+}
+
+fn outgoing_key(room: &str) -> String {
+    format!("roomhooksout:{}", room)
+}
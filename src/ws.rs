@@ -0,0 +1,169 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures_util::{stream, SinkExt, StreamExt};
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{error, info};
+
+use crate::access;
+use crate::app::App;
+use crate::client::ServerEvent;
+use crate::keepalive::{Due, Keepalive};
+use crate::metrics;
+use crate::registry::ConnectionRegistry;
+use crate::render::{Renderer, TextRenderer};
+use crate::server::ServerContext;
+use crate::shutdown::Shutdown;
+
+/// Accepts WebSocket connections and runs each one as a regular `App`.
+pub async fn listen(
+    listener: TcpListener,
+    ctx: Arc<ServerContext>,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    let local_addr = format!("ws://{}", listener.local_addr()?);
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = shutdown.recv() => return Ok(()),
+        };
+
+        let ctx = Arc::clone(&ctx);
+        let local_addr = local_addr.clone();
+
+        tokio::spawn(async move {
+            let _connection = metrics::connection();
+
+            if let Err(e) = handle(stream, addr, &ctx, local_addr).await {
+                error!("{}", e)
+            };
+        });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    addr: SocketAddr,
+    ctx: &Arc<ServerContext>,
+    local_addr: String,
+) -> io::Result<()> {
+    let mut socket = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(io::Error::other)?;
+
+    if !ctx.permits(addr.ip()).await {
+        info!("Refused connection from {}, not allowed by access", addr);
+        socket
+            .send(Message::text(access::DENIED))
+            .await
+            .map_err(io::Error::other)?;
+
+        return socket.close(None).await.map_err(io::Error::other);
+    }
+
+    if let Some(ban) = ctx.ban_for(addr.ip()).await {
+        let banned = Message::text(ban.notice().trim_end());
+        socket.send(banned).await.map_err(io::Error::other)?;
+
+        return socket.close(None).await.map_err(io::Error::other);
+    }
+
+    if ctx.is_full() {
+        let full = Message::text("Server is full, try again later");
+        socket.send(full).await.map_err(io::Error::other)?;
+
+        return socket.close(None).await.map_err(io::Error::other);
+    }
+
+    // The app speaks the line protocol on one end of the pipe while the
+    // bridge translates frames on the other.
+    let (client, server) = io::duplex(64 * 1024);
+    tokio::spawn(bridge(socket, server, TextRenderer, Arc::clone(ctx)));
+
+    let conn = ConnectionRegistry::register(&ctx.registry, addr.to_string(), local_addr);
+
+    App::new(client, addr, conn, Arc::clone(ctx))
+        .without_keepalive()
+        .run()
+        .await
+}
+
+// Pings after `keepalive_secs` without sending a frame, and closes the socket
+// if nothing at all comes back within `keepalive_grace_secs`
+async fn bridge<S>(
+    socket: WebSocketStream<S>,
+    server: DuplexStream,
+    mut renderer: impl Renderer,
+    ctx: Arc<ServerContext>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut sink, mut frames) = socket.split();
+    let (reader, mut writer) = io::split(server);
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut keepalive = Keepalive::new();
+
+    loop {
+        let check = keepalive.next_check(&ctx.config.load());
+
+        tokio::select! {
+            frame = frames.next() => {
+                keepalive.heard();
+                match frame {
+                    Some(Ok(Message::Text(text))) => {
+                        // A frame is one line, so embedded newlines can't smuggle in commands
+                        let line = format!("{}\n", text.trim_end().replace(['\r', '\n'], " "));
+
+                        if writer.write_all(line.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Pings are answered by tungstenite itself, and any frame
+                    // answers ours
+                    Some(Ok(Message::Pong(_) | Message::Ping(_) | Message::Binary(_) | Message::Frame(_))) => {}
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                }
+            }
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => {
+                    let frames = renderer
+                        .render(ServerEvent::parse(&line).into())
+                        .into_iter()
+                        .map(|frame| Ok(Message::text(frame)));
+                    if sink.send_all(&mut stream::iter(frames)).await.is_err() {
+                        break;
+                    }
+                    keepalive.wrote();
+                }
+                // The app has finished, eg after `>exit`
+                _ => break,
+            },
+            _ = time::sleep_until(check.unwrap_or_else(Instant::now).into()), if check.is_some() => {
+                match keepalive.due(&ctx.config.load()) {
+                    Some(Due::Probe) => {
+                        keepalive.probed();
+                        if sink.send(Message::Ping(Default::default())).await.is_err() {
+                            break;
+                        }
+                        keepalive.wrote();
+                    }
+                    // Peer never answered the last ping
+                    Some(Due::Dead) => break,
+                    None => {}
+                }
+            }
+        }
+    }
+
+    // Dropping both halves of the pipe ends `App::run`, which leaves any room
+    let _ = sink.close().await;
+}
@@ -0,0 +1,477 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::command::Command;
+use chatsapp::config::RuntimeConfig;
+use chatsapp::errors::Code;
+use chatsapp::room::{self, CreateRoomOpts};
+use chatsapp::server::ServerContext;
+use chatsapp::store::MemoryStore;
+use chatsapp::{account, mention, shutdown};
+
+use crate::common::{self, expect, LIVE};
+
+// Bob, who's registered with hunter22
+async fn log_in(addr: SocketAddr, reply: &str) -> Client {
+    let mut client = Client::connect(addr).await.unwrap();
+    client.set_username("bob").await.unwrap();
+    expect(&mut client, ">login hunter22", reply).await;
+    client
+}
+
+#[tokio::test]
+async fn multi_login() {
+    let (addr, store, _) = common::serve().await;
+    account::set_password(&*store, "bob", "hunter22")
+        .await
+        .unwrap();
+
+    // By default the newest login wins
+    let mut laptop = log_in(addr, "Logged in as bob").await;
+    let mut phone = log_in(addr, "Logged in as bob").await;
+    let replaced = ServerEvent::Info("Logged in from another location".into());
+    while laptop.next_event().await.unwrap() != replaced {}
+    assert_eq!(laptop.recv().await.unwrap(), None);
+
+    // Or the first one keeps it
+    expect(
+        &mut phone,
+        ">set multi-login deny",
+        "Multi-login set to deny",
+    )
+    .await;
+    let mut tablet = log_in(
+        addr,
+        "[E_ALREADY_LOGGED_IN] bob is already logged in elsewhere",
+    )
+    .await;
+
+    // Or both stay, and both hear from the room
+    expect(
+        &mut phone,
+        ">set multi-login allow",
+        "Multi-login set to allow",
+    )
+    .await;
+    expect(&mut tablet, ">login hunter22", "Logged in as bob").await;
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    let created = "Room 'rust' created, join it with >join-room rust";
+    expect(&mut alice, ">create-room rust", created).await;
+    for bob in [&mut phone, &mut tablet] {
+        expect(bob, ">join-room rust", LIVE).await;
+    }
+    alice.join("rust").await.unwrap();
+    alice.send("hi bob").await.unwrap();
+    let hi = ServerEvent::Chat {
+        user: "alice".into(),
+        text: "hi bob".into(),
+    };
+    for bob in [&mut phone, &mut tablet] {
+        while bob.next_event().await.unwrap() != hi {}
+    }
+    assert_eq!(
+        account::multi_login(&*store, "bob").await.unwrap(),
+        account::MultiLogin::Allow
+    );
+}
+
+#[tokio::test]
+async fn register() {
+    let store = Arc::new(MemoryStore::default());
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = ServerContext::new(store.clone(), trigger);
+    let config = RuntimeConfig::parse("limits = { login_grace_secs = 1 }").unwrap();
+    ctx.config.store(Arc::new(config));
+    let addr = common::listen(Arc::new(ctx), shutdown).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    expect(
+        &mut bob,
+        ">register short",
+        "[E_PASSWORD_TOO_SHORT] Passwords must be at least 8 characters",
+    )
+    .await;
+    expect(&mut bob, ">register hunter22", "bob is now registered").await;
+    expect(
+        &mut bob,
+        ">register hunter22",
+        "[E_NAME_TAKEN] bob is already registered",
+    )
+    .await;
+
+    // Someone else taking the name can't join rooms as it, and is renamed
+    // once the grace period's up
+    let mut mallory = Client::connect(addr).await.unwrap();
+    expect(
+        &mut mallory,
+        ">set-username bob",
+        "bob is registered, use >login password within 1s or you'll be renamed",
+    )
+    .await;
+    expect(
+        &mut mallory,
+        ">join-room rust",
+        "[E_NEEDS_LOGIN] Log in as bob with >login password first",
+    )
+    .await;
+    expect(
+        &mut mallory,
+        ">login hunter2",
+        "[E_WRONG_PASSWORD] Wrong password",
+    )
+    .await;
+    loop {
+        if let ServerEvent::Info(line) = mallory.next_event().await.unwrap() {
+            if line.starts_with("You didn't log in as bob in time, you're now guest-") {
+                break;
+            }
+        }
+    }
+
+    // The right password from anywhere else is fine
+    let mut laptop = Client::connect(addr).await.unwrap();
+    laptop.set_username("bob").await.unwrap();
+    expect(&mut laptop, ">login hunter22", "Logged in as bob").await;
+    expect(
+        &mut laptop,
+        ">passwd hunter2 something",
+        "[E_WRONG_PASSWORD] Wrong password",
+    )
+    .await;
+    expect(
+        &mut laptop,
+        ">passwd hunter22 something",
+        "Password changed",
+    )
+    .await;
+    assert!(account::verify(&*store, "bob", "something").await.unwrap());
+    assert!(!account::verify(&*store, "bob", "hunter22").await.unwrap());
+}
+
+#[tokio::test]
+async fn verify() {
+    let (_, store, _) = common::serve().await;
+    // Hashed with PBKDF2-HMAC-SHA1, before Argon2
+    let legacy = "pbkdf2-sha1$1000$000102030405060708090a0b0c0d0e0f$9b19801cbff668449b5d6830a324f990f68c331e";
+    store
+        .hash_set("account:bob", "password", legacy)
+        .await
+        .unwrap();
+    assert!(!account::verify(&*store, "bob", "hunter23").await.unwrap());
+    let stored = store.hash_get("account:bob", "password").await.unwrap();
+    assert_eq!(stored.as_deref(), Some(legacy));
+
+    // Logging in still works, and rehashes it
+    assert!(account::verify(&*store, "bob", "hunter22").await.unwrap());
+    let stored = store.hash_get("account:bob", "password").await.unwrap();
+    assert!(stored.unwrap().starts_with("$argon2id$"));
+    assert!(account::verify(&*store, "bob", "hunter22").await.unwrap());
+    assert!(!account::verify(&*store, "alice", "hunter22").await.unwrap());
+}
+
+#[tokio::test]
+async fn is_guest() {
+    let (addr, store, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.command(Command::Me).await.unwrap();
+    let me = alice.next_event().await.unwrap().to_string();
+    let guest = me
+        .strip_prefix("Username: ")
+        .unwrap()
+        .split(',')
+        .next()
+        .unwrap()
+        .to_owned();
+    assert!(account::is_guest(&guest));
+
+    // Guests can create and join rooms, but don't own them
+    let opts = CreateRoomOpts {
+        join: true,
+        ..Default::default()
+    };
+    let create = Command::CreateRoom {
+        name: "rust".into(),
+        opts,
+    };
+    alice.command(create).await.unwrap();
+    common::until(&mut alice, LIVE).await;
+    assert_eq!(room::info(&*store, "rust").await.unwrap().owner, None);
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    // Picking a name tells the room
+    alice.set_username("alice").await.unwrap();
+    let set = ServerEvent::Info("Username set to 'alice'".into());
+    assert_eq!(alice.next_event().await.unwrap(), set);
+    let renamed = ServerEvent::Info(format!("{} is now known as alice", guest));
+    while bob.next_event().await.unwrap() != renamed {}
+    alice.send("hi").await.unwrap();
+    let hi = ServerEvent::Chat {
+        user: "alice".into(),
+        text: "hi".into(),
+    };
+    assert_eq!(bob.next_event().await.unwrap(), hi);
+
+    // Nobody else can take a guest name
+    bob.set_username(&guest).await.unwrap();
+    let taken = "[E_RESERVED] Names starting with 'guest-' are for guests, pick another";
+    assert_eq!(bob.next_event().await.unwrap().to_string(), taken);
+}
+
+#[tokio::test]
+async fn is_registered() {
+    let (addr, store, _) = common::serve().await;
+    account::set_password(&*store, "Bob", "hunter22")
+        .await
+        .unwrap();
+    // Case and composition don't make a different name
+    assert!(account::is_registered(&*store, "bob").await.unwrap());
+    account::set_password(&*store, "Ren\u{e9}", "hunter22")
+        .await
+        .unwrap();
+    assert!(account::is_registered(&*store, "rene\u{301}")
+        .await
+        .unwrap());
+
+    let mut client = Client::connect(addr).await.unwrap();
+    client.set_username("BOB").await.unwrap();
+    let event = client.next_event().await.unwrap().to_string();
+    assert!(
+        event.starts_with("BOB is registered, use >login password"),
+        "{}",
+        event
+    );
+
+    // Nor does a Cyrillic lookalike get past as a different user
+    client.set_username("\u{412}ob").await.unwrap();
+    let mixed = "Usernames can't mix alphabets, eg Latin and Cyrillic letters";
+    let error = ServerEvent::Error {
+        code: Code::InvalidUsername,
+        text: mixed.into(),
+    };
+    assert_eq!(client.next_event().await.unwrap(), error);
+}
+
+#[tokio::test]
+async fn dnd() {
+    let (addr, store, _) = common::serve().await;
+    account::set_password(&*store, "bob", "hunter22")
+        .await
+        .unwrap();
+
+    async fn me(client: &mut Client) -> String {
+        client.send(">me").await.unwrap();
+        loop {
+            let line = client.next_event().await.unwrap().to_string();
+            if line.starts_with("Username: ") {
+                return line;
+            }
+        }
+    }
+
+    let mut bob = log_in(addr, "Logged in as bob").await;
+    let on = "Do not disturb is on, direct messages to you will be refused";
+    expect(&mut bob, ">dnd on", on).await;
+    assert!(me(&mut bob).await.ends_with(", Do not disturb: on"));
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    let refused = "[E_DO_NOT_DISTURB] bob is in do-not-disturb mode";
+    expect(&mut alice, ">dm bob got a minute?", refused).await;
+
+    // Room messages still reach them, but mentions aren't saved for later
+    alice.create_room("rust").await.unwrap();
+    expect(&mut alice, ">join-room rust", LIVE).await;
+    alice.send("@bob ping").await.unwrap();
+
+    // It's still on when they come back
+    drop(bob);
+    expect(&mut alice, ">dm bob still busy?", refused).await;
+    assert_eq!(mention::unread(&*store, "bob").await.unwrap(), 0);
+    let mut bob = log_in(addr, "Logged in as bob").await;
+    assert!(me(&mut bob).await.ends_with(", Do not disturb: on"));
+
+    // Until they message someone themselves
+    expect(&mut bob, ">dm alice free now", "[dm to alice] free now").await;
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("Do not disturb is off".into())
+    );
+    expect(&mut alice, ">dm bob great", "[dm to bob] great").await;
+    assert_eq!(account::dnd(&*store, "bob").await.unwrap(), Some(false));
+
+    // Unregistered names have it until they disconnect
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    expect(&mut carol, ">dnd on", on).await;
+    expect(
+        &mut alice,
+        ">dm carol hi",
+        "[E_DO_NOT_DISTURB] carol is in do-not-disturb mode",
+    )
+    .await;
+    assert_eq!(account::dnd(&*store, "carol").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn presence_msgs() {
+    let (addr, store, _) = common::serve().await;
+    account::set_password(&*store, "bob", "hunter22")
+        .await
+        .unwrap();
+
+    // What's shown on joining, up to going live
+    async fn join(client: &mut Client) -> Vec<ServerEvent> {
+        client.send(">join-room rust").await.unwrap();
+        let mut history = vec![];
+        loop {
+            match client.next_event().await.unwrap() {
+                event if event.to_string() == LIVE => return history,
+                event => history.push(event),
+            }
+        }
+    }
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    join(&mut alice).await;
+
+    let mut bob = log_in(addr, "Logged in as bob").await;
+    expect(
+        &mut bob,
+        ">set-presence-msgs off",
+        "Joins and leaves are hidden",
+    )
+    .await;
+    let history = join(&mut bob).await;
+    assert!(
+        history.contains(&ServerEvent::Info("joins/leaves hidden (1)".into())),
+        "{:?}",
+        history
+    );
+    assert!(!history
+        .iter()
+        .any(|event| matches!(event, ServerEvent::Joined(_))));
+
+    // The same join reaches both, only alice is shown it
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    join(&mut carol).await;
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Joined("bob".into())
+    );
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Joined("carol".into())
+    );
+    carol.send("hi").await.unwrap();
+    let hi = ServerEvent::Chat {
+        user: "carol".into(),
+        text: "hi".into(),
+    };
+    assert_eq!(alice.next_event().await.unwrap(), hi);
+    assert_eq!(bob.next_event().await.unwrap(), hi);
+
+    // Kept for when they come back
+    drop(bob);
+    assert_eq!(
+        account::presence_msgs(&*store, "bob").await.unwrap(),
+        Some(false)
+    );
+    let mut bob = log_in(addr, "Logged in as bob").await;
+    let history = join(&mut bob).await;
+    let collapsed = |event: &ServerEvent| event.to_string().starts_with("joins/leaves hidden (");
+    assert!(history.iter().any(collapsed), "{:?}", history);
+    expect(
+        &mut bob,
+        ">set-presence-msgs on",
+        "Joins and leaves are shown",
+    )
+    .await;
+    carol.send(">leave").await.unwrap();
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Left("carol".into())
+    );
+
+    // Unregistered names have it until they disconnect
+    expect(
+        &mut carol,
+        ">set-presence-msgs off",
+        "Joins and leaves are hidden",
+    )
+    .await;
+    assert_eq!(
+        account::presence_msgs(&*store, "carol").await.unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn theme() {
+    let (addr, store, _) = common::serve().await;
+    account::set_password(&*store, "bob", "hunter22")
+        .await
+        .unwrap();
+
+    // Sends the command, then returns the first line the check passes
+    async fn find(client: &mut Client, command: &str, check: impl Fn(&str) -> bool) -> String {
+        client.send(command).await.unwrap();
+        loop {
+            let line = client.next_event().await.unwrap().to_string();
+            if check(&line) {
+                return line;
+            }
+        }
+    }
+    // `[12:01] <alice> hi`, whatever the time
+    fn compact(line: &str, text: &str) -> bool {
+        line.starts_with('[') && line.ends_with(&format!("] <alice> {}", text))
+    }
+
+    let mut bob = log_in(addr, "Logged in as bob").await;
+    let listed = find(&mut bob, ">themes", |line| line.starts_with("compact ")).await;
+    assert!(listed.ends_with("] <bob> hi there"), "{}", listed);
+    expect(&mut bob, ">set-theme compact", "Theme set to compact").await;
+    find(&mut bob, ">set-theme neon", |line| {
+        line.starts_with("[E_INVALID_ARGUMENT]")
+    })
+    .await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    expect(&mut alice, ">join-room rust", LIVE).await;
+    alice.send("hi").await.unwrap();
+
+    // History and live messages both, only for bob
+    find(&mut bob, ">join-room rust", |line| compact(line, "hi")).await;
+    bob.send("hey").await.unwrap();
+    let hey = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "hey".into(),
+    };
+    while alice.next_event().await.unwrap() != hey {}
+    alice.send("live").await.unwrap();
+    while !compact(&bob.next_event().await.unwrap().to_string(), "live") {}
+
+    // Kept for when they come back
+    drop(bob);
+    assert_eq!(
+        account::theme(&*store, "bob").await.unwrap().as_deref(),
+        Some("compact")
+    );
+    let mut bob = log_in(addr, "Logged in as bob").await;
+    find(&mut bob, ">join-room rust", |line| compact(line, "live")).await;
+    assert_eq!(account::theme(&*store, "alice").await.unwrap(), None);
+}
@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chatsapp::app::App;
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::config::RuntimeConfig;
+use chatsapp::registry::ConnectionRegistry;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::common::{self, LIVE};
+
+#[tokio::test]
+async fn user() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    bob.create_room("go").await.unwrap();
+    bob.send(">rejoin").await.unwrap();
+    let never = ServerEvent::Info("You haven't been in a room yet".into());
+    while bob.next_event().await.unwrap() != never {}
+
+    bob.join("rust").await.unwrap();
+    common::until(&mut bob, LIVE).await;
+    bob.send(">leave").await.unwrap();
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("You left 'rust'".into())
+    );
+    bob.send(">me").await.unwrap();
+    let me = bob.next_event().await.unwrap().to_string();
+    assert!(me.ends_with(", Last room: rust"), "{}", me);
+    bob.send(">rejoin").await.unwrap();
+    let joined = ServerEvent::Info("Joined 'rust' — 1 member".into());
+    assert_eq!(bob.next_event().await.unwrap(), joined);
+    common::until(&mut bob, LIVE).await;
+
+    // Joining somewhere else leaves it too
+    bob.join("go").await.unwrap();
+    common::until(&mut bob, LIVE).await;
+    bob.send(">rejoin").await.unwrap();
+    assert_eq!(bob.next_event().await.unwrap(), joined);
+    common::until(&mut bob, LIVE).await;
+    bob.send(">rejoin").await.unwrap();
+    let go = ServerEvent::Info("Joined 'go' — 1 member".into());
+    assert_eq!(bob.next_event().await.unwrap(), go);
+}
+
+#[tokio::test]
+async fn app() {
+    let (addr, _, ctx) = common::serve().await;
+    let config = RuntimeConfig::parse("limits = { max_invalid_commands = 3 }").unwrap();
+    ctx.config.store(Arc::new(config));
+
+    // Garbage, which is either an unknown command or chat outside a room
+    let mut scanner = Client::connect(addr).await.unwrap();
+    for line in [">\x16\x03\x01", "SSH-2.0-OpenSSH_9.6", ">help me"] {
+        scanner.send(line).await.unwrap();
+    }
+    let too_many = ServerEvent::Info("Too many invalid commands, disconnecting".into());
+    while scanner.next_event().await.unwrap() != too_many {}
+    assert!(scanner.next_event().await.is_err());
+
+    // Someone making the odd typo is fine
+    let mut alice = Client::connect(addr).await.unwrap();
+    for line in [">lsit", ">jion rust", ">list", "hello?", ">lsit", ">me"] {
+        alice.send(line).await.unwrap();
+    }
+    alice.set_username("alice").await.unwrap();
+
+    // Browsers only get the one line
+    let mut browser = TcpStream::connect(addr).await.unwrap();
+    browser
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut lines = BufReader::new(browser).lines();
+    let mut sent = vec![];
+    while let Some(line) = lines.next_line().await.unwrap() {
+        sent.push(line);
+    }
+    assert_eq!(
+        sent.last().unwrap(),
+        "This is a chat server, not a web server"
+    );
+
+    // Telnet negotiates before the first command, which still works
+    let (reader, mut telnet) = TcpStream::connect(addr).await.unwrap().into_split();
+    telnet
+        .write_all(b"\xff\xfd\x03\xff\xfb\x18\xff\xfb\x1f>find caf\xe9\r\n")
+        .await
+        .unwrap();
+    let mut lines = BufReader::new(reader).lines();
+    let found = loop {
+        let line = lines.next_line().await.unwrap().unwrap();
+        if line.starts_with("No rooms match") {
+            break line;
+        }
+    };
+    assert_eq!(
+        found,
+        "No rooms match 'caf\u{fffd}', try >list to see them all"
+    );
+}
+
+#[tokio::test]
+async fn run() {
+    let (addr, _, ctx) = common::serve().await;
+    let config = RuntimeConfig::parse("broker = { write_timeout_secs = 1 }").unwrap();
+    ctx.config.store(Arc::new(config));
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    common::until(&mut alice, LIVE).await;
+
+    // bob's connection has room for what joining writes, and is never read
+    let (stream, mut bob) = tokio::io::duplex(1024);
+    let bob_addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+    let conn = ConnectionRegistry::register(&ctx.registry, bob_addr.to_string(), "test".into());
+    let serving = tokio::spawn(App::new(stream, bob_addr, conn, Arc::clone(&ctx)).run());
+    bob.write_all(b">set-username bob\n>join-room rust\n")
+        .await
+        .unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    // So the room's writes to him back up until he's dropped
+    for n in 0..100 {
+        alice
+            .send(&format!("{} {}", n, "x".repeat(100)))
+            .await
+            .unwrap();
+    }
+    while alice.next_event().await.unwrap() != ServerEvent::Left("bob".into()) {}
+    serving.await.unwrap().unwrap();
+    assert!(ctx.registry.find_by_username("bob").is_none());
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use chatsapp::admin::{self, AdminContext};
+use chatsapp::audit::{self, AuditAction};
+use chatsapp::client::Client;
+use chatsapp::{roles, shutdown};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::common::{self, expect};
+
+#[tokio::test]
+async fn recent() {
+    let (addr, store, ctx) = common::serve().await;
+    roles::seed(&*store, &["alice".to_owned()]).await.unwrap();
+
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+    let admin_ctx = AdminContext {
+        server: Arc::clone(&ctx),
+        token: None,
+        config_path: None,
+    };
+    let (_trigger, shutdown) = shutdown::channel();
+    tokio::spawn(admin::listen(admin_listener, Arc::new(admin_ctx), shutdown));
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    common::register(&mut bob, "bob").await;
+    bob.join("rust").await.unwrap();
+
+    expect(&mut alice, ">op bob", "bob is now an admin").await;
+    expect(&mut alice, ">deop bob", "bob is no longer an admin").await;
+    expect(&mut alice, ">broadcast hi", "[broadcast] hi").await;
+    expect(&mut alice, ">notice back soon", "[SERVER] back soon").await;
+    let banned = "203.0.113.0/24 is now banned, 0 connection(s) closed";
+    expect(&mut alice, ">ipban 203.0.113.0/24 spam", banned).await;
+    let unbanned = "203.0.113.0/24 is no longer banned";
+    expect(&mut alice, ">ipunban 203.0.113.0/24", unbanned).await;
+    expect(&mut alice, ">mod add bob", "bob is now a moderator of rust").await;
+    expect(
+        &mut alice,
+        ">mod remove bob",
+        "bob is no longer a moderator of rust",
+    )
+    .await;
+    expect(&mut alice, ">slowmode 5", "Slow mode set to 5s by alice").await;
+
+    let stream = TcpStream::connect(admin_addr).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    lines.next_line().await.unwrap();
+
+    let snapshot = ctx.registry.snapshot();
+    let bob = snapshot
+        .iter()
+        .find(|conn| conn.username.as_deref() == Some("bob"))
+        .unwrap();
+    let kick = format!(">kick {}", bob.id);
+    for command in [">force-leave bob", &kick, ">delete-room rust"] {
+        writer
+            .write_all(format!("{}\n", command).as_bytes())
+            .await
+            .unwrap();
+        lines.next_line().await.unwrap();
+    }
+
+    expect(&mut alice, ">shutdown 60 upgrading", "Shutting down").await;
+
+    let entries = audit::recent(&*store, 100).await.unwrap();
+    let actions: Vec<_> = entries.iter().map(|entry| entry.action).collect();
+    assert_eq!(
+        actions,
+        [
+            AuditAction::Op,
+            AuditAction::Deop,
+            AuditAction::Broadcast,
+            AuditAction::Notice,
+            AuditAction::IpBan,
+            AuditAction::IpUnban,
+            AuditAction::AddMod,
+            AuditAction::RemoveMod,
+            AuditAction::SlowMode,
+            AuditAction::ForceLeave,
+            AuditAction::Kick,
+            AuditAction::DeleteRoom,
+            AuditAction::Shutdown,
+        ]
+    );
+
+    let ban = &entries[4];
+    assert_eq!(ban.actor, "alice");
+    assert_eq!(ban.target.as_deref(), Some("203.0.113.0/24"));
+    assert_eq!(ban.reason.as_deref(), Some("spam"));
+    assert!(entries[11].actor.starts_with("admin@"));
+    assert_eq!(entries[11].room.as_deref(), Some("rust"));
+    assert_eq!(entries[12].reason.as_deref(), Some("upgrading"));
+}
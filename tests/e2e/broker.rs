@@ -0,0 +1,170 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chatsapp::account::{self, MultiLogin};
+use chatsapp::broker::{self, RoomHandle};
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::command::Command;
+use chatsapp::config::BrokerConfig;
+use chatsapp::server::{self, ServerContext};
+use chatsapp::shutdown;
+use chatsapp::store::{MemoryStore, RoomStore, SlowStore};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use crate::common::{self, expect, until, LIVE};
+
+#[tokio::test]
+async fn broker_event() {
+    let (addr, store, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.command(Command::SetTyping(true)).await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    carol.join("rust").await.unwrap();
+    until(&mut carol, LIVE).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+    while carol.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    // The second is dropped, it's too soon after the first
+    bob.command(Command::Typing).await.unwrap();
+    bob.command(Command::Typing).await.unwrap();
+    bob.send("hi").await.unwrap();
+
+    let hi = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "hi".into(),
+    };
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Info("* bob is typing".into())
+    );
+    assert_eq!(alice.next_event().await.unwrap(), hi);
+    assert_eq!(carol.next_event().await.unwrap(), hi);
+
+    let history = store.recent("rust", 10).await.unwrap();
+    assert!(history.iter().all(|msg| !msg.contains("typing")));
+}
+
+#[tokio::test]
+async fn spawn_broker() {
+    let latency = Duration::from_millis(100);
+    let store: Arc<dyn RoomStore> = Arc::new(SlowStore::new(MemoryStore::default(), latency));
+    store.create("rust").await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = Arc::new(ServerContext::new(Arc::clone(&store), trigger));
+    let config = BrokerConfig::default();
+    broker::spawn_broker("rust".into(), &ctx.rooms, &store, &config).await;
+    tokio::spawn(server::listen(listener, Arc::clone(&ctx), shutdown));
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.command(Command::List(Default::default()))
+        .await
+        .unwrap();
+    while bob.next_event().await.unwrap() != ServerEvent::Info("rust".into()) {}
+
+    // Another room is created while bob's join waits on the store
+    bob.join("rust").await.unwrap();
+    tokio::time::sleep(latency / 2).await;
+    let start = Instant::now();
+    let go = broker::spawn_broker("go".into(), &ctx.rooms, &store, &config).await;
+    assert!(start.elapsed() < latency / 4);
+    assert!(!go.is_closed());
+
+    while bob.next_event().await.unwrap() != ServerEvent::Info("Start of chat".into()) {}
+
+    // A running room is left as it is, bob's still in it
+    let rust = ctx.rooms.read().await["rust"].clone();
+    assert!(broker::spawn_broker("rust".into(), &ctx.rooms, &store, &config).await == rust);
+
+    // But one whose broker's stopped is replaced
+    let (tx, rx) = mpsc::channel(1);
+    drop(rx);
+    let stopped = RoomHandle::new("zig".into(), tx);
+    ctx.rooms
+        .write()
+        .await
+        .insert("zig".into(), stopped.clone());
+    let zig = broker::spawn_broker("zig".into(), &ctx.rooms, &store, &config).await;
+    assert!(zig != stopped && !zig.is_closed());
+    assert!(ctx.rooms.read().await["zig"] == zig);
+}
+
+#[tokio::test]
+async fn broker() {
+    let store = Arc::new(MemoryStore::default());
+    // Being on two connections at once needs a registered name
+    account::set_password(&*store, "bob", "hunter22")
+        .await
+        .unwrap();
+    account::set_multi_login(&*store, "bob", MultiLogin::Allow)
+        .await
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = ServerContext::new(store, trigger);
+    tokio::spawn(server::listen(listener, Arc::new(ctx), shutdown));
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+
+    let mut laptop = Client::connect(addr).await.unwrap();
+    laptop.set_username("bob").await.unwrap();
+    expect(&mut laptop, ">login hunter22", "Logged in as bob").await;
+    laptop.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    let mut phone = Client::connect(addr).await.unwrap();
+    phone.set_username("bob").await.unwrap();
+    expect(&mut phone, ">login hunter22", "Logged in as bob").await;
+    phone.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    // Both of bob's connections get everything, past their join history
+    alice.send("hi").await.unwrap();
+    let hi = ServerEvent::Chat {
+        user: "alice".into(),
+        text: "hi".into(),
+    };
+    while laptop.next_event().await.unwrap() != hi {}
+    while phone.next_event().await.unwrap() != hi {}
+
+    // Including what the other one sends
+    laptop.send("hey").await.unwrap();
+    let hey = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "hey".into(),
+    };
+    assert_eq!(alice.next_event().await.unwrap(), hey);
+    assert_eq!(phone.next_event().await.unwrap(), hey);
+
+    // And one leaving doesn't take the other with it
+    laptop.leave().await.unwrap();
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Left("bob".into())
+    );
+    alice.send("still there?").await.unwrap();
+    let still = ServerEvent::Chat {
+        user: "alice".into(),
+        text: "still there?".into(),
+    };
+    while phone.next_event().await.unwrap() != still {}
+}
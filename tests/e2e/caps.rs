@@ -0,0 +1,70 @@
+use chatsapp::client::Client;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn caps() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+
+    // Clients that never ask get what they always have
+    let (reader, mut alice) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut alice_lines = BufReader::new(reader).lines();
+    alice
+        .write_all(b">set-username alice\n>join-room rust\n")
+        .await
+        .unwrap();
+    while alice_lines.next_line().await.unwrap().unwrap() != LIVE {}
+
+    let (reader, mut carol) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut lines = BufReader::new(reader).lines();
+    carol.write_all(b">capabilities\n").await.unwrap();
+    let mut line = lines.next_line().await.unwrap().unwrap();
+    while !line.starts_with("CAP") {
+        line = lines.next_line().await.unwrap().unwrap();
+    }
+    assert_eq!(line, "CAP v=1 typing mentions ansi");
+    carol
+        .write_all(b">hello client=tui/0.3 caps=json,typing,mentions\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        lines.next_line().await.unwrap().unwrap(),
+        "CAP-ACK v=1 typing mentions"
+    );
+    carol
+        .write_all(b">set-username carol\n>join-room rust\n")
+        .await
+        .unwrap();
+    while lines.next_line().await.unwrap().unwrap() != LIVE {}
+
+    bob.send(">typing").await.unwrap();
+    bob.send("hi @carol and @alice").await.unwrap();
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "* bob is typing");
+    assert_eq!(
+        lines.next_line().await.unwrap().unwrap(),
+        "\x1b[1mbob: hi @carol and @alice\x1b[0m"
+    );
+    assert_eq!(
+        alice_lines.next_line().await.unwrap().unwrap(),
+        "-- carol has joined the room --"
+    );
+    assert_eq!(
+        alice_lines.next_line().await.unwrap().unwrap(),
+        "bob: hi @carol and @alice"
+    );
+
+    // Saying hello again replaces what they had
+    carol.write_all(b">hello\n").await.unwrap();
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "CAP-ACK v=1");
+    bob.send(">typing").await.unwrap();
+    bob.send("@carol?").await.unwrap();
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "bob: @carol?");
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::chatlog::{self, ChatLog};
+use chatsapp::client::Client;
+use chatsapp::server::ServerContext;
+use chatsapp::store::MemoryStore;
+use chatsapp::{room, shutdown};
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn chat_log() {
+    let dir = std::env::temp_dir().join(format!("chatsapp-log-{}", std::process::id()));
+    let (trigger, shutdown) = shutdown::channel();
+    let mut ctx = ServerContext::new(Arc::new(MemoryStore::default()), trigger);
+    ctx.chat_log = ChatLog::start(dir.clone(), false).unwrap();
+    let addr = common::listen(Arc::new(ctx), shutdown).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+    bob.send("hi").await.unwrap();
+    bob.send(">dm alice secret").await.unwrap();
+    bob.send("bye").await.unwrap();
+
+    // Written in the background, DMs left out
+    let path = dir.join(chatlog::file_name(room::get_time_in_ms()));
+    let mut log = String::new();
+    while !log.contains("bye") {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        log = std::fs::read_to_string(&path).unwrap_or_default();
+    }
+    let lines: Vec<&str> = log
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1)
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "rust bob has joined the room",
+            "rust bob: hi",
+            "rust bob: bye"
+        ]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,181 @@
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::command::Command;
+use chatsapp::errors::Code;
+use chatsapp::room::CreateRoomOpts;
+
+use crate::common::{self, expect, until, LIVE};
+
+#[tokio::test]
+async fn client() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    let set = ServerEvent::Info("Username set to 'alice'".into());
+    assert_eq!(alice.next_event().await.unwrap(), set);
+    let created = ServerEvent::Info("Room 'rust' created, join it with >join-room rust".into());
+    assert_eq!(alice.next_event().await.unwrap(), created);
+    // The room's history, framed so it's clear where live messages start
+    for line in ["Joined 'rust' — 1 member", "Start of chat", LIVE] {
+        assert_eq!(
+            alice.next_event().await.unwrap(),
+            ServerEvent::Info(line.into())
+        );
+    }
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Joined("bob".into())
+    );
+    // History ends with alice's join
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("Username set to 'bob'".into())
+    );
+    let joined = ServerEvent::Info("Joined 'rust' — 2 members".into());
+    assert_eq!(bob.next_event().await.unwrap(), joined);
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("Start of chat".into())
+    );
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Joined("alice".into())
+    );
+    assert_eq!(bob.next_event().await.unwrap().to_string(), LIVE);
+
+    bob.send("hi").await.unwrap();
+    bob.command(Command::Leave).await.unwrap();
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("You left 'rust'".into())
+    );
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Chat {
+            user: "bob".into(),
+            text: "hi".into()
+        }
+    );
+}
+
+#[tokio::test]
+async fn set_username() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    let mut reply = async |username: &str| {
+        bob.set_username(username).await.unwrap();
+        bob.next_event().await.unwrap().to_string()
+    };
+
+    assert_eq!(
+        reply("  ").await,
+        "[E_INVALID_USERNAME] Usernames can't be blank. Usage: >set-username name"
+    );
+    assert_eq!(reply(" bob ").await, "Username set to 'bob'");
+    assert_eq!(reply("bob").await, "Username set to 'bob'");
+    assert_eq!(
+        reply("robert").await,
+        "Username changed from 'bob' to 'robert'"
+    );
+
+    // Stored composed, and letters can't be swapped for lookalikes
+    assert_eq!(
+        reply("Rene\u{301}").await,
+        "Username changed from 'robert' to 'Ren\u{e9}'"
+    );
+    let mixed = "[E_INVALID_USERNAME] Usernames can't mix alphabets, eg Latin and Cyrillic letters";
+    assert_eq!(reply("\u{42c}ob").await, mixed);
+    let space =
+        "[E_INVALID_USERNAME] Usernames can't contain ' ', only letters, numbers, - _ and .";
+    assert_eq!(reply("bob smith").await, space);
+
+    // Only one connection can have a name at a time, however it's cased
+    let mut mallory = Client::connect(addr).await.unwrap();
+    let taken = "[E_NAME_TAKEN] REN\u{c9} is already in use, pick another";
+    expect(&mut mallory, ">set-username REN\u{c9}", taken).await;
+    expect(
+        &mut bob,
+        ">set-username bob",
+        "Username changed from 'Ren\u{e9}' to 'bob'",
+    )
+    .await;
+    let set = "Username set to 'REN\u{c9}'";
+    expect(&mut mallory, ">set-username REN\u{c9}", set).await;
+}
+
+#[tokio::test]
+async fn create_room() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.next_event().await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    let hint = ServerEvent::Info("Room 'rust' created, join it with >join-room rust".into());
+    assert_eq!(bob.next_event().await.unwrap(), hint);
+
+    let create_and_join = |room: &str| Command::CreateRoom {
+        name: room.into(),
+        opts: CreateRoomOpts {
+            join: true,
+            topic: Some("lifetimes & despair".into()),
+            ..Default::default()
+        },
+    };
+    bob.command(create_and_join("go")).await.unwrap();
+    let lines = [
+        "Room 'go' created",
+        "Joined 'go' — 1 member, topic: lifetimes & despair",
+        "Start of chat",
+        LIVE,
+    ];
+    for line in lines {
+        assert_eq!(
+            bob.next_event().await.unwrap(),
+            ServerEvent::Info(line.into())
+        );
+    }
+}
+
+#[tokio::test]
+async fn join() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    // Joining again does nothing, and a room that isn't there keeps him in rust
+    bob.join("rust").await.unwrap();
+    let already = ServerEvent::Info("You're already in 'rust'".into());
+    while bob.next_event().await.unwrap() != already {}
+    bob.join("go").await.unwrap();
+    let not_found = ServerEvent::Error {
+        code: Code::RoomNotFound,
+        text: "Room not found".into(),
+    };
+    while bob.next_event().await.unwrap() != not_found {}
+
+    bob.send("still here").await.unwrap();
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Chat {
+            user: "bob".into(),
+            text: "still here".into()
+        }
+    );
+}
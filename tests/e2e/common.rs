@@ -0,0 +1,99 @@
+// What the end to end tests share: a server on a free port, and the ways
+// they talk to it
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::errors::Code;
+use chatsapp::server::{self, ServerContext};
+use chatsapp::shutdown::{self, Shutdown};
+#[cfg(feature = "sqlite-backend")]
+use chatsapp::sqlite::SqliteStore;
+use chatsapp::store::{MemoryStore, RoomStore};
+use tokio::net::TcpListener;
+
+pub const LIVE: &str = "--- you are now live ---";
+
+// <Address, Store, Context> of a server on the backend under test, see
+// `store`
+pub async fn serve() -> (SocketAddr, Arc<dyn RoomStore>, Arc<ServerContext>) {
+    let store = store();
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = Arc::new(ServerContext::new(store.clone(), trigger));
+    let addr = listen(ctx.clone(), shutdown).await;
+
+    (addr, store, ctx)
+}
+
+// `CHATSAPP_TEST_STORE=memory` or `sqlite`, defaulting to SQLite when the
+// `sqlite-backend` feature's on. SQLite's kept in memory too, so nothing needs
+// cleaning up.
+pub fn store() -> Arc<dyn RoomStore> {
+    let default = if cfg!(feature = "sqlite-backend") {
+        "sqlite"
+    } else {
+        "memory"
+    };
+
+    match env::var("CHATSAPP_TEST_STORE")
+        .as_deref()
+        .unwrap_or(default)
+    {
+        "memory" => Arc::new(MemoryStore::default()),
+        #[cfg(feature = "sqlite-backend")]
+        "sqlite" => Arc::new(SqliteStore::open(":memory:").unwrap()),
+        other => panic!(
+            "CHATSAPP_TEST_STORE={} isn't a backend this build has",
+            other
+        ),
+    }
+}
+
+// Address of a server with `ctx`, for when it's set up differently
+pub async fn listen(ctx: Arc<ServerContext>, shutdown: Shutdown) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server::listen(listener, ctx, shutdown));
+
+    addr
+}
+
+// `name` in room rust, once its history's been replayed
+pub async fn connect(addr: SocketAddr, name: &str) -> Client {
+    let mut client = Client::connect(addr).await.unwrap();
+    client.set_username(name).await.unwrap();
+    client.join("rust").await.unwrap();
+    until(&mut client, LIVE).await;
+
+    client
+}
+
+// Registers the name `client` has set, which logs it in, so admin and room
+// roles count for it
+pub async fn register(client: &mut Client, name: &str) {
+    let registered = format!("{} is now registered", name);
+    expect(client, ">register hunter22", &registered).await;
+}
+
+// Skips ahead to `line`
+pub async fn until(client: &mut Client, line: &str) {
+    while client.next_event().await.unwrap().to_string() != line {}
+}
+
+// Sends the command, then skips ahead to the expected reply
+pub async fn expect(client: &mut Client, command: &str, reply: &str) {
+    client.send(command).await.unwrap();
+    until(client, reply).await;
+}
+
+// <Code, Text> of the error the line's refused with
+pub async fn refused(client: &mut Client, line: &str) -> (Code, String) {
+    client.send(line).await.unwrap();
+    loop {
+        if let ServerEvent::Error { code, text } = client.next_event().await.unwrap() {
+            return (code, text);
+        }
+    }
+}
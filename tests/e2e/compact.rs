@@ -0,0 +1,68 @@
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::metrics::metrics;
+use chatsapp::{compact, roles, room};
+
+use crate::common;
+
+#[tokio::test]
+async fn run() {
+    let (addr, store, ctx) = common::serve().await;
+    for name in ["rust", "go"] {
+        store.create(name).await.unwrap();
+    }
+    let notes = [
+        ("message removed by carol\n".to_owned(), 1),
+        ("message removed by dave\n".to_owned(), 2),
+    ];
+    store.append_many("rust", &notes, None).await.unwrap();
+
+    let rooms = vec!["rust".to_owned(), "go".to_owned()];
+    assert_eq!(compact::run(&ctx, &rooms, None).await, 1);
+    assert_eq!(metrics().last_compacted.get(), 1);
+    assert!(metrics().compacted.get() >= 1);
+    assert!(room::info(&*store, "go")
+        .await
+        .unwrap()
+        .last_compacted
+        .is_some());
+
+    // Admins can run it whenever, for every room or just one
+    store.append_many("go", &notes, None).await.unwrap();
+    roles::grant(&*store, "alice").await.unwrap();
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.send(">compact").await.unwrap();
+    let mut progress = vec![];
+    loop {
+        let line = alice.next_event().await.unwrap().to_string();
+        if line.starts_with("Compaction finished") {
+            assert_eq!(
+                line,
+                "Compaction finished, 1 message(s) removed from 2 room(s)"
+            );
+            break;
+        }
+        progress.push(line);
+    }
+    assert!(progress.contains(&"Compacting 2 room(s)".to_owned()));
+    assert!(progress.contains(&"Compacted go, 1 message(s) removed".to_owned()));
+    assert_eq!(
+        store.recent("go", 10).await.unwrap(),
+        ["Start of chat\n", "message removed by dave\n"]
+    );
+
+    alice.send(">compact rust").await.unwrap();
+    let done = ServerEvent::Info("Compaction finished, 0 message(s) removed from 1 room(s)".into());
+    while alice.next_event().await.unwrap() != done {}
+    alice.send(">compact nowhere").await.unwrap();
+    let not_found = ServerEvent::parse("[E_ROOM_NOT_FOUND] Room not found");
+    while alice.next_event().await.unwrap() != not_found {}
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.send(">compact").await.unwrap();
+    let forbidden = ServerEvent::parse("[E_FORBIDDEN] You need to be an admin to do that");
+    while bob.next_event().await.unwrap() != forbidden {}
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::{account, dm};
+
+use crate::common::{self, expect};
+
+#[tokio::test]
+async fn send() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+
+    // Kept for when bob comes online
+    let offline = "bob is offline; message will be delivered when they return";
+    expect(&mut alice, ">dm bob are you there?", offline).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.command(">dm-history alice".parse().unwrap())
+        .await
+        .unwrap();
+    let missed = ServerEvent::Chat {
+        user: "alice".into(),
+        text: "are you there?".into(),
+    };
+    while bob.next_event().await.unwrap() != missed {}
+
+    expect(&mut alice, ">dm bob hi bob", "[dm to bob] hi bob").await;
+    while bob.next_event().await.unwrap() != ServerEvent::Info("[dm] alice: hi bob".into()) {}
+
+    expect(&mut bob, ">dm alice hello", "[dm to alice] hello").await;
+    alice
+        .command(">dm-history bob 1".parse().unwrap())
+        .await
+        .unwrap();
+    let reply = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "hello".into(),
+    };
+    while alice.next_event().await.unwrap() != reply {}
+
+    // Only the two of them can read it
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    expect(&mut carol, ">dm-history bob", "No messages with bob").await;
+}
+
+#[tokio::test]
+async fn queue() {
+    let (addr, store, _) = common::serve().await;
+
+    // From 2024, long past the default week it's kept for
+    let week = Duration::from_secs(7 * 24 * 60 * 60);
+    dm::queue(&*store, "bob", "carol: long gone\n", 1_706_702_465_000)
+        .await
+        .unwrap();
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    let offline = "bob is offline; message will be delivered when they return";
+    expect(&mut alice, ">dm bob are you there?", offline).await;
+    expect(&mut alice, ">dm bob call me", offline).await;
+
+    // Delivered once, with when each was sent, when bob picks the name
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    let header = "2 direct messages while you were offline:";
+    while bob.next_event().await.unwrap().to_string() != header {}
+    for text in ["are you there?", "call me"] {
+        let line = bob.next_event().await.unwrap().to_string();
+        assert!(
+            line.starts_with("[dm ") && line.ends_with(&format!(" UTC] alice: {}", text)),
+            "{}",
+            line
+        );
+    }
+    assert!(dm::take_offline(&*store, "bob", week)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Someone in do-not-disturb mode refuses them outright
+    drop(bob);
+    account::set_password(&*store, "dave", "hunter22")
+        .await
+        .unwrap();
+    account::set_dnd(&*store, "dave", true).await.unwrap();
+    expect(
+        &mut alice,
+        ">dm dave hi",
+        "[E_DO_NOT_DISTURB] dave is in do-not-disturb mode",
+    )
+    .await;
+    assert!(dm::take_offline(&*store, "dave", week)
+        .await
+        .unwrap()
+        .is_empty());
+}
@@ -0,0 +1,56 @@
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::errors::Code;
+
+use crate::common::{self, refused};
+
+#[tokio::test]
+async fn code() {
+    let (addr, _, _) = common::serve().await;
+    // Lines are sent as typed, so commands go through the server's parser
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    while bob.next_event().await.unwrap() != ServerEvent::Info("Username set to 'bob'".into()) {}
+
+    let refusals = [
+        (">leave", Code::NotInRoom),
+        (">room-info", Code::NotInRoom),
+        (">join-room nowhere", Code::RoomNotFound),
+        (">op alice", Code::Forbidden),
+        (">register short", Code::PasswordTooShort),
+        (">login hunter22", Code::NotRegistered),
+        (">resume token", Code::ResumeNotFirst),
+        (">set-username guest-1a2b", Code::Reserved),
+        (">set-username b\u{43e}b", Code::InvalidUsername),
+        (">dm bob hi", Code::InvalidArgument),
+    ];
+    for (line, code) in refusals {
+        assert_eq!(refused(&mut bob, line).await.0, code, "{}", line);
+    }
+
+    bob.create_room("rust").await.unwrap();
+    bob.next_event().await.unwrap();
+    assert_eq!(
+        refused(&mut bob, ">create-room rust").await.0,
+        Code::NameTaken
+    );
+
+    // Lines that don't parse are followed by a pointer to `>help`
+    let invalid = [
+        (">hepl", Code::UnknownCommand),
+        (">join-room", Code::InvalidArgument),
+        (">create-room go --jion", Code::InvalidArgument),
+    ];
+    for (line, code) in invalid {
+        assert_eq!(refused(&mut bob, line).await.0, code, "{}", line);
+        bob.next_event().await.unwrap();
+    }
+
+    // And they're all documented
+    bob.send(">help errors").await.unwrap();
+    bob.next_event().await.unwrap();
+    for code in Code::ALL {
+        let line = bob.next_event().await.unwrap().to_string();
+        assert!(line.starts_with(code.as_str()), "{}", line);
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::config::{FilterMode, RuntimeConfig};
+use chatsapp::filter::FILTER_WORDS_KEY;
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn word_filter() {
+    let (addr, store, ctx) = common::serve().await;
+    store.set_add(FILTER_WORDS_KEY, "darn").await.unwrap();
+    let mut config = RuntimeConfig::default();
+    config.filter.mode = FilterMode::Mask;
+    ctx.config.store(Arc::new(config));
+    ctx.reload_filter().await.unwrap();
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    let set = ServerEvent::Info("Username set to 'alice'".into());
+    assert_eq!(alice.next_event().await.unwrap(), set);
+    let created = ServerEvent::Info("Room 'rust' created, join it with >join-room rust".into());
+    assert_eq!(alice.next_event().await.unwrap(), created);
+    until(&mut alice, LIVE).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    alice.next_event().await.unwrap();
+
+    bob.send("well DAARN it").await.unwrap();
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Chat {
+            user: "bob".into(),
+            text: "well ***** it".into()
+        }
+    );
+
+    // Only the masked message is kept
+    let history = store.recent("rust", 1).await.unwrap();
+    assert_eq!(history, ["bob: well ***** it\n"]);
+}
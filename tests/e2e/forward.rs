@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::config::RuntimeConfig;
+use chatsapp::{forward, webhook};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time;
+
+use crate::common::{self, expect};
+
+#[tokio::test]
+async fn forwarder() {
+    let (addr, _, ctx) = common::serve().await;
+    // The endpoint's on loopback
+    let config = RuntimeConfig::parse("broker = { allow_private_webhooks = true }").unwrap();
+    ctx.config.store(Arc::new(config));
+
+    // Stands in for the receiving service
+    let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/chat", endpoint.local_addr().unwrap());
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    alice
+        .command(format!(">webhook-out add {}", url).parse().unwrap())
+        .await
+        .unwrap();
+    let secret = loop {
+        if let ServerEvent::Info(line) = alice.next_event().await.unwrap() {
+            if let Some((_, secret)) = line.split_once("signed with secret ") {
+                break secret.to_owned();
+            }
+        }
+    };
+
+    alice.send("hello").await.unwrap();
+
+    let (mut stream, _) = endpoint.accept().await.unwrap();
+    let mut req = Vec::new();
+    let mut chunk = [0; 1024];
+    while !String::from_utf8_lossy(&req).ends_with('}') {
+        let n = stream.read(&mut chunk).await.unwrap();
+        req.extend_from_slice(&chunk[..n]);
+    }
+    stream
+        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+        .await
+        .unwrap();
+
+    let req = String::from_utf8(req).unwrap();
+    let (head, body) = req.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("POST /chat HTTP/1.1"));
+    assert!(body.starts_with(r#"{"room":"rust","user":"alice","text":"hello","ts":"#));
+
+    let signature = format!(
+        "X-Chatsapp-Signature: sha256={}",
+        forward::sign(&secret, body)
+    );
+    assert!(head.lines().any(|line| line == signature));
+}
+
+#[tokio::test]
+async fn is_private() {
+    let (addr, store, _) = common::serve().await;
+    let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/chat", endpoint.local_addr().unwrap());
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    for (url, host) in [
+        (url.as_str(), "127.0.0.1"),
+        ("http://10.0.0.1/chat", "10.0.0.1"),
+        ("http://[::1]:8080/", "::1"),
+    ] {
+        let refused = format!(
+            "[E_INVALID_ARGUMENT] Can't send messages to {}: {} only resolves to private addresses",
+            url, host
+        );
+        expect(&mut alice, &format!(">webhook-out add {}", url), &refused).await;
+    }
+
+    // Nor is anything sent to one that got in some other way
+    webhook::add_outgoing(&*store, "rust", &url).await.unwrap();
+    alice.send("hello").await.unwrap();
+    let accepted = time::timeout(Duration::from_millis(500), endpoint.accept()).await;
+    assert!(accepted.is_err());
+}
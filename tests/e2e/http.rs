@@ -0,0 +1,243 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::client::Client;
+use chatsapp::http::{self, Health, HttpState};
+use chatsapp::shutdown;
+use chatsapp::store::RedisStore;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn health_fails_without_storage() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Nothing listens on port 1, so the storage check fails
+    let store = RedisStore::new(redis::Client::open("redis://127.0.0.1:1/").unwrap());
+    let state = HttpState::new(Arc::new(store), Arc::new(Health::default()));
+    state.health.set_accepting(true);
+
+    let (trigger, shutdown) = shutdown::channel();
+    let server = tokio::spawn(http::listen(listener, Arc::new(state), shutdown));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /healthz HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+
+    assert!(res.starts_with("HTTP/1.1 503 Service Unavailable"));
+    assert!(res.ends_with(r#"{"status":"unavailable","failed":["storage"]}"#));
+
+    trigger.trigger();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn rooms_and_messages() {
+    let (chat_addr, store, ctx) = common::serve().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut state = HttpState::new(store.clone(), Arc::new(Health::default()));
+    state.admin_token = Some("secret".into());
+    state.server.set(ctx).ok().unwrap();
+    let (_trigger, shutdown) = shutdown::channel();
+    tokio::spawn(http::listen(listener, Arc::new(state), shutdown));
+
+    // Returns the status and the body, as JSON
+    async fn get(addr: SocketAddr, path: &str, token: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            path, token
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        let (head, body) = res.split_once("\r\n\r\n").unwrap();
+        (
+            head[9..12].parse().unwrap(),
+            serde_json::from_str(body).unwrap(),
+        )
+    }
+
+    // A room with a long history, and bob in it
+    let mut bob = Client::connect(chat_addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust lang").await.unwrap();
+    bob.create_room("go").await.unwrap();
+    while !bob
+        .next_event()
+        .await
+        .unwrap()
+        .to_string()
+        .starts_with("Room 'go' created")
+    {}
+    for score in 1..=150 {
+        store
+            .append("rust lang", &format!("alice: {}\n", score), score, None)
+            .await
+            .unwrap();
+    }
+    bob.join("rust lang").await.unwrap();
+    until(&mut bob, LIVE).await;
+
+    assert_eq!(get(addr, "/api/rooms", "guess").await.0, 401);
+    let rooms = json!([{"name": "go", "members": 0}, {"name": "rust lang", "members": 1}]);
+    assert_eq!(get(addr, "/api/rooms", "secret").await, (200, rooms));
+
+    // Newest first, a page at a time
+    let (status, page) = get(addr, "/api/rooms/rust%20lang/messages?limit=2", "secret").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        page[0],
+        json!({"score": 150, "kind": "chat", "user": "alice", "text": "150"})
+    );
+    assert_eq!(page[1]["kind"], "joined");
+    let (_, page) = get(
+        addr,
+        "/api/rooms/rust%20lang/messages?before=150&limit=1",
+        "secret",
+    )
+    .await;
+    assert_eq!(
+        page,
+        json!([{"score": 149, "kind": "chat", "user": "alice", "text": "149"}])
+    );
+    let (_, page) = get(
+        addr,
+        "/api/rooms/rust%20lang/messages?before=2&limit=5",
+        "secret",
+    )
+    .await;
+    assert_eq!(
+        page,
+        json!([
+            {"score": 0, "kind": "notice", "text": "Start of chat"},
+            {"score": 1, "kind": "chat", "user": "alice", "text": "1"},
+        ])
+    );
+
+    // Capped, however many are asked for
+    let (_, page) = get(addr, "/api/rooms/rust%20lang/messages?limit=1000", "secret").await;
+    assert_eq!(page.as_array().unwrap().len(), 100);
+    let (_, page) = get(addr, "/api/rooms/rust%20lang/messages", "secret").await;
+    assert_eq!(page.as_array().unwrap().len(), 50);
+    assert_eq!(
+        get(addr, "/api/rooms/rust%20lang/messages?limit=x", "secret")
+            .await
+            .0,
+        400
+    );
+    assert_eq!(get(addr, "/api/rooms/java/messages", "secret").await.0, 404);
+
+    // bob's session token will do too
+    bob.send(">session").await.unwrap();
+    let token = loop {
+        let line = bob.next_event().await.unwrap().to_string();
+        if let Some(rest) = line.strip_prefix("Session token ") {
+            break rest.split(',').next().unwrap().to_owned();
+        }
+    };
+    assert_eq!(get(addr, "/api/rooms", &token).await.0, 200);
+    let rust = "/api/rooms/rust%20lang/messages?limit=1";
+    assert_eq!(get(addr, rust, &token).await.0, 200);
+
+    // But only for the room he's in, as `>history` is
+    let go = "/api/rooms/go/messages";
+    assert_eq!(
+        get(addr, go, &token).await,
+        (403, json!({"error": "not in the room"}))
+    );
+    bob.join("go").await.unwrap();
+    until(&mut bob, LIVE).await;
+    assert_eq!(get(addr, go, &token).await.0, 200);
+    assert_eq!(get(addr, rust, &token).await.0, 403);
+}
+
+#[tokio::test]
+async fn room_stream() {
+    let (chat_addr, store, ctx) = common::serve().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut state = HttpState::new(store.clone(), Arc::new(Health::default()));
+    state.admin_token = Some("secret".into());
+    state.server.set(Arc::clone(&ctx)).ok().unwrap();
+    let (_trigger, shutdown) = shutdown::channel();
+    tokio::spawn(http::listen(listener, Arc::new(state), shutdown));
+
+    let mut bob = Client::connect(chat_addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+
+    // Returns the status line, and the stream's lines once the headers are read
+    type Stream = (
+        Lines<BufReader<OwnedReadHalf>>,
+        tokio::net::tcp::OwnedWriteHalf,
+    );
+    async fn open(addr: SocketAddr, room: &str, headers: &str) -> (String, Stream) {
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let req = format!("GET /api/rooms/{}/stream HTTP/1.1\r\n{}\r\n", room, headers);
+        writer.write_all(req.as_bytes()).await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        let status = lines.next_line().await.unwrap().unwrap();
+        while !lines.next_line().await.unwrap().unwrap().is_empty() {}
+        (status, (lines, writer))
+    }
+    // The `data` of the next event, with its id
+    async fn next(stream: &mut Stream) -> (i64, Value) {
+        let id = stream.0.next_line().await.unwrap().unwrap();
+        let data = stream.0.next_line().await.unwrap().unwrap();
+        assert_eq!(stream.0.next_line().await.unwrap().unwrap(), "");
+        let data: Value = serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap();
+        (id.strip_prefix("id: ").unwrap().parse().unwrap(), data)
+    }
+
+    let auth = "Authorization: Bearer secret\r\n";
+    assert!(open(addr, "rust", "").await.0.contains("401"));
+    assert!(open(addr, "java", auth).await.0.contains("404"));
+
+    let (status, mut live) = open(addr, "rust", auth).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    bob.send("hi").await.unwrap();
+    let (id, data) = next(&mut live).await;
+    assert_eq!(
+        data,
+        json!({"score": id, "kind": "chat", "user": "bob", "text": "hi"})
+    );
+
+    // Everything since the start of chat, then live again
+    let (_, mut resumed) = open(addr, "rust", &format!("{}Last-Event-ID: 0\r\n", auth)).await;
+    assert_eq!(next(&mut resumed).await.1["kind"], "joined");
+    assert_eq!(next(&mut resumed).await.1["text"], "hi");
+    bob.send("bye").await.unwrap();
+    assert_eq!(next(&mut resumed).await.1["text"], "bye");
+    assert_eq!(next(&mut live).await.1["text"], "bye");
+
+    // Four at a time from one address
+    let mut more = vec![];
+    for _ in 0..2 {
+        more.push(open(addr, "rust", auth).await.1);
+    }
+    assert!(open(addr, "rust", auth).await.0.contains("429"));
+
+    // The room stops sending to streams once they're closed
+    let room = ctx.rooms.read().await["rust"].clone();
+    while room.viewers() < 4 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    drop((live, resumed, more));
+    while room.viewers() > 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
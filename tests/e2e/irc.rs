@@ -0,0 +1,64 @@
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::{irc, shutdown};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::common;
+
+#[tokio::test]
+async fn join_and_chat() {
+    let (addr, _, ctx) = common::serve().await;
+    let irc_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let irc_addr = irc_listener.local_addr().unwrap();
+    let (_trigger, shutdown) = shutdown::channel();
+    tokio::spawn(irc::listen(irc_listener, ctx, shutdown));
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    common::until(&mut alice, common::LIVE).await;
+
+    let (reader, mut bob) = TcpStream::connect(irc_addr).await.unwrap().into_split();
+    let mut lines = BufReader::new(reader).lines();
+    // Skips ahead to the first line starting with `start`
+    async fn expect(lines: &mut Lines<impl AsyncBufRead + Unpin>, start: &str) -> String {
+        loop {
+            let line = lines.next_line().await.unwrap().unwrap();
+            if line.starts_with(start) {
+                return line;
+            }
+        }
+    }
+
+    bob.write_all(b"NICK bob\r\nUSER bob 0 * :Bob\r\n")
+        .await
+        .unwrap();
+    expect(&mut lines, ":chatsapp 001 bob :Welcome").await;
+
+    bob.write_all(b"LIST\r\n").await.unwrap();
+    expect(&mut lines, ":chatsapp 322 bob #rust 1 :").await;
+
+    bob.write_all(b"JOIN #rust\r\n").await.unwrap();
+    expect(&mut lines, ":bob!bob@chatsapp JOIN #rust").await;
+    let names = expect(&mut lines, ":chatsapp 353 bob = #rust :").await;
+    assert!(names.ends_with(":alice bob"));
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    alice.send("hi bob").await.unwrap();
+    expect(&mut lines, ":alice!alice@chatsapp PRIVMSG #rust :hi bob").await;
+
+    bob.write_all(b"PRIVMSG #rust :hi alice\r\n").await.unwrap();
+    let chat = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "hi alice".into(),
+    };
+    while alice.next_event().await.unwrap() != chat {}
+
+    bob.write_all(b"PING :check\r\n").await.unwrap();
+    expect(&mut lines, ":chatsapp PONG chatsapp :check").await;
+
+    bob.write_all(b"PART #rust\r\n").await.unwrap();
+    expect(&mut lines, ":bob!bob@chatsapp PART #rust").await;
+    while alice.next_event().await.unwrap() != ServerEvent::Left("bob".into()) {}
+}
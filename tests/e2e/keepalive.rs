@@ -0,0 +1,55 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::config::RuntimeConfig;
+use chatsapp::keepalive;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn probe() {
+    let (addr, _, ctx) = common::serve().await;
+    let config =
+        RuntimeConfig::parse("keepalive_secs = 1\nkeepalive_grace_secs = 1\naway_after_secs = 2")
+            .unwrap();
+    ctx.config.store(Arc::new(config));
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+
+    // A peer that's stopped writing never answers, so it's dropped and leaves
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (reader, mut silent) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    silent
+        .write_all(b">set-username bob\n>join-room rust\n")
+        .await
+        .unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+    while lines.next_line().await.unwrap().unwrap() != keepalive::PROBE {}
+    while alice.next_event().await.unwrap() != ServerEvent::Left("bob".into()) {}
+    assert!(ctx.registry.find_by_username("bob").is_none());
+
+    // So is one that's stopped reading, it can't have seen the probe to answer
+    let mut deaf = TcpStream::connect(addr).await.unwrap();
+    deaf.write_all(b">set-username carol\n>join-room rust\n")
+        .await
+        .unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("carol".into()) {}
+    while alice.next_event().await.unwrap() != ServerEvent::Left("carol".into()) {}
+
+    // Answering keeps the connection, without counting as being active
+    assert!(tokio::time::timeout(Duration::from_secs(4), alice.recv())
+        .await
+        .is_err());
+    assert!(ctx.registry.find_by_username("alice").unwrap().away);
+    alice.send(">me").await.unwrap();
+    let me = alice.next_event().await.unwrap().to_string();
+    assert!(me.starts_with("Username: alice"), "{}", me);
+}
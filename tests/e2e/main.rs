@@ -0,0 +1,36 @@
+mod common;
+
+mod account;
+mod app;
+mod audit;
+mod broker;
+mod caps;
+mod chatlog;
+mod client;
+mod compact;
+mod dm;
+mod errors;
+mod filter;
+mod forward;
+mod http;
+mod irc;
+mod keepalive;
+mod mention;
+mod page;
+mod pending;
+mod quote;
+mod reactions;
+mod registry;
+mod render;
+mod report;
+mod roles;
+mod room;
+mod server;
+mod session;
+mod shutdown;
+#[cfg(feature = "sqlite-backend")]
+mod sqlite;
+mod store;
+mod systemd;
+mod webhook;
+mod ws;
@@ -0,0 +1,40 @@
+use chatsapp::client::{Client, ServerEvent};
+
+use crate::common::{self, expect, until, LIVE};
+
+#[tokio::test]
+async fn unread() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    carol.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("carol".into()) {}
+
+    carol.send("hey @bob and @alice").await.unwrap();
+    let chat = ServerEvent::Chat {
+        user: "carol".into(),
+        text: "hey @bob and @alice".into(),
+    };
+    while alice.next_event().await.unwrap() != chat {}
+
+    // bob wasn't there, so finds out on logging in
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("Username set to 'bob'".into())
+    );
+    let notice = ServerEvent::Info("You have 1 unread mention — use >mentions to view".into());
+    assert_eq!(bob.next_event().await.unwrap(), notice);
+    expect(&mut bob, ">mentions", "[rust] carol: hey @bob and @alice").await;
+    expect(&mut bob, ">mentions", "No unread mentions").await;
+
+    // alice saw it as it happened
+    expect(&mut alice, ">mentions", "No unread mentions").await;
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use chatsapp::client::Client;
+use chatsapp::config::RuntimeConfig;
+use chatsapp::room;
+
+use crate::common;
+
+#[tokio::test]
+async fn paginate() {
+    let (addr, store, ctx) = common::serve().await;
+    for n in 1..=300 {
+        let name = format!("room-{:03}", n);
+        room::create(&*store, &name, None, &Default::default())
+            .await
+            .unwrap();
+        if n % 5 == 0 {
+            room::set_tags(&*store, &name, &["fives".to_owned()])
+                .await
+                .unwrap();
+        }
+    }
+
+    // The lines sent back, up to the footer or an error
+    async fn page(client: &mut Client, command: &str) -> (Vec<String>, String) {
+        client.send(command).await.unwrap();
+        let mut lines = vec![];
+        loop {
+            let line = client.next_event().await.unwrap().to_string();
+            if line.starts_with("Page ") || line.starts_with("[E_") {
+                return (lines, line);
+            }
+            lines.push(line);
+        }
+    }
+
+    // Newest first, then by name, so the same page comes back each time
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    assert_eq!(
+        bob.next_event().await.unwrap().to_string(),
+        "Username set to 'bob'"
+    );
+    let (rooms, footer) = page(&mut bob, ">list").await;
+    assert_eq!(rooms.len(), 25);
+    assert_eq!(
+        (rooms[0].as_str(), rooms[24].as_str()),
+        ("room-300", "room-276")
+    );
+    assert_eq!(footer, "Page 1/12 — '>list page 2' for more");
+    let (rooms, footer) = page(&mut bob, ">list page 2").await;
+    assert_eq!(
+        (rooms[0].as_str(), rooms[24].as_str()),
+        ("room-275", "room-251")
+    );
+    assert_eq!(footer, "Page 2/12 — '>list page 3' for more");
+    assert_eq!(page(&mut bob, ">list page 2").await.0, rooms);
+    let (rooms, footer) = page(&mut bob, ">list page 12").await;
+    assert_eq!((rooms.len(), rooms[24].as_str()), (25, "room-001"));
+    assert_eq!(footer, "Page 12/12");
+    let (_, error) = page(&mut bob, ">list page 13").await;
+    assert_eq!(
+        error,
+        "[E_INVALID_ARGUMENT] There's no page 13, pick one from 1 to 12"
+    );
+
+    // Tags and searches are paged the same way
+    let (rooms, footer) = page(&mut bob, ">list tag:fives page 3").await;
+    assert_eq!(
+        (rooms.len(), rooms[0].as_str(), rooms[9].as_str()),
+        (10, "room-050", "room-005")
+    );
+    assert_eq!(footer, "Page 3/3");
+    let (_, error) = page(&mut bob, ">list tag:fives page 4").await;
+    assert_eq!(
+        error,
+        "[E_INVALID_ARGUMENT] There's no page 4, pick one from 1 to 3"
+    );
+
+    // With the page size configured
+    ctx.config
+        .store(Arc::new(RuntimeConfig::parse("page_size = 8").unwrap()));
+    let (rooms, footer) = page(&mut bob, ">find room-0").await;
+    assert_eq!((rooms.len(), rooms[0].as_str()), (8, "room-099 (0 online)"));
+    assert_eq!(footer, "Page 1/3 — '>find room-0 page 2' for more");
+    let (rooms, footer) = page(&mut bob, ">find room-0 page 3").await;
+    assert_eq!((rooms.len(), footer.as_str()), (4, "Page 3/3"));
+
+    ctx.config
+        .store(Arc::new(RuntimeConfig::parse("page_size = 40").unwrap()));
+    let (rooms, footer) = page(&mut bob, ">list page 8").await;
+    assert_eq!((rooms.len(), rooms[19].as_str()), (20, "room-001"));
+    assert_eq!(footer, "Page 8/8");
+}
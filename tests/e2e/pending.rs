@@ -0,0 +1,53 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::server::ServerContext;
+use chatsapp::shutdown;
+use chatsapp::store::{MemoryStore, RoomStore, SlowStore};
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn pending_writes() {
+    let store = Arc::new(SlowStore::new(MemoryStore::default(), Duration::ZERO));
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = Arc::new(ServerContext::new(store.clone(), trigger));
+    let addr = common::listen(ctx.clone(), shutdown).await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    // Still sent while storage is down
+    store.set_down(true);
+    for text in ["one", "two"] {
+        alice.send(text).await.unwrap();
+        let chat = ServerEvent::Chat {
+            user: "alice".into(),
+            text: text.into(),
+        };
+        assert_eq!(bob.next_event().await.unwrap(), chat);
+    }
+    assert_eq!(ctx.pending.len(), 2);
+
+    // And saved in order once it's back
+    store.set_down(false);
+    alice.send("three").await.unwrap();
+    let three = ServerEvent::Chat {
+        user: "alice".into(),
+        text: "three".into(),
+    };
+    assert_eq!(bob.next_event().await.unwrap(), three);
+    assert!(ctx.pending.is_empty());
+
+    let history = store.recent("rust", 3).await.unwrap();
+    assert_eq!(history, ["alice: one\n", "alice: two\n", "alice: three\n"]);
+}
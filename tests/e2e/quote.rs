@@ -0,0 +1,48 @@
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::errors::Code;
+
+use crate::common::{self, refused, until, LIVE};
+
+#[tokio::test]
+async fn delivered() {
+    let (addr, store, _) = common::serve().await;
+
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    carol.create_room("rust").await.unwrap();
+    carol.join("rust").await.unwrap();
+    until(&mut carol, LIVE).await;
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+    while carol.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    carol.send("anyone used tokio?").await.unwrap();
+    carol.send("hello?").await.unwrap();
+    let hello = ServerEvent::Chat {
+        user: "carol".into(),
+        text: "hello?".into(),
+    };
+    while bob.next_event().await.unwrap() != hello {}
+
+    // Sent, and stored, as one message
+    bob.send(">quote 2 yes, lots").await.unwrap();
+    let text = "> carol: anyone used tokio? \u{21b5} yes, lots";
+    let quoted = ServerEvent::Chat {
+        user: "bob".into(),
+        text: text.into(),
+    };
+    assert_eq!(carol.next_event().await.unwrap(), quoted);
+    let history = store.recent("rust", 1).await.unwrap();
+    assert_eq!(history, [format!("bob: {}\n", text)]);
+
+    // carol's own messages aren't sent back to her, but bob's join and the
+    // history's "Start of chat" were
+    let not_chat = |n| (Code::InvalidArgument, format!("Message {} isn't chat", n));
+    assert_eq!(refused(&mut carol, ">quote 2 hi").await, not_chat(2));
+    assert_eq!(refused(&mut carol, ">quote 3 hi").await, not_chat(3));
+    let text = "There's no message 4 to quote, 1 is the latest and 3 the oldest";
+    let out_of_range = (Code::InvalidArgument, text.to_owned());
+    assert_eq!(refused(&mut carol, ">quote 4 hi").await, out_of_range);
+}
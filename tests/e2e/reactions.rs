@@ -0,0 +1,90 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::config::RuntimeConfig;
+use chatsapp::errors::Code;
+use chatsapp::filter::WordFilter;
+
+use crate::common::{self, connect, expect, refused, until, LIVE};
+
+#[tokio::test]
+async fn react() {
+    let (addr, store, ctx) = common::serve().await;
+
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    carol.create_room("rust").await.unwrap();
+    carol.join("rust").await.unwrap();
+    until(&mut carol, LIVE).await;
+    let mut alice = connect(addr, "alice").await;
+    let mut bob = connect(addr, "bob").await;
+
+    carol.send("anyone used tokio?").await.unwrap();
+    let asked = ServerEvent::Chat {
+        user: "carol".into(),
+        text: "anyone used tokio?".into(),
+    };
+    while alice.next_event().await.unwrap() != asked {}
+    while bob.next_event().await.unwrap() != asked {}
+
+    // Numbered as `>quote` numbers them, and everyone there is told
+    let thumbs = "bob reacted to carol's message with \u{1f44d}";
+    expect(&mut bob, ">react 1 \u{1f44d}", thumbs).await;
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Info(thumbs.into())
+    );
+    while carol.next_event().await.unwrap() != ServerEvent::Info(thumbs.into()) {}
+
+    // For terminals without emoji a word does, and the notice counts too
+    let word = "alice reacted to carol's message with +1";
+    expect(&mut alice, ">react 2 +1", word).await;
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info(word.into())
+    );
+
+    // Reacting again changes it
+    let tada = "bob reacted to carol's message with tada";
+    expect(&mut bob, ">react 2 tada", tada).await;
+    alice.send(">reactions 3").await.unwrap();
+    let listed = ["Reactions to carol's message:", "+1 alice", "tada bob"];
+    while alice.next_event().await.unwrap().to_string() != listed[0] {}
+    for line in &listed[1..] {
+        assert_eq!(alice.next_event().await.unwrap().to_string(), *line);
+    }
+
+    // They're filtered and held back as messages are
+    ctx.config.store(Arc::new(
+        RuntimeConfig::parse("[filter]\nmode = \"block\"").unwrap(),
+    ));
+    ctx.filter.store(Arc::new(WordFilter::new(["heck"])));
+    let blocked = "[E_BLOCKED] Your message was blocked by the word filter";
+    expect(&mut bob, ">react 2 heck", blocked).await;
+    ctx.mutes
+        .insert("bob".into(), Instant::now() + Duration::from_secs(60));
+    let muted = "[E_RATE_LIMITED] You're muted for repeating messages, try again in 60s";
+    expect(&mut bob, ">react 2 +1", muted).await;
+    ctx.mutes.remove("bob");
+
+    // History is replayed without them
+    let history = store.recent("rust", 10).await.unwrap();
+    assert!(history.contains(&"carol: anyone used tokio?\n".to_owned()));
+    assert!(!history.iter().any(|line| line.contains("reacted")));
+
+    // Only chat has reactions
+    let (code, text) = refused(&mut alice, ">react 1 +1").await;
+    assert_eq!(code, Code::InvalidArgument);
+    assert_eq!(text, "Message 1 isn't chat, only chat has reactions");
+    let (_, text) = refused(&mut alice, ">react 99 +1").await;
+    assert!(text.starts_with("There's no message 99, 1 is the latest"));
+    let (_, text) = refused(&mut alice, ">react 1 far too many words").await;
+    assert!(text.starts_with("Too many arguments"));
+
+    // Nor once it's removed
+    carol.send(">undo").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Retracted("carol".into()) {}
+    let (_, text) = refused(&mut alice, ">react 4 +1").await;
+    assert!(text.ends_with(" has already been removed"), "{}", text);
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::config::RuntimeConfig;
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn idle() {
+    let (addr, _, ctx) = common::serve().await;
+    ctx.config.store(Arc::new(
+        RuntimeConfig::parse("away_after_secs = 1").unwrap(),
+    ));
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    while bob.next_event().await.unwrap() != ServerEvent::Info("Username set to 'bob'".into()) {}
+
+    // Only once they've been quiet in a room for long enough, and the room
+    // isn't told
+    let away = || ctx.registry.find_by_username("alice").unwrap().idle();
+    assert_eq!(away(), None);
+    while away().is_none() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    bob.send(">who ali*").await.unwrap();
+    let users = bob.next_event().await.unwrap().to_string();
+    assert!(users.contains(", idle "), "{}", users);
+    bob.send(">dm alice you there?").await.unwrap();
+    let sent = bob.next_event().await.unwrap();
+    assert_eq!(sent, ServerEvent::Info("[dm to alice] you there?".into()));
+    let note = bob.next_event().await.unwrap().to_string();
+    assert!(note.starts_with("alice is away, idle for "), "{}", note);
+
+    // Anything they send brings them back, and their idle time with them
+    alice.send("back").await.unwrap();
+    bob.send(">users ali*").await.unwrap();
+    let users = bob.next_event().await.unwrap().to_string();
+    assert!(
+        users.starts_with("alice - rust (joined ") && !users.contains("idle"),
+        "{}",
+        users
+    );
+}
@@ -0,0 +1,114 @@
+use chatsapp::client::{Client, ServerEvent};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn room_view() {
+    let (addr, store, _) = common::serve().await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+
+    // Lines as they're written, history and live alike
+    let (reader, mut alice) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut lines = BufReader::new(reader).lines();
+    alice
+        .write_all(b">set-username alice\n>join-room rust\n")
+        .await
+        .unwrap();
+    let mut history = vec![];
+    loop {
+        let line = lines.next_line().await.unwrap().unwrap();
+        if line == LIVE {
+            break;
+        }
+        history.push(line);
+    }
+    assert!(history.ends_with(&[
+        "-- Start of chat --".into(),
+        "-- bob has joined the room --".into()
+    ]));
+    bob.send("hi").await.unwrap();
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), "bob: hi");
+
+    // Dimmed
+    alice.write_all(b">set-ansi on\n>me\n").await.unwrap();
+    assert!(lines
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .starts_with("Username: alice"));
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    carol.join("rust").await.unwrap();
+    let joined = "\x1b[2m-- carol has joined the room --\x1b[0m";
+    assert_eq!(lines.next_line().await.unwrap().unwrap(), joined);
+    // Clients read them either way
+    assert_eq!(
+        ServerEvent::parse(joined),
+        ServerEvent::Joined("carol".into())
+    );
+    assert_eq!(
+        ServerEvent::parse("-- carol has joined the room --"),
+        ServerEvent::Joined("carol".into())
+    );
+
+    // carol doesn't see joins, in history or live, but they're stored
+    until(&mut carol, LIVE).await;
+    carol.send(">set-quiet on").await.unwrap();
+    carol.send(">leave").await.unwrap();
+    carol.send(">join-room rust").await.unwrap();
+    let mut seen = vec![];
+    loop {
+        match carol.next_event().await.unwrap() {
+            event if event.to_string() == LIVE => break,
+            event => seen.push(event),
+        }
+    }
+    assert!(seen.contains(&ServerEvent::Chat {
+        user: "bob".into(),
+        text: "hi".into()
+    }));
+    assert!(!seen
+        .iter()
+        .any(|event| matches!(event, ServerEvent::Joined(_) | ServerEvent::Left(_))));
+    let mut dave = Client::connect(addr).await.unwrap();
+    dave.set_username("dave").await.unwrap();
+    dave.join("rust").await.unwrap();
+    until(&mut dave, LIVE).await;
+    bob.send("still here?").await.unwrap();
+    let still = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "still here?".into(),
+    };
+    assert_eq!(carol.next_event().await.unwrap(), still);
+    let history = store.recent("rust", 2).await.unwrap();
+    assert_eq!(history[0], "dave has joined the room\n");
+}
+
+#[tokio::test]
+async fn uptime() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.send(">uptime").await.unwrap();
+    let uptime = loop {
+        match bob.next_event().await.unwrap() {
+            ServerEvent::Info(line) if line.starts_with("Server up ") => break line,
+            _ => {}
+        }
+    };
+    let (server, session) = uptime.split_once("; ").unwrap();
+    assert!(
+        server.starts_with("Server up 0s (since 20") && server.ends_with(" UTC)"),
+        "{}",
+        server
+    );
+    assert_eq!(session, "your session: 0s");
+}
@@ -0,0 +1,118 @@
+use chatsapp::audit::{self, AuditAction};
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::{report, roles};
+
+use crate::common::{self, expect, LIVE};
+
+#[tokio::test]
+async fn file() {
+    let (addr, store, _) = common::serve().await;
+    roles::grant(&*store, "admin").await.unwrap();
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    expect(&mut alice, ">join-room rust", LIVE).await;
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    expect(&mut bob, ">join-room rust", LIVE).await;
+    for line in [
+        "buy my crypto",
+        "hi alice",
+        "seriously, buy it",
+        "last chance",
+    ] {
+        bob.send(line).await.unwrap();
+    }
+    let last = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "last chance".into(),
+    };
+    while alice.next_event().await.unwrap() != last {}
+
+    let thanks = "Thanks, your report about bob has been passed on to the admins";
+    expect(&mut alice, ">report bob spamming crypto", thanks).await;
+    expect(
+        &mut alice,
+        ">report alice me",
+        "[E_INVALID_ARGUMENT] You can't report yourself",
+    )
+    .await;
+
+    let reports = report::open(&*store, 10).await.unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(
+        (reports[0].reporter.as_str(), reports[0].target.as_str()),
+        ("alice", "bob")
+    );
+    assert_eq!(reports[0].room.as_deref(), Some("rust"));
+    assert_eq!(reports[0].reason, "spamming crypto");
+    assert_eq!(
+        reports[0].context,
+        ["hi alice", "seriously, buy it", "last chance"]
+    );
+
+    // Only so many at a time
+    expect(&mut alice, ">report bob again", thanks).await;
+    expect(
+        &mut alice,
+        ">report carol rude",
+        "Thanks, your report about carol has been passed on to the admins",
+    )
+    .await;
+    let limited = "[E_RATE_LIMITED] You've sent a lot of reports lately, try again later";
+    expect(&mut alice, ">report dave rude", limited).await;
+    assert_eq!(report::open(&*store, 10).await.unwrap().len(), 3);
+
+    // Admins go through them, newest last
+    let mut admin = Client::connect(addr).await.unwrap();
+    admin.set_username("admin").await.unwrap();
+    common::register(&mut admin, "admin").await;
+    admin.send(">reports 2").await.unwrap();
+    let mut listed = vec![];
+    while listed.len() < 2 {
+        let line = admin.next_event().await.unwrap().to_string();
+        if line.starts_with('#') {
+            listed.push(line);
+        }
+    }
+    assert!(
+        listed[0].starts_with("#2 ") && listed[0].ends_with(" alice reported bob in rust: again"),
+        "{}",
+        listed[0]
+    );
+    assert!(
+        listed[1].ends_with(" alice reported carol in rust: rude"),
+        "{}",
+        listed[1]
+    );
+
+    expect(
+        &mut admin,
+        ">report-close 1 warned them",
+        "Closed report #1 about bob",
+    )
+    .await;
+    expect(&mut admin, ">report-close 1", "There's no open report #1").await;
+    let ids: Vec<u64> = report::open(&*store, 10)
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| r.id)
+        .collect();
+    assert_eq!(ids, [2, 3]);
+
+    let entries = audit::recent(&*store, 10).await.unwrap();
+    let actions: Vec<_> = entries.iter().map(|entry| entry.action).collect();
+    assert_eq!(actions, [AuditAction::Reports, AuditAction::ReportClose]);
+    assert_eq!(entries[1].target.as_deref(), Some("bob"));
+    assert_eq!(entries[1].reason.as_deref(), Some("#1 warned them"));
+
+    // Nobody else can
+    expect(
+        &mut bob,
+        ">reports",
+        "[E_FORBIDDEN] You need to be an admin to do that",
+    )
+    .await;
+}
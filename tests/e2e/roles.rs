@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use chatsapp::account;
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::errors::Code;
+use chatsapp::server::ServerContext;
+use chatsapp::store::MemoryStore;
+use chatsapp::{roles, shutdown};
+
+use crate::common::{self, expect, refused};
+
+#[tokio::test]
+async fn is_admin() {
+    let store = Arc::new(MemoryStore::default());
+    roles::seed(&*store, &["alice".to_owned()]).await.unwrap();
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = ServerContext::new(store.clone(), trigger);
+    let addr = common::listen(Arc::new(ctx), shutdown.clone()).await;
+
+    let denied = (
+        Code::Forbidden,
+        "You need to be an admin to do that".to_owned(),
+    );
+
+    // Until alice registers, anyone can set her name, but it's not enough
+    let mut mallory = Client::connect(addr).await.unwrap();
+    mallory.set_username("Alice").await.unwrap();
+    assert_eq!(
+        mallory.next_event().await.unwrap(),
+        ServerEvent::Info("Username set to 'Alice'".into())
+    );
+    assert_eq!(refused(&mut mallory, ">op mallory").await, denied);
+    assert_eq!(refused(&mut mallory, ">shutdown").await, denied);
+    assert!(!shutdown.is_shutdown());
+    drop(mallory);
+
+    account::set_password(&*store, "alice", "hunter22")
+        .await
+        .unwrap();
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    assert_eq!(refused(&mut alice, ">op bob").await, denied);
+    expect(&mut alice, ">login hunter22", "Logged in as alice").await;
+
+    // Only names that can't be taken from them
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    assert_eq!(
+        refused(&mut alice, ">op bob").await,
+        (
+            Code::NotRegistered,
+            "bob isn't registered, only registered names can be admins".to_owned()
+        )
+    );
+    common::register(&mut bob, "bob").await;
+    expect(&mut alice, ">op BOB", "BOB is now an admin").await;
+    expect(&mut bob, ">broadcast hello", "[broadcast] hello").await;
+
+    // Revoking applies to the next command, without reconnecting
+    expect(&mut alice, ">deop bob", "bob is no longer an admin").await;
+    assert_eq!(refused(&mut bob, ">shutdown").await, denied);
+    assert!(!shutdown.is_shutdown());
+}
@@ -0,0 +1,990 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::audit::{self, AuditAction};
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::command::Command;
+use chatsapp::config::RuntimeConfig;
+use chatsapp::errors::Code;
+use chatsapp::room::{self, MyRoom, RoomEvent};
+use chatsapp::{account, broker, roles};
+use tokio::time;
+
+use crate::common::{self, connect, expect, refused, until, LIVE};
+
+#[tokio::test]
+async fn create() {
+    let (addr, store, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    let set = ServerEvent::Info("Username set to 'alice'".into());
+    assert_eq!(alice.next_event().await.unwrap(), set);
+    let created = ServerEvent::Info("Room 'rust' created, join it with >join-room rust".into());
+    assert_eq!(alice.next_event().await.unwrap(), created);
+    alice.join("rust").await.unwrap();
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.next_event().await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    let taken = ServerEvent::Error {
+        code: Code::NameTaken,
+        text: "Room name taken".into(),
+    };
+    assert_eq!(bob.next_event().await.unwrap(), taken);
+
+    // Joining after still reaches alice
+    bob.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+    bob.send("hi").await.unwrap();
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        ServerEvent::Chat {
+            user: "bob".into(),
+            text: "hi".into()
+        }
+    );
+
+    // Settings can be given up front
+    let go = r#">create-room go --topic "Go, mostly" --max-length 100"#;
+    bob.command(go.parse().unwrap()).await.unwrap();
+    let created = ServerEvent::Info("Room 'go' created, join it with >join-room go".into());
+    while bob.next_event().await.unwrap() != created {}
+    let info = room::info(&*store, "go").await.unwrap();
+    assert_eq!(info.topic.as_deref(), Some("Go, mostly"));
+    assert_eq!(info.max_message_len, Some(100));
+}
+
+#[tokio::test]
+async fn owned_by() {
+    let (addr, store, ctx) = common::serve().await;
+    roles::seed(&*store, &["alice".to_owned()]).await.unwrap();
+    let config = RuntimeConfig::parse("limits = { rooms_per_user = 2, max_rooms = 3 }").unwrap();
+    ctx.config.store(Arc::new(config));
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("a").await.unwrap();
+    bob.create_room("b").await.unwrap();
+    expect(
+        &mut bob,
+        ">create-room c",
+        "[E_LIMIT_REACHED] You have reached your room limit (2)",
+    )
+    .await;
+
+    // Deleting one frees up a slot
+    room::delete(&*store, "a").await.unwrap();
+    assert_eq!(room::owned_by(&*store, "bob").await.unwrap(), 1);
+    bob.create_room("c").await.unwrap();
+    expect(&mut bob, ">list", "c").await;
+
+    // Admins have no limit of their own, but the server still does
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("d").await.unwrap();
+    expect(
+        &mut alice,
+        ">create-room e",
+        "[E_LIMIT_REACHED] Server room limit reached (3)",
+    )
+    .await;
+    assert_eq!(room::owned_by(&*store, "alice").await.unwrap(), 1);
+
+    // Deleting any room frees a slot on the server
+    room::delete(&*store, "b").await.unwrap();
+    expect(
+        &mut alice,
+        ">create-room e",
+        "Room 'e' created, join it with >join-room e",
+    )
+    .await;
+
+    // Reserved names are left to admins
+    room::delete(&*store, "c").await.unwrap();
+    expect(
+        &mut bob,
+        ">create-room _ops",
+        "[E_RESERVED] '_ops' is a reserved room name",
+    )
+    .await;
+    expect(
+        &mut bob,
+        ">create-room lobby",
+        "[E_RESERVED] 'lobby' is a reserved room name",
+    )
+    .await;
+    let created = "Room 'lobby' created, join it with >join-room lobby";
+    expect(&mut alice, ">create-room lobby", created).await;
+}
+
+#[tokio::test]
+async fn permission() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut owner = Client::connect(addr).await.unwrap();
+    owner.set_username("alice").await.unwrap();
+    common::register(&mut owner, "alice").await;
+    owner.create_room("rust").await.unwrap();
+    owner.join("rust").await.unwrap();
+    until(&mut owner, LIVE).await;
+
+    let mut moderator = Client::connect(addr).await.unwrap();
+    moderator.set_username("bob").await.unwrap();
+    moderator.join("rust").await.unwrap();
+    let mut member = Client::connect(addr).await.unwrap();
+    member.set_username("carol").await.unwrap();
+    member.join("rust").await.unwrap();
+    let mut outsider = Client::connect(addr).await.unwrap();
+    outsider.set_username("dave").await.unwrap();
+
+    let not_in_room = "[E_NOT_IN_ROOM] You're not currently in a room.";
+    let owner_only = "[E_FORBIDDEN] Only the room owner can do that";
+    let mods_only = "[E_FORBIDDEN] You need to be a room moderator to do that";
+
+    expect(&mut member, ">mod add carol", owner_only).await;
+    expect(&mut outsider, ">mod add dave", not_in_room).await;
+    expect(&mut owner, ">mod add bob", "bob is now a moderator of rust").await;
+    expect(&mut moderator, ">mod add carol", owner_only).await;
+
+    expect(&mut member, ">mods", owner_only).await;
+    expect(&mut outsider, ">mods", not_in_room).await;
+    expect(&mut moderator, ">mods", owner_only).await;
+    expect(&mut owner, ">mods", "bob").await;
+
+    expect(&mut member, ">slowmode 5", mods_only).await;
+    expect(&mut outsider, ">slowmode 5", not_in_room).await;
+    let log_in = "[E_FORBIDDEN] You need to be logged in to use your role in rust, >register or >login first";
+    expect(&mut moderator, ">slowmode 5", log_in).await;
+    common::register(&mut moderator, "bob").await;
+    expect(&mut moderator, ">slowmode 5", "Slow mode set to 5s by bob").await;
+    expect(&mut owner, ">slowmode 0", "Slow mode disabled by alice").await;
+
+    expect(&mut member, ">mod remove bob", owner_only).await;
+    expect(&mut outsider, ">mod remove bob", not_in_room).await;
+    expect(&mut moderator, ">mod remove bob", owner_only).await;
+    expect(
+        &mut owner,
+        ">mod remove bob",
+        "bob is no longer a moderator of rust",
+    )
+    .await;
+    expect(&mut moderator, ">slowmode 5", mods_only).await;
+
+    // Anyone could take an unregistered owner's name, so it isn't enough
+    let mut erin = Client::connect(addr).await.unwrap();
+    erin.set_username("erin").await.unwrap();
+    expect(&mut erin, ">create-room go --join", LIVE).await;
+    drop(erin);
+    let mut mallory = Client::connect(addr).await.unwrap();
+    // The name's free once the server notices erin's gone
+    loop {
+        mallory.send(">set-username Erin").await.unwrap();
+        match mallory.next_event().await.unwrap() {
+            ServerEvent::Error {
+                code: Code::NameTaken,
+                ..
+            } => time::sleep(Duration::from_millis(10)).await,
+            event => break assert_eq!(event.to_string(), "Username set to 'Erin'"),
+        }
+    }
+    expect(&mut mallory, ">join-room go", LIVE).await;
+    let log_in =
+        "[E_FORBIDDEN] You need to be logged in to use your role in go, >register or >login first";
+    expect(&mut mallory, ">room-set topic mine now", log_in).await;
+    expect(&mut mallory, ">mod add mallory", log_in).await;
+}
+
+#[tokio::test]
+async fn transfer() {
+    let (addr, store, _) = common::serve().await;
+    account::set_password(&*store, "carol", "hunter22")
+        .await
+        .unwrap();
+    roles::grant(&*store, "admin").await.unwrap();
+
+    async fn named(addr: SocketAddr, name: &str) -> Client {
+        let mut client = Client::connect(addr).await.unwrap();
+        client.set_username(name).await.unwrap();
+        common::register(&mut client, name).await;
+        client
+    }
+
+    let mut alice = named(addr, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    expect(&mut alice, ">join-room rust", LIVE).await;
+    let mut bob = named(addr, "bob").await;
+    expect(&mut bob, ">join-room rust", LIVE).await;
+
+    let owner_only = "[E_FORBIDDEN] Only the room owner can do that";
+    expect(&mut bob, ">transfer-ownership bob", owner_only).await;
+    let unknown = "[E_INVALID_ARGUMENT] There's nobody called dave online or registered";
+    expect(&mut alice, ">transfer-ownership dave", unknown).await;
+
+    // The room hears about it
+    alice.send(">transfer-ownership bob").await.unwrap();
+    let notice = ServerEvent::Info("alice handed ownership of rust to bob".into());
+    while bob.next_event().await.unwrap() != notice {}
+    assert_eq!(
+        room::info(&*store, "rust").await.unwrap().owner.as_deref(),
+        Some("bob")
+    );
+    assert_eq!(room::owned_by(&*store, "alice").await.unwrap(), 0);
+    assert_eq!(room::owned_by(&*store, "bob").await.unwrap(), 1);
+    let (owned, _) = room::my_rooms(&*store, "bob").await.unwrap();
+    assert_eq!(owned[0].name, "rust");
+    assert!(room::my_rooms(&*store, "alice").await.unwrap().0.is_empty());
+
+    // The old owner can't do anything owner-only any more, the new one can
+    expect(&mut alice, ">mod add alice", owner_only).await;
+    expect(&mut alice, ">room-set topic mine now", owner_only).await;
+    expect(&mut alice, ">transfer-ownership alice", owner_only).await;
+    expect(
+        &mut bob,
+        ">mod add alice",
+        "alice is now a moderator of rust",
+    )
+    .await;
+
+    // Admins can take over abandoned rooms, even for someone who's offline
+    let mut admin = named(addr, "admin").await;
+    expect(&mut admin, ">join-room rust", LIVE).await;
+    expect(&mut admin, ">transfer-ownership carol", owner_only).await;
+    let forced = "admin handed ownership of rust to carol";
+    expect(&mut admin, ">transfer-ownership carol --force", forced).await;
+    expect(
+        &mut bob,
+        ">transfer-ownership carol --force",
+        "[E_FORBIDDEN] You need to be an admin to do that",
+    )
+    .await;
+    assert_eq!(
+        room::info(&*store, "rust").await.unwrap().owner.as_deref(),
+        Some("carol")
+    );
+
+    let entries = audit::recent(&*store, 10).await.unwrap();
+    let transfers: Vec<_> = entries
+        .iter()
+        .filter(|entry| entry.action == AuditAction::TransferOwnership)
+        .map(|entry| {
+            (
+                entry.actor.as_str(),
+                entry.target.as_deref(),
+                entry.reason.as_deref(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        transfers,
+        [
+            ("alice", Some("bob"), Some("from alice")),
+            ("admin", Some("carol"), Some("from bob"))
+        ]
+    );
+}
+
+#[tokio::test]
+async fn set_slow_mode() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    common::register(&mut bob, "bob").await;
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+
+    bob.command(Command::SlowMode(5)).await.unwrap();
+    let denied = "You need to be a room moderator to do that".into();
+    let denied = ServerEvent::Error {
+        code: Code::Forbidden,
+        text: denied,
+    };
+    assert_eq!(bob.next_event().await.unwrap(), denied);
+
+    alice.command(Command::SlowMode(60)).await.unwrap();
+    let notice = ServerEvent::Info("Slow mode set to 60s by alice".into());
+    assert_eq!(bob.next_event().await.unwrap(), notice);
+
+    bob.send("one").await.unwrap();
+    bob.send("two").await.unwrap();
+    let wait = ServerEvent::Error {
+        code: Code::RateLimited,
+        text: "Slow mode: wait 60s".into(),
+    };
+    assert_eq!(bob.next_event().await.unwrap(), wait);
+
+    // Members already in the room pick up changes, once their app's caught up
+    async fn sync(client: &mut Client) {
+        client
+            .command(Command::List(Default::default()))
+            .await
+            .unwrap();
+        while client.next_event().await.unwrap() != ServerEvent::Info("rust".into()) {}
+    }
+    async fn sent(to: &mut Client, text: &str) {
+        let chat = ServerEvent::Chat {
+            user: "bob".into(),
+            text: text.into(),
+        };
+        while to.next_event().await.unwrap() != chat {}
+    }
+
+    alice.command(Command::SlowMode(0)).await.unwrap();
+    let off = ServerEvent::Info("Slow mode disabled by alice".into());
+    while bob.next_event().await.unwrap() != off {}
+    sync(&mut bob).await;
+    bob.send("three").await.unwrap();
+    sent(&mut alice, "three").await;
+
+    // Becoming a moderator lifts it straight away too
+    alice.command(Command::SlowMode(60)).await.unwrap();
+    alice.command(Command::AddMod("bob".into())).await.unwrap();
+    let modded = ServerEvent::Info("bob is now a moderator of rust".into());
+    while alice.next_event().await.unwrap() != modded {}
+    sync(&mut bob).await;
+    bob.send("four").await.unwrap();
+    bob.send("five").await.unwrap();
+    sent(&mut alice, "four").await;
+    sent(&mut alice, "five").await;
+}
+
+#[tokio::test]
+async fn set_tags() {
+    let (addr, store, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    for (room, tags) in [("chess", "Games,eu"), ("go", "games"), ("rust", "lang")] {
+        alice.create_room(room).await.unwrap();
+        alice.join(room).await.unwrap();
+        let set = format!(">room-set tags {}", tags);
+        expect(
+            &mut alice,
+            &set,
+            &format!("Tags for {} set to {}", room, tags.to_lowercase()),
+        )
+        .await;
+    }
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    expect(
+        &mut bob,
+        ">room-set tags spam",
+        "[E_FORBIDDEN] Only the room owner can do that",
+    )
+    .await;
+
+    // Most recently active first
+    expect(&mut bob, ">list tag:games", "go").await;
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("chess".into())
+    );
+    expect(&mut bob, ">list tag:nothing", "No rooms tagged nothing").await;
+
+    expect(&mut bob, ">tags", "games (2)").await;
+    room::delete(&*store, "go").await.unwrap();
+    expect(&mut bob, ">tags", "games (1)").await;
+}
+
+#[tokio::test]
+async fn set_max_message_len() {
+    let (addr, _, ctx) = common::serve().await;
+    let mut config = RuntimeConfig::default();
+    config.limits.max_message_len = 10;
+    ctx.config.store(Arc::new(config));
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+
+    // Ten characters is fine, however many bytes they take
+    for text in ["a".repeat(10), "日".repeat(10)] {
+        bob.send(&text).await.unwrap();
+        let chat = ServerEvent::Chat {
+            user: "bob".into(),
+            text,
+        };
+        assert_eq!(alice.next_event().await.unwrap(), chat);
+    }
+
+    bob.send(&"日".repeat(11)).await.unwrap();
+    let too_long = "Message too long (11/10 characters)".into();
+    let too_long = ServerEvent::Error {
+        code: Code::TooLong,
+        text: too_long,
+    };
+    while bob.next_event().await.unwrap() != too_long {}
+
+    // The owner can raise it for the room
+    alice
+        .command(">room-set max-length 11".parse().unwrap())
+        .await
+        .unwrap();
+    let set = ServerEvent::Info("Max message length for rust set to 11 characters".into());
+    while alice.next_event().await.unwrap() != set {}
+    // Gives bob's connection a moment to see the change
+    bob.command(">me".parse().unwrap()).await.unwrap();
+    bob.next_event().await.unwrap();
+    bob.send(&"日".repeat(11)).await.unwrap();
+    let chat = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "日".repeat(11),
+    };
+    assert_eq!(alice.next_event().await.unwrap(), chat);
+}
+
+#[tokio::test]
+async fn set_ephemeral() {
+    let (addr, store, ctx) = common::serve().await;
+    room::create(&*store, "scratch", None, &Default::default())
+        .await
+        .unwrap();
+    let ttl = Some(Duration::from_millis(100));
+    room::set_ephemeral(&*store, "scratch", ttl).await.unwrap();
+    let chat = |text: &str| RoomEvent::Chat(text.to_owned());
+    room::event(&ctx, chat("old"), "scratch", "bob", ttl).await;
+    time::sleep(Duration::from_millis(150)).await;
+    room::event(&ctx, chat("new"), "scratch", "bob", ttl).await;
+    let history = store.recent("scratch", 10).await.unwrap();
+    assert_eq!(history, ["Start of chat\n", "bob: new\n"]);
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    expect(
+        &mut alice,
+        ">create-room standup --join --ephemeral 200ms",
+        LIVE,
+    )
+    .await;
+    expect(&mut alice, ">room-info", "Messages expire after 200ms").await;
+    alice.send("yesterday").await.unwrap();
+    time::sleep(Duration::from_millis(250)).await;
+
+    // Expired before bob gets there
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("standup").await.unwrap();
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("Username set to 'bob'".into())
+    );
+    let joined = ServerEvent::Info("Joined 'standup' — 2 members".into());
+    assert_eq!(bob.next_event().await.unwrap(), joined);
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("Start of chat".into())
+    );
+
+    expect(
+        &mut bob,
+        ">room-set ephemeral 2h",
+        "[E_FORBIDDEN] Only the room owner can do that",
+    )
+    .await;
+    expect(
+        &mut alice,
+        ">room-set ephemeral 2h",
+        "Messages in standup now expire after 2h",
+    )
+    .await;
+    expect(&mut alice, ">room-info", "Messages expire after 2h").await;
+    expect(
+        &mut alice,
+        ">room-set ephemeral",
+        "Messages in standup no longer expire",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn sort() {
+    // Through `>list`, with who's in each room right now
+    let (addr, store, ctx) = common::serve().await;
+    for name in ["alto", "bass", "cello", "drum"] {
+        room::create(&*store, name, None, &Default::default())
+            .await
+            .unwrap();
+        broker::spawn_broker(name.into(), &ctx.rooms, &ctx.store, &Default::default()).await;
+    }
+
+    async fn enter(addr: SocketAddr, name: &str, room: &str) -> Client {
+        let mut client = Client::connect(addr).await.unwrap();
+        client.set_username(name).await.unwrap();
+        client.join(room).await.unwrap();
+        client
+    }
+    // Once the last to join is heard from, the room's counted everyone
+    async fn heard(last: &mut Client, name: &str, other: &mut Client) {
+        last.send("hi").await.unwrap();
+        let hi = ServerEvent::Chat {
+            user: name.into(),
+            text: "hi".into(),
+        };
+        while other.next_event().await.unwrap() != hi {}
+    }
+    let mut alice = enter(addr, "alice", "alto").await;
+    let mut dave = enter(addr, "dave", "alto").await;
+    heard(&mut dave, "dave", &mut alice).await;
+    let mut bob = enter(addr, "bob", "bass").await;
+    let _carol = enter(addr, "carol", "bass").await;
+    let mut erin = enter(addr, "erin", "bass").await;
+    heard(&mut erin, "erin", &mut bob).await;
+
+    let mut frank = Client::connect(addr).await.unwrap();
+    frank.send(">list sort:members").await.unwrap();
+    let mut listed = vec![];
+    for _ in 0..4 {
+        listed.push(frank.next_event().await.unwrap().to_string());
+    }
+    // Then the most recently active
+    assert_eq!(listed, ["bass", "alto", "drum", "cello"]);
+
+    frank.send(">list sort:size").await.unwrap();
+    let error = frank.next_event().await.unwrap().to_string();
+    assert_eq!(error, "[E_INVALID_ARGUMENT] 'size' isn't a valid sort for '>list', pick one of name, members, activity or created");
+}
+
+#[tokio::test]
+async fn my_rooms() {
+    let (addr, store, _) = common::serve().await;
+
+    // Sends the command, then returns the lines up to the expected one
+    async fn lines_until(client: &mut Client, command: &str, last: &str) -> Vec<String> {
+        client.send(command).await.unwrap();
+        let mut lines = vec![];
+        loop {
+            let line = client.next_event().await.unwrap().to_string();
+            lines.push(line.clone());
+            if line == last {
+                return lines;
+            }
+        }
+    }
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    for name in ["go", "old", "zig"] {
+        alice.create_room(name).await.unwrap();
+    }
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    expect(&mut bob, ">join-room go", LIVE).await;
+    expect(&mut bob, ">join-room old", LIVE).await;
+    expect(&mut bob, ">join-room rust", LIVE).await;
+
+    let names = |rooms: &[MyRoom]| rooms.iter().map(|r| r.name.clone()).collect::<Vec<_>>();
+    let (owned, joined) = room::my_rooms(&*store, "bob").await.unwrap();
+    assert_eq!(names(&owned), ["rust"]);
+    let mut joined = names(&joined);
+    joined.sort();
+    assert_eq!(joined, ["go", "old"]);
+    let (owned, joined) = room::my_rooms(&*store, "alice").await.unwrap();
+    assert_eq!(owned.len(), 3);
+    assert!(joined.is_empty());
+
+    // Works outside rooms too, with who's in each
+    bob.send(">leave").await.unwrap();
+    room::delete(&*store, "old").await.unwrap();
+    let lines = lines_until(&mut bob, ">my-rooms", "  (deleted) old").await;
+    let lines: Vec<&str> = lines
+        .iter()
+        .map(String::as_str)
+        .skip_while(|l| *l != "Rooms you own:")
+        .collect();
+    assert!(
+        lines[1].starts_with("  rust - 0 here, active "),
+        "{}",
+        lines[1]
+    );
+    assert_eq!(lines[2], "Rooms you've joined:");
+    assert!(
+        lines[3].starts_with("  go - 0 here, active "),
+        "{}",
+        lines[3]
+    );
+
+    // The deleted room's been pruned
+    let (_, joined) = room::my_rooms(&*store, "bob").await.unwrap();
+    assert_eq!(names(&joined), ["go"]);
+
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    expect(
+        &mut carol,
+        ">my-rooms",
+        "You haven't created or joined any rooms",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn pick_random() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    let none = "No rooms to join, why not create one with >create-room name --join";
+    expect(&mut bob, ">random-room", none).await;
+
+    bob.create_room("rust").await.unwrap();
+    expect(&mut bob, ">random-room", "Picked 'rust' at random").await;
+    until(&mut bob, LIVE).await;
+    // Never the room they're already in
+    expect(&mut bob, ">random-room", none).await;
+}
+
+#[tokio::test]
+async fn remove_message() {
+    let (addr, store, _) = common::serve().await;
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+    let mut bob = connect(addr, "bob").await;
+    let mut carol = connect(addr, "carol").await;
+    common::register(&mut carol, "carol").await;
+    alice.send(">mod add carol").await.unwrap();
+
+    bob.send("something offensive").await.unwrap();
+    let offensive = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "something offensive".into(),
+    };
+    while carol.next_event().await.unwrap() != offensive {}
+
+    // Ids come with the history
+    carol.send(">history --ids").await.unwrap();
+    let id = loop {
+        let line = carol.next_event().await.unwrap().to_string();
+        if let Some(id) = line.strip_suffix("] bob: something offensive") {
+            break id.trim_start_matches('[').to_owned();
+        }
+    };
+
+    // Only moderators and the owner can remove them
+    assert_eq!(
+        refused(&mut bob, &format!(">delete-msg {}", id)).await.0,
+        Code::Forbidden
+    );
+    carol.send(&format!(">delete-msg {}", id)).await.unwrap();
+    let removed = ServerEvent::Info(format!("Removed message {}", id));
+    while carol.next_event().await.unwrap() != removed {}
+
+    // Whoever's there is told
+    while alice.next_event().await.unwrap() != ServerEvent::Removed("carol".into()) {}
+
+    // Only chat can be removed, and only once
+    let (already, _) = refused(&mut alice, &format!(">delete-msg {}", id)).await;
+    assert_eq!(already, Code::InvalidArgument);
+    assert_eq!(
+        refused(&mut alice, ">delete-msg 0").await.0,
+        Code::InvalidArgument
+    );
+    assert_eq!(
+        refused(&mut alice, ">delete-msg 42").await.0,
+        Code::MessageNotFound
+    );
+
+    // Replayed in its place
+    let mut dave = Client::connect(addr).await.unwrap();
+    dave.set_username("dave").await.unwrap();
+    dave.join("rust").await.unwrap();
+    let mut history = vec![];
+    loop {
+        match dave.next_event().await.unwrap() {
+            event if event.to_string() == LIVE => break,
+            event => history.push(event),
+        }
+    }
+    assert!(history.contains(&ServerEvent::Removed("carol".into())));
+    assert!(!history.contains(&offensive));
+    dave.send(">history").await.unwrap();
+    while dave.next_event().await.unwrap() != ServerEvent::Removed("carol".into()) {}
+
+    let entry = &audit::recent(&*store, 1).await.unwrap()[0];
+    assert_eq!(entry.action, AuditAction::DeleteMessage);
+    assert_eq!(
+        (entry.actor.as_str(), entry.target.as_deref()),
+        ("carol", Some(id.as_str()))
+    );
+    assert_eq!(entry.reason.as_deref(), Some("bob: something offensive"));
+}
+
+#[tokio::test]
+async fn retract_message() {
+    let (addr, store, ctx) = common::serve().await;
+
+    let chat = |user: &str, text: &str| ServerEvent::Chat {
+        user: user.into(),
+        text: text.into(),
+    };
+    let retracted = ServerEvent::Retracted("bob".into());
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+    let nothing = "[E_MESSAGE_NOT_FOUND] You haven't sent a message in this room to undo";
+    expect(&mut bob, ">undo", nothing).await;
+
+    // Everyone there sees the note, and so does anyone joining later
+    bob.send("wrong room, sorry").await.unwrap();
+    while alice.next_event().await.unwrap() != chat("bob", "wrong room, sorry") {}
+    expect(&mut bob, ">undo", "Message removed").await;
+    assert_eq!(alice.next_event().await.unwrap(), retracted);
+    assert_eq!(
+        store.recent("rust", 1).await.unwrap(),
+        ["bob removed their message\n"]
+    );
+
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    carol.join("rust").await.unwrap();
+    let mut history = vec![];
+    loop {
+        match carol.next_event().await.unwrap() {
+            event if event.to_string() == LIVE => break,
+            event => history.push(event),
+        }
+    }
+    assert!(history.contains(&retracted));
+    assert!(!history.contains(&chat("bob", "wrong room, sorry")));
+
+    // Only the once, and `>delete-last` is the same thing
+    let removed = "[E_INVALID_ARGUMENT] Message ";
+    bob.send(">delete-last").await.unwrap();
+    loop {
+        let line = bob.next_event().await.unwrap().to_string();
+        if line.starts_with(removed) {
+            assert!(line.ends_with(" has already been removed"));
+            break;
+        }
+    }
+
+    // Nor once a moderator's removed it
+    bob.send("oops").await.unwrap();
+    alice.send(">history --ids").await.unwrap();
+    let id = loop {
+        let line = alice.next_event().await.unwrap().to_string();
+        if let Some(id) = line.strip_suffix("] bob: oops") {
+            break id.trim_start_matches('[').to_owned();
+        }
+    };
+    expect(
+        &mut alice,
+        &format!(">delete-msg {}", id),
+        &format!("Removed message {}", id),
+    )
+    .await;
+    let removed = format!(
+        "[E_INVALID_ARGUMENT] Message {} has already been removed",
+        id
+    );
+    expect(&mut bob, ">undo", &removed).await;
+
+    // Nor once it's been too long
+    ctx.config.store(Arc::new(
+        RuntimeConfig::parse("edit_window_secs = 1").unwrap(),
+    ));
+    bob.send("too late").await.unwrap();
+    while alice.next_event().await.unwrap() != chat("bob", "too late") {}
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let expired = "[E_EDIT_EXPIRED] Messages can only be removed for 1s after they're sent";
+    expect(&mut bob, ">undo", expired).await;
+
+    // Or at all, when it's off
+    ctx.config.store(Arc::new(
+        RuntimeConfig::parse("edit_window_secs = 0").unwrap(),
+    ));
+    bob.send("again").await.unwrap();
+    expect(
+        &mut bob,
+        ">undo",
+        "[E_FORBIDDEN] Removing messages is turned off",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn edit_message() {
+    let (addr, store, ctx) = common::serve().await;
+
+    let chat = |user: &str, text: &str| ServerEvent::Chat {
+        user: user.into(),
+        text: text.into(),
+    };
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+    let nothing = "[E_MESSAGE_NOT_FOUND] You haven't sent a message in this room to edit";
+    expect(&mut bob, ">edit hi", nothing).await;
+
+    // Everyone there sees the new version, and so does anyone joining later
+    bob.send("helo").await.unwrap();
+    while alice.next_event().await.unwrap() != chat("bob", "helo") {}
+    expect(&mut bob, ">edit hello", "Message edited").await;
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        chat("bob", "hello (edited)")
+    );
+    assert_eq!(
+        store.recent("rust", 1).await.unwrap(),
+        ["bob: hello (edited)\n"]
+    );
+    expect(&mut bob, ">edit hello all", "Message edited").await;
+    assert_eq!(
+        alice.next_event().await.unwrap(),
+        chat("bob", "hello all (edited)")
+    );
+
+    // Held back when muted, as messages are
+    ctx.mutes.insert(
+        "bob".into(),
+        std::time::Instant::now() + Duration::from_secs(60),
+    );
+    let muted = "[E_RATE_LIMITED] You're muted for repeating messages, try again in 60s";
+    expect(&mut bob, ">edit hello muted", muted).await;
+    ctx.mutes.remove("bob");
+
+    let mut carol = Client::connect(addr).await.unwrap();
+    carol.set_username("carol").await.unwrap();
+    carol.join("rust").await.unwrap();
+    let mut history = vec![];
+    loop {
+        match carol.next_event().await.unwrap() {
+            event if event.to_string() == LIVE => break,
+            event => history.push(event),
+        }
+    }
+    assert!(history.contains(&chat("bob", "hello all (edited)")));
+    assert!(!history.contains(&chat("bob", "helo")));
+
+    // Not once a moderator's removed it
+    alice.send(">history --ids").await.unwrap();
+    let id = loop {
+        let line = alice.next_event().await.unwrap().to_string();
+        if let Some(id) = line.strip_suffix("] bob: hello all (edited)") {
+            break id.trim_start_matches('[').to_owned();
+        }
+    };
+    expect(
+        &mut alice,
+        &format!(">delete-msg {}", id),
+        &format!("Removed message {}", id),
+    )
+    .await;
+    let removed = format!(
+        "[E_INVALID_ARGUMENT] Message {} has already been removed",
+        id
+    );
+    expect(&mut bob, ">edit hello?", &removed).await;
+
+    // Nor once it's been too long
+    ctx.config.store(Arc::new(
+        RuntimeConfig::parse("edit_window_secs = 1").unwrap(),
+    ));
+    bob.send("tpyo").await.unwrap();
+    while alice.next_event().await.unwrap() != chat("bob", "tpyo") {}
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let expired = "[E_EDIT_EXPIRED] Messages can only be edited for 1s after they're sent";
+    expect(&mut bob, ">edit typo", expired).await;
+
+    // Or at all, when it's off
+    ctx.config.store(Arc::new(
+        RuntimeConfig::parse("edit_window_secs = 0").unwrap(),
+    ));
+    bob.send("again").await.unwrap();
+    expect(
+        &mut bob,
+        ">edit again!",
+        "[E_FORBIDDEN] Editing messages is turned off",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn purge_user() {
+    let (addr, store, _) = common::serve().await;
+    for name in ["rust", "go", "zig"] {
+        store.create(name).await.unwrap();
+    }
+    store.append("go", "bob: hi\n", 1, None).await.unwrap();
+
+    // Admins purge every room, hearing about each as it's done
+    store
+        .append("rust", "bob: again\n", 2000, None)
+        .await
+        .unwrap();
+    roles::grant(&*store, "alice").await.unwrap();
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.send(">purge-user bob").await.unwrap();
+    let mut progress = vec![];
+    loop {
+        let line = alice.next_event().await.unwrap().to_string();
+        if line.starts_with("Purge of bob finished") {
+            assert_eq!(line, "Purge of bob finished, 2 message(s) in 3 room(s)");
+            break;
+        }
+        progress.push(line);
+    }
+    assert!(progress.contains(&"Purging bob's messages from 3 room(s)".to_owned()));
+    assert!(progress.contains(&"Purged 1 message(s) from bob in rust".to_owned()));
+    assert!(progress.contains(&"Purged 1 message(s) from bob in go".to_owned()));
+    assert_eq!(
+        store.recent("go", 1).await.unwrap(),
+        ["message removed by alice\n"]
+    );
+
+    let entry = &audit::recent(&*store, 1).await.unwrap()[0];
+    assert_eq!(entry.action, AuditAction::PurgeUser);
+    assert_eq!(entry.target.as_deref(), Some("bob"));
+    assert_eq!(entry.reason.as_deref(), Some("2 message(s)"));
+
+    // Or just one
+    alice.send(">purge-user bob nowhere").await.unwrap();
+    let not_found = ServerEvent::parse("[E_ROOM_NOT_FOUND] Room not found");
+    while alice.next_event().await.unwrap() != not_found {}
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use chatsapp::access::DENY_KEY;
+use chatsapp::config::RuntimeConfig;
+use chatsapp::server::ServerContext;
+use chatsapp::shutdown;
+use chatsapp::store::{MemoryStore, RoomStore};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::common;
+
+#[tokio::test]
+async fn drain() {
+    let (addr, _, ctx) = common::serve().await;
+    let config = RuntimeConfig::parse("shutdown_countdown_secs = 1").unwrap();
+    ctx.config.store(Arc::new(config));
+
+    let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+    let mut lines = BufReader::new(reader).lines();
+    writer.write_all(b">set-username bob\n").await.unwrap();
+    while lines.next_line().await.unwrap().unwrap() != "Username set to 'bob'" {}
+
+    ctx.shutdown.trigger();
+    let draining = tokio::spawn({
+        let ctx = Arc::clone(&ctx);
+        async move { ctx.drain().await }
+    });
+    let warning = lines.next_line().await.unwrap().unwrap();
+    assert_eq!(warning, "[SERVER] Shutting down in 1 second");
+
+    // No new rooms in the meantime
+    writer.write_all(b">create-room rust\n").await.unwrap();
+    let refused = lines.next_line().await.unwrap().unwrap();
+    assert_eq!(refused, "[E_SHUTTING_DOWN] Server is shutting down");
+
+    // Then the connection's closed, rather than dropped
+    assert_eq!(
+        lines.next_line().await.unwrap().unwrap(),
+        "[SERVER] Goodbye"
+    );
+    assert_eq!(lines.next_line().await.unwrap(), None);
+    draining.await.unwrap();
+    assert!(ctx.registry.is_empty());
+}
+
+#[tokio::test]
+async fn permits() {
+    let store = Arc::new(MemoryStore::default());
+    store.set_add(DENY_KEY, "10.6.6.0/24").await.unwrap();
+    let (trigger, shutdown) = shutdown::channel();
+    let mut ctx = ServerContext::new(store.clone(), trigger);
+    ctx.proxy_protocol = true;
+    let config = RuntimeConfig::parse(r#"access = { allow = ["10.0.0.0/8", "2001:db8::/32"] }"#);
+    ctx.config.store(Arc::new(config.unwrap()));
+    let ctx = Arc::new(ctx);
+    let addr = common::listen(Arc::clone(&ctx), shutdown).await;
+
+    // The first line back, having come through the proxy with `header`
+    async fn first_line(addr: std::net::SocketAddr, header: &str) -> String {
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        writer.write_all(header.as_bytes()).await.unwrap();
+        writer.write_all(b">set-username bob\n").await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            let line = lines.next_line().await.unwrap().unwrap();
+            if line == "Access denied" || line == "Username set to 'bob'" {
+                return line;
+            }
+        }
+    }
+    let tcp4 = |source: &str| format!("PROXY TCP4 {} 127.0.0.1 51234 8000\r\n", source);
+    let tcp6 = |source: &str| format!("PROXY TCP6 {} ::1 51234 8000\r\n", source);
+
+    assert_eq!(
+        first_line(addr, &tcp4("10.1.2.3")).await,
+        "Username set to 'bob'"
+    );
+    assert_eq!(
+        first_line(addr, &tcp6("2001:db8::7")).await,
+        "Username set to 'bob'"
+    );
+    assert_eq!(
+        first_line(addr, &tcp4("203.0.113.7")).await,
+        "Access denied"
+    );
+    assert_eq!(
+        first_line(addr, &tcp6("2001:db9::7")).await,
+        "Access denied"
+    );
+    // The set's deny wins over the config's allow
+    assert_eq!(first_line(addr, &tcp4("10.6.6.6")).await, "Access denied");
+    // Without a source it's the proxy itself, which isn't allowed
+    assert_eq!(first_line(addr, "PROXY UNKNOWN\r\n").await, "Access denied");
+
+    // Reloads apply straight away, an empty allowlist letting everyone in
+    ctx.config.store(Arc::new(RuntimeConfig::default()));
+    assert_eq!(
+        first_line(addr, &tcp4("203.0.113.7")).await,
+        "Username set to 'bob'"
+    );
+    assert_eq!(first_line(addr, &tcp4("10.6.6.6")).await, "Access denied");
+}
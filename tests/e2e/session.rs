@@ -0,0 +1,80 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::config::RuntimeConfig;
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn create() {
+    let (addr, _, ctx) = common::serve().await;
+
+    // Asks for a session, returning its token
+    async fn session(client: &mut Client) -> String {
+        client.command(">session".parse().unwrap()).await.unwrap();
+        loop {
+            if let ServerEvent::Info(line) = client.next_event().await.unwrap() {
+                if let Some(rest) = line.strip_prefix("Session token ") {
+                    break rest.split(',').next().unwrap().to_owned();
+                }
+            }
+        }
+    }
+
+    // Resumes on a new connection, returning its first reply
+    async fn resume(addr: std::net::SocketAddr, token: &str) -> (Client, String) {
+        let mut client = Client::connect(addr).await.unwrap();
+        client
+            .command(format!(">resume {}", token).parse().unwrap())
+            .await
+            .unwrap();
+        loop {
+            let line = client.next_event().await.unwrap().to_string();
+            if line.starts_with("Resumed") || line.starts_with("[E_SESSION_EXPIRED]") {
+                break (client, line);
+            }
+        }
+    }
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    until(&mut alice, LIVE).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.join("rust").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Joined("bob".into()) {}
+    let token = session(&mut bob).await;
+
+    // Dropping and coming straight back goes unnoticed by the room
+    drop(bob);
+    let (mut bob, line) = resume(addr, &token).await;
+    assert!(line.starts_with("Resumed as bob in rust, your new session token is "));
+    bob.send("back").await.unwrap();
+    let back = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "back".into(),
+    };
+    assert_eq!(alice.next_event().await.unwrap(), back);
+
+    // Tokens only work once
+    let (_, line) = resume(addr, &token).await;
+    assert_eq!(
+        line,
+        "[E_SESSION_EXPIRED] Session expired, set your username again"
+    );
+
+    // Or until they expire
+    let config = RuntimeConfig::parse("session_ttl_secs = 1").unwrap();
+    ctx.config.store(Arc::new(config));
+    let token = session(&mut bob).await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let (_, line) = resume(addr, &token).await;
+    assert_eq!(
+        line,
+        "[E_SESSION_EXPIRED] Session expired, set your username again"
+    );
+}
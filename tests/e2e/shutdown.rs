@@ -0,0 +1,43 @@
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::errors::Code;
+use chatsapp::roles;
+
+use crate::common;
+
+#[tokio::test]
+async fn shutdown_request() {
+    let (addr, store, ctx) = common::serve().await;
+    roles::seed(&*store, &["alice".to_owned()]).await.unwrap();
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    while bob.next_event().await.unwrap() != ServerEvent::Info("Username set to 'bob'".into()) {}
+
+    // Notices go to everyone, without shutting down
+    alice.send(">notice Restarting soon").await.unwrap();
+    let notice = ServerEvent::Info("[SERVER] Restarting soon".into());
+    assert_eq!(bob.next_event().await.unwrap(), notice);
+    assert!(!ctx.shutdown.is_triggered());
+
+    // Only admins can do either
+    for line in [">notice hi", ">shutdown"] {
+        bob.send(line).await.unwrap();
+        let ServerEvent::Error { code, .. } = bob.next_event().await.unwrap() else {
+            panic!("'{}' wasn't refused", line);
+        };
+        assert_eq!(code, Code::Forbidden);
+    }
+
+    alice.send(">shutdown 1 upgrading redis").await.unwrap();
+    while alice.next_event().await.unwrap() != ServerEvent::Info("Shutting down".into()) {}
+    ctx.drain().await;
+    let warning = ServerEvent::Info("[SERVER] Shutting down in 1 second: upgrading redis".into());
+    assert_eq!(bob.next_event().await.unwrap(), warning);
+    assert_eq!(
+        bob.next_event().await.unwrap(),
+        ServerEvent::Info("[SERVER] Goodbye".into())
+    );
+}
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::server::ServerContext;
+use chatsapp::shutdown;
+use chatsapp::sqlite::SqliteStore;
+use chatsapp::store::RoomStore;
+use tokio::time;
+
+use crate::common::{self, until, LIVE};
+
+#[tokio::test]
+async fn sqlite_store() {
+    let path = std::env::temp_dir().join(format!("chatsapp-{}.db", std::process::id()));
+    let store = Arc::new(SqliteStore::open(&path).unwrap());
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = ServerContext::new(store.clone(), trigger);
+    let addr = common::listen(Arc::new(ctx), shutdown).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.set_username("bob").await.unwrap();
+    bob.create_room("rust").await.unwrap();
+    bob.join("rust").await.unwrap();
+    until(&mut bob, LIVE).await;
+    bob.send("hi").await.unwrap();
+    bob.send(">leave").await.unwrap();
+    // The broker writes it in its own time
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.recent("rust", 10).await.unwrap().len() < 4 {
+        assert!(Instant::now() < deadline, "history wasn't written in time");
+        time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Still there when the file's opened again
+    drop(store);
+    let store = SqliteStore::open(&path).unwrap();
+    let history = [
+        "Start of chat\n",
+        "bob has joined the room\n",
+        "bob: hi\n",
+        "bob has left the room\n",
+    ];
+    assert_eq!(store.recent("rust", 10).await.unwrap(), history);
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    alice.join("rust").await.unwrap();
+    let hi = ServerEvent::Chat {
+        user: "bob".into(),
+        text: "hi".into(),
+    };
+    while alice.next_event().await.unwrap() != hi {}
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::command::Command;
+use chatsapp::metrics::metrics;
+use chatsapp::server::ServerContext;
+use chatsapp::shutdown;
+use chatsapp::store::{MemoryStore, RoomStore, SlowStore, TimedStore};
+
+use crate::common::{self, LIVE};
+
+#[tokio::test]
+async fn memory_store() {
+    let (addr, _, _) = common::serve().await;
+
+    let mut client = Client::connect(addr).await.unwrap();
+    client.set_username("bob").await.unwrap();
+    client.create_room("rust").await.unwrap();
+    client.join("rust").await.unwrap();
+    let set = ServerEvent::Info("Username set to 'bob'".into());
+    assert_eq!(client.next_event().await.unwrap(), set);
+    let created = ServerEvent::Info("Room 'rust' created, join it with >join-room rust".into());
+    assert_eq!(client.next_event().await.unwrap(), created);
+
+    // History is replayed on join
+    let joined = ServerEvent::Info("Joined 'rust' — 1 member".into());
+    assert_eq!(client.next_event().await.unwrap(), joined);
+    assert_eq!(
+        client.next_event().await.unwrap(),
+        ServerEvent::Info("Start of chat".into())
+    );
+    assert_eq!(client.next_event().await.unwrap().to_string(), LIVE);
+
+    client
+        .command(Command::List(Default::default()))
+        .await
+        .unwrap();
+    assert_eq!(
+        client.next_event().await.unwrap(),
+        ServerEvent::Info("rust".into())
+    );
+}
+
+#[tokio::test]
+async fn timed_store() {
+    let slow = SlowStore::new(MemoryStore::default(), Duration::from_millis(5));
+    let store = TimedStore::new(slow, Some(Duration::from_millis(1)));
+
+    store.create("rust").await.unwrap();
+    for text in ["one", "two", "three"] {
+        store
+            .append("rust", &format!("bob: {}\n", text), 1, None)
+            .await
+            .unwrap();
+    }
+    store.recent("rust", 10).await.unwrap();
+
+    let histogram = |op: &str| {
+        metrics()
+            .redis_latency
+            .with_label_values(&[op])
+            .get_sample_count()
+    };
+    assert_eq!(histogram("create"), 1);
+    assert_eq!(histogram("zadd"), 3);
+    assert_eq!(histogram("zrevrange"), 1);
+
+    // Along with percentiles over the last 5 minutes
+    let zadd = metrics()
+        .redis_summary()
+        .into_iter()
+        .find(|op| op.op == "zadd")
+        .unwrap();
+    assert_eq!(zadd.count, 3);
+    assert!(zadd.p50 >= Duration::from_millis(5));
+    assert!(zadd.p99 >= zadd.p50);
+
+    // Which `>stats` shows
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = ServerContext::new(Arc::new(store), trigger);
+    let addr = common::listen(Arc::new(ctx), shutdown).await;
+
+    let mut bob = Client::connect(addr).await.unwrap();
+    bob.send(">stats").await.unwrap();
+    let mut stats = Vec::new();
+    while !stats.iter().any(|line: &String| line.contains("zadd:")) {
+        stats.push(bob.next_event().await.unwrap().to_string());
+    }
+    assert!(stats
+        .iter()
+        .any(|line| line.contains("Redis, last 5 minutes:")));
+    assert!(stats.last().unwrap().contains("zadd: 3 ops, p50 "));
+}
@@ -0,0 +1,27 @@
+use std::net::SocketAddr;
+
+use chatsapp::systemd;
+
+#[tokio::test]
+async fn listeners() {
+    // Nothing handed over by systemd, so each address is bound instead
+    let binds: Vec<SocketAddr> = vec![
+        "127.0.0.1:0".parse().unwrap(),
+        "127.0.0.1:0".parse().unwrap(),
+    ];
+    let listeners = systemd::listeners(&binds).await.unwrap();
+    let bound: Vec<_> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+    assert_eq!(bound.len(), 2);
+    assert!(bound.iter().all(|addr| addr.port() != 0));
+    assert_ne!(bound[0], bound[1]);
+
+    // Binding an address that's in use fails them all
+    let taken = bound[0];
+    let e = systemd::listeners(&["127.0.0.1:0".parse().unwrap(), taken])
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+    assert!(e
+        .to_string()
+        .starts_with(&format!("Failed to bind {}", taken)));
+}
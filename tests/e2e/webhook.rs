@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use chatsapp::client::{Client, ServerEvent};
+use chatsapp::http::{self, Health, HttpState};
+use chatsapp::shutdown;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::common;
+
+#[tokio::test]
+async fn post_to_room() {
+    let (addr, store, ctx) = common::serve().await;
+    let http_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = http_listener.local_addr().unwrap();
+    let state = HttpState::new(store, Arc::new(Health::default()));
+    state.server.set(ctx).ok().unwrap();
+    let (_trigger, shutdown) = shutdown::channel();
+    tokio::spawn(http::listen(http_listener, Arc::new(state), shutdown));
+
+    async fn post(addr: std::net::SocketAddr, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    let mut alice = Client::connect(addr).await.unwrap();
+    alice.set_username("alice").await.unwrap();
+    common::register(&mut alice, "alice").await;
+    alice.create_room("rust").await.unwrap();
+    alice.join("rust").await.unwrap();
+    alice
+        .command(">webhook create ci".parse().unwrap())
+        .await
+        .unwrap();
+    let token = loop {
+        if let ServerEvent::Info(line) = alice.next_event().await.unwrap() {
+            if let Some(rest) = line.strip_prefix("Webhook ci created, POST to /hooks/") {
+                break rest.split_whitespace().next().unwrap().to_owned();
+            }
+        }
+    };
+
+    let path = format!("/hooks/{}", token);
+    let res = post(http_addr, &path, r#"{"text": "build failed"}"#).await;
+    assert!(res.starts_with("HTTP/1.1 200 OK"));
+    let chat = ServerEvent::Chat {
+        user: "ci".into(),
+        text: "build failed".into(),
+    };
+    while alice.next_event().await.unwrap() != chat {}
+
+    let res = post(http_addr, "/hooks/nope", r#"{"text": "hi"}"#).await;
+    assert!(res.starts_with("HTTP/1.1 404 Not Found"));
+    let big = format!(r#"{{"text": "{}"}}"#, "a".repeat(5000));
+    assert!(post(http_addr, &path, &big)
+        .await
+        .starts_with("HTTP/1.1 413"));
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use chatsapp::server::ServerContext;
+use chatsapp::store::MemoryStore;
+use chatsapp::{shutdown, ws};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::test]
+async fn greeting_and_exit() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (trigger, shutdown) = shutdown::channel();
+    let ctx = ServerContext::new(Arc::new(MemoryStore::default()), trigger);
+    tokio::spawn(ws::listen(listener, Arc::new(ctx), shutdown));
+
+    let url = format!("ws://{}", addr);
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+    // Every line of the greeting arrives as its own frame
+    let first = socket.next().await.unwrap().unwrap();
+    assert_eq!(first.to_text().unwrap(), "Welcome to ChatsApp!");
+    for _ in 0..4 {
+        socket.next().await.unwrap().unwrap();
+    }
+
+    socket.send(Message::text(">me")).await.unwrap();
+    let me = socket.next().await.unwrap().unwrap();
+    assert!(me.to_text().unwrap().starts_with("Username: guest-"));
+
+    // Exiting closes the socket from the server side
+    socket.send(Message::text(">exit")).await.unwrap();
+    while let Some(Ok(frame)) = socket.next().await {
+        if frame.is_close() {
+            break;
+        }
+    }
+}